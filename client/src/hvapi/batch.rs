@@ -0,0 +1,135 @@
+//! Batched command submission.
+//!
+//! A `CommandBatch` collects commands and submits them with a single CPUID exit per `MAX_BATCH_SIZE`
+//! entries, returning the outcome of each command in the order it was added.
+
+use {
+    crate::hvapi::{error::HypervisorApiError, HypervisorCommunicator},
+    shared::{BatchData, ClientCommand, Command, CommandResult, Pod, ProcessMemoryOperation, SessionKey, MAX_BATCH_SIZE},
+    std::marker::PhantomData,
+};
+
+/// A command that can be signed and submitted as part of a batch.
+trait BatchEntry {
+    /// Stamps the command with `nonce` and signs it with the session key.
+    fn sign(&mut self, key: &SessionKey, nonce: u64);
+
+    /// Returns the address of the command.
+    fn as_ptr(&self) -> u64;
+}
+
+impl<T: Pod> BatchEntry for ClientCommand<T> {
+    fn sign(&mut self, key: &SessionKey, nonce: u64) {
+        ClientCommand::sign(self, key, nonce)
+    }
+
+    fn as_ptr(&self) -> u64 {
+        ClientCommand::as_ptr(self)
+    }
+}
+
+/// A batch of commands against an opened process.
+///
+/// The lifetime ties the batch to the client buffers it reads from or writes to, which must stay
+/// alive until the batch has been submitted.
+pub struct CommandBatch<'a> {
+    process_cr3: u64,
+    entries: Vec<Box<dyn BatchEntry>>,
+    _buffers: PhantomData<&'a mut [u8]>,
+}
+
+impl<'a> CommandBatch<'a> {
+    /// Creates an empty batch against the process with the given CR3.
+    pub(crate) fn new(process_cr3: u64) -> Self {
+        Self {
+            process_cr3,
+            entries: Vec::new(),
+            _buffers: PhantomData,
+        }
+    }
+
+    /// Queues a read of `buffer.len()` bytes from `address` in the opened process.
+    pub fn read_process_memory(&mut self, address: u64, buffer: &'a mut [u8]) -> &mut Self {
+        self.push_memory_operation(Command::ReadProcessMemory, address, buffer.as_mut_ptr() as u64, buffer.len())
+    }
+
+    /// Queues a write of `buffer` to `address` in the opened process.
+    pub fn write_process_memory(&mut self, address: u64, buffer: &'a [u8]) -> &mut Self {
+        self.push_memory_operation(Command::WriteProcessMemory, address, buffer.as_ptr() as u64, buffer.len())
+    }
+
+    /// Returns the number of queued commands.
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Returns `true` if no commands are queued.
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Submits the batch and returns the outcome of each command, in the order they were queued.
+    ///
+    /// The outer `Result` fails only if a batch as a whole was rejected, for example because the
+    /// session could not be opened; individual command failures are reported in the returned vector.
+    pub fn submit(mut self) -> Result<Vec<Result<(), HypervisorApiError>>, HypervisorApiError> {
+        let mut outcomes = Vec::with_capacity(self.entries.len());
+
+        for chunk in self.entries.chunks_mut(MAX_BATCH_SIZE as usize) {
+            let results = Self::submit_chunk(chunk)?;
+            outcomes.extend(results.iter().map(HypervisorCommunicator::decode_command_result));
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Queues a read or write command for the opened process.
+    fn push_memory_operation(&mut self, command: Command, address: u64, buffer: u64, buffer_size: usize) -> &mut Self {
+        let memory_operation = ProcessMemoryOperation {
+            process_id: 0,
+            guest_cr3: self.process_cr3,
+            address,
+            buffer,
+            buffer_size: buffer_size as u64,
+            transferred: 0,
+        };
+
+        self.entries.push(Box::new(ClientCommand::new(command, memory_operation)));
+        self
+    }
+
+    /// Signs and submits up to `MAX_BATCH_SIZE` commands in a single VM exit.
+    fn submit_chunk(chunk: &mut [Box<dyn BatchEntry>]) -> Result<Vec<CommandResult>, HypervisorApiError> {
+        HypervisorCommunicator::with_session(|session| {
+            // The batch consumes the first nonce, its entries the following ones in order.
+            let batch_nonce = session.next_nonce;
+            for (index, entry) in chunk.iter_mut().enumerate() {
+                entry.sign(&session.key, batch_nonce + 1 + index as u64);
+            }
+            session.next_nonce += chunk.len() as u64 + 1;
+
+            let commands: Vec<u64> = chunk.iter().map(|entry| entry.as_ptr()).collect();
+            let mut results = vec![
+                CommandResult {
+                    status: 0,
+                    error_code: 0,
+                    protocol_version: 0,
+                };
+                chunk.len()
+            ];
+
+            let batch_data = BatchData {
+                commands: commands.as_ptr() as u64,
+                results: results.as_mut_ptr() as u64,
+                count: chunk.len() as u64,
+            };
+
+            let mut client_command = ClientCommand::new(Command::Batch, batch_data);
+            client_command.sign(&session.key, batch_nonce);
+
+            HypervisorCommunicator::decode_result(HypervisorCommunicator::call_hypervisor(client_command.as_ptr()))?;
+
+            Ok(results)
+        })
+    }
+}
@@ -0,0 +1,28 @@
+use {shared::ErrorCode, thiserror::Error};
+
+#[derive(Error, Debug)]
+pub enum HypervisorApiError {
+    #[error("Hypervisor failed to handle the command: {0:?}")]
+    Hypervisor(ErrorCode),
+
+    #[error("Hypervisor failed with an unknown error code: {0:#x}")]
+    UnknownErrorCode(u64),
+
+    #[error("Hypervisor returned an unknown command status: {0:#x}")]
+    UnknownStatus(u64),
+
+    #[error("Hypervisor speaks protocol version {hypervisor}, but this client was built for version {client}")]
+    VersionMismatch { hypervisor: u64, client: u32 },
+
+    #[error("Only {copied:#x} of {requested:#x} bytes were copied before reaching a page that is not present")]
+    PartialCopy { copied: u64, requested: u64 },
+
+    #[error("Invalid process name: {0}")]
+    InvalidProcessName(String),
+
+    #[error("Invalid pattern: {0}")]
+    InvalidPattern(String),
+
+    #[error("Failed to find the syscall number for: {0}")]
+    SyscallNotFound(String),
+}
@@ -5,10 +5,22 @@
 
 #![allow(dead_code)]
 
+pub mod batch;
+pub mod error;
+
 use {
-    crate::{pemem::djb2_hash, ssn::Syscall},
-    shared::{ClientCommand, ClientDataPayload, Command, HookData, ProcessMemoryOperation, PASSWORD},
-    std::arch::asm,
+    crate::{
+        hvapi::{batch::CommandBatch, error::HypervisorApiError},
+        pemem::djb2_hash,
+        ssn::Syscall,
+    },
+    shared::{
+        AddressHookData, AddressTranslation, ClientCommand, CloseSessionData, Command, CommandResult, CommandStatus, ErrorCode, HookData, HookEntry,
+        HookKind, HookStatistics, HypervisorInfo, InfoData, ListData, ModuleEntry, ModuleListData, OpenProcessData, OpenedProcess, Pattern,
+        PatternScanData, PhysicalMemoryOperation, Pod, ProcessEntry, ProcessMemoryOperation, SessionData, SessionKey, TranslationData,
+        HYPERCALL_LEAF, MAX_PHYSICAL_MEMORY_SIZE, PROTOCOL_VERSION,
+    },
+    std::{arch::asm, sync::Mutex},
 };
 
 /// Struct to encapsulate the result of a CPUID instruction.
@@ -20,85 +32,403 @@ pub struct CpuidResult {
     pub edx: u64,
 }
 
+/// Number of matches retrieved per `Command::PatternScan`.
+const PATTERN_SCAN_BATCH_SIZE: usize = 256;
+
+/// The session shared with the hypervisor.
+struct ClientSession {
+    /// The key used to sign every command.
+    key: SessionKey,
+    /// The nonce for the next command; the hypervisor rejects any nonce it has already seen.
+    next_nonce: u64,
+}
+
+/// The session of this client, opened lazily by the first command.
+///
+/// The lock is held while a command is in flight so nonces reach the hypervisor in increasing order.
+static SESSION: Mutex<Option<ClientSession>> = Mutex::new(None);
+
 /// Struct representing the hypervisor communicator.
 pub struct HypervisorCommunicator {
-    process_cr3: u64,
+    process: OpenedProcess,
 }
 
 impl HypervisorCommunicator {
     /// Enables a kernel EPT hook by specifying the function name.
-    pub fn enable_ept_kernel_hook(&self, function_name: &str) -> Option<()> {
+    pub fn enable_ept_kernel_hook(&self, function_name: &str) -> Result<(), HypervisorApiError> {
         self.manage_ept_kernel_hook(function_name, Command::EnableKernelEptHook)
     }
 
     /// Disables a kernel EPT hook by specifying the function name.
-    pub fn disable_ept_kernel_hook(&self, function_name: &str) -> Option<()> {
+    pub fn disable_ept_kernel_hook(&self, function_name: &str) -> Result<(), HypervisorApiError> {
         self.manage_ept_kernel_hook(function_name, Command::DisableKernelEptHook)
     }
 
     /// Internal function to manage (enable/disable) kernel EPT hooks.
-    fn manage_ept_kernel_hook(&self, function_name: &str, command: Command) -> Option<()> {
+    fn manage_ept_kernel_hook(&self, function_name: &str, command: Command) -> Result<(), HypervisorApiError> {
         // Lookup the syscall number using the function hash
         let mut syscall = Syscall::new();
         let function_hash = djb2_hash(function_name.as_bytes());
-        let syscall_number = syscall.get_ssn_by_hash(function_hash)?;
+        let syscall_number = syscall
+            .get_ssn_by_hash(function_hash)
+            .ok_or_else(|| HypervisorApiError::SyscallNotFound(function_name.to_string()))?;
 
         log::debug!("Function: {} Syscall number: {}", function_name, syscall_number);
 
         let hook_data = HookData {
             function_hash,
-            syscall_number,
+            syscall_number: syscall_number as u32,
+            module_hash: 0,
+            session_id: 0,
         };
 
-        let client_command = ClientCommand {
-            command,
-            payload: ClientDataPayload::Hook(hook_data),
+        match Self::send_command(command, hook_data) {
+            Ok(()) => {
+                log::debug!("Successfully managed EPT hook for function: {}", function_name);
+                Ok(())
+            }
+            Err(e) => {
+                log::error!("Failed to manage EPT hook for function: {}: {}", function_name, e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Enables an EPT hook on an export of any loaded kernel module, such as `fltmgr.sys` and `FltRegisterFilter`.
+    ///
+    /// The module is looked up by its base name in `PsLoadedModuleList`, ignoring case.
+    pub fn enable_ept_module_hook(&self, module_name: &str, function_name: &str) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_module_hook(module_name, function_name, Command::EnableKernelEptHook)
+    }
+
+    /// Disables an EPT hook on an export of a loaded kernel module.
+    pub fn disable_ept_module_hook(&self, module_name: &str, function_name: &str) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_module_hook(module_name, function_name, Command::DisableKernelEptHook)
+    }
+
+    /// Internal function to manage (enable/disable) EPT hooks on exports of kernel modules.
+    fn manage_ept_module_hook(module_name: &str, function_name: &str, command: Command) -> Result<(), HypervisorApiError> {
+        log::debug!("Module: {} Function: {}", module_name, function_name);
+
+        let hook_data = HookData {
+            function_hash: djb2_hash(function_name.as_bytes()),
+            syscall_number: 0,
+            module_hash: djb2_hash(module_name.as_bytes()),
+            session_id: 0,
         };
 
-        let result = Self::call_hypervisor(client_command.as_ptr());
+        Self::send_command(command, hook_data)
+            .inspect_err(|e| log::error!("Failed to manage EPT hook for function: {}!{}: {}", module_name, function_name, e))
+    }
 
-        if result.eax == 1 {
-            log::debug!("Successfully managed EPT hook for function: {}", function_name);
-            Some(())
-        } else {
-            log::error!("Failed to manage EPT hook for function: {}", function_name);
-            None
-        }
+    /// Enables an EPT hook on a win32k syscall, such as `NtUserGetForegroundWindow`, in the session space of a session.
+    ///
+    /// Session space is private to each session, so the hook only applies to the processes of `session_id`.
+    pub fn enable_ept_win32k_hook(&self, function_name: &str, session_id: u32) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_win32k_hook(function_name, session_id, Command::EnableKernelEptHook)
+    }
+
+    /// Disables an EPT hook on a win32k syscall in the session space of a session.
+    pub fn disable_ept_win32k_hook(&self, function_name: &str, session_id: u32) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_win32k_hook(function_name, session_id, Command::DisableKernelEptHook)
+    }
+
+    /// Internal function to manage (enable/disable) EPT hooks on win32k syscalls.
+    fn manage_ept_win32k_hook(function_name: &str, session_id: u32, command: Command) -> Result<(), HypervisorApiError> {
+        let function_hash = djb2_hash(function_name.as_bytes());
+        let syscall_number =
+            Syscall::get_win32k_ssn_by_hash(function_hash).ok_or_else(|| HypervisorApiError::SyscallNotFound(function_name.to_string()))?;
+
+        log::debug!("Function: {} Win32k syscall number: {:#x} Session: {}", function_name, syscall_number, session_id);
+
+        let hook_data = HookData {
+            function_hash,
+            syscall_number: syscall_number as u32,
+            module_hash: 0,
+            session_id,
+        };
+
+        Self::send_command(command, hook_data).inspect_err(|e| log::error!("Failed to manage EPT hook for win32k function: {}: {}", function_name, e))
+    }
+
+    /// Enables a `vmcall` EPT hook at an address of the opened process.
+    ///
+    /// `function_hash` identifies the hook in `enumerate_hooks`. The address must be executable and the hook must not cross a page boundary.
+    pub fn enable_ept_address_hook(&self, address: u64, function_hash: u32) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_address_hook(Command::EnableAddressEptHook, self.process.directory_table_base, address, HookKind::Vmcall, function_hash, 0)
+    }
+
+    /// Disables an EPT hook installed by `enable_ept_address_hook` at the same address.
+    pub fn disable_ept_address_hook(&self, address: u64) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_address_hook(Command::DisableAddressEptHook, self.process.directory_table_base, address, HookKind::Vmcall, 0, 0)
+    }
+
+    /// Enables an EPT hook at an address translated with an explicit CR3, or 0 for the CR3 of the calling process.
+    ///
+    /// Page hooks need the accesses to watch, use `enable_ept_page_watch_with_cr3` for them.
+    pub fn enable_ept_address_hook_with_cr3(guest_cr3: u64, address: u64, hook_kind: HookKind, function_hash: u32) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_address_hook(Command::EnableAddressEptHook, guest_cr3, address, hook_kind, function_hash, 0)
+    }
+
+    /// Disables an EPT hook installed by `enable_ept_address_hook_with_cr3` or `enable_ept_page_watch_with_cr3` with the same CR3 and address.
+    pub fn disable_ept_address_hook_with_cr3(guest_cr3: u64, address: u64, hook_kind: HookKind) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_address_hook(Command::DisableAddressEptHook, guest_cr3, address, hook_kind, 0, 0)
+    }
+
+    /// Watches accesses to the page of the opened process containing an address.
+    ///
+    /// `watch_access` is a combination of `WATCH_READ` and `WATCH_WRITE`. Every watched access is logged by the hypervisor,
+    /// and `function_hash` identifies the hook in `enumerate_hooks`.
+    pub fn enable_ept_page_watch(&self, address: u64, watch_access: u64, function_hash: u32) -> Result<(), HypervisorApiError> {
+        Self::enable_ept_page_watch_with_cr3(self.process.directory_table_base, address, watch_access, function_hash)
+    }
+
+    /// Stops watching the page of the opened process containing an address.
+    pub fn disable_ept_page_watch(&self, address: u64) -> Result<(), HypervisorApiError> {
+        Self::disable_ept_address_hook_with_cr3(self.process.directory_table_base, address, HookKind::Page)
+    }
+
+    /// Watches accesses to the page containing an address translated with an explicit CR3, or 0 for the CR3 of the calling process.
+    ///
+    /// Kernel structures can be watched through the CR3 of any process.
+    pub fn enable_ept_page_watch_with_cr3(guest_cr3: u64, address: u64, watch_access: u64, function_hash: u32) -> Result<(), HypervisorApiError> {
+        Self::manage_ept_address_hook(Command::EnableAddressEptHook, guest_cr3, address, HookKind::Page, function_hash, watch_access)
+    }
+
+    /// Internal function to manage (enable/disable) EPT hooks at arbitrary addresses.
+    fn manage_ept_address_hook(
+        command: Command,
+        guest_cr3: u64,
+        address: u64,
+        hook_kind: HookKind,
+        function_hash: u32,
+        watch_access: u64,
+    ) -> Result<(), HypervisorApiError> {
+        log::debug!("{:?} at address: {:#x} with CR3: {:#x}", command, address, guest_cr3);
+
+        let hook_data = AddressHookData {
+            guest_cr3,
+            guest_va: address,
+            hook_kind: hook_kind as u32,
+            function_hash,
+            watch_access,
+        };
+
+        Self::send_command(command, hook_data).inspect_err(|e| log::error!("Failed to manage EPT hook at address: {:#x}: {}", address, e))
     }
 
     /// Creates a new instance of `HypervisorCommunicator`, retrieves the process CR3, and stores it.
-    pub fn open_process(process_id: u64) -> Option<Self> {
+    pub fn open_process(process_id: u64) -> Result<Self, HypervisorApiError> {
         log::debug!("Opening process with ID: {}", process_id);
 
-        let mut communicator = Self { process_cr3: 0 };
+        Self::open(|buffer| Ok(OpenProcessData::by_process_id(process_id, buffer)))
+    }
+
+    /// Opens a process by its image name, such as `notepad.exe`, without calling ToolHelp first.
+    ///
+    /// Fails with `ErrorCode::AmbiguousProcessName` if more than one process has this image name.
+    pub fn open_process_by_name(image_name: &str) -> Result<Self, HypervisorApiError> {
+        log::debug!("Opening process with image name: {}", image_name);
+
+        Self::open(|buffer| {
+            OpenProcessData::by_image_name(image_name, buffer).ok_or_else(|| HypervisorApiError::InvalidProcessName(image_name.to_string()))
+        })
+    }
+
+    /// Opens a process by the guest virtual address of its `_EPROCESS` structure.
+    pub fn open_process_by_eprocess(eprocess: u64) -> Result<Self, HypervisorApiError> {
+        log::debug!("Opening process with _EPROCESS: {:#x}", eprocess);
+
+        Self::open(|buffer| Ok(OpenProcessData::by_eprocess(eprocess, buffer)))
+    }
+
+    /// Returns the process ID, `_EPROCESS` address and directory table bases of the opened process.
+    pub fn process(&self) -> &OpenedProcess {
+        &self.process
+    }
+
+    /// Sends an `OpenProcess` command built by `request` around the buffer that receives the `OpenedProcess`.
+    fn open(request: impl FnOnce(u64) -> Result<OpenProcessData, HypervisorApiError>) -> Result<Self, HypervisorApiError> {
+        // SAFETY: `OpenedProcess` is `Pod`, so the all-zero bit pattern is valid.
+        let mut process: OpenedProcess = unsafe { core::mem::zeroed() };
+        let request = request(&mut process as *mut OpenedProcess as u64)?;
+
+        match Self::send_command(Command::OpenProcess, request) {
+            Ok(()) => {
+                log::debug!("Opened process {} with CR3: {:#x}", process.process_id, process.directory_table_base);
+                Ok(Self { process })
+            }
+            Err(e) => {
+                log::error!("Failed to open process: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Lists the EPT hooks installed by the hypervisor, including their shadow pages and current EPT permissions.
+    pub fn list_hooks(&self) -> Result<Vec<HookEntry>, HypervisorApiError> {
+        Self::list(Command::EnumerateHooks, |list| list)
+    }
+
+    /// Lists the hit counters of the installed EPT hooks, with the time, caller, process and processor of their last hit.
+    pub fn list_hook_statistics(&self) -> Result<Vec<HookStatistics>, HypervisorApiError> {
+        Self::list(Command::QueryHookStatistics, |list| list)
+    }
+
+    /// Lists the guest processes by walking `_EPROCESS.ActiveProcessLinks` in the hypervisor.
+    ///
+    /// Unlike a ToolHelp snapshot, this does not depend on user mode APIs the guest can tamper with.
+    pub fn list_processes() -> Result<Vec<ProcessEntry>, HypervisorApiError> {
+        Self::list(Command::EnumerateProcesses, |list| list)
+    }
+
+    /// Lists the modules loaded into a process by walking its `PEB` loader data in the hypervisor.
+    ///
+    /// For WOW64 processes the modules of the 32-bit `PEB` are included and flagged by `ModuleEntry::is_wow64`.
+    /// No handle to the target process is needed.
+    pub fn list_modules(process_id: u64) -> Result<Vec<ModuleEntry>, HypervisorApiError> {
+        Self::list(Command::EnumerateModules, |list| ModuleListData { process_id, list })
+    }
+
+    /// Queries the build features, virtualized processor count, heap usage and VMX capabilities of the hypervisor.
+    ///
+    /// Callers should check `HypervisorInfo::info_version` before relying on fields added in later versions.
+    pub fn get_hypervisor_info() -> Result<HypervisorInfo, HypervisorApiError> {
+        // SAFETY: `HypervisorInfo` is `Pod`, so the all-zero bit pattern is valid.
+        let mut info: HypervisorInfo = unsafe { core::mem::zeroed() };
+
+        let info_data = InfoData {
+            buffer: &mut info as *mut HypervisorInfo as u64,
+        };
+
+        Self::send_command(Command::GetHypervisorInfo, info_data)?;
+
+        log::debug!("Hypervisor info: {:?}", info);
+
+        Ok(info)
+    }
 
-        let command_payload = ClientDataPayload::Memory(ProcessMemoryOperation {
-            process_id: Some(process_id),
-            guest_cr3: None,
-            address: None,
-            buffer: &mut communicator.process_cr3 as *mut u64 as u64,
-            buffer_size: size_of::<u64>() as u64,
-        });
+    /// Releases the hypervisor session held by this process, so that another client can open one.
+    ///
+    /// The next command sent by this process opens a new session. Does nothing if no session is open.
+    pub fn close_session() -> Result<(), HypervisorApiError> {
+        let mut session = SESSION.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
 
-        let client_command = ClientCommand {
-            command: Command::OpenProcess,
-            payload: command_payload,
+        let Some(current) = session.as_mut() else {
+            return Ok(());
         };
 
-        let result = Self::call_hypervisor(client_command.as_ptr());
+        let mut client_command = ClientCommand::new(Command::CloseSession, CloseSessionData::default());
+        client_command.sign(&current.key, current.next_nonce);
+        current.next_nonce += 1;
+
+        Self::decode_result(Self::call_hypervisor(client_command.as_ptr()))?;
+        *session = None;
+
+        log::debug!("Closed hypervisor session");
+
+        Ok(())
+    }
+
+    /// Retrieves an array of entries from the hypervisor, growing the buffer until every entry fits.
+    ///
+    /// `payload` builds the command payload around the `ListData` describing the buffer.
+    fn list<T: Pod, P: Pod>(command: Command, payload: impl Fn(ListData) -> P) -> Result<Vec<T>, HypervisorApiError> {
+        let mut entries: Vec<T> = Vec::new();
+
+        loop {
+            let mut count = 0u64;
+
+            let list = ListData {
+                buffer: entries.as_mut_ptr() as u64,
+                capacity: entries.capacity() as u64,
+                count: &mut count as *mut u64 as u64,
+            };
+
+            Self::send_command(command, payload(list))?;
+
+            if count <= entries.capacity() as u64 {
+                // SAFETY: the hypervisor wrote `count` entries, and `T: Pod` is valid for any bit pattern.
+                unsafe { entries.set_len(count as usize) };
+                return Ok(entries);
+            }
 
-        if result.eax == 1 {
-            log::debug!("Opened process with CR3: {:#x}", communicator.process_cr3);
-            Some(communicator)
-        } else {
-            log::error!("Failed to open process");
-            None
+            // The list grew or the buffer was too small; retry with room for every entry.
+            entries.reserve_exact(count as usize);
+        }
+    }
+
+    /// Wraps `payload` in a versioned `ClientCommand`, signs it with the session key, sends it to the hypervisor and decodes the returned status.
+    ///
+    /// The session is opened on first use. The hypervisor only hands out one session key at a time, so other
+    /// clients cannot talk to it until this process calls `close_session` or exits.
+    fn send_command<T: Pod>(command: Command, payload: T) -> Result<(), HypervisorApiError> {
+        Self::with_session(|session| {
+            let mut client_command = ClientCommand::new(command, payload);
+            client_command.sign(&session.key, session.next_nonce);
+            session.next_nonce += 1;
+
+            Self::decode_result(Self::call_hypervisor(client_command.as_ptr()))
+        })
+    }
+
+    /// Runs `f` with the session locked, opening the session first if needed.
+    fn with_session<R>(f: impl FnOnce(&mut ClientSession) -> Result<R, HypervisorApiError>) -> Result<R, HypervisorApiError> {
+        let mut session = SESSION.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+
+        if session.is_none() {
+            *session = Some(Self::open_session()?);
+        }
+
+        f(session.as_mut().unwrap())
+    }
+
+    /// Performs the session handshake and retrieves the session key.
+    fn open_session() -> Result<ClientSession, HypervisorApiError> {
+        let mut key = SessionKey { k0: 0, k1: 0 };
+
+        let session_data = SessionData {
+            key_buffer: &mut key as *mut SessionKey as u64,
+        };
+
+        let client_command = ClientCommand::new(Command::OpenSession, session_data);
+        Self::decode_result(Self::call_hypervisor(client_command.as_ptr()))?;
+
+        log::debug!("Opened hypervisor session");
+
+        Ok(ClientSession { key, next_nonce: 1 })
+    }
+
+    /// Decodes the registers returned by the hypervisor.
+    fn decode_result(result: CpuidResult) -> Result<(), HypervisorApiError> {
+        Self::decode_command_result(&CommandResult {
+            status: result.eax,
+            error_code: result.ebx,
+            protocol_version: result.edx,
+        })
+    }
+
+    /// Decodes the outcome of a command.
+    ///
+    /// On failure the hypervisor reports an `ErrorCode`; on a version mismatch it reports its protocol version.
+    fn decode_command_result(result: &CommandResult) -> Result<(), HypervisorApiError> {
+        match CommandStatus::from_u64(result.status) {
+            Some(CommandStatus::Success) => Ok(()),
+            Some(CommandStatus::Failure) => match ErrorCode::from_u64(result.error_code) {
+                Some(code) => Err(HypervisorApiError::Hypervisor(code)),
+                None => Err(HypervisorApiError::UnknownErrorCode(result.error_code)),
+            },
+            Some(CommandStatus::VersionMismatch) => Err(HypervisorApiError::VersionMismatch {
+                hypervisor: result.protocol_version,
+                client: PROTOCOL_VERSION,
+            }),
+            None => Err(HypervisorApiError::UnknownStatus(result.status)),
         }
     }
 
     /// Sends a command to the hypervisor using CPUID.
     fn call_hypervisor(command_rcx: u64) -> CpuidResult {
-        let mut rax = PASSWORD;
+        let mut rax = HYPERCALL_LEAF;
         let mut rbx;
         let mut rcx = command_rcx;
         let mut rdx;
@@ -124,59 +454,196 @@ impl HypervisorCommunicator {
         }
     }
 
+    /// Starts a batch of commands against the opened process that is submitted in a single VM exit.
+    pub fn batch<'a>(&self) -> CommandBatch<'a> {
+        CommandBatch::new(self.process.directory_table_base)
+    }
+
     /// Reads memory from the opened process using the stored CR3.
-    pub fn read_process_memory(&self, address: u64, buffer: &mut [u8]) -> Option<()> {
+    ///
+    /// If a page is not present part way through, `PartialCopy` reports how many bytes were read.
+    pub fn read_process_memory(&self, address: u64, buffer: &mut [u8]) -> Result<(), HypervisorApiError> {
         log::debug!("Reading memory from address: {:#x}", address);
 
+        let mut transferred = 0u64;
         let memory_operation = ProcessMemoryOperation {
-            process_id: None,
-            guest_cr3: Some(self.process_cr3),
-            address: Some(address),
+            process_id: 0,
+            guest_cr3: self.process.directory_table_base,
+            address,
             buffer: buffer.as_ptr() as u64,
             buffer_size: buffer.len() as u64,
+            transferred: &mut transferred as *mut u64 as u64,
         };
 
-        let client_command = ClientCommand {
-            command: Command::ReadProcessMemory,
-            payload: ClientDataPayload::Memory(memory_operation),
-        };
-
-        let result = Self::call_hypervisor(client_command.as_ptr());
-
-        if result.eax == 1 {
-            log::debug!("Memory read successfully");
-            Some(())
-        } else {
-            log::error!("Failed to read memory");
-            None
+        match Self::send_command(Command::ReadProcessMemory, memory_operation) {
+            Ok(()) => {
+                log::debug!("Memory read successfully");
+                Ok(())
+            }
+            Err(HypervisorApiError::Hypervisor(ErrorCode::PartialMemoryCopy)) => {
+                log::error!("Failed to read memory: only {:#x} of {:#x} bytes were copied", transferred, buffer.len());
+                Err(HypervisorApiError::PartialCopy {
+                    copied: transferred,
+                    requested: buffer.len() as u64,
+                })
+            }
+            Err(e) => {
+                log::error!("Failed to read memory: {}", e);
+                Err(e)
+            }
         }
     }
 
     /// Writes memory to the opened process using the stored CR3.
-    pub fn write_process_memory(&self, address: u64, buffer: &[u8]) -> Option<()> {
+    ///
+    /// If a page is not present part way through, `PartialCopy` reports how many bytes were written.
+    pub fn write_process_memory(&self, address: u64, buffer: &[u8]) -> Result<(), HypervisorApiError> {
         log::debug!("Writing memory to address: {:#x}", address);
 
+        let mut transferred = 0u64;
         let memory_operation = ProcessMemoryOperation {
-            process_id: None,
-            guest_cr3: Some(self.process_cr3),
-            address: Some(address),
+            process_id: 0,
+            guest_cr3: self.process.directory_table_base,
+            address,
             buffer: buffer.as_ptr() as u64,
             buffer_size: buffer.len() as u64,
+            transferred: &mut transferred as *mut u64 as u64,
         };
 
-        let client_command = ClientCommand {
-            command: Command::WriteProcessMemory,
-            payload: ClientDataPayload::Memory(memory_operation),
+        match Self::send_command(Command::WriteProcessMemory, memory_operation) {
+            Ok(()) => {
+                log::debug!("Memory written successfully");
+                Ok(())
+            }
+            Err(HypervisorApiError::Hypervisor(ErrorCode::PartialMemoryCopy)) => {
+                log::error!("Failed to write memory: only {:#x} of {:#x} bytes were copied", transferred, buffer.len());
+                Err(HypervisorApiError::PartialCopy {
+                    copied: transferred,
+                    requested: buffer.len() as u64,
+                })
+            }
+            Err(e) => {
+                log::error!("Failed to write memory: {}", e);
+                Err(e)
+            }
+        }
+    }
+
+    /// Translates a virtual address of the opened process, reporting every guest paging and EPT entry visited.
+    pub fn translate_address(&self, address: u64) -> Result<AddressTranslation, HypervisorApiError> {
+        Self::translate_address_with_cr3(self.process.directory_table_base, address)
+    }
+
+    /// Translates a virtual address under an explicit CR3, reporting every guest paging and EPT entry visited.
+    ///
+    /// A `guest_cr3` of 0 translates with the CR3 of the calling process.
+    pub fn translate_address_with_cr3(guest_cr3: u64, address: u64) -> Result<AddressTranslation, HypervisorApiError> {
+        log::debug!("Translating address: {:#x} with CR3: {:#x}", address, guest_cr3);
+
+        // SAFETY: `AddressTranslation` is `Pod`, so the all-zero bit pattern is valid.
+        let mut translation: AddressTranslation = unsafe { core::mem::zeroed() };
+
+        let translation_data = TranslationData {
+            guest_cr3,
+            guest_va: address,
+            buffer: &mut translation as *mut AddressTranslation as u64,
         };
 
-        let result = Self::call_hypervisor(client_command.as_ptr());
+        Self::send_command(Command::TranslateAddress, translation_data)?;
+
+        Ok(translation)
+    }
+
+    /// Scans a range of the opened process for an IDA-style pattern such as `"4C 8B ? 48 83 EC"`.
+    ///
+    /// The scan runs in the hypervisor and skips pages that are not present. Returns the offset of every match from `start`.
+    pub fn pattern_scan(&self, start: u64, size: u64, pattern: &str) -> Result<Vec<u64>, HypervisorApiError> {
+        Self::pattern_scan_with_cr3(self.process.directory_table_base, start, size, pattern)
+    }
+
+    /// Scans a guest virtual address range for an IDA-style pattern using an explicit CR3, or 0 for the CR3 of the calling process.
+    ///
+    /// The hypervisor scans at most `MAX_PATTERN_SCAN_SIZE` bytes per command, so large ranges take several commands.
+    /// Returns the offset of every match from `start`.
+    pub fn pattern_scan_with_cr3(guest_cr3: u64, start: u64, size: u64, pattern: &str) -> Result<Vec<u64>, HypervisorApiError> {
+        log::debug!("Scanning {:#x} bytes at {:#x} with CR3: {:#x} for: {}", size, start, guest_cr3, pattern);
+
+        let pattern = Pattern::parse(pattern).ok_or_else(|| HypervisorApiError::InvalidPattern(pattern.to_string()))?;
+        let end = start
+            .checked_add(size)
+            .ok_or(HypervisorApiError::Hypervisor(ErrorCode::ScanRangeTooLarge))?;
+
+        let mut matches = Vec::new();
+        let mut buffer: Vec<u64> = Vec::with_capacity(PATTERN_SCAN_BATCH_SIZE);
+        let mut cursor = start;
 
-        if result.eax == 1 {
-            log::debug!("Memory written successfully");
-            Some(())
-        } else {
-            log::error!("Failed to write memory");
-            None
+        while cursor < end {
+            let mut count = 0u64;
+            let mut next_cursor = end;
+
+            let scan = PatternScanData {
+                guest_cr3,
+                start: cursor,
+                size: end - cursor,
+                pattern,
+                list: ListData {
+                    buffer: buffer.as_mut_ptr() as u64,
+                    capacity: buffer.capacity() as u64,
+                    count: &mut count as *mut u64 as u64,
+                },
+                cursor: &mut next_cursor as *mut u64 as u64,
+            };
+
+            Self::send_command(Command::PatternScan, scan)?;
+
+            // SAFETY: the hypervisor wrote `count` offsets, at most the capacity of the buffer.
+            unsafe { buffer.set_len((count as usize).min(buffer.capacity())) };
+            matches.extend(buffer.drain(..).map(|offset| cursor - start + offset));
+
+            // Each command either reports a match or reads past the cursor, so the scan always moves forward.
+            cursor = next_cursor;
         }
+
+        Ok(matches)
+    }
+
+    /// Reads guest physical memory.
+    ///
+    /// The hypervisor refuses ranges that overlap its own memory or are not RAM. Buffers larger than
+    /// `MAX_PHYSICAL_MEMORY_SIZE` are transferred in several commands.
+    pub fn read_physical_memory(address: u64, buffer: &mut [u8]) -> Result<(), HypervisorApiError> {
+        log::debug!("Reading physical memory from address: {:#x}", address);
+
+        for (index, chunk) in buffer.chunks_mut(MAX_PHYSICAL_MEMORY_SIZE as usize).enumerate() {
+            let memory_operation = PhysicalMemoryOperation {
+                address: address + (index * MAX_PHYSICAL_MEMORY_SIZE as usize) as u64,
+                buffer: chunk.as_mut_ptr() as u64,
+                buffer_size: chunk.len() as u64,
+            };
+
+            Self::send_command(Command::ReadPhysicalMemory, memory_operation)?;
+        }
+
+        Ok(())
+    }
+
+    /// Writes guest physical memory.
+    ///
+    /// The hypervisor refuses ranges that overlap its own memory or are not RAM. Buffers larger than
+    /// `MAX_PHYSICAL_MEMORY_SIZE` are transferred in several commands.
+    pub fn write_physical_memory(address: u64, buffer: &[u8]) -> Result<(), HypervisorApiError> {
+        log::debug!("Writing physical memory to address: {:#x}", address);
+
+        for (index, chunk) in buffer.chunks(MAX_PHYSICAL_MEMORY_SIZE as usize).enumerate() {
+            let memory_operation = PhysicalMemoryOperation {
+                address: address + (index * MAX_PHYSICAL_MEMORY_SIZE as usize) as u64,
+                buffer: chunk.as_ptr() as u64,
+                buffer_size: chunk.len() as u64,
+            };
+
+            Self::send_command(Command::WritePhysicalMemory, memory_operation)?;
+        }
+
+        Ok(())
     }
 }
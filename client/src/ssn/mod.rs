@@ -1,12 +1,17 @@
 use {
-    crate::pemem::{djb2_hash, get_exports_by_name, get_loaded_module_by_hash},
+    crate::pemem::{djb2_hash, get_export_by_hash, get_exports_by_name, get_loaded_module_by_hash},
     obfstr::obfstr,
+    shared::WIN32K_SYSCALL_BASE,
     std::collections::BTreeMap,
+    windows_sys::Win32::System::LibraryLoader::LoadLibraryA,
 };
 
 /// The hash of the ntdll.dll module name.
 const NTDLL_HASH: u32 = 0x1edab0ed;
 
+/// The start of a `win32u.dll` syscall stub: `mov r10, rcx; mov eax, imm32`.
+const SYSCALL_STUB_PREFIX: [u8; 4] = [0x4C, 0x8B, 0xD1, 0xB8];
+
 /// Represents a system call utility to interact with ntdll.dll exports.
 pub struct Syscall {
     nt_exports: Vec<(String, usize)>,
@@ -41,6 +46,36 @@ impl Syscall {
         None
     }
 
+    /// Retrieves the win32k syscall number of a `win32u.dll` export, such as `NtUserGetForegroundWindow`, by reading it from its stub.
+    ///
+    /// # Arguments
+    ///
+    /// * `function_hash` - The hash of the function name.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u16>` - The syscall number if found, otherwise `None`.
+    pub fn get_win32k_ssn_by_hash(function_hash: u32) -> Option<u16> {
+        let win32u_base = unsafe { LoadLibraryA(obfstr!("win32u.dll\0").as_ptr()) } as *mut u8;
+
+        if win32u_base.is_null() {
+            return None;
+        }
+
+        let stub = unsafe { get_export_by_hash(win32u_base, function_hash)? };
+        let stub = unsafe { std::slice::from_raw_parts(stub as *const u8, SYSCALL_STUB_PREFIX.len() + 4) };
+
+        if stub[..SYSCALL_STUB_PREFIX.len()] != SYSCALL_STUB_PREFIX {
+            return None;
+        }
+
+        let syscall_number = u32::from_le_bytes(stub[SYSCALL_STUB_PREFIX.len()..].try_into().ok()?);
+
+        (WIN32K_SYSCALL_BASE..=u16::MAX as u32)
+            .contains(&syscall_number)
+            .then_some(syscall_number as u16)
+    }
+
     /// Sorts the exports by address and returns a vector of tuples containing the name and address.
     ///
     /// # Returns
@@ -85,6 +85,27 @@ impl<const SIZE: usize> ListHeap<SIZE> {
         unsafe { (self.0.as_ptr() as *const u8).add(SIZE).sub(Link::SIZE) as *mut _ }
     }
 
+    /// Returns the total free space in the heap.
+    ///
+    /// # Returns
+    ///
+    /// The sum of the free space of all links, in bytes.
+    pub fn free_space(&self) -> usize {
+        let _guard = ALLOCATOR_MUTEX.lock();
+
+        unsafe {
+            let mut total_freespace = 0usize;
+
+            let mut link = self.first_link();
+            while (*link).next != link {
+                total_freespace += (&*link).free_space() as usize;
+                link = (*link).next;
+            }
+
+            total_freespace
+        }
+    }
+
     /// Debugging function to print the current state of the heap.
     pub fn _debug(&self) {
         unsafe {
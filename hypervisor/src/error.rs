@@ -1,4 +1,4 @@
-use {alloc::ffi::NulError, thiserror_no_std::Error};
+use {alloc::ffi::NulError, shared::ErrorCode, thiserror_no_std::Error};
 
 #[derive(Error, Debug)]
 pub enum HypervisorError {
@@ -244,4 +244,201 @@ pub enum HypervisorError {
 
     #[error("Guest page table unmapping error")]
     GuestPageUnmapError,
+
+    #[error("Client protocol version does not match the hypervisor")]
+    ProtocolVersionMismatch,
+
+    #[error("Invalid command")]
+    InvalidCommand,
+
+    #[error("Command size does not match its payload")]
+    InvalidCommandSize,
+
+    #[error("Failed to access guest memory")]
+    GuestMemoryAccessFailed,
+
+    #[error("Process not found")]
+    ProcessNotFound,
+
+    #[error("Hook already installed")]
+    HookAlreadyInstalled,
+
+    #[error("Session not established")]
+    SessionNotEstablished,
+
+    #[error("Session already established")]
+    SessionAlreadyEstablished,
+
+    #[error("Failed to generate session key")]
+    SessionKeyGenerationFailed,
+
+    #[error("Invalid command MAC")]
+    InvalidCommandMac,
+
+    #[error("Replayed command")]
+    ReplayedCommand,
+
+    #[error("Batch too large")]
+    BatchTooLarge,
+
+    #[error("Access to hypervisor memory denied")]
+    HypervisorMemoryAccessDenied,
+
+    #[error("Memory was only partially copied")]
+    PartialMemoryCopy,
+
+    #[error("Invalid pattern")]
+    InvalidPattern,
+
+    #[error("Scan range too large")]
+    ScanRangeTooLarge,
+
+    #[error("More than one process matches the image name")]
+    AmbiguousProcessName,
+
+    #[error("Hook type is not supported")]
+    UnsupportedHookType,
+
+    #[error("Address is not executable")]
+    AddressNotExecutable,
+
+    #[error("Hook crosses a page boundary")]
+    HookCrossesPageBoundary,
+
+    #[error("Kernel module not found")]
+    ModuleNotFound,
+
+    #[error("No GUI process found in the session")]
+    SessionNotFound,
+
+    #[error("Page is not split")]
+    PageNotSplit,
+
+    #[error("Transfer too large")]
+    TransferTooLarge,
+
+    #[error("Physical address is not guest RAM")]
+    NotGuestRam,
+
+    #[error("Too many pages to restore after single-stepping")]
+    MtfRestoreListFull,
+
+    #[error("Timed out waiting for all processors to invalidate their EPT")]
+    EptFlushTimeout,
+}
+
+impl HypervisorError {
+    /// Returns the stable numeric code reported to the guest for this error.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            HypervisorError::CPUUnsupported => ErrorCode::CPUUnsupported,
+            HypervisorError::VMXUnsupported => ErrorCode::VMXUnsupported,
+            HypervisorError::EPTUnsupported => ErrorCode::EPTUnsupported,
+            HypervisorError::MTRRUnsupported => ErrorCode::MTRRUnsupported,
+            HypervisorError::VMXBIOSLock => ErrorCode::VMXBIOSLock,
+            HypervisorError::MemoryAllocationFailed(_) => ErrorCode::MemoryAllocationFailed,
+            HypervisorError::VirtualToPhysicalAddressFailed => ErrorCode::VirtualToPhysicalAddressFailed,
+            HypervisorError::VMXONFailed => ErrorCode::VMXONFailed,
+            HypervisorError::VMXOFFFailed => ErrorCode::VMXOFFFailed,
+            HypervisorError::VMCLEARFailed => ErrorCode::VMCLEARFailed,
+            HypervisorError::VMPTRLDFailed => ErrorCode::VMPTRLDFailed,
+            HypervisorError::VMREADFailed => ErrorCode::VMREADFailed,
+            HypervisorError::VMWRITEFailed => ErrorCode::VMWRITEFailed,
+            HypervisorError::VMLAUNCHFailed => ErrorCode::VMLAUNCHFailed,
+            HypervisorError::VMRESUMEFailed => ErrorCode::VMRESUMEFailed,
+            HypervisorError::ProcessorSwitchFailed => ErrorCode::ProcessorSwitchFailed,
+            HypervisorError::VcpuIsNone => ErrorCode::VcpuIsNone,
+            HypervisorError::UnknownVMExitReason => ErrorCode::UnknownVMExitReason,
+            HypervisorError::UnknownVMInstructionError => ErrorCode::UnknownVMInstructionError,
+            HypervisorError::VmFailInvalid => ErrorCode::VmFailInvalid,
+            HypervisorError::UnhandledVmExit => ErrorCode::UnhandledVmExit,
+            HypervisorError::KeRaiseIrqlToDpcLevelNull => ErrorCode::KeRaiseIrqlToDpcLevelNull,
+            HypervisorError::InvalidEptPml4BaseAddress => ErrorCode::InvalidEptPml4BaseAddress,
+            HypervisorError::MemoryTypeResolutionError => ErrorCode::MemoryTypeResolutionError,
+            HypervisorError::InvalidCr3BaseAddress => ErrorCode::InvalidCr3BaseAddress,
+            HypervisorError::InvalidBytes => ErrorCode::InvalidBytes,
+            HypervisorError::NotEnoughBytes => ErrorCode::NotEnoughBytes,
+            HypervisorError::NoInstructions => ErrorCode::NoInstructions,
+            HypervisorError::RelativeInstruction => ErrorCode::RelativeInstruction,
+            HypervisorError::UnsupportedInstruction => ErrorCode::UnsupportedInstruction,
+            HypervisorError::VmxNotInitialized => ErrorCode::VmxNotInitialized,
+            HypervisorError::HookError => ErrorCode::HookError,
+            HypervisorError::PrimaryEPTNotProvided => ErrorCode::PrimaryEPTNotProvided,
+            HypervisorError::InvalidPml4Entry => ErrorCode::InvalidPml4Entry,
+            HypervisorError::InvalidPdptEntry => ErrorCode::InvalidPdptEntry,
+            HypervisorError::InvalidPdEntry => ErrorCode::InvalidPdEntry,
+            HypervisorError::InvalidPtEntry => ErrorCode::InvalidPtEntry,
+            HypervisorError::InvalidPermissionCharacter => ErrorCode::InvalidPermissionCharacter,
+            HypervisorError::UnalignedAddressError => ErrorCode::UnalignedAddressError,
+            HypervisorError::AlreadySplitError => ErrorCode::AlreadySplitError,
+            HypervisorError::OutOfMemory => ErrorCode::OutOfMemory,
+            HypervisorError::PageAlreadySplit => ErrorCode::PageAlreadySplit,
+            HypervisorError::HookManagerNotProvided => ErrorCode::HookManagerNotProvided,
+            HypervisorError::NtQuerySystemInformationFailed => ErrorCode::NtQuerySystemInformationFailed,
+            HypervisorError::ExAllocatePoolFailed => ErrorCode::ExAllocatePoolFailed,
+            HypervisorError::PatternNotFound => ErrorCode::PatternNotFound,
+            HypervisorError::SsdtNotFound => ErrorCode::SsdtNotFound,
+            HypervisorError::FailedToCreateCString(_) => ErrorCode::FailedToCreateCString,
+            HypervisorError::GetKernelBaseFailed => ErrorCode::GetKernelBaseFailed,
+            HypervisorError::FailedToGetKernelSize => ErrorCode::FailedToGetKernelSize,
+            HypervisorError::FailedToGetExport => ErrorCode::FailedToGetExport,
+            HypervisorError::HexParseError => ErrorCode::HexParseError,
+            HypervisorError::VMFailToLaunch => ErrorCode::VMFailToLaunch,
+            HypervisorError::VmInstructionError => ErrorCode::VmInstructionError,
+            HypervisorError::LargePageRemapError => ErrorCode::LargePageRemapError,
+            HypervisorError::FailedToGetImageBaseAddress => ErrorCode::FailedToGetImageBaseAddress,
+            HypervisorError::UnknownVmcallCommand => ErrorCode::UnknownVmcallCommand,
+            HypervisorError::UnknownGuestAgentCommand => ErrorCode::UnknownGuestAgentCommand,
+            HypervisorError::OutOfHooks => ErrorCode::OutOfHooks,
+            HypervisorError::FailedToGetCurrentHookIndex => ErrorCode::FailedToGetCurrentHookIndex,
+            HypervisorError::TooManyHooks => ErrorCode::TooManyHooks,
+            HypervisorError::HookNotFound => ErrorCode::HookNotFound,
+            HypervisorError::InlineHookNotFound => ErrorCode::InlineHookNotFound,
+            HypervisorError::OldRflagsNotSet => ErrorCode::OldRflagsNotSet,
+            HypervisorError::MtfCounterNotSet => ErrorCode::MtfCounterNotSet,
+            HypervisorError::InvalidPreAllocPtIndex => ErrorCode::InvalidPreAllocPtIndex,
+            HypervisorError::ShadowPageAllocationError => ErrorCode::ShadowPageAllocationError,
+            HypervisorError::PageTablesAllocationError => ErrorCode::PageTablesAllocationError,
+            HypervisorError::ShadowPagesUnavailable => ErrorCode::ShadowPagesUnavailable,
+            HypervisorError::PageTablesUnavailable => ErrorCode::PageTablesUnavailable,
+            HypervisorError::ShadowPageNotFound => ErrorCode::ShadowPageNotFound,
+            HypervisorError::PageTableNotFound => ErrorCode::PageTableNotFound,
+            HypervisorError::PageTableAlreadyMapped => ErrorCode::PageTableAlreadyMapped,
+            HypervisorError::ShadowPageAlreadyMapped => ErrorCode::ShadowPageAlreadyMapped,
+            HypervisorError::KernelHookMissing => ErrorCode::KernelHookMissing,
+            HypervisorError::ActiveMappingError => ErrorCode::ActiveMappingError,
+            HypervisorError::LargePtMappingError => ErrorCode::LargePtMappingError,
+            HypervisorError::HookInfoNotFound => ErrorCode::HookInfoNotFound,
+            HypervisorError::EptMisconfiguration => ErrorCode::EptMisconfiguration,
+            HypervisorError::LargePageUnmapError => ErrorCode::LargePageUnmapError,
+            HypervisorError::GuestPageUnmapError => ErrorCode::GuestPageUnmapError,
+            HypervisorError::ProtocolVersionMismatch => ErrorCode::ProtocolVersionMismatch,
+            HypervisorError::InvalidCommand => ErrorCode::InvalidCommand,
+            HypervisorError::InvalidCommandSize => ErrorCode::InvalidCommandSize,
+            HypervisorError::GuestMemoryAccessFailed => ErrorCode::GuestMemoryAccessFailed,
+            HypervisorError::ProcessNotFound => ErrorCode::ProcessNotFound,
+            HypervisorError::HookAlreadyInstalled => ErrorCode::HookAlreadyInstalled,
+            HypervisorError::SessionNotEstablished => ErrorCode::SessionNotEstablished,
+            HypervisorError::SessionAlreadyEstablished => ErrorCode::SessionAlreadyEstablished,
+            HypervisorError::SessionKeyGenerationFailed => ErrorCode::SessionKeyGenerationFailed,
+            HypervisorError::InvalidCommandMac => ErrorCode::InvalidCommandMac,
+            HypervisorError::ReplayedCommand => ErrorCode::ReplayedCommand,
+            HypervisorError::BatchTooLarge => ErrorCode::BatchTooLarge,
+            HypervisorError::HypervisorMemoryAccessDenied => ErrorCode::HypervisorMemoryAccessDenied,
+            HypervisorError::PartialMemoryCopy => ErrorCode::PartialMemoryCopy,
+            HypervisorError::InvalidPattern => ErrorCode::InvalidPattern,
+            HypervisorError::ScanRangeTooLarge => ErrorCode::ScanRangeTooLarge,
+            HypervisorError::AmbiguousProcessName => ErrorCode::AmbiguousProcessName,
+            HypervisorError::UnsupportedHookType => ErrorCode::UnsupportedHookType,
+            HypervisorError::AddressNotExecutable => ErrorCode::AddressNotExecutable,
+            HypervisorError::HookCrossesPageBoundary => ErrorCode::HookCrossesPageBoundary,
+            HypervisorError::ModuleNotFound => ErrorCode::ModuleNotFound,
+            HypervisorError::SessionNotFound => ErrorCode::SessionNotFound,
+            HypervisorError::PageNotSplit => ErrorCode::PageNotSplit,
+            HypervisorError::TransferTooLarge => ErrorCode::TransferTooLarge,
+            HypervisorError::NotGuestRam => ErrorCode::NotGuestRam,
+            HypervisorError::MtfRestoreListFull => ErrorCode::MtfRestoreListFull,
+            HypervisorError::EptFlushTimeout => ErrorCode::EptFlushTimeout,
+        }
+    }
 }
@@ -3,15 +3,22 @@
 //! This module introduces the `PhysicalAddress` structure that simplifies operations around
 //! physical addresses. It provides conversions between virtual addresses (VAs) and physical addresses (PAs),
 //! as well as methods for extracting page frame numbers (PFNs) and other address-related information.
+//!
+//! Guest virtual memory is accessed through `GuestMemory`, which copies data page by page into and out of
+//! buffers owned by the caller.
 
 use {
     crate::{
         error::HypervisorError,
-        intel::{ept::Ept, paging::PageTables, support::vmread},
+        intel::{ept::Ept, hooks::hook_manager::HookManager, paging::PageTables, support::vmread},
+        windows::nt::types::UNICODE_STRING,
     },
-    log::trace,
+    alloc::vec::Vec,
+    core::mem::{offset_of, MaybeUninit},
+    log::{error, trace},
+    shared::{Pattern, PatternMatches, Pod},
     x86::{
-        bits64::paging::{PAddr, BASE_PAGE_SHIFT},
+        bits64::paging::{PAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE},
         vmx::vmcs,
     },
 };
@@ -64,6 +71,19 @@ impl PhysicalAddress {
         let guest_pa = unsafe { PageTables::translate_guest_virtual_to_guest_physical(guest_cr3, va)? };
         trace!("Guest VA: {:#x} -> Guest PA: {:#x}", va, guest_pa);
 
+        Self::pa_from_guest_pa(guest_pa)
+    }
+
+    /// Converts a guest physical address to a host physical address using the EPT of the current processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to translate.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<u64, HypervisorError>` containing the host physical address on success, or an error if the translation fails.
+    fn pa_from_guest_pa(guest_pa: u64) -> Result<u64, HypervisorError> {
         // Translate the guest physical address (GPA) to a host physical address (HPA) using the Extended Page Table (EPT).
         // In a 1:1 mapping, the guest physical address is the same as the host physical address.
         // This translation is performed to handle cases where paging/EPT changes occur.
@@ -112,186 +132,322 @@ impl PhysicalAddress {
     pub fn pa_from_va_with_explicit_cr3(va: u64, guest_cr3: u64) -> Result<u64, HypervisorError> {
         Self::pa_from_va(va, guest_cr3)
     }
+}
+
+/// The privilege level a guest memory access is checked against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessMode {
+    /// Any present page can be read and written, as the hypervisor is more privileged than the guest kernel.
+    Kernel,
+    /// Only pages accessible from user mode can be accessed, and only writable pages can be written.
+    User,
+}
 
-    /// Reads a value from a guest virtual address using the current guest CR3.
+impl AccessMode {
+    /// Returns the access mode matching the current privilege level (CPL) of the guest.
     ///
-    /// This function reads from the guest virtual address using the current CR3 value from the VMCS.
+    /// # Returns
+    ///
+    /// `AccessMode::User` if the guest is running at CPL 3, otherwise `AccessMode::Kernel`.
+    pub fn current() -> Self {
+        // The CPL is the DPL of the stack segment.
+        match (vmread(vmcs::guest::SS_ACCESS_RIGHTS) >> 5) & 0b11 {
+            3 => Self::User,
+            _ => Self::Kernel,
+        }
+    }
+}
+
+/// A view of guest virtual memory through a specific guest CR3 and access mode.
+///
+/// Every page is translated and checked on its own, so buffers do not need to be physically contiguous.
+/// Data is always copied into or out of buffers owned by the caller; no reference into guest memory is handed out.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GuestMemory {
+    /// The CR3 value used for translation.
+    guest_cr3: u64,
+    /// The privilege level the accesses are checked against.
+    access_mode: AccessMode,
+}
+
+impl GuestMemory {
+    /// Creates a view of guest memory.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to read from.
+    /// * `guest_cr3` - The CR3 value to use for translation.
+    /// * `access_mode` - The privilege level the accesses are checked against.
+    pub fn new(guest_cr3: u64, access_mode: AccessMode) -> Self {
+        Self { guest_cr3, access_mode }
+    }
+
+    /// Creates a view of guest memory through the current guest CR3 from the VMCS.
+    ///
+    /// # Arguments
+    ///
+    /// * `access_mode` - The privilege level the accesses are checked against.
+    pub fn with_current_cr3(access_mode: AccessMode) -> Self {
+        Self::new(vmread(vmcs::guest::CR3), access_mode)
+    }
+
+    /// Returns the CR3 value used for translation.
+    pub fn guest_cr3(&self) -> u64 {
+        self.guest_cr3
+    }
+
+    /// Reads guest memory into a buffer.
     ///
-    /// # Safety
+    /// # Arguments
     ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// * `va` - The guest virtual address to read from.
+    /// * `buffer` - The buffer that receives the data.
     ///
     /// # Returns
     ///
-    /// A `Result<T, HypervisorError>` containing the read value on success, or an error if the read fails.
-    pub fn read_guest_virt_with_current_cr3<T: Sized>(ptr: *const T) -> Option<T> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_current_cr3(ptr as u64).ok()? as *const T;
-        Some(unsafe { phys_addr.read() })
+    /// `Ok(())` if the whole buffer was read, or `GuestMemoryAccessFailed` if any page is not present or not accessible.
+    pub fn read_into(&self, va: u64, buffer: &mut [u8]) -> Result<(), HypervisorError> {
+        let read = self.for_each_page(va, buffer.len(), false, |pa, offset, chunk| unsafe {
+            core::ptr::copy_nonoverlapping(pa as *const u8, buffer[offset..].as_mut_ptr(), chunk)
+        });
+
+        if read != buffer.len() {
+            return Err(HypervisorError::GuestMemoryAccessFailed);
+        }
+
+        Ok(())
     }
 
-    /// Reads a value from a guest virtual address using a specified guest CR3.
-    ///
-    /// This function reads from the guest virtual address using a CR3 value provided by the caller.
+    /// Reads a value from guest memory.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to read from.
-    /// * `guest_cr3` - The CR3 value to use for translation.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// * `va` - The guest virtual address to read from.
     ///
     /// # Returns
     ///
-    /// A `Result<T, HypervisorError>` containing the read value on success, or an error if the read fails.
-    pub fn read_guest_virt_with_explicit_cr3<T: Sized>(ptr: *const T, guest_cr3: u64) -> Option<T> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_explicit_cr3(ptr as u64, guest_cr3).ok()? as *const T;
-        Some(unsafe { phys_addr.read() })
+    /// The value, or `GuestMemoryAccessFailed` if any page of it is not present or not accessible.
+    pub fn read<T: Pod>(&self, va: u64) -> Result<T, HypervisorError> {
+        let mut value = MaybeUninit::<T>::uninit();
+        let bytes = unsafe { core::slice::from_raw_parts_mut(value.as_mut_ptr() as *mut u8, size_of::<T>()) };
+
+        self.read_into(va, bytes)?;
+
+        Ok(unsafe { value.assume_init() })
     }
 
-    /// Reads a slice of guest memory using the current guest CR3.
-    ///
-    /// This function reads from the guest virtual address using the current CR3 value from the VMCS.
+    /// Reads an array of values from guest memory.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to start reading from.
-    /// * `len` - The number of elements to read.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// * `va` - The guest virtual address of the first value.
+    /// * `count` - The number of values to read.
     ///
     /// # Returns
     ///
-    /// A `Result<&[T], HypervisorError>` containing the borrowed slice on success, or an error if the read fails.
-    pub fn read_guest_virt_slice_with_current_cr3<'a, T: Sized>(ptr: *const T, len: usize) -> Option<&'a [T]> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_current_cr3(ptr as u64).ok()? as *const T;
-        Some(unsafe { core::slice::from_raw_parts(phys_addr, len) })
+    /// The values, or `GuestMemoryAccessFailed` if any page of them is not present or not accessible.
+    pub fn read_vec<T: Pod>(&self, va: u64, count: usize) -> Result<Vec<T>, HypervisorError> {
+        let mut values = Vec::<T>::with_capacity(count);
+        let bytes = unsafe { core::slice::from_raw_parts_mut(values.as_mut_ptr() as *mut u8, count * size_of::<T>()) };
+
+        self.read_into(va, bytes)?;
+        unsafe { values.set_len(count) };
+
+        Ok(values)
     }
 
-    /// Reads a slice of guest memory using a specified guest CR3.
-    ///
-    /// This function reads from the guest virtual address using a CR3 value provided by the caller.
+    /// Reads the characters of a `_UNICODE_STRING`.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to start reading from.
-    /// * `len` - The number of elements to read.
-    /// * `guest_cr3` - The CR3 value to use for translation.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// * `va` - The guest virtual address of the `_UNICODE_STRING` structure.
     ///
     /// # Returns
     ///
-    /// A `Result<&[T], HypervisorError>` containing the borrowed slice on success, or an error if the read fails.
-    pub fn read_guest_virt_slice_with_explicit_cr3<'a, T: Sized>(ptr: *const T, len: usize, guest_cr3: u64) -> Option<&'a [T]> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_explicit_cr3(ptr as u64, guest_cr3).ok()? as *const T;
-        Some(unsafe { core::slice::from_raw_parts(phys_addr, len) })
+    /// The `Length / 2` characters of the string, without a terminator, or `GuestMemoryAccessFailed` if they could not be read.
+    pub fn read_unicode_string(&self, va: u64) -> Result<Vec<u16>, HypervisorError> {
+        let length = self.read::<u16>(va.wrapping_add(offset_of!(UNICODE_STRING, Length) as u64))?;
+        let buffer = self.read::<u64>(va.wrapping_add(offset_of!(UNICODE_STRING, Buffer) as u64))?;
+
+        if buffer == 0 {
+            return Ok(Vec::new());
+        }
+
+        self.read_vec(buffer, length as usize / 2)
     }
 
-    /// Writes a value to a guest virtual address using the current guest CR3.
+    /// Writes data to guest memory.
     ///
-    /// This function writes to the guest virtual address using the current CR3 value from the VMCS.
+    /// Every page is checked before anything is written, so the data is either written completely or not at all.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to write to.
-    /// * `value` - The value to write.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// * `va` - The guest virtual address to write to.
+    /// * `data` - The data to write.
     ///
     /// # Returns
     ///
-    /// A `Result<(), HypervisorError>` indicating success or failure.
-    pub fn write_guest_virt_with_current_cr3<T: Sized>(ptr: *mut T, value: T) -> Option<()> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_current_cr3(ptr as u64).ok()? as *mut T;
-        unsafe {
-            phys_addr.write(value);
+    /// `Ok(())` if the data was written, or `GuestMemoryAccessFailed` if any page is not present or not accessible.
+    pub fn write_from(&self, va: u64, data: &[u8]) -> Result<(), HypervisorError> {
+        if self.for_each_page(va, data.len(), true, |_, _, _| {}) != data.len() {
+            return Err(HypervisorError::GuestMemoryAccessFailed);
         }
-        Some(())
+
+        self.for_each_page(va, data.len(), true, |pa, offset, chunk| unsafe {
+            core::ptr::copy_nonoverlapping(data[offset..].as_ptr(), pa as *mut u8, chunk)
+        });
+
+        Ok(())
     }
 
-    /// Writes a value to a guest virtual address using a specified guest CR3.
-    ///
-    /// This function writes to the guest virtual address using a CR3 value provided by the caller.
+    /// Writes a value to guest memory.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to write to.
+    /// * `va` - The guest virtual address to write to.
     /// * `value` - The value to write.
-    /// * `guest_cr3` - The CR3 value to use for translation.
     ///
-    /// # Safety
+    /// # Returns
+    ///
+    /// `Ok(())` if the value was written, or `GuestMemoryAccessFailed` if any page of it is not present or not accessible.
+    pub fn write<T: Pod>(&self, va: u64, value: &T) -> Result<(), HypervisorError> {
+        self.write_from(va, unsafe { core::slice::from_raw_parts(value as *const T as *const u8, size_of::<T>()) })
+    }
+
+    /// Copies guest memory between two views, page by page.
+    ///
+    /// Each chunk is bounded by the page boundaries of both the source and the destination,
+    /// and both pages are translated and checked on their own.
     ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// # Arguments
+    ///
+    /// * `src` - The view to copy from.
+    /// * `src_va` - The guest virtual address to copy from.
+    /// * `dst` - The view to copy to.
+    /// * `dst_va` - The guest virtual address to copy to.
+    /// * `len` - The number of bytes to copy.
     ///
     /// # Returns
     ///
-    /// A `Result<(), HypervisorError>` indicating success or failure.
-    pub fn write_guest_virt_with_explicit_cr3<T: Sized>(ptr: *mut T, value: T, guest_cr3: u64) -> Option<()> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_explicit_cr3(ptr as u64, guest_cr3).ok()? as *mut T;
-        unsafe {
-            phys_addr.write(value);
+    /// The number of bytes copied before the first page of either buffer that is not present or not accessible.
+    pub fn copy(src: &GuestMemory, src_va: u64, dst: &GuestMemory, dst_va: u64, len: usize) -> usize {
+        let mut copied = 0;
+
+        while copied < len {
+            let src_page_va = src_va.wrapping_add(copied as u64);
+            let dst_page_va = dst_va.wrapping_add(copied as u64);
+            let chunk = (len - copied).min(bytes_left_in_page(src_page_va)).min(bytes_left_in_page(dst_page_va));
+
+            let (Ok(src_pa), Ok(dst_pa)) = (src.translate(src_page_va, false), dst.translate(dst_page_va, true)) else {
+                break;
+            };
+
+            unsafe { core::ptr::copy(src_pa as *const u8, dst_pa as *mut u8, chunk) };
+            copied += chunk;
         }
-        Some(())
+
+        copied
     }
 
-    /// Writes a slice of data to guest memory using the current guest CR3.
+    /// Scans a guest virtual address range for a pattern, page by page.
     ///
-    /// This function writes to the guest virtual address using the current CR3 value from the VMCS.
+    /// Pages that are not present or not accessible are skipped. Matches that span two consecutive
+    /// pages are found as long as both pages are present.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to start writing to.
-    /// * `data` - The slice of data to write.
+    /// * `start` - The guest virtual address the scan starts at.
+    /// * `size` - The number of bytes to scan.
+    /// * `max_read` - The number of bytes after which the scan stops; `PatternMatches::cursor` tells where to resume it.
+    /// * `pattern` - The pattern to search for. It must not be empty.
     ///
-    /// # Safety
+    /// # Returns
+    ///
+    /// An iterator over the offset of every match from `start`, in ascending order.
+    pub fn scan<'a>(
+        &'a self,
+        start: u64,
+        size: u64,
+        max_read: u64,
+        pattern: &'a Pattern,
+    ) -> PatternMatches<'a, impl FnMut(u64, &mut [u8]) -> bool + 'a> {
+        PatternMatches::new(pattern, start, size, max_read, move |va, buffer| {
+            let read = self.read_into(va, buffer).is_ok();
+            if !read {
+                trace!("Skipping guest VA: {:#x}", va);
+            }
+            read
+        })
+    }
+
+    /// Translates a guest virtual address to a host physical address and checks it against the access mode.
+    ///
+    /// # Arguments
     ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// * `va` - The guest virtual address to translate.
+    /// * `write` - Whether the page is going to be written.
     ///
     /// # Returns
     ///
-    /// A `Result<(), HypervisorError>` indicating success or failure.
-    pub fn write_guest_virt_slice_with_current_cr3<T: Sized>(ptr: *mut T, data: &[T]) -> Option<()> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_current_cr3(ptr as u64).ok()? as *mut T;
-        unsafe {
-            core::ptr::copy_nonoverlapping(data.as_ptr(), phys_addr, data.len());
+    /// The host physical address, an error describing the paging level at which translation failed,
+    /// `GuestMemoryAccessFailed` if the page is not accessible with the access mode,
+    /// or `HypervisorMemoryAccessDenied` if the page is memory allocated by the hypervisor.
+    pub fn translate(&self, va: u64, write: bool) -> Result<u64, HypervisorError> {
+        let walk = unsafe { PageTables::walk_guest_virtual_to_guest_physical(self.guest_cr3, va) };
+        let guest_pa = walk.address.ok_or_else(|| walk.error())?;
+
+        if self.access_mode == AccessMode::User {
+            let rights = PageTables::effective_access_rights(&walk);
+
+            if !rights.user || (write && !rights.writable) {
+                trace!("Guest VA: {:#x} is not {} from user mode", va, if write { "writable" } else { "readable" });
+                return Err(HypervisorError::GuestMemoryAccessFailed);
+            }
+        }
+
+        let host_pa = PhysicalAddress::pa_from_guest_pa(guest_pa)?;
+
+        // The CR3 may come from the user mode client and map any page.
+        if HookManager::overlaps_allocated_memory(host_pa, 1) {
+            error!("Guest VA: {:#x} maps hypervisor memory at {:#x}", va, host_pa);
+            return Err(HypervisorError::HypervisorMemoryAccessDenied);
         }
-        Some(())
+
+        Ok(host_pa)
     }
 
-    /// Writes a slice of data to guest memory using a specified guest CR3.
-    ///
-    /// This function writes to the guest virtual address using a CR3 value provided by the caller.
+    /// Splits a guest virtual address range at page boundaries and translates each part.
     ///
     /// # Arguments
     ///
-    /// * `ptr` - The guest virtual address to start writing to.
-    /// * `data` - The slice of data to write.
-    /// * `guest_cr3` - The CR3 value to use for translation.
-    ///
-    /// # Safety
-    ///
-    /// This function is unsafe because it involves raw memory access and relies on the integrity of the VMCS.
+    /// * `va` - The start of the guest virtual address range.
+    /// * `len` - The length of the range in bytes.
+    /// * `write` - Whether the range is going to be written.
+    /// * `f` - Called with the host physical address, the offset into the range and the length of each part.
     ///
     /// # Returns
     ///
-    /// A `Result<(), HypervisorError>` indicating success or failure.
-    pub fn write_guest_virt_slice_with_explicit_cr3<T: Sized>(ptr: *mut T, data: &[T], guest_cr3: u64) -> Option<()> {
-        let phys_addr = PhysicalAddress::pa_from_va_with_explicit_cr3(ptr as u64, guest_cr3).ok()? as *mut T;
-        unsafe {
-            core::ptr::copy_nonoverlapping(data.as_ptr(), phys_addr, data.len());
+    /// The number of bytes processed before the first page that is not present or not accessible.
+    fn for_each_page(&self, va: u64, len: usize, write: bool, mut f: impl FnMut(u64, usize, usize)) -> usize {
+        let mut offset = 0;
+
+        while offset < len {
+            let page_va = va.wrapping_add(offset as u64);
+            let chunk = (len - offset).min(bytes_left_in_page(page_va));
+
+            let Ok(pa) = self.translate(page_va, write) else {
+                trace!("Guest VA: {:#x} is not accessible, stopping after {:#x} bytes", page_va, offset);
+                break;
+            };
+
+            f(pa, offset, chunk);
+            offset += chunk;
         }
-        Some(())
+
+        offset
     }
 }
+
+/// Returns the number of bytes from an address to the end of its 4 KB page.
+fn bytes_left_in_page(va: u64) -> usize {
+    BASE_PAGE_SIZE - (va as usize & (BASE_PAGE_SIZE - 1))
+}
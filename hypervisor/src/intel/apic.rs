@@ -0,0 +1,73 @@
+//! Sends NMIs to other processors through the local APIC, in xAPIC or x2APIC mode, whichever the guest enabled.
+//!
+//! Credits to the Intel® 64 and IA-32 Architectures Software Developer's Manual: 11.6 ISSUING INTERPROCESSOR INTERRUPTS
+//! and 11.12.9 ICR Operation in x2APIC Mode.
+
+use {
+    crate::intel::support::{rdmsr, wrmsr},
+    core::ptr::{read_volatile, write_volatile},
+};
+
+/// The MSR holding the base address and mode of the local APIC.
+const IA32_APIC_BASE: u32 = 0x1b;
+
+/// Set in `IA32_APIC_BASE` when the local APIC is in x2APIC mode.
+const APIC_BASE_X2APIC_ENABLE: u64 = 1 << 10;
+
+/// The bits of `IA32_APIC_BASE` holding the physical address of the xAPIC registers.
+const APIC_BASE_ADDRESS_MASK: u64 = 0x000f_ffff_ffff_f000;
+
+/// The MSR of the interrupt command register in x2APIC mode.
+const X2APIC_ICR: u32 = 0x830;
+
+/// The offset of the low half of the interrupt command register in the xAPIC registers.
+const XAPIC_ICR_LOW: u64 = 0x300;
+
+/// The offset of the high half of the interrupt command register in the xAPIC registers, holding the destination.
+const XAPIC_ICR_HIGH: u64 = 0x310;
+
+/// The NMI delivery mode of the interrupt command register.
+const ICR_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+
+/// The assert level of the interrupt command register, required for every delivery mode but INIT level de-assert.
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+
+/// Set in the low half of the xAPIC interrupt command register while the previous IPI has not been sent yet.
+const XAPIC_ICR_SEND_PENDING: u32 = 1 << 12;
+
+/// Sends an NMI to a processor.
+///
+/// The xAPIC registers are accessed through the identity map of the host, which covers them. In xAPIC mode the
+/// destination register is restored afterwards, in case the guest of the current processor was about to send an IPI.
+///
+/// # Arguments
+///
+/// * `apic_id` - The APIC ID of the processor, which must be below 256 in xAPIC mode.
+pub fn send_nmi(apic_id: u32) {
+    let apic_base = rdmsr(IA32_APIC_BASE);
+    let command = ICR_DELIVERY_MODE_NMI | ICR_LEVEL_ASSERT;
+
+    if apic_base & APIC_BASE_X2APIC_ENABLE != 0 {
+        wrmsr(X2APIC_ICR, (u64::from(apic_id) << 32) | u64::from(command));
+        return;
+    }
+
+    let icr_low = ((apic_base & APIC_BASE_ADDRESS_MASK) + XAPIC_ICR_LOW) as *mut u32;
+    let icr_high = ((apic_base & APIC_BASE_ADDRESS_MASK) + XAPIC_ICR_HIGH) as *mut u32;
+
+    unsafe {
+        while read_volatile(icr_low) & XAPIC_ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+
+        let guest_destination = read_volatile(icr_high);
+        write_volatile(icr_high, apic_id << 24);
+        write_volatile(icr_low, command);
+
+        while read_volatile(icr_low) & XAPIC_ICR_SEND_PENDING != 0 {
+            core::hint::spin_loop();
+        }
+
+        write_volatile(icr_high, guest_destination);
+    }
+}
@@ -8,14 +8,52 @@
 use {
     crate::intel::support::{sgdt, sidt},
     alloc::vec::Vec,
+    core::arch::global_asm,
     x86::{
+        bits64::segmentation::Descriptor64,
         dtables::DescriptorTablePointer,
         segmentation::{
             cs, BuildDescriptor, CodeSegmentType, Descriptor, DescriptorBuilder, GateDescriptorBuilder, SegmentDescriptorBuilder, SegmentSelector,
         },
+        vmx::vmcs,
     },
 };
 
+/// The number of vectors of the IDT.
+const IDT_VECTOR_COUNT: usize = 256;
+
+/// The vector of non-maskable interrupts.
+const NMI_VECTOR: usize = 2;
+
+extern "C" {
+    /// Handles an NMI received while the processor runs the hypervisor, e.g. one sent by `ept_sync` to make it exit.
+    ///
+    /// The NMI cannot be handled by the hypervisor, so NMI-window exiting is enabled in the current VMCS and the NMI is
+    /// handled by `vmexit::nmi::handle_nmi_window` as soon as the guest resumes. Must not be called directly.
+    fn host_nmi_handler();
+}
+
+global_asm!(
+    r#"
+// NMI handler of the host IDT. VMREAD and VMWRITE change RFLAGS only, which IRETQ restores.
+.global host_nmi_handler
+host_nmi_handler:
+    push    rax
+    push    rdx
+
+    mov     rdx, {primary_procbased_exec_controls}
+    vmread  rax, rdx
+    or      rax, {nmi_window_exiting}
+    vmwrite rdx, rax
+
+    pop     rdx
+    pop     rax
+    iretq
+"#,
+    primary_procbased_exec_controls = const vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS,
+    nmi_window_exiting = const vmcs::control::PrimaryControls::NMI_WINDOW_EXITING.bits(),
+);
+
 /// Represents the descriptor tables (GDT and IDT) for the host and guest.
 /// Contains the GDT, IDT, TSS, and their respective register pointers.
 #[repr(C, align(4096))]
@@ -118,8 +156,8 @@ impl Descriptors {
         descriptors.cs = SegmentSelector::new(1, x86::Ring::Ring0);
         descriptors.tr = SegmentSelector::new(2, x86::Ring::Ring0);
 
-        // Initialize the IDT with empty descriptors for the host
-        descriptors.idt = Self::copy_current_idt();
+        // The host only handles NMIs, any other interrupt or exception in the host is fatal.
+        descriptors.idt = Self::host_idt(descriptors.cs);
         descriptors.idtr = DescriptorTablePointer::new_from_slice(&descriptors.idt);
 
         log::debug!("New GDT with TSS and IDT created for host successfully!");
@@ -163,22 +201,31 @@ impl Descriptors {
             .finish()
     }
 
-    /// Copies the current IDT for the guest.
-    fn copy_current_idt() -> Vec<u64> {
-        log::trace!("Copying current IDT");
-
-        // Get the current IDTR
-        let current_idtr = sidt();
+    /// Builds the IDT of the host, in which only the NMI vector is present.
+    ///
+    /// # Arguments
+    ///
+    /// - `cs`: The code segment selector of the host.
+    ///
+    /// # Returns
+    ///
+    /// The IDT entries, two for each 16-byte gate descriptor.
+    fn host_idt(cs: SegmentSelector) -> Vec<u64> {
+        log::trace!("Building host IDT");
 
-        // Create a slice from the current IDT entries.
-        let current_idt = unsafe { core::slice::from_raw_parts(current_idtr.base.cast::<u64>(), usize::from(current_idtr.limit + 1) / 8) };
+        let mut idt = alloc::vec![0u64; IDT_VECTOR_COUNT * 2];
 
-        // Create a new IDT from the slice.
-        let new_idt = current_idt.to_vec();
+        let nmi_gate: Descriptor64 =
+            <DescriptorBuilder as GateDescriptorBuilder<u64>>::interrupt_descriptor(cs, host_nmi_handler as unsafe extern "C" fn() as usize as u64)
+                .present()
+                .dpl(x86::Ring::Ring0)
+                .finish();
+        let nmi_gate: [u64; 2] = unsafe { core::mem::transmute(nmi_gate) };
+        idt[NMI_VECTOR * 2..NMI_VECTOR * 2 + 2].copy_from_slice(&nmi_gate);
 
-        log::trace!("Copied current IDT");
+        log::trace!("Built host IDT");
 
-        new_idt
+        idt
     }
 }
 
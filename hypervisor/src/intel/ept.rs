@@ -12,12 +12,16 @@ use {
             invept::invept_all_contexts,
             invvpid::invvpid_all_contexts,
             mtrr::{MemoryType, Mtrr},
+            paging::PageWalk,
         },
     },
     bitfield::bitfield,
-    core::ptr::addr_of,
+    core::{
+        ptr::{addr_of, write_volatile},
+        sync::atomic::{fence, Ordering},
+    },
     log::*,
-    x86::bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
+    x86::bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, LARGE_PAGE_SIZE},
 };
 
 /// Represents the entire Extended Page Table structure.
@@ -146,80 +150,61 @@ impl Ept {
     ///
     /// A `Result<u64, HypervisorError>` containing the host physical address on success.
     pub unsafe fn translate_guest_pa_to_host_pa(ept_base: u64, guest_pa: u64) -> Result<u64, HypervisorError> {
-        let guest_pa = VAddr::from(guest_pa);
-
-        // Cast the EPT base to the PML4 table structure.
-        let pml4_table = ept_base as *const Pml4;
-
-        // Calculate the PML4 index and access the corresponding entry.
-        let pml4_index = pml4_index(guest_pa);
-        let pml4_entry = &(*pml4_table).0.entries[pml4_index];
-
-        // Check if the PML4 entry is present (readable).
-        if !pml4_entry.readable() {
-            error!("PML4 entry is not present: {:#x}", guest_pa);
-            return Err(HypervisorError::InvalidPml4Entry);
-        }
-
-        // Cast the entry to the PDPT table structure.
-        let pdpt_table = (pml4_entry.pfn() << BASE_PAGE_SHIFT) as *const Pdpt;
+        let walk = Self::walk_guest_pa_to_host_pa(ept_base, guest_pa);
 
-        // Calculate the PDPT index and access the corresponding entry.
-        let pdpt_index = pdpt_index(guest_pa);
-        let pdpt_entry = &(*pdpt_table).0.entries[pdpt_index];
-
-        // Check if the PDPT entry is present (readable).
-        if !pdpt_entry.readable() {
-            error!("PDPT entry is not present: {:#x}", guest_pa);
-            return Err(HypervisorError::InvalidPdptEntry);
-        }
-
-        // Check if the PDPT entry is a huge page (1 GB), if so, calculate the host physical address.
-        if pdpt_entry.large() {
-            let host_pa = (pdpt_entry.pfn() << BASE_PAGE_SHIFT) + (guest_pa.as_u64() % HUGE_PAGE_SIZE as u64);
-            return Ok(host_pa);
-        }
-
-        // Cast the entry to the PD table structure.
-        let pd_table = (pdpt_entry.pfn() << BASE_PAGE_SHIFT) as *const Pd;
+        walk.address.ok_or_else(|| {
+            error!("{} entry is not present: {:#x}", PageWalk::LEVEL_NAMES[walk.levels - 1], guest_pa);
+            walk.error()
+        })
+    }
 
-        // Calculate the PD index and access the corresponding entry.
-        let pd_index = pd_index(guest_pa);
-        let pd_entry = &(*pd_table).0.entries[pd_index];
+    /// Walks the EPT hierarchy for a guest physical address, recording every entry visited.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept_base` - The base address of the EPT structure.
+    /// * `guest_pa` - The guest physical address to translate.
+    ///
+    /// # Safety
+    ///
+    /// `ept_base` must point to a valid EPT PML4 table in identity-mapped host memory.
+    ///
+    /// # Returns
+    ///
+    /// A `PageWalk` with the EPT PML4E, PDPTE, PDE and PTE visited, stopping at the first non-present entry or the entry mapping the page.
+    pub unsafe fn walk_guest_pa_to_host_pa(ept_base: u64, guest_pa: u64) -> PageWalk {
+        let guest_pa = VAddr::from(guest_pa);
+        let indices = [pml4_index(guest_pa), pdpt_index(guest_pa), pd_index(guest_pa), pt_index(guest_pa)];
 
-        // Check if the PD entry is present (readable).
-        if !pd_entry.readable() {
-            error!("PD entry is not present: {:#x}", guest_pa);
-            return Err(HypervisorError::InvalidPdEntry);
-        }
+        let mut walk = PageWalk::default();
 
-        // Check if the PD entry is a large page (2 MB), if so, calculate the host physical address.
-        if pd_entry.large() {
-            let host_pa = (pd_entry.pfn() << BASE_PAGE_SHIFT) + (guest_pa.as_u64() % LARGE_PAGE_SIZE as u64);
-            return Ok(host_pa);
-        }
+        // Cast the EPT base to the PML4 table structure.
+        let mut table = ept_base as *const Table;
 
-        // Cast the entry to the PT table structure.
-        let pt_table = (pd_entry.pfn() << BASE_PAGE_SHIFT) as *const Pt;
+        for (level, index) in indices.into_iter().enumerate() {
+            let entry = (*table).entries[index];
+            walk.entries[level] = entry.0;
+            walk.levels = level + 1;
 
-        // Calculate the PT index and access the corresponding entry.
-        let pt_index = pt_index(guest_pa);
-        let pt_entry = &(*pt_table).0.entries[pt_index];
+            // Check if the entry is present (readable).
+            // Ept hooks would make the guest_pa X-only at some point, so PT entries are not checked.
+            if level != PageWalk::PT_LEVEL && !entry.readable() {
+                return walk;
+            }
 
-        // Ept hooks would make the guest_pa X-only at some point, so this would fail and is commented out.
+            // A PDPTE or PDE may map a huge (1 GB) or large (2 MB) page; a PTE always maps a 4 KB page.
+            if level == PageWalk::PT_LEVEL || (level != PageWalk::PML4_LEVEL && entry.large()) {
+                let page_size = PageWalk::PAGE_SIZES[level];
+                walk.page_size = page_size;
+                walk.address = Some((entry.pfn() << BASE_PAGE_SHIFT) + (guest_pa.as_u64() % page_size));
+                return walk;
+            }
 
-        /*
-        // Check if the PT entry is present (readable).
-        if !pt_entry.readable() {
-            error!("PT entry is not present: {:#x}", guest_pa);
-            return Err(HypervisorError::InvalidPtEntry);
+            // Cast the entry to the next table structure.
+            table = (entry.pfn() << BASE_PAGE_SHIFT) as *const Table;
         }
-         */
 
-        // The PT entry is a 4 KB page, calculate the host physical address.
-        let host_pa = (pt_entry.pfn() << BASE_PAGE_SHIFT) + (guest_pa.as_u64() % BASE_PAGE_SIZE as u64);
-
-        Ok(host_pa)
+        unreachable!("the PT level always terminates the walk")
     }
 
     /// Checks if a guest physical address is part of a large 2MB page.
@@ -275,9 +260,6 @@ impl Ept {
         // Get the memory type of the large page, before we unmap (reset) it.
         let memory_type = pde.memory_type();
 
-        // Zero out the PD entry to ensure it's clean.
-        *pde = Entry(0);
-
         trace!("Dumping EPT entries while splitting......");
 
         // Map the unmapped physical memory to 4KB pages.
@@ -296,12 +278,117 @@ impl Ept {
         }
 
         // Update the PDE to point to the new page table.
+        Self::write_entry(pde, Self::page_table_entry(pt));
+
+        Ok(())
+    }
+
+    /// Splits a large 2MB page using a page table that already maps its 4KB pages, as populated by
+    /// `split_2mb_to_4kb` on the EPT of another processor.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The guest physical address within the 2MB page that needs to be split.
+    /// * `pt`: The populated page table of the 2MB page.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful.
+    pub fn split_2mb_with_populated_pt(&mut self, guest_pa: u64, pt: &Pt) -> Result<(), HypervisorError> {
+        trace!("Splitting 2mb page with populated page table: {:#x}", guest_pa);
+
+        let guest_pa = VAddr::from(guest_pa);
+        let pde = &mut self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+
+        if !pde.large() {
+            trace!("Page is already split: {:x}.", guest_pa);
+            return Err(HypervisorError::PageAlreadySplit);
+        }
+
+        Self::write_entry(pde, Self::page_table_entry(pt));
+
+        Ok(())
+    }
+
+    /// Checks if a 2MB page is split into 4KB pages mapped by the given page table.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The guest physical address within the 2MB page.
+    /// * `pt`: The page table to check for.
+    ///
+    /// # Returns
+    ///
+    /// `true` if the PDE of the 2MB page references `pt`, otherwise `false`.
+    pub fn is_split_with(&self, guest_pa: u64, pt: &Pt) -> bool {
+        let guest_pa = VAddr::from(guest_pa);
+        let pde = &self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+
+        !pde.large() && pde.pfn() == (pt as *const _ as u64) >> BASE_PAGE_SHIFT
+    }
+
+    /// Builds a PDE that references a page table.
+    fn page_table_entry(pt: &Pt) -> Entry {
+        let mut pde = Entry(0);
         pde.set_readable(true);
         pde.set_writable(true);
         pde.set_executable(true);
         pde.set_memory_type(0); // Table 29-6. Format of an EPT Page-Directory Entry (PDE) that References an EPT Page Table: 6:3 Reserved (must be 0)
         pde.set_large(false); // This is no longer a large page.
-        pde.set_pfn((pt as *mut _ as u64) >> BASE_PAGE_SHIFT);
+        pde.set_pfn((pt as *const _ as u64) >> BASE_PAGE_SHIFT);
+        pde
+    }
+
+    /// Replaces an entry with a single store, after the page table it may reference has been written.
+    ///
+    /// The EPT of a processor may be modified by another processor while it is in use, so the
+    /// processor walking it must never see a partially updated entry.
+    fn write_entry(entry: &mut Entry, value: Entry) {
+        fence(Ordering::Release);
+        unsafe { write_volatile(entry, value) };
+    }
+
+    /// Merges the 512 4KB pages of a split 2MB page back into a large page, undoing `split_2mb_to_4kb`.
+    ///
+    /// The large page is identity mapped with full permissions and the memory type the MTRRs give its range,
+    /// so any hook or hidden page left in the page table is discarded. The page table is no longer referenced afterwards.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa`: The guest physical address within the 2MB page that needs to be merged.
+    /// * `pt`: The page table the 2MB page was split with.
+    ///
+    /// # Returns
+    ///
+    /// A `Result<(), HypervisorError>` indicating if the operation was successful, or `PageNotSplit` if the
+    /// 2MB page is not mapped through `pt`.
+    pub fn merge_4kb_to_2mb(&mut self, guest_pa: u64, pt: &Pt) -> Result<(), HypervisorError> {
+        trace!("Merging 4kb pages into 2mb page: {:#x}", guest_pa);
+
+        let guest_pa = VAddr::from(guest_pa).align_down_to_large_page();
+
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
+
+        // Only merge pages split with this page table, the first 2MB are mapped through the EPT's own page table.
+        if !self.is_split_with(guest_pa.as_u64(), pt) {
+            trace!("Page is not split with the given page table: {:x}.", guest_pa);
+            return Err(HypervisorError::PageNotSplit);
+        }
+
+        let memory_type = Mtrr::new()
+            .find(guest_pa.as_u64()..guest_pa.as_u64() + LARGE_PAGE_SIZE as u64)
+            .ok_or(HypervisorError::MemoryTypeResolutionError)?;
+
+        let mut large_pde = Entry(0);
+        large_pde.set_readable(true);
+        large_pde.set_writable(true);
+        large_pde.set_executable(true);
+        large_pde.set_memory_type(memory_type as u64);
+        large_pde.set_large(true);
+        large_pde.set_pfn(guest_pa.as_u64() >> BASE_PAGE_SHIFT);
+
+        Self::write_entry(&mut self.pd[pdpt_index].0.entries[pd_index], large_pde);
 
         Ok(())
     }
@@ -406,6 +493,49 @@ impl Ept {
         Ok(old_hpa)
     }
 
+    /// Retrieves the current access permissions of the page containing a guest physical address.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to look up.
+    /// * `pt` - The page table used for the page if it has been split into 4KB pages.
+    ///
+    /// # Returns
+    ///
+    /// The `AccessType` of the 4KB page, or of the 2MB page if it has not been split.
+    pub fn get_page_permissions(&self, guest_pa: u64, pt: &Pt) -> AccessType {
+        let guest_pa = VAddr::from(guest_pa);
+        let pdpt_index = pdpt_index(guest_pa);
+        let pd_index = pd_index(guest_pa);
+        let pt_index = pt_index(guest_pa);
+
+        let pde = &self.pd[pdpt_index].0.entries[pd_index];
+        let entry = if pde.large() { pde } else { &pt.0.entries[pt_index] };
+
+        let mut access_type = AccessType::empty();
+        access_type.set(AccessType::READ, entry.readable());
+        access_type.set(AccessType::WRITE, entry.writable());
+        access_type.set(AccessType::EXECUTE, entry.executable());
+        access_type
+    }
+
+    /// Retrieves the host physical address the 4KB page containing a guest physical address is mapped to.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_pa` - The guest physical address to look up.
+    /// * `pt` - The page table the 2MB page containing `guest_pa` is split with.
+    ///
+    /// # Returns
+    ///
+    /// The host physical address of the 4KB page, or `None` if the 2MB page has not been split.
+    pub fn get_mapped_host_pa(&self, guest_pa: u64, pt: &Pt) -> Option<u64> {
+        let guest_pa = VAddr::from(guest_pa);
+        let pde = &self.pd[pdpt_index(guest_pa)].0.entries[pd_index(guest_pa)];
+
+        (!pde.large()).then(|| pt.0.entries[pt_index(guest_pa)].pfn() << BASE_PAGE_SHIFT)
+    }
+
     pub fn dump_ept_entries(&self, guest_pa: u64, pt: &Pt) {
         let guest_pa = VAddr::from(guest_pa);
         let pdpt_index = pdpt_index(guest_pa);
@@ -0,0 +1,373 @@
+//! Keeps the EPTs of all virtualized processors consistent with each other.
+//!
+//! Every processor owns its own EPT and its own page table for each split 2MB page, so the pages it swaps while
+//! single-stepping a hook do not affect the others. A hook, unhook or hide is applied to the EPT and page table of
+//! every processor instead. The processor making the change writes to the EPTs of the others directly while holding
+//! the hook manager lock, which serializes every EPT change.
+//!
+//! Translations cached by another processor are only invalidated by that processor. Each change publishes a new
+//! EPT generation, and every processor invalidates its EPT and VPID contexts on its next VM exit when it is behind.
+//! The processor that made the change sends an NMI to every processor that is behind, which exits on NMIs, and waits
+//! for all of them to catch up before it reports the change as done and resumes its guest. The wait is bounded: a
+//! processor that does not exit in time makes the change fail with `EptFlushTimeout`.
+//!
+//! An NMI sent this way is not forwarded to the guest, see `take_nmi_kick`. NMIs do not queue, so a guest NMI that
+//! arrives together with it is dropped as well.
+//!
+//! The exception bitmap of every processor is kept in sync the same way: breakpoints are only intercepted while
+//! int3 hooks are installed, and every processor intercepts them before the int3 of a new hook can be executed.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            apic::send_nmi,
+            ept::Ept,
+            hooks::{inline::InlineHookType, memory_manager::MemoryManager},
+            invept::invept_all_contexts,
+            invvpid::invvpid_all_contexts,
+            support::{apic_id, rdtsc, vmwrite},
+            vm::Vm,
+            vmerror::ExceptionInterrupt,
+        },
+    },
+    alloc::vec::Vec,
+    core::sync::atomic::{AtomicU64, AtomicUsize, Ordering},
+    log::*,
+    spin::Mutex,
+    x86::vmx::vmcs,
+};
+
+/// Number of TSC ticks to wait for every processor to invalidate its EPT before giving up.
+const FLUSH_TIMEOUT_TSC_TICKS: u64 = 1 << 32;
+
+/// The generation of the EPTs, incremented after every change other processors have to invalidate.
+/// It starts at 1 so a processor whose generation is 0 always invalidates on its next VM exit.
+static EPT_GENERATION: AtomicU64 = AtomicU64::new(1);
+
+/// The number of int3 hooks being installed, for which every processor intercepts breakpoints before the hook is written.
+static PENDING_BREAKPOINT_HOOKS: AtomicUsize = AtomicUsize::new(0);
+
+/// The EPTs of all virtualized processors.
+pub static SHARED_EPT_REGISTRY: Mutex<EptRegistry> = Mutex::new(EptRegistry::new());
+
+/// The EPT of a virtualized processor.
+#[derive(Debug, Clone, Copy)]
+struct ProcessorEpt {
+    /// The APIC ID of the processor.
+    apic_id: u32,
+
+    /// The address of the EPT of the processor.
+    ept: u64,
+
+    /// The last generation the processor invalidated its EPT and VPID contexts for.
+    flushed_generation: u64,
+
+    /// Whether an NMI was sent to the processor to make it exit, which it has not received yet.
+    nmi_kick_pending: bool,
+}
+
+/// Registry of the EPTs of all virtualized processors.
+#[derive(Debug)]
+pub struct EptRegistry {
+    /// The registered EPTs.
+    processors: Vec<ProcessorEpt>,
+}
+
+impl Default for EptRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl EptRegistry {
+    /// Creates an empty registry.
+    pub const fn new() -> Self {
+        Self { processors: Vec::new() }
+    }
+
+    /// Registers the EPT of the current processor, which must stay at the same address until the hypervisor exits.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the current processor.
+    pub fn register(&mut self, vm: &mut Vm) {
+        let generation = EPT_GENERATION.load(Ordering::Acquire);
+        vm.ept_generation = generation;
+
+        debug!("Registering EPT of processor {} at {:#x}", apic_id(), &vm.primary_ept as *const Ept as u64);
+
+        self.processors.push(ProcessorEpt {
+            apic_id: apic_id(),
+            ept: &vm.primary_ept as *const Ept as u64,
+            flushed_generation: generation,
+            nmi_kick_pending: false,
+        });
+    }
+
+    /// Records the generation a processor has invalidated its EPT and VPID contexts for.
+    ///
+    /// # Arguments
+    ///
+    /// * `ept` - The address of the EPT of the processor.
+    /// * `generation` - The generation, or `u64::MAX` if the processor will invalidate them before it runs guest code again.
+    fn set_flushed_generation(&mut self, ept: u64, generation: u64) {
+        if let Some(processor) = self.processors.iter_mut().find(|processor| processor.ept == ept) {
+            processor.flushed_generation = generation;
+        }
+    }
+
+    /// Sends an NMI to every processor that has not invalidated its EPT and VPID contexts for a generation yet, so it
+    /// exits and does. A processor that was already sent one is not sent another until it received it.
+    ///
+    /// # Arguments
+    ///
+    /// * `generation` - The generation the processors have to reach.
+    fn kick_processors_behind(&mut self, generation: u64) {
+        for processor in self
+            .processors
+            .iter_mut()
+            .filter(|processor| processor.flushed_generation < generation && !processor.nmi_kick_pending)
+        {
+            trace!("Sending NMI to processor {} to invalidate EPT generation {}", processor.apic_id, generation);
+            processor.nmi_kick_pending = true;
+            send_nmi(processor.apic_id);
+        }
+    }
+
+    /// Returns the oldest generation any registered processor has invalidated its EPT and VPID contexts for.
+    pub fn flushed_generation(&self) -> u64 {
+        self.processors
+            .iter()
+            .map(|processor| processor.flushed_generation)
+            .min()
+            .unwrap_or_else(|| EPT_GENERATION.load(Ordering::Acquire))
+    }
+}
+
+/// Applies a change to the EPT of the current processor and to the EPTs of every other virtualized processor.
+///
+/// Must be called with the hook manager locked, which serializes the changes made to the EPTs of other processors.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+/// * `f` - The change to apply to each EPT.
+///
+/// # Returns
+///
+/// * `Ok(())` - The change was applied to every EPT.
+/// * `Err(E)` - The first error returned by `f`, the EPTs it was not applied to yet are left unchanged.
+pub fn for_each_ept<E>(vm: &mut Vm, mut f: impl FnMut(&mut Ept) -> Result<(), E>) -> Result<(), E> {
+    f(&mut vm.primary_ept)?;
+
+    let own_ept = &vm.primary_ept as *const Ept as u64;
+    let registry = SHARED_EPT_REGISTRY.lock();
+
+    for processor in registry.processors.iter().filter(|processor| processor.ept != own_ept) {
+        trace!("Applying EPT change to processor {}", processor.apic_id);
+        f(unsafe { &mut *(processor.ept as *mut Ept) })?;
+    }
+
+    Ok(())
+}
+
+/// Invalidates the EPT and VPID contexts of the current processor and requests every other processor to do the same.
+///
+/// Must be called with the hook manager locked after the EPTs were changed. The current processor waits for the
+/// other processors in `wait_for_pending_flush` once the lock is released, before it reports the change as done.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+pub fn invalidate_all_processors(vm: &mut Vm) {
+    invept_all_contexts();
+    invvpid_all_contexts();
+
+    publish_generation(vm);
+}
+
+/// Publishes a new EPT generation that every other processor has to acknowledge on its next VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor, which waits for the others in `wait_for_pending_flush`.
+fn publish_generation(vm: &mut Vm) {
+    let generation = EPT_GENERATION.fetch_add(1, Ordering::AcqRel) + 1;
+    trace!("Requesting EPT invalidation on all processors for generation {}", generation);
+
+    vm.ept_generation = generation;
+    vm.pending_ept_flush = Some(generation);
+    SHARED_EPT_REGISTRY
+        .lock()
+        .set_flushed_generation(&vm.primary_ept as *const Ept as u64, generation);
+}
+
+/// Makes every processor intercept breakpoints before an int3 hook is installed, and keeps them intercepted until
+/// `end_breakpoint_hook` is called, by which time the installed hook keeps them intercepted.
+///
+/// Must be called without the hook manager locked, as it waits for every other processor to reach its next VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+///
+/// # Returns
+///
+/// * `Ok(())` - Every processor intercepts breakpoints.
+/// * `Err(HypervisorError::EptFlushTimeout)` - A processor did not reach its next VM exit in time, the hook must not be installed.
+///   `end_breakpoint_hook` has already been called.
+pub fn begin_breakpoint_hook(vm: &mut Vm) -> Result<(), HypervisorError> {
+    PENDING_BREAKPOINT_HOOKS.fetch_add(1, Ordering::AcqRel);
+    sync_exception_bitmap(vm);
+
+    // Every processor updates its exception bitmap before it acknowledges the generation.
+    publish_generation(vm);
+    wait_for_pending_flush(vm).inspect_err(|_| end_breakpoint_hook())
+}
+
+/// Ends the installation of an int3 hook started with `begin_breakpoint_hook`, whether or not it was installed.
+pub fn end_breakpoint_hook() {
+    PENDING_BREAKPOINT_HOOKS.fetch_sub(1, Ordering::AcqRel);
+}
+
+/// Intercepts breakpoints on the current processor while int3 hooks are installed or being installed, and stops
+/// intercepting them once none are left. Called on every VM exit, before `sync_ept`.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+pub fn sync_exception_bitmap(vm: &mut Vm) {
+    let intercept_breakpoints = PENDING_BREAKPOINT_HOOKS.load(Ordering::Acquire) > 0 || MemoryManager::has_inline_hooks(InlineHookType::Int3);
+
+    if vm.intercepts_breakpoints == intercept_breakpoints {
+        return;
+    }
+
+    trace!(
+        "{} breakpoints",
+        if intercept_breakpoints {
+            "Intercepting"
+        } else {
+            "No longer intercepting"
+        }
+    );
+
+    // Breakpoints that are not hooks are re-injected by handle_exception.
+    let exception_bitmap = if intercept_breakpoints {
+        1u64 << (ExceptionInterrupt::Breakpoint as u32)
+    } else {
+        0
+    };
+    vmwrite(vmcs::control::EXCEPTION_BITMAP, exception_bitmap);
+
+    vm.intercepts_breakpoints = intercept_breakpoints;
+}
+
+/// Invalidates the EPT and VPID contexts of the current processor if another processor changed the EPTs since
+/// it last did. Called on every VM exit, before the exit is handled.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+pub fn sync_ept(vm: &mut Vm) {
+    let generation = EPT_GENERATION.load(Ordering::Acquire);
+
+    if vm.ept_generation >= generation {
+        return;
+    }
+
+    trace!("Invalidating EPT for generation {}", generation);
+    invept_all_contexts();
+    invvpid_all_contexts();
+
+    vm.ept_generation = generation;
+    SHARED_EPT_REGISTRY
+        .lock()
+        .set_flushed_generation(&vm.primary_ept as *const Ept as u64, generation);
+}
+
+/// Marks the current processor as not running guest code until its next VM exit, such as while it waits for a SIPI,
+/// so other processors do not wait for it. It invalidates its EPT and VPID contexts on that exit.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+pub fn park(vm: &mut Vm) {
+    vm.ept_generation = 0;
+    SHARED_EPT_REGISTRY
+        .lock()
+        .set_flushed_generation(&vm.primary_ept as *const Ept as u64, u64::MAX);
+}
+
+/// Waits for every processor to invalidate the EPT changes made by the current processor, if it made any.
+///
+/// Must be called without the hook manager locked, as the other processors may need it to reach their next VM exit.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+///
+/// # Returns
+///
+/// * `Ok(())` - Every processor invalidated the changes, or none were made.
+/// * `Err(HypervisorError::EptFlushTimeout)` - A processor did not invalidate them in time and may still use the old translations.
+pub fn wait_for_pending_flush(vm: &mut Vm) -> Result<(), HypervisorError> {
+    let Some(generation) = vm.pending_ept_flush.take() else {
+        return Ok(());
+    };
+
+    let start = rdtsc();
+
+    loop {
+        {
+            let mut registry = SHARED_EPT_REGISTRY.lock();
+            if registry.flushed_generation() >= generation {
+                break;
+            }
+
+            registry.kick_processors_behind(generation);
+        }
+
+        // Processors waiting for each other must also acknowledge each other's changes.
+        sync_ept(vm);
+
+        if rdtsc().wrapping_sub(start) > FLUSH_TIMEOUT_TSC_TICKS {
+            error!("Timed out waiting for all processors to invalidate EPT generation {}", generation);
+            return Err(HypervisorError::EptFlushTimeout);
+        }
+
+        core::hint::spin_loop();
+    }
+
+    trace!("All processors invalidated EPT generation {}", generation);
+
+    Ok(())
+}
+
+/// Returns the oldest generation any registered processor has invalidated its EPT and VPID contexts for.
+/// Memory referenced by the EPTs before a change can be freed once this reaches the generation of the change.
+pub fn flushed_generation() -> u64 {
+    SHARED_EPT_REGISTRY.lock().flushed_generation()
+}
+
+/// Consumes the NMI sent to the current processor by `kick_processors_behind`, if one is pending. Called when the
+/// processor receives an NMI, which is forwarded to the guest unless it was sent to make the processor exit.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance of the current processor.
+///
+/// # Returns
+///
+/// `true` if an NMI was sent to the processor to make it exit, in which case it must not be forwarded to the guest.
+pub fn take_nmi_kick(vm: &Vm) -> bool {
+    let own_ept = &vm.primary_ept as *const Ept as u64;
+
+    SHARED_EPT_REGISTRY
+        .lock()
+        .processors
+        .iter_mut()
+        .find(|processor| processor.ept == own_ept)
+        .is_some_and(|processor| core::mem::take(&mut processor.nmi_kick_pending))
+}
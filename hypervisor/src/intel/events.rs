@@ -64,11 +64,24 @@ impl EventInjection {
     }
 
     /// Inject Breakpoint (#BP) to the guest (Event Injection).
+    ///
+    /// The breakpoint is injected as a software exception, so the guest sees the return address after the `int3` instruction.
     fn breakpoint() -> u32 {
         let mut event = EventInjection(0);
 
         event.set_vector(ExceptionInterrupt::Breakpoint as u32);
-        event.set_type(InterruptionType::HardwareException as u32);
+        event.set_type(InterruptionType::SoftwareException as u32);
+        event.set_valid(VALID);
+
+        event.0
+    }
+
+    /// Inject Non-Maskable Interrupt (NMI) to the guest (Event Injection).
+    fn non_maskable_interrupt() -> u32 {
+        let mut event = EventInjection(0);
+
+        event.set_vector(ExceptionInterrupt::NonMaskableInterrupt as u32);
+        event.set_type(InterruptionType::NonMaskableInterrupt as u32);
         event.set_valid(VALID);
 
         event.0
@@ -133,12 +146,27 @@ impl EventInjection {
     /// This function is used to signal to the guest that a breakpoint exception
     /// has occurred, typically used for debugging purposes.
     ///
+    /// # Arguments
+    ///
+    /// * `instruction_length` - The length of the instruction that raised the breakpoint, skipped by the guest handler.
+    ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
     /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
-    pub fn vmentry_inject_bp() {
+    pub fn vmentry_inject_bp(instruction_length: u32) {
+        vmwrite(vmcs::control::VMENTRY_INSTRUCTION_LEN, instruction_length);
         vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::breakpoint());
     }
 
+    /// Injects an NMI into the guest.
+    ///
+    /// The guest must not block NMIs, which is the case when the NMI window opened.
+    ///
+    /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 25.8.3 VM-Entry Controls for Event Injection
+    /// and Table 25-17. Format of the VM-Entry Interruption-Information Field.
+    pub fn vmentry_inject_nmi() {
+        vmwrite(vmcs::control::VMENTRY_INTERRUPTION_INFO_FIELD, EventInjection::non_maskable_interrupt());
+    }
+
     /// Injects an undefined opcode exception into the guest.
     ///
     /// This function is used to signal to the guest that an invalid or undefined opcode
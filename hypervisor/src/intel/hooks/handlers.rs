@@ -0,0 +1,227 @@
+//! Registry of the Rust handlers run when an inline hook fires.
+//!
+//! A handler is registered for a function hash, which matches every hook installed for that function,
+//! or for the guest virtual address of a single hooked function. `handle_inline_hook` calls it with the guest
+//! registers before the overwritten instructions are single-stepped, so it can log or rewrite the arguments
+//! of the call, or skip the function entirely with a chosen return value.
+//!
+//! A return handler is run when the hooked function returns, with the return value in RAX and the call frame
+//! recorded on entry, so it can inspect the output parameters. The return is trapped without writing guest memory:
+//! the page holding the return address is mapped without execute access until the call returns, and the return is
+//! recognized by the guest CR3 and stack pointer it leaves behind. Every other instruction fetched from that page while
+//! a call is in progress exits and is single-stepped, so return handlers are costly for functions called from hot code
+//! and the handlers of `windows::log` are only registered on request. Returns to a hooked or watched page are not trapped.
+
+use {
+    crate::{
+        intel::{
+            addresses::{AccessMode, GuestMemory},
+            capture::GuestRegisters,
+            hooks::memory_manager::HookInfo,
+        },
+        windows::{
+            log::{
+                log_mm_is_address_valid_params, log_nt_create_file_params, log_nt_handle_result, log_nt_open_process_params,
+                log_nt_query_system_information_params, log_nt_status_result,
+            },
+            nt::pe::djb2_hash,
+        },
+    },
+    alloc::collections::BTreeMap,
+    log::*,
+};
+
+/// What a hook handler decides to do with the hooked call.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HookAction {
+    /// Run the original function, with any argument registers the handler rewrote.
+    Continue,
+
+    /// Return to the caller with the given value in RAX without running the original function.
+    Return(u64),
+}
+
+/// A hook handler, called with the guest registers at the entry of the hooked function.
+///
+/// Changes to RIP and RSP are ignored, the hook dispatcher sets them when the call is skipped.
+pub type HookHandler = fn(&mut GuestRegisters, &HookInfo) -> HookAction;
+
+/// A return handler, called with the guest registers when the hooked function returns to its caller.
+///
+/// RAX holds the return value and can be rewritten. Changes to RIP and RSP are ignored.
+pub type ReturnHandler = fn(&mut GuestRegisters, &HookInfo, &CallFrame);
+
+/// A hooked call whose return is intercepted, recorded when the hook fires on entry.
+#[derive(Debug, Clone, Copy)]
+pub struct CallFrame {
+    /// The guest virtual address of the hooked function.
+    pub function_va: u64,
+
+    /// The guest CR3 the call was made with.
+    pub guest_cr3: u64,
+
+    /// The stack slot holding the return address, which is the stack pointer on entry.
+    pub return_slot: u64,
+
+    /// The return address the hooked function was called with.
+    pub return_address: u64,
+
+    /// The register arguments of the call (RCX, RDX, R8 and R9). Stack arguments are still in place above the return slot.
+    pub arguments: [u64; 4],
+}
+
+/// Identifies the hooks a handler applies to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum HookTarget {
+    /// Every hook installed for the function with this hash.
+    FunctionHash(u32),
+
+    /// The hook installed at this guest virtual address.
+    Address(u64),
+}
+
+/// Maps hook targets to their handlers.
+#[derive(Debug, Clone)]
+pub struct HookHandlerRegistry {
+    /// The registered handlers.
+    handlers: BTreeMap<HookTarget, HookHandler>,
+
+    /// The registered return handlers.
+    return_handlers: BTreeMap<HookTarget, ReturnHandler>,
+}
+
+impl Default for HookHandlerRegistry {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl HookHandlerRegistry {
+    /// Constructs a registry holding the logging handlers of `windows::log`.
+    ///
+    /// # Returns
+    /// A new instance of `HookHandlerRegistry`.
+    pub fn new() -> Self {
+        let mut registry = Self {
+            handlers: BTreeMap::new(),
+            return_handlers: BTreeMap::new(),
+        };
+
+        registry.register(HookTarget::FunctionHash(djb2_hash("NtCreateFile".as_bytes())), |registers, _| {
+            log_nt_create_file_params(registers);
+            HookAction::Continue
+        });
+        registry.register(HookTarget::FunctionHash(djb2_hash("NtOpenProcess".as_bytes())), |registers, _| {
+            log_nt_open_process_params(registers);
+            HookAction::Continue
+        });
+        registry.register(HookTarget::FunctionHash(djb2_hash("NtQuerySystemInformation".as_bytes())), |registers, _| {
+            log_nt_query_system_information_params(registers);
+            HookAction::Continue
+        });
+        registry.register(HookTarget::FunctionHash(djb2_hash("MmIsAddressValid".as_bytes())), |registers, _| {
+            log_mm_is_address_valid_params(registers);
+            HookAction::Continue
+        });
+
+        registry
+    }
+
+    /// Registers the return handlers of `windows::log`, which log the results of the hooked system calls.
+    ///
+    /// They are not registered by default, since every call through these hooks then traps the page it returns to.
+    pub fn register_nt_return_handlers(&mut self) {
+        self.register_return(HookTarget::FunctionHash(djb2_hash("NtCreateFile".as_bytes())), |registers, _, frame| {
+            log_nt_handle_result("NtCreateFile", registers, read_output_handle(frame));
+        });
+        self.register_return(HookTarget::FunctionHash(djb2_hash("NtOpenProcess".as_bytes())), |registers, _, frame| {
+            log_nt_handle_result("NtOpenProcess", registers, read_output_handle(frame));
+        });
+        self.register_return(HookTarget::FunctionHash(djb2_hash("NtQuerySystemInformation".as_bytes())), |registers, _, _| {
+            log_nt_status_result("NtQuerySystemInformation", registers);
+        });
+    }
+
+    /// Registers a handler, replacing any handler already registered for the same target.
+    ///
+    /// # Arguments
+    /// * `target` - The hooks the handler applies to.
+    /// * `handler` - The handler to run when one of these hooks fires.
+    pub fn register(&mut self, target: HookTarget, handler: HookHandler) {
+        trace!("Registering hook handler for {:x?}", target);
+        self.handlers.insert(target, handler);
+    }
+
+    /// Removes the handler registered for a target.
+    ///
+    /// # Arguments
+    /// * `target` - The target to remove the handler of.
+    ///
+    /// # Returns
+    /// `true` if a handler was registered for the target, otherwise `false`.
+    pub fn unregister(&mut self, target: HookTarget) -> bool {
+        trace!("Unregistering hook handler for {:x?}", target);
+        self.handlers.remove(&target).is_some()
+    }
+
+    /// Finds the handler of a hook. A handler registered for its address takes precedence over one registered for its function hash.
+    ///
+    /// # Arguments
+    /// * `hook_info` - The hook that fired.
+    ///
+    /// # Returns
+    /// An `Option` containing the handler if one is registered for the hook.
+    pub fn find(&self, hook_info: &HookInfo) -> Option<HookHandler> {
+        self.handlers
+            .get(&HookTarget::Address(hook_info.guest_function_va))
+            .or_else(|| self.handlers.get(&HookTarget::FunctionHash(hook_info.function_hash)))
+            .copied()
+    }
+
+    /// Registers a return handler, replacing any return handler already registered for the same target.
+    ///
+    /// # Arguments
+    /// * `target` - The hooks the return handler applies to.
+    /// * `handler` - The return handler to run when a call through one of these hooks returns.
+    pub fn register_return(&mut self, target: HookTarget, handler: ReturnHandler) {
+        trace!("Registering return handler for {:x?}", target);
+        self.return_handlers.insert(target, handler);
+    }
+
+    /// Removes the return handler registered for a target. Returns of calls already in progress are still trapped, but run no handler.
+    ///
+    /// # Arguments
+    /// * `target` - The target to remove the return handler of.
+    ///
+    /// # Returns
+    /// `true` if a return handler was registered for the target, otherwise `false`.
+    pub fn unregister_return(&mut self, target: HookTarget) -> bool {
+        trace!("Unregistering return handler for {:x?}", target);
+        self.return_handlers.remove(&target).is_some()
+    }
+
+    /// Finds the return handler of a hook, with the same precedence as `find`.
+    ///
+    /// # Arguments
+    /// * `hook_info` - The hook that fired.
+    ///
+    /// # Returns
+    /// An `Option` containing the return handler if one is registered for the hook.
+    pub fn find_return(&self, hook_info: &HookInfo) -> Option<ReturnHandler> {
+        self.return_handlers
+            .get(&HookTarget::Address(hook_info.guest_function_va))
+            .or_else(|| self.return_handlers.get(&HookTarget::FunctionHash(hook_info.function_hash)))
+            .copied()
+    }
+}
+
+/// Reads the handle written by a function that returns a handle through a pointer in its first argument.
+///
+/// # Arguments
+/// * `frame` - The call frame of the function.
+///
+/// # Returns
+/// An `Option` containing the handle, or `None` if the pointer could not be read.
+fn read_output_handle(frame: &CallFrame) -> Option<u64> {
+    GuestMemory::new(frame.guest_cr3, AccessMode::Kernel).read::<u64>(frame.arguments[0]).ok()
+}
@@ -2,33 +2,48 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            addresses::PhysicalAddress,
+            addresses::{AccessMode, GuestMemory, PhysicalAddress},
             bitmap::{MsrAccessType, MsrBitmap, MsrOperation},
-            ept::AccessType,
+            ept::{AccessType, Ept, Pt},
+            ept_sync::{flushed_generation, for_each_ept, invalidate_all_processors},
             hooks::{
+                handlers::{CallFrame, HookHandlerRegistry},
                 inline::{InlineHook, InlineHookType},
                 memory_manager::MemoryManager,
             },
-            invept::invept_all_contexts,
-            invvpid::invvpid_all_contexts,
+            page::Page,
+            support::{rdtsc, vmread},
             vm::Vm,
         },
         windows::{
-            nt::pe::{get_export_by_hash, get_image_base_address, get_size_of_image},
+            nt::pe::{djb2_hash, get_export_by_hash, get_guest_export_by_hash, get_image_base_address, get_size_of_image},
+            peb::{get_loaded_modules_from_list, LoadedModule},
             ssdt::ssdt_hook::SsdtHook,
         },
     },
-    alloc::vec::Vec,
-    core::intrinsics::copy_nonoverlapping,
+    alloc::{boxed::Box, collections::BTreeMap, vec::Vec},
+    core::{intrinsics::copy_nonoverlapping, mem::size_of},
     lazy_static::lazy_static,
     log::*,
-    spin::Mutex,
+    shared::{HookData, HookKind, WIN32K_SYSCALL_BASE},
+    spin::{Mutex, RwLock},
     x86::{
         bits64::paging::{PAddr, BASE_PAGE_SIZE},
         msr,
+        vmx::vmcs,
     },
 };
 
+/// The maximum number of hooked calls whose return can be trapped at the same time.
+const MAX_PENDING_RETURNS: usize = 4096;
+
+/// Number of TSC ticks after which a hooked call that has not returned is assumed to have been unwound, roughly 20s at 3GHz.
+const PENDING_RETURN_TIMEOUT_TSC_TICKS: u64 = 1 << 36;
+
+/// The memory ranges allocated by the hypervisor, each a tuple of start address and size.
+/// Kept outside of the hook manager so guest page tables can be checked against them while it is locked.
+static ALLOCATED_MEMORY_RANGES: RwLock<Vec<(usize, usize)>> = RwLock::new(Vec::new());
+
 /// Enum representing different types of hooks that can be applied.
 #[derive(Debug, Clone, Copy)]
 pub enum EptHookType {
@@ -37,8 +52,45 @@ pub enum EptHookType {
     Function(InlineHookType),
 
     /// Hook for hiding or monitoring access to a specific page.
-    /// No inline hook type is required for page hooks.
-    Page,
+    /// No inline hook type is required for page hooks, instead the accesses to watch are specified.
+    /// Watching reads also traps writes, as EPT cannot grant write access without read access.
+    Page(AccessType),
+}
+
+impl From<EptHookType> for HookKind {
+    fn from(ept_hook_type: EptHookType) -> Self {
+        match ept_hook_type {
+            EptHookType::Function(InlineHookType::Int3) => HookKind::Int3,
+            EptHookType::Function(InlineHookType::Cpuid) => HookKind::Cpuid,
+            EptHookType::Function(InlineHookType::Vmcall) => HookKind::Vmcall,
+            EptHookType::Page(_) => HookKind::Page,
+        }
+    }
+}
+
+/// The session a hook on session space applies to.
+#[derive(Debug, Clone, Copy)]
+pub struct HookSession {
+    /// The ID of the session.
+    pub session_id: u32,
+    /// The directory table base of a GUI process in the session, which maps its session space.
+    pub directory_table_base: u64,
+}
+
+/// A hooked call whose return is trapped, see `HookManager::trap_return`.
+#[derive(Debug, Clone, Copy)]
+pub struct PendingReturn {
+    /// The call frame passed to the return handler.
+    pub frame: CallFrame,
+
+    /// The guest physical address of the hooked function, which identifies its hook.
+    pub guest_function_pa: u64,
+
+    /// The guest physical address of the page holding the return address.
+    pub return_page_pa: u64,
+
+    /// The TSC when the call was made.
+    pub tsc: u64,
 }
 
 /// Represents hook manager structures for hypervisor operations.
@@ -63,13 +115,22 @@ pub struct HookManager {
     /// The size of ntoskrnl.exe.
     pub ntoskrnl_size: u64,
 
+    /// The kernel modules linked into `PsLoadedModuleList` as of the last walk, refreshed when a module is not found.
+    pub kernel_modules: Vec<LoadedModule>,
+
+    /// The handlers run when an inline hook fires, before the overwritten instructions are single-stepped.
+    pub handlers: HookHandlerRegistry,
+
+    /// The hooked calls whose return is trapped, keyed by the guest CR3 of the call and the stack pointer after it returns.
+    pub pending_returns: BTreeMap<(u64, u64), PendingReturn>,
+
+    /// The guest pages holding the return address of a pending return, with the number of pending returns to each.
+    /// They are mapped without execute access on every processor while returns to them are pending.
+    pub return_traps: BTreeMap<u64, usize>,
+
     /// A flag indicating whether the CPUID cache information has been called. This will be used to perform hooks at boot time when SSDT has been initialized.
     /// KiSetCacheInformation -> KiSetCacheInformationIntel -> KiSetStandardizedCacheInformation -> __cpuid(4, 0)
     pub has_cpuid_cache_info_been_called: bool,
-
-    /// A vector to keep track of allocated memory ranges for debugging and management purposes.
-    /// Each element is a tuple where the first value is the start address and the second value is the size of the allocation.
-    pub allocated_memory_ranges: Vec<(usize, usize)>,
 }
 
 lazy_static! {
@@ -82,6 +143,10 @@ lazy_static! {
     /// - `ntoskrnl_base_va`: Virtual address of the Windows kernel (ntoskrnl.exe).
     /// - `ntoskrnl_base_pa`: Physical address of the Windows kernel (ntoskrnl.exe).
     /// - `ntoskrnl_size`: Size of the Windows kernel (ntoskrnl.exe).
+    /// - `kernel_modules`: Kernel modules found in `PsLoadedModuleList` by the last walk.
+    /// - `handlers`: The registry of hook handlers, holding the default logging handlers.
+    /// - `pending_returns`: The hooked calls whose return is trapped.
+    /// - `return_traps`: The pages holding the return address of a pending return.
     /// - `has_cpuid_cache_info_been_called`: Flag indicating whether the CPUID cache information has been called.
     pub static ref SHARED_HOOK_MANAGER: Mutex<HookManager> = Mutex::new(HookManager {
         memory_manager: MemoryManager::new(),
@@ -90,8 +155,11 @@ lazy_static! {
         ntoskrnl_base_va: 0,
         ntoskrnl_base_pa: 0,
         ntoskrnl_size: 0,
+        kernel_modules: Vec::new(),
+        handlers: HookHandlerRegistry::new(),
+        pending_returns: BTreeMap::new(),
+        return_traps: BTreeMap::new(),
         has_cpuid_cache_info_been_called: false,
-        allocated_memory_ranges: Vec::with_capacity(128),
     });
 }
 
@@ -125,12 +193,61 @@ impl HookManager {
     /// * `start` - The start address of the memory allocation.
     /// * `size` - The size of the memory allocation.
     pub fn record_allocation(&mut self, start: usize, size: usize) {
-        self.allocated_memory_ranges.push((start, size));
+        ALLOCATED_MEMORY_RANGES.write().push((start, size));
+    }
+
+    /// Checks if a physical address range overlaps a recorded allocation, without locking the hook manager.
+    ///
+    /// The recorded allocations hold all of the hypervisor memory but the dummy page: the hypervisor image, including
+    /// its heap and therefore the shadow pages and page tables of the memory manager, and the per-processor stacks.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The start of the physical address range.
+    /// * `size` - The size of the range in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the range overlaps a recorded allocation or wraps around the address space, otherwise `false`.
+    pub fn overlaps_allocated_memory(start: u64, size: u64) -> bool {
+        let Some(end) = start.checked_add(size) else {
+            return true;
+        };
+
+        ALLOCATED_MEMORY_RANGES
+            .read()
+            .iter()
+            .any(|&(allocation_start, allocation_size)| start < (allocation_start + allocation_size) as u64 && (allocation_start as u64) < end)
+    }
+
+    /// Checks if a physical address range overlaps memory owned by the hypervisor.
+    ///
+    /// This covers the recorded allocations (the hypervisor image, including its heap, and the per-processor stacks
+    /// holding each `Vm` and its EPT), the dummy page, and the shadow pages and page tables of the memory manager.
+    ///
+    /// # Arguments
+    ///
+    /// * `start` - The start of the physical address range.
+    /// * `size` - The size of the range in bytes.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the range overlaps hypervisor memory or wraps around the address space, otherwise `false`.
+    pub fn overlaps_hypervisor_memory(&self, start: u64, size: u64) -> bool {
+        let Some(end) = start.checked_add(size) else {
+            return true;
+        };
+
+        let overlaps = |pa: u64, size: u64| start < pa + size && pa < end;
+
+        Self::overlaps_allocated_memory(start, size)
+            || (self.dummy_page_pa != 0 && overlaps(self.dummy_page_pa, BASE_PAGE_SIZE as u64))
+            || self.memory_manager.overlaps_managed_memory(start, end)
     }
 
     /// Prints the allocated memory ranges for debugging purposes.
     pub fn print_allocated_memory(&self) {
-        self.allocated_memory_ranges.iter().for_each(|(start, size)| {
+        ALLOCATED_MEMORY_RANGES.read().iter().for_each(|(start, size)| {
             debug!("Memory Range: Start = {:#x}, Size = {:#x}", start, size);
         });
     }
@@ -159,11 +276,16 @@ impl HookManager {
 
     /// Manages an EPT hook for a kernel function, enabling or disabling it.
     ///
+    /// Functions of ntoskrnl.exe are found through its exports, falling back to the SSDT for syscalls it does not export.
+    /// Win32k syscalls are found in the shadow SSDT, which points into session space: they require a `session`, whose
+    /// directory table base is used to translate them and whose ID is recorded with the hook.
+    /// Functions of any other kernel module are found through the exports of the module in `PsLoadedModuleList`.
+    ///
     /// # Arguments
     ///
     /// * `vm` - The virtual machine to install/remove the hook on.
-    /// * `function_hash` - The hash of the function to hook/unhook.
-    /// * `syscall_number` - The syscall number to use if `get_export_by_hash` fails.
+    /// * `hook` - The module hash, function hash and syscall number of the function to hook/unhook.
+    /// * `session` - The session to hook session space in, required for win32k syscalls.
     /// * `ept_hook_type` - The type of EPT hook to use.
     /// * `enable` - A boolean indicating whether to enable (true) or disable (false) the hook.
     ///
@@ -174,40 +296,128 @@ impl HookManager {
     pub fn manage_kernel_ept_hook(
         &mut self,
         vm: &mut Vm,
-        function_hash: u32,
-        syscall_number: u16,
+        hook: HookData,
+        session: Option<HookSession>,
         ept_hook_type: EptHookType,
         enable: bool,
     ) -> Result<(), HypervisorError> {
         let action = if enable { "Enabling" } else { "Disabling" };
-        debug!("{} EPT hook for function: {:#x}", action, function_hash);
+        debug!("{} EPT hook for function: {:#x} in module: {:#x}", action, hook.function_hash, hook.module_hash);
 
         trace!("Ntoskrnl base VA: {:#x}", self.ntoskrnl_base_va);
         trace!("Ntoskrnl base PA: {:#x}", self.ntoskrnl_base_pa);
         trace!("Ntoskrnl size: {:#x}", self.ntoskrnl_size);
 
-        let function_va = unsafe {
-            if let Some(va) = get_export_by_hash(self.ntoskrnl_base_pa as _, self.ntoskrnl_base_va as _, function_hash) {
-                va
-            } else {
-                let ssdt_function_address =
-                    SsdtHook::find_ssdt_function_address(syscall_number as _, false, self.ntoskrnl_base_pa as _, self.ntoskrnl_size as _);
-                match ssdt_function_address {
-                    Ok(ssdt_hook) => ssdt_hook.guest_function_va as *mut u8,
-                    Err(_) => return Err(HypervisorError::FailedToGetExport),
+        let guest_cr3 = session.map_or_else(|| vmread(vmcs::guest::CR3), |session| session.directory_table_base);
+        let win32k = hook.syscall_number >= WIN32K_SYSCALL_BASE;
+
+        if win32k && session.is_none() {
+            error!("Win32k syscall {:#x} requires a session", hook.syscall_number);
+            return Err(HypervisorError::SessionNotFound);
+        }
+
+        let function_va = if hook.module_hash != 0 {
+            self.get_kernel_module_export(guest_cr3, hook.module_hash, hook.function_hash)? as *mut u8
+        } else {
+            unsafe {
+                if let Some(va) = get_export_by_hash(self.ntoskrnl_base_pa as _, self.ntoskrnl_base_va as _, hook.function_hash) {
+                    va
+                } else {
+                    let ssdt_function_address = SsdtHook::find_ssdt_function_address(
+                        hook.syscall_number as _,
+                        win32k,
+                        self.ntoskrnl_base_pa as _,
+                        self.ntoskrnl_size as _,
+                        guest_cr3,
+                    );
+                    match ssdt_function_address {
+                        Ok(ssdt_hook) => ssdt_hook.guest_function_va as *mut u8,
+                        Err(_) => return Err(HypervisorError::FailedToGetExport),
+                    }
                 }
             }
         };
 
         if enable {
-            self.ept_hook_function(vm, function_va as _, function_hash, ept_hook_type)?;
+            let session_id = session.map(|session| session.session_id);
+            self.ept_hook_function(vm, guest_cr3, function_va as _, hook.function_hash, session_id, ept_hook_type)?;
         } else {
-            self.ept_unhook_function(vm, function_va as _, ept_hook_type)?;
+            self.ept_unhook_function(vm, guest_cr3, function_va as _, ept_hook_type)?;
         }
 
         Ok(())
     }
 
+    /// Rebuilds the kernel module table by walking `PsLoadedModuleList`.
+    ///
+    /// # Example
+    ///
+    /// ntoskrnl export:
+    ///     LIST_ENTRY PsLoadedModuleList
+    ///
+    /// struct _KLDR_DATA_TABLE_ENTRY
+    ///     struct _LIST_ENTRY InLoadOrderLinks;                                    //0x0
+    ///     VOID* DllBase;                                                          //0x30
+    ///     ULONG SizeOfImage;                                                      //0x40
+    ///     struct _UNICODE_STRING BaseDllName;                                     //0x58
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The table was rebuilt successfully.
+    /// * `Err(HypervisorError)` - `FailedToGetExport` if ntoskrnl.exe does not export the list, or the error of the walk.
+    pub fn refresh_kernel_modules(&mut self) -> Result<(), HypervisorError> {
+        let ps_loaded_module_list =
+            unsafe { get_export_by_hash(self.ntoskrnl_base_pa as _, self.ntoskrnl_base_va as _, djb2_hash("PsLoadedModuleList".as_bytes())) }
+                .ok_or(HypervisorError::FailedToGetExport)?;
+
+        trace!("PsLoadedModuleList address: {:#p}", ps_loaded_module_list);
+
+        self.kernel_modules = get_loaded_modules_from_list(ps_loaded_module_list as u64, vmread(vmcs::guest::CR3))?;
+        debug!("Found {} kernel modules", self.kernel_modules.len());
+
+        Ok(())
+    }
+
+    /// Resolves an export of a loaded kernel module.
+    ///
+    /// The cached module table is used first. If the module is not in it, or the export cannot be resolved from
+    /// the cached base because the module was unloaded, the table is refreshed so drivers loaded since the last
+    /// walk are found, and the lookup is retried once.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_cr3` - The CR3 the module is mapped in, which must map the right session for session space drivers.
+    /// * `module_hash` - The hash of the base name of the module, such as `fltmgr.sys`.
+    /// * `export_hash` - The hash of the export.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(u64)` - The guest virtual address of the export.
+    /// * `Err(HypervisorError)` - `ModuleNotFound` if no such module is loaded, or `FailedToGetExport` if it has no such export.
+    pub fn get_kernel_module_export(&mut self, guest_cr3: u64, module_hash: u32, export_hash: u32) -> Result<u64, HypervisorError> {
+        let memory = GuestMemory::new(guest_cr3, AccessMode::Kernel);
+
+        for refresh in [false, true] {
+            if refresh {
+                self.refresh_kernel_modules()?;
+            }
+
+            if let Some(export) = self
+                .kernel_modules
+                .iter()
+                .find(|module| module.name_hash() == module_hash)
+                .and_then(|module| get_guest_export_by_hash(&memory, module.base, export_hash))
+            {
+                return Ok(export);
+            }
+        }
+
+        match self.kernel_modules.iter().any(|module| module.name_hash() == module_hash) {
+            true => Err(HypervisorError::FailedToGetExport),
+            false => Err(HypervisorError::ModuleNotFound),
+        }
+    }
+
     /// Hides the hypervisor memory from the guest by installing EPT hooks on all allocated memory regions.
     ///
     /// This function iterates through the recorded memory allocations and calls `ept_hide_hypervisor_memory`
@@ -223,8 +433,8 @@ impl HookManager {
     ///
     /// Returns `Ok(())` if the hooks were successfully installed, `Err(HypervisorError)` otherwise.
     pub fn hide_hypervisor_memory(&mut self, vm: &mut Vm, page_permissions: AccessType) -> Result<(), HypervisorError> {
-        let pages: Vec<u64> = self
-            .allocated_memory_ranges
+        let pages: Vec<u64> = ALLOCATED_MEMORY_RANGES
+            .read()
             .iter()
             .step_by(BASE_PAGE_SIZE)
             .map(|(start, _size)| *start as u64)
@@ -260,27 +470,23 @@ impl HookManager {
 
         trace!("Dummy page PA: {:#x}", dummy_page_pa);
 
-        trace!("Mapping large page");
-        // Map the large page to the pre-allocated page table, if it hasn't been mapped already.
-        self.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
-
-        let pre_alloc_pt = self
-            .memory_manager
-            .get_page_table_as_mut(guest_large_page_pa.as_u64())
-            .ok_or(HypervisorError::PageTableNotFound)?;
+        // Split the large page on every processor, if it hasn't been split already.
+        trace!("Swapping guest page: {:#x} with dummy page: {:#x}", guest_page_pa.as_u64(), dummy_page_pa);
+        let result = self.split_large_page(vm, guest_large_page_pa.as_u64()).and_then(|()| {
+            self.for_each_page_table(vm, guest_large_page_pa.as_u64(), |ept, pt| {
+                ept.swap_page(guest_page_pa.as_u64(), dummy_page_pa, page_permissions, pt)
+            })
+        });
 
-        // Check if a guest page has already been split.
-        if vm.primary_ept.is_large_page(guest_page_pa.as_u64()) {
-            trace!("Splitting 2MB page to 4KB pages for Primary EPT: {:#x}", guest_large_page_pa);
-            vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
+        if let Err(e) = result {
+            self.release_unused_large_page(vm, guest_large_page_pa.as_u64());
+            return Err(e);
         }
 
-        trace!("Swapping guest page: {:#x} with dummy page: {:#x}", guest_page_pa.as_u64(), dummy_page_pa);
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), dummy_page_pa, page_permissions, pre_alloc_pt)?;
+        invalidate_all_processors(vm);
 
-        invept_all_contexts();
-        invvpid_all_contexts();
+        // Hidden pages are never unhidden, so their reference keeps the large page split.
+        self.memory_manager.acquire_large_page(guest_large_page_pa.as_u64());
 
         trace!("EPT hide hypervisor memory completed successfully");
 
@@ -290,9 +496,9 @@ impl HookManager {
     /// Installs an EPT hook for a function.
     ///
     /// # Steps:
-    /// 1. Map the large page to the pre-allocated page table, if it hasn't been mapped already.
+    /// 1. Map a page table to the large page in the EPT of every processor it is not split on yet.
     ///
-    /// 2. Check if the large page has already been split. If not, split it into 4KB pages.
+    /// 2. Split the large page into 4KB pages with those page tables.
     ///
     /// 3. Check if the guest page is already processed. If not, map the guest page to the shadow page.
     ///    Ensure the memory manager maintains a set of processed guest pages to track this mapping.
@@ -306,13 +512,19 @@ impl HookManager {
     ///
     /// 7. Invalidate the EPT and VPID contexts to ensure the changes take effect.
     ///
-    /// These operations are performed only once per guest page to avoid overwriting existing hooks on the same page.
+    /// Steps 2 to 7 are performed only once per guest page. A function on a page that is already hooked only has its inline hook
+    /// written into a copy of the shadow page, which then replaces it, unless it would overlap another hook or the page is watched
+    /// by a page hook.
+    ///
+    /// If the hook cannot be installed, a large page that was split for it and holds no other hook is merged back.
     ///
     /// # Arguments
     ///
     /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_cr3` - The CR3 used to translate `guest_function_va`.
     /// * `guest_function_va` - The virtual address of the function or page to be hooked.
     /// * `function_hash` - The hash of the function to be hooked.
+    /// * `session_id` - The session whose session space is hooked, if `guest_function_va` is in session space.
     /// * `ept_hook_type` - The type of EPT hook to be installed.
     ///
     /// # Returns
@@ -321,13 +533,15 @@ impl HookManager {
     pub fn ept_hook_function(
         &mut self,
         vm: &mut Vm,
+        guest_cr3: u64,
         guest_function_va: u64,
         function_hash: u32,
+        session_id: Option<u32>,
         ept_hook_type: EptHookType,
     ) -> Result<(), HypervisorError> {
-        debug!("Creating EPT hook for function at VA: {:#x}", guest_function_va);
+        debug!("Creating EPT hook for function at VA: {:#x} with CR3: {:#x}", guest_function_va, guest_cr3);
 
-        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?);
+        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_explicit_cr3(guest_function_va, guest_cr3)?);
         debug!("Guest function PA: {:#x}", guest_function_pa.as_u64());
 
         let guest_page_pa = guest_function_pa.align_down_to_base_page();
@@ -336,24 +550,47 @@ impl HookManager {
         let guest_large_page_pa = guest_function_pa.align_down_to_large_page();
         debug!("Guest large page PA: {:#x}", guest_large_page_pa.as_u64());
 
-        // 1. Map the large page to the pre-allocated page table, if it hasn't been mapped already.
-        // We must map the large page to the pre-allocated page table before accessing it.
-        debug!("Mapping large page");
-        self.memory_manager.map_large_page_to_pt(guest_large_page_pa.as_u64())?;
-
-        // 2. Check if the large page has already been split. If not, split it into 4KB pages.
+        // 1. and 2. Map a page table to the large page and split it into 4KB pages on every processor it is not split on yet.
         debug!("Checking if large page has already been split");
-        if vm.primary_ept.is_large_page(guest_page_pa.as_u64()) {
-            // We must map the large page to the pre-allocated page table before accessing it.
-            let pre_alloc_pt = self
-                .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
+        let result = self
+            .split_large_page(vm, guest_large_page_pa.as_u64())
+            .and_then(|()| self.install_ept_hook(vm, guest_function_va, guest_function_pa, function_hash, session_id, ept_hook_type));
 
-            debug!("Splitting 2MB page to 4KB pages for Primary EPT: {:#x}", guest_large_page_pa);
-            vm.primary_ept.split_2mb_to_4kb(guest_large_page_pa.as_u64(), pre_alloc_pt)?;
+        if result.is_err() {
+            self.release_unused_large_page(vm, guest_large_page_pa.as_u64());
         }
 
+        result
+    }
+
+    /// Performs steps 3 to 7 of `ept_hook_function` once the large page of the function is split on every processor.
+    ///
+    /// If a step fails, the guest page is left as it was before the hook was installed.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_function_va` - The virtual address of the function or page to be hooked.
+    /// * `guest_function_pa` - The guest physical address `guest_function_va` translates to.
+    /// * `function_hash` - The hash of the function to be hooked.
+    /// * `session_id` - The session whose session space is hooked, if `guest_function_va` is in session space.
+    /// * `ept_hook_type` - The type of EPT hook to be installed.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the hook was successfully installed, `Err(HypervisorError)` otherwise.
+    fn install_ept_hook(
+        &mut self,
+        vm: &mut Vm,
+        guest_function_va: u64,
+        guest_function_pa: PAddr,
+        function_hash: u32,
+        session_id: Option<u32>,
+        ept_hook_type: EptHookType,
+    ) -> Result<(), HypervisorError> {
+        let guest_page_pa = guest_function_pa.align_down_to_base_page();
+        let guest_large_page_pa = guest_function_pa.align_down_to_large_page();
+
         // 3. Check if the guest page is already processed. If not, map the guest page to the shadow page.
         // Ensure the memory manager maintains a set of processed guest pages to track this mapping.
         if !self.memory_manager.is_guest_page_processed(guest_page_pa.as_u64()) {
@@ -365,6 +602,7 @@ impl HookManager {
                 guest_function_pa.as_u64(),
                 ept_hook_type,
                 function_hash,
+                session_id,
             )?;
 
             // We must map the guest page to the shadow page before accessing it.
@@ -387,28 +625,90 @@ impl HookManager {
                     debug!("Installing inline hook at shadow function PA: {:#x}", shadow_function_pa.as_u64());
                     InlineHook::new(shadow_function_pa.as_u64() as *mut u8, inline_hook_type).detour64();
                 }
-                EptHookType::Page => {
-                    unimplemented!("Page hooks are not yet implemented");
+                EptHookType::Page(_) => {
+                    // The guest page stays mapped, the watch is implemented by the EPT permissions alone.
+                    debug!("Watching page at PA: {:#x}", guest_page_pa.as_u64());
                 }
             }
 
-            let pre_alloc_pt = self
-                .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
+            // 6. Change the permissions of the guest page to read-write only, or remove the watched accesses of a page hook.
+            let access_type = match ept_hook_type {
+                EptHookType::Function(_) => AccessType::READ_WRITE,
+                EptHookType::Page(watched_access) => Self::page_hook_permissions(watched_access),
+            };
+
+            debug!("Changing EPT permissions for page to {:?}: {:#x}", access_type, guest_page_pa);
+            if let Err(e) = self
+                .for_each_page_table(vm, guest_large_page_pa.as_u64(), |ept, pt| ept.modify_page_permissions(guest_page_pa.as_u64(), access_type, pt))
+            {
+                // Processors that were already changed get the original permissions back before the shadow page is dropped.
+                self.for_each_page_table(vm, guest_large_page_pa.as_u64(), |ept, pt| {
+                    ept.swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pt)
+                })?;
+                invalidate_all_processors(vm);
+
+                self.memory_manager.unmap_guest_from_shadow_page(guest_page_pa.as_u64())?;
+                return Err(e);
+            }
 
-            // 6. Change the permissions of the guest page to read-write only.
-            debug!("Changing Primary EPT permissions for page to Read-Write (RW) only: {:#x}", guest_page_pa);
-            vm.primary_ept
-                .modify_page_permissions(guest_page_pa.as_u64(), AccessType::READ_WRITE, pre_alloc_pt)?;
+            // 7. Invalidate the EPT and VPID contexts of every processor to ensure the changes take effect.
+            invalidate_all_processors(vm);
 
-            // 7. Invalidate the EPT and VPID contexts to ensure the changes take effect.
-            invept_all_contexts();
-            invvpid_all_contexts();
+            self.memory_manager.acquire_large_page(guest_large_page_pa.as_u64());
+            self.drop_return_traps(guest_page_pa.as_u64());
 
             debug!("EPT hook created and enabled successfully");
+        } else if self
+            .memory_manager
+            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+            .is_some()
+        {
+            error!("Function at PA: {:#x} is already hooked", guest_function_pa.as_u64());
+            return Err(HypervisorError::HookAlreadyInstalled);
+        } else if let (EptHookType::Function(inline_hook_type), None) = (ept_hook_type, self.memory_manager.get_page_watch(guest_page_pa.as_u64())) {
+            // The page is already shadowed for other functions, so only the detour of this one is added to a copy of the
+            // shadow page, which then replaces it. Its permissions are already set.
+            let hook_end = guest_function_pa.as_u64() + Self::hook_size(ept_hook_type) as u64;
+
+            if let Some(hook_info) = self.memory_manager.get_hook_info(guest_page_pa.as_u64()).and_then(|hooks| {
+                hooks.iter().find(|hook| {
+                    guest_function_pa.as_u64() < hook.guest_function_pa + Self::hook_size(hook.ept_hook_type) as u64
+                        && hook.guest_function_pa < hook_end
+                })
+            }) {
+                error!("Hook for function at PA: {:#x} would overlap the hook at PA: {:#x}", guest_function_pa.as_u64(), hook_info.guest_function_pa);
+                return Err(HypervisorError::HookAlreadyInstalled);
+            }
+
+            debug!("Adding hook to already processed guest page: {:#x}", guest_page_pa.as_u64());
+            self.memory_manager.map_guest_to_shadow_page(
+                guest_page_pa.as_u64(),
+                guest_function_va,
+                guest_function_pa.as_u64(),
+                ept_hook_type,
+                function_hash,
+                session_id,
+            )?;
+
+            let shadow_page = self.memory_manager.copy_shadow_page(guest_page_pa.as_u64())?;
+            let shadow_page_pa = PAddr::from(&*shadow_page as *const Page as u64);
+
+            let shadow_function_pa = PAddr::from(Self::calculate_function_offset_in_host_shadow_page(shadow_page_pa, guest_function_pa));
+            debug!("Installing inline hook at shadow function PA: {:#x}", shadow_function_pa.as_u64());
+            InlineHook::new(shadow_function_pa.as_u64() as *mut u8, inline_hook_type).detour64();
+
+            if let Err(e) = self.replace_shadow_page(vm, guest_page_pa.as_u64(), shadow_page) {
+                self.memory_manager.remove_hook_info(guest_page_pa.as_u64(), guest_function_pa.as_u64())?;
+                return Err(e);
+            }
+
+            self.memory_manager.acquire_large_page(guest_large_page_pa.as_u64());
+
+            debug!("EPT hook added to shadow page successfully");
         } else {
-            debug!("Guest page already processed, skipping hook installation and permission modification.");
+            // The permissions of a watched page would conflict with those of the hooks already on it.
+            error!("Page at PA: {:#x} is already hooked", guest_page_pa.as_u64());
+            return Err(HypervisorError::HookAlreadyInstalled);
         }
 
         Ok(())
@@ -416,43 +716,583 @@ impl HookManager {
 
     /// Removes an EPT hook for a function.
     ///
+    /// The hook is identified by the guest physical address `guest_function_va` translates to, so it must be
+    /// removed with a CR3 that maps the same page as the one it was installed with. If other hooks remain on the
+    /// page, only the instructions overwritten by this hook are restored in the shadow page. When the last hook or
+    /// hidden page in the 2MB region is gone, the region is merged back into a large page and its page table is freed.
+    ///
     /// # Arguments
     ///
     /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_cr3` - The CR3 used to translate `guest_function_va`.
     /// * `guest_function_va` - The virtual address of the function or page to be unhooked.
     /// * `ept_hook_type` - The type of EPT hook to be removed.
     ///
     /// # Returns
     ///
-    /// * Returns `Ok(())` if the hook was successfully removed, `Err(HypervisorError)` otherwise.
-    pub fn ept_unhook_function(&mut self, vm: &mut Vm, guest_function_va: u64, _ept_hook_type: EptHookType) -> Result<(), HypervisorError> {
-        debug!("Removing EPT hook for function at VA: {:#x}", guest_function_va);
+    /// * Returns `Ok(())` if the hook was successfully removed, `HypervisorError::HookNotFound` if no hook is installed
+    ///   at the address, or another `HypervisorError` otherwise.
+    pub fn ept_unhook_function(
+        &mut self,
+        vm: &mut Vm,
+        guest_cr3: u64,
+        guest_function_va: u64,
+        _ept_hook_type: EptHookType,
+    ) -> Result<(), HypervisorError> {
+        debug!("Removing EPT hook for function at VA: {:#x} with CR3: {:#x}", guest_function_va, guest_cr3);
 
-        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(guest_function_va)?);
+        let guest_function_pa = PAddr::from(PhysicalAddress::pa_from_va_with_explicit_cr3(guest_function_va, guest_cr3)?);
         debug!("Guest function PA: {:#x}", guest_function_pa.as_u64());
 
         let guest_page_pa = guest_function_pa.align_down_to_base_page();
         debug!("Guest page PA: {:#x}", guest_page_pa.as_u64());
 
+        let Some(hook_info) = self
+            .memory_manager
+            .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+        else {
+            error!("No hook is installed for function at PA: {:#x}", guest_function_pa.as_u64());
+            return Err(HypervisorError::HookNotFound);
+        };
+
+        let hook_size = Self::hook_size(hook_info.ept_hook_type);
+
         let guest_large_page_pa = guest_function_pa.align_down_to_large_page();
         debug!("Guest large page PA: {:#x}", guest_large_page_pa.as_u64());
 
-        let pre_alloc_pt = self
+        let other_hooks = self
+            .memory_manager
+            .get_hook_info(guest_page_pa.as_u64())
+            .map_or(0, |hooks| hooks.len() - 1);
+
+        if other_hooks > 0 {
+            // Other hooks on the page stay installed, so only the overwritten instructions of this function are restored,
+            // in a copy of the shadow page which then replaces it. The hook information is only dropped once the detour
+            // is no longer mapped, so a processor still executing it finds the hook it belongs to.
+            let shadow_page = self.memory_manager.copy_shadow_page(guest_page_pa.as_u64())?;
+            let shadow_page_pa = PAddr::from(&*shadow_page as *const Page as u64);
+
+            let shadow_function_pa = Self::calculate_function_offset_in_host_shadow_page(shadow_page_pa, guest_function_pa);
+            debug!("Restoring {} bytes at shadow function PA: {:#x}", hook_size, shadow_function_pa);
+            unsafe { copy_nonoverlapping(guest_function_pa.as_u64() as *const u8, shadow_function_pa as *mut u8, hook_size) };
+
+            self.replace_shadow_page(vm, guest_page_pa.as_u64(), shadow_page)?;
+            self.memory_manager.remove_hook_info(guest_page_pa.as_u64(), guest_function_pa.as_u64())?;
+        } else {
+            // Swap the page back and restore the original page permissions on every processor.
+            self.for_each_page_table(vm, guest_large_page_pa.as_u64(), |ept, pt| {
+                ept.swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pt)
+            })?;
+
+            // Update the memory manager to indicate that the guest page is no longer processed (unmapped/unhooked).
+            // This will allow the page to be reprocessed/remapped/rehooked if needed.
+            self.memory_manager.unmap_guest_from_shadow_page(guest_page_pa.as_u64())?;
+
+            invalidate_all_processors(vm);
+        }
+
+        // Calls still in progress through the hook no longer run its return handler.
+        self.release_pending_returns(vm, guest_function_pa.as_u64())?;
+
+        self.release_large_page(vm, guest_large_page_pa.as_u64())
+    }
+
+    /// Replaces the shadow page of a guest page with an updated copy on every processor.
+    ///
+    /// Other processors may be executing the current shadow page, so it is not modified in place. Processors that map it
+    /// are remapped to the copy, and it is freed once every processor has invalidated its EPT. Processors that map the
+    /// guest page at the moment pick up the copy the next time they execute the page. If a processor cannot be remapped,
+    /// every processor is left on the current shadow page.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The guest physical address of the page.
+    /// * `shadow_page` - The updated copy of the shadow page.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the shadow page was replaced, `Err(HypervisorError)` otherwise.
+    fn replace_shadow_page(&mut self, vm: &mut Vm, guest_page_pa: u64, shadow_page: Box<Page>) -> Result<(), HypervisorError> {
+        let new_shadow_page_pa = &*shadow_page as *const Page as u64;
+        let old_shadow_page = self.memory_manager.replace_shadow_page(guest_page_pa, shadow_page)?;
+        let old_shadow_page_pa = &*old_shadow_page as *const Page as u64;
+
+        let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page().as_u64();
+        let mut remap = |hook_manager: &mut Self, from: u64, to: u64| {
+            hook_manager.for_each_page_table(vm, guest_large_page_pa, |ept, pt| {
+                if ept.get_mapped_host_pa(guest_page_pa, pt) == Some(from) {
+                    ept.remap_gpa_to_hpa(guest_page_pa, to, pt)?;
+                }
+                Ok(())
+            })
+        };
+
+        trace!("Replacing shadow page: {:#x} with: {:#x}", old_shadow_page_pa, new_shadow_page_pa);
+        if let Err(e) = remap(self, old_shadow_page_pa, new_shadow_page_pa) {
+            // Processors that were already remapped go back to the current shadow page, which stays in place.
+            if let Err(e) = remap(self, new_shadow_page_pa, old_shadow_page_pa) {
+                error!("Failed to restore shadow page: {:#x}: {:?}", old_shadow_page_pa, e);
+            }
+
+            let new_shadow_page = self.memory_manager.replace_shadow_page(guest_page_pa, old_shadow_page)?;
+            invalidate_all_processors(vm);
+            self.memory_manager.retire_shadow_page(new_shadow_page, vm.ept_generation);
+
+            return Err(e);
+        }
+
+        invalidate_all_processors(vm);
+
+        self.memory_manager.retire_shadow_page(old_shadow_page, vm.ept_generation);
+        self.memory_manager.free_retired_memory(flushed_generation());
+
+        Ok(())
+    }
+
+    /// Drops the reference a removed hook held on its large page, merging the page back into a 2MB page if it was the last one.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_large_page_pa` - The large guest physical address the hook was installed in.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the reference was dropped, `Err(HypervisorError)` otherwise.
+    fn release_large_page(&mut self, vm: &mut Vm, guest_large_page_pa: u64) -> Result<(), HypervisorError> {
+        if self.memory_manager.release_large_page(guest_large_page_pa)? > 0 {
+            return Ok(());
+        }
+
+        self.merge_large_page(vm, guest_large_page_pa)
+    }
+
+    /// Merges a large page split for a hook that failed to install back into a 2MB page, unless it is referenced by other
+    /// hooks or hidden pages.
+    ///
+    /// The hook failed anyway, so an error while merging is only logged and the page stays split.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_large_page_pa` - The large guest physical address the hook was to be installed in.
+    fn release_unused_large_page(&mut self, vm: &mut Vm, guest_large_page_pa: u64) {
+        if self.memory_manager.large_page_ref_count(guest_large_page_pa) > 0 || !self.memory_manager.has_page_tables(guest_large_page_pa) {
+            return;
+        }
+
+        if let Err(e) = self.merge_large_page(vm, guest_large_page_pa) {
+            error!("Failed to merge unused large page: {:#x}: {:?}", guest_large_page_pa, e);
+        }
+    }
+
+    /// Merges the 4KB pages of a large page that is no longer referenced back into a 2MB page and frees its page tables.
+    ///
+    /// Processors the large page was never split on are skipped, so a split that failed part way can be undone as well.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_large_page_pa` - The large guest physical address to merge.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the large page was merged, `Err(HypervisorError)` otherwise.
+    fn merge_large_page(&mut self, vm: &mut Vm, guest_large_page_pa: u64) -> Result<(), HypervisorError> {
+        let memory_manager = &mut self.memory_manager;
+
+        // A region that was already split before it was hooked, like the first 2MB, is left as it is.
+        debug!("Merging 4KB pages back to 2MB page: {:#x}", guest_large_page_pa);
+        for_each_ept(vm, |ept| {
+            let Some(pt) = memory_manager.get_page_table_as_mut(guest_large_page_pa, ept) else {
+                return Ok(());
+            };
+
+            match ept.merge_4kb_to_2mb(guest_large_page_pa, pt) {
+                Ok(()) | Err(HypervisorError::PageNotSplit) => Ok(()),
+                Err(e) => Err(e),
+            }
+        })?;
+
+        invalidate_all_processors(vm);
+
+        // Other processors may still cache the page tables until they invalidate their EPT, so they are only freed once they all have.
+        self.memory_manager.retire_large_page_tables(guest_large_page_pa, vm.ept_generation)?;
+        self.memory_manager.free_retired_memory(flushed_generation());
+
+        Ok(())
+    }
+
+    /// Splits a large page into 4KB pages on every processor it is not split on yet, each with its own page table.
+    ///
+    /// Each processor swaps hooked pages in its own page table while it single-steps them, so the page tables are
+    /// never shared. Processors that start after the large page was split get a copy of the page table of another
+    /// processor, with the pages of the hooks already installed in it reset to the permissions they are installed with.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_large_page_pa` - The large guest physical address to split.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the large page is split on every processor, `Err(HypervisorError)` otherwise.
+    fn split_large_page(&mut self, vm: &mut Vm, guest_large_page_pa: u64) -> Result<(), HypervisorError> {
+        let memory_manager = &mut self.memory_manager;
+        let mut copied_epts = Vec::new();
+
+        for_each_ept(vm, |ept| {
+            if memory_manager.get_page_table(guest_large_page_pa, ept).is_some() {
+                return Ok(());
+            }
+
+            let (pt, is_copied) = memory_manager.map_large_page_to_pt(guest_large_page_pa, ept)?;
+
+            // A region that was already split before it was hooked, like the first 2MB, is left as it is.
+            if !ept.is_large_page(guest_large_page_pa) {
+                return Ok(());
+            }
+
+            if is_copied {
+                copied_epts.push(ept as *const Ept as u64);
+                ept.split_2mb_with_populated_pt(guest_large_page_pa, pt)
+            } else {
+                debug!("Splitting 2MB page to 4KB pages: {:#x}", guest_large_page_pa);
+                ept.split_2mb_to_4kb(guest_large_page_pa, pt)
+            }
+        })?;
+
+        if copied_epts.is_empty() {
+            return Ok(());
+        }
+
+        // The copied page table may have been taken while its processor was single-stepping a hooked or trapped page.
+        let hooked_pages: Vec<(u64, AccessType)> = self
             .memory_manager
-            .get_page_table_as_mut(guest_large_page_pa.as_u64())
-            .ok_or(HypervisorError::PageTableNotFound)?;
+            .hook_mappings()
+            .filter(|(guest_page_pa, _)| PAddr::from(*guest_page_pa).align_down_to_large_page().as_u64() == guest_large_page_pa)
+            .map(|(guest_page_pa, mapping)| {
+                let access_type = match mapping.hooks.iter().find_map(|hook| match hook.ept_hook_type {
+                    EptHookType::Page(watched_access) => Some(watched_access),
+                    EptHookType::Function(_) => None,
+                }) {
+                    Some(watched_access) => Self::page_hook_permissions(watched_access),
+                    None => AccessType::READ_WRITE,
+                };
+                (guest_page_pa, access_type)
+            })
+            .chain(
+                self.return_traps
+                    .keys()
+                    .filter(|guest_page_pa| PAddr::from(**guest_page_pa).align_down_to_large_page().as_u64() == guest_large_page_pa)
+                    .map(|guest_page_pa| (*guest_page_pa, AccessType::READ_WRITE)),
+            )
+            .collect();
+
+        self.for_each_page_table(vm, guest_large_page_pa, |ept, pt| {
+            if !copied_epts.contains(&(ept as *const Ept as u64)) {
+                return Ok(());
+            }
+
+            hooked_pages
+                .iter()
+                .try_for_each(|&(guest_page_pa, access_type)| ept.swap_page(guest_page_pa, guest_page_pa, access_type, pt))
+        })
+    }
+
+    /// Applies a change to the page table a large page is split with on every processor.
+    ///
+    /// Must be called with the hook manager locked, see `for_each_ept`.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_large_page_pa` - The large guest physical address.
+    /// * `f` - The change to apply to the EPT and page table of each processor.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the change was applied on every processor, `PageTableNotFound` if the large page is not split
+    ///   on a processor, or the first error returned by `f`.
+    fn for_each_page_table(
+        &mut self,
+        vm: &mut Vm,
+        guest_large_page_pa: u64,
+        mut f: impl FnMut(&mut Ept, &mut Pt) -> Result<(), HypervisorError>,
+    ) -> Result<(), HypervisorError> {
+        let memory_manager = &mut self.memory_manager;
+
+        for_each_ept(vm, |ept| {
+            let pt = memory_manager
+                .get_page_table_as_mut(guest_large_page_pa, ept)
+                .ok_or(HypervisorError::PageTableNotFound)?;
+            f(ept, pt)
+        })
+    }
+
+    /// Traps the return of a hooked call without modifying guest memory.
+    ///
+    /// The hook fires on the first instruction of the function, so the return address is at the top of the guest stack.
+    /// The page it points to is mapped without execute access on every processor, and the instruction fetch at the return
+    /// address with the stack pointer just above the return slot is recognized by `take_returned_call`. Other instructions
+    /// executed from the page are single-stepped while the call is in progress.
+    ///
+    /// Returns to a page that is hooked, watched or owned by the hypervisor are not trapped.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance, with the guest registers on entry to the hooked function.
+    /// * `guest_function_pa` - The guest physical address of the hooked function.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(())` - The return will be trapped, or the call returns normally because its return cannot be trapped.
+    /// * `Err(HypervisorError)` - The return address could not be read or translated, or the page could not be trapped.
+    pub fn trap_return(&mut self, vm: &mut Vm, guest_function_pa: u64) -> Result<(), HypervisorError> {
+        self.evict_stale_returns(vm)?;
+
+        let registers = &vm.guest_registers;
+        let guest_memory = GuestMemory::with_current_cr3(AccessMode::Kernel);
+        let key = (guest_memory.guest_cr3(), registers.rsp.wrapping_add(size_of::<u64>() as u64));
+
+        if self.pending_returns.len() >= MAX_PENDING_RETURNS && !self.pending_returns.contains_key(&key) {
+            warn!("Too many pending returns, not trapping the return of the call at VA: {:#x}", registers.rip);
+            return Ok(());
+        }
+
+        let return_address = guest_memory.read::<u64>(registers.rsp)?;
+        let return_page_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(return_address)?)
+            .align_down_to_base_page()
+            .as_u64();
+
+        // The permissions of a hooked or watched page belong to its hooks.
+        if self.memory_manager.is_guest_page_processed(return_page_pa) || self.overlaps_hypervisor_memory(return_page_pa, BASE_PAGE_SIZE as u64) {
+            trace!("Not trapping the return of the call at VA: {:#x} to hooked page: {:#x}", registers.rip, return_page_pa);
+            return Ok(());
+        }
+
+        let pending_return = PendingReturn {
+            frame: CallFrame {
+                function_va: registers.rip,
+                guest_cr3: guest_memory.guest_cr3(),
+                return_slot: registers.rsp,
+                return_address,
+                arguments: [registers.rcx, registers.rdx, registers.r8, registers.r9],
+            },
+            guest_function_pa,
+            return_page_pa,
+            tsc: rdtsc(),
+        };
+
+        trace!("Trapping return of the call at VA: {:#x} to VA: {:#x} on page: {:#x}", registers.rip, return_address, return_page_pa);
+
+        self.acquire_return_trap(vm, return_page_pa)?;
+
+        // A return left behind by a call that never returned, e.g. because it was unwound, is replaced when its stack is reused.
+        if let Some(stale_return) = self.pending_returns.insert(key, pending_return) {
+            self.release_return_trap(vm, stale_return.return_page_pa)?;
+        }
+
+        Ok(())
+    }
+
+    /// Takes the pending return of a hooked call that is returning, if the instruction fetch that faulted is its return.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance, with the guest registers at the faulting instruction fetch.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Some(PendingReturn))` - The hooked call returned to the faulting instruction, and its page is no longer trapped for it.
+    /// * `Ok(None)` - The faulting instruction is not the return of a pending call.
+    /// * `Err(HypervisorError)` - The page could not be made executable again.
+    pub fn take_returned_call(&mut self, vm: &mut Vm) -> Result<Option<PendingReturn>, HypervisorError> {
+        let key = (vmread(vmcs::guest::CR3), vm.guest_registers.rsp);
+
+        // Another instruction executed from the page, or a call with the same stack pointer that does not return here.
+        if !matches!(self.pending_returns.get(&key), Some(pending_return) if pending_return.frame.return_address == vm.guest_registers.rip) {
+            return Ok(None);
+        }
+
+        let Some(pending_return) = self.pending_returns.remove(&key) else {
+            return Ok(None);
+        };
+
+        self.release_return_trap(vm, pending_return.return_page_pa)?;
+
+        Ok(Some(pending_return))
+    }
+
+    /// Checks if returns to a guest page are trapped, in which case it is mapped without execute access.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_page_pa` - The guest physical address of the page.
+    ///
+    /// # Returns
+    ///
+    /// `true` if a pending return of a hooked call is trapped on the page.
+    pub fn is_return_trap(&self, guest_page_pa: u64) -> bool {
+        self.return_traps.contains_key(&guest_page_pa)
+    }
+
+    /// Stops trapping the returns of calls that have been pending for too long, which were most likely unwound.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the stale returns were evicted, `Err(HypervisorError)` if a page could not be made executable again.
+    pub fn evict_stale_returns(&mut self, vm: &mut Vm) -> Result<(), HypervisorError> {
+        let now = rdtsc();
+        let mut released_pages = Vec::new();
+
+        self.pending_returns.retain(|_, pending_return| {
+            let is_stale = now.wrapping_sub(pending_return.tsc) > PENDING_RETURN_TIMEOUT_TSC_TICKS;
+            if is_stale {
+                trace!("Evicting stale return of the call at VA: {:#x}", pending_return.frame.function_va);
+                released_pages.push(pending_return.return_page_pa);
+            }
+            !is_stale
+        });
+
+        released_pages
+            .into_iter()
+            .try_for_each(|guest_page_pa| self.release_return_trap(vm, guest_page_pa))
+    }
+
+    /// Stops trapping the returns of the calls in progress through a hook that is being removed.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_function_pa` - The guest physical address of the hooked function.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the returns are no longer trapped, `Err(HypervisorError)` if a page could not be made executable again.
+    fn release_pending_returns(&mut self, vm: &mut Vm, guest_function_pa: u64) -> Result<(), HypervisorError> {
+        let mut released_pages = Vec::new();
+
+        self.pending_returns.retain(|_, pending_return| {
+            let is_released = pending_return.guest_function_pa == guest_function_pa;
+            if is_released {
+                released_pages.push(pending_return.return_page_pa);
+            }
+            !is_released
+        });
 
-        // Swap the page back and restore the original page permissions
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+        released_pages
+            .into_iter()
+            .try_for_each(|guest_page_pa| self.release_return_trap(vm, guest_page_pa))
+    }
 
-        // Update the memory manager to indicate that the guest page is no longer processed (unmapped/unhooked).
-        // This will allow the page to be reprocessed/remapped/rehooked if needed.
-        self.memory_manager.unmap_guest_from_shadow_page(guest_page_pa.as_u64())?;
+    /// Makes a page non-executable on every processor for a pending return to it, unless other pending returns already did.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The guest physical address of the page holding the return address.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the page is trapped, `Err(HypervisorError)` otherwise, in which case it is left executable.
+    fn acquire_return_trap(&mut self, vm: &mut Vm, guest_page_pa: u64) -> Result<(), HypervisorError> {
+        if let Some(pending_returns) = self.return_traps.get_mut(&guest_page_pa) {
+            *pending_returns += 1;
+            return Ok(());
+        }
+
+        let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page().as_u64();
+
+        let result = self.split_large_page(vm, guest_large_page_pa).and_then(|()| {
+            self.for_each_page_table(vm, guest_large_page_pa, |ept, pt| ept.swap_page(guest_page_pa, guest_page_pa, AccessType::READ_WRITE, pt))
+        });
+
+        if let Err(e) = result {
+            // Processors that were already changed get execute access back before the large page is merged again.
+            if self.memory_manager.has_page_tables(guest_large_page_pa) {
+                if let Err(e) = self.for_each_page_table(vm, guest_large_page_pa, |ept, pt| {
+                    ept.swap_page(guest_page_pa, guest_page_pa, AccessType::READ_WRITE_EXECUTE, pt)
+                }) {
+                    error!("Failed to restore return page: {:#x}: {:?}", guest_page_pa, e);
+                }
+                invalidate_all_processors(vm);
+            }
+
+            self.release_unused_large_page(vm, guest_large_page_pa);
+            return Err(e);
+        }
+
+        invalidate_all_processors(vm);
+
+        self.memory_manager.acquire_large_page(guest_large_page_pa);
+        self.return_traps.insert(guest_page_pa, 1);
 
         Ok(())
     }
 
+    /// Drops a pending return to a page, making it executable on every processor again if it was the last one.
+    ///
+    /// # Arguments
+    ///
+    /// * `vm` - The virtual machine instance of the hypervisor.
+    /// * `guest_page_pa` - The guest physical address of the page holding the return address.
+    ///
+    /// # Returns
+    ///
+    /// * Returns `Ok(())` if the pending return was dropped, `Err(HypervisorError)` otherwise.
+    fn release_return_trap(&mut self, vm: &mut Vm, guest_page_pa: u64) -> Result<(), HypervisorError> {
+        let Some(pending_returns) = self.return_traps.get_mut(&guest_page_pa) else {
+            return Ok(());
+        };
+
+        *pending_returns -= 1;
+        if *pending_returns > 0 {
+            return Ok(());
+        }
+
+        self.return_traps.remove(&guest_page_pa);
+
+        let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page().as_u64();
+
+        trace!("No longer trapping returns to page: {:#x}", guest_page_pa);
+        self.for_each_page_table(vm, guest_large_page_pa, |ept, pt| ept.swap_page(guest_page_pa, guest_page_pa, AccessType::READ_WRITE_EXECUTE, pt))?;
+
+        invalidate_all_processors(vm);
+
+        self.release_large_page(vm, guest_large_page_pa)
+    }
+
+    /// Stops trapping returns to a page that was just hooked, whose permissions now belong to its hook.
+    ///
+    /// The calls returning to the page return without running their return handler.
+    ///
+    /// # Arguments
+    ///
+    /// * `guest_page_pa` - The guest physical address of the hooked page.
+    fn drop_return_traps(&mut self, guest_page_pa: u64) {
+        if self.return_traps.remove(&guest_page_pa).is_none() {
+            return;
+        }
+
+        debug!("Dropping the pending returns to hooked page: {:#x}", guest_page_pa);
+        self.pending_returns
+            .retain(|_, pending_return| pending_return.return_page_pa != guest_page_pa);
+
+        // The hook holds its own reference on the large page, so it is not merged.
+        if let Err(e) = self
+            .memory_manager
+            .release_large_page(PAddr::from(guest_page_pa).align_down_to_large_page().as_u64())
+        {
+            error!("Failed to release return page: {:#x}: {:?}", guest_page_pa, e);
+        }
+    }
+
     /// Copies the guest page to the pre-allocated host shadow page.
     ///
     /// # Arguments
@@ -497,6 +1337,24 @@ impl HookManager {
         host_shadow_page_pa.as_u64() + guest_function_pa.base_page_offset()
     }
 
+    /// Returns the EPT permissions of a page watched by a page hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `watched_access` - The accesses watched by the hook.
+    ///
+    /// # Returns
+    ///
+    /// The permissions without the watched accesses. Execute access is always kept, and watching reads also removes
+    /// write access, as write access without read access is an EPT misconfiguration.
+    pub fn page_hook_permissions(watched_access: AccessType) -> AccessType {
+        if watched_access.contains(AccessType::READ) {
+            AccessType::EXECUTE
+        } else {
+            AccessType::READ_EXECUTE
+        }
+    }
+
     /// Returns the size of the hook code in bytes based on the EPT hook type.
     ///
     /// # Returns
@@ -505,7 +1363,7 @@ impl HookManager {
     pub fn hook_size(hook_type: EptHookType) -> usize {
         match hook_type {
             EptHookType::Function(inline_hook_type) => InlineHook::hook_size(inline_hook_type),
-            EptHookType::Page => 0, // Assuming page hooks do not have a hook size
+            EptHookType::Page(_) => 0, // Page hooks do not overwrite any instructions
         }
     }
 
@@ -6,12 +6,21 @@ use {
     crate::{
         allocator::box_zeroed,
         error::HypervisorError,
-        intel::{ept::Pt, hooks::hook_manager::EptHookType, page::Page},
+        intel::{
+            ept::{AccessType, Ept, Pt},
+            hooks::{hook_manager::EptHookType, inline::InlineHookType},
+            page::Page,
+        },
     },
     alloc::{boxed::Box, collections::BTreeMap, vec::Vec},
+    core::sync::atomic::{AtomicUsize, Ordering},
     log::trace,
 };
 
+/// The number of installed function hooks of each inline hook type, indexed by `InlineHookType`.
+/// Kept outside of the memory manager so VM exits that cannot be a hook are recognized without locking the hook manager.
+static INLINE_HOOK_COUNTS: [AtomicUsize; 3] = [AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0)];
+
 /// Represents the hook information for a specific guest virtual address and EPT hook type.
 #[derive(Debug, Clone)]
 pub struct HookInfo {
@@ -23,6 +32,46 @@ pub struct HookInfo {
     pub ept_hook_type: EptHookType,
     /// Hash of the function to be hooked.
     pub function_hash: u32,
+    /// The session whose session space the hook was installed in, if it applies to a single session.
+    pub session_id: Option<u32>,
+    /// Hit counters of the hook, updated on every processor the hook fires on.
+    pub counters: HookCounters,
+}
+
+/// The hit counters of a hook and the context of its last hit.
+///
+/// Hits are recorded and queried with the hook manager locked, so the counters and the context of the last hit are
+/// always read together.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct HookCounters {
+    /// Number of times the hook was hit.
+    pub hit_count: u64,
+    /// Timestamp counter of the processor the hook was last hit on.
+    pub last_hit_tsc: u64,
+    /// Return address of the last call through a function hook, or RIP of the last watched access of a page hook.
+    pub last_caller_rip: u64,
+    /// Unique process ID of the process the hook was last hit in.
+    pub last_process_id: u64,
+    /// APIC ID of the processor the hook was last hit on.
+    pub last_vcpu_id: u32,
+}
+
+impl HookCounters {
+    /// Records a hit of the hook.
+    ///
+    /// # Arguments
+    ///
+    /// * `tsc` - The timestamp counter at the time of the hit.
+    /// * `caller_rip` - The return address of the call through the hook, or the RIP of the access to a watched page.
+    /// * `process_id` - The unique process ID of the current process, or 0 if it is not known.
+    /// * `vcpu_id` - The APIC ID of the processor the hook was hit on.
+    pub fn record_hit(&mut self, tsc: u64, caller_rip: u64, process_id: u64, vcpu_id: u32) {
+        self.hit_count += 1;
+        self.last_hit_tsc = tsc;
+        self.last_caller_rip = caller_rip;
+        self.last_process_id = process_id;
+        self.last_vcpu_id = vcpu_id;
+    }
 }
 
 /// Represents the mapping information for a guest page.
@@ -40,8 +89,15 @@ pub struct HookMapping {
 pub struct MemoryManager {
     /// Mappings of guest physical addresses to their respective hook mappings.
     guest_page_mappings: BTreeMap<u64, HookMapping>,
-    /// Mappings of large guest physical addresses to their respective page tables.
-    large_page_table_mappings: BTreeMap<u64, Box<Pt>>,
+    /// Mappings of large guest physical addresses and the EPTs they are split in to the page tables they are split with.
+    /// Every processor has its own page table, so a page can be swapped on one processor without affecting the others.
+    large_page_table_mappings: BTreeMap<(u64, u64), Box<Pt>>,
+    /// Number of hooks and hidden pages in each large guest page mapped to a page table.
+    large_page_ref_counts: BTreeMap<u64, usize>,
+    /// Page tables of merged large pages, with the EPT generation every processor must reach before they are freed.
+    retired_page_tables: Vec<(u64, Box<Pt>)>,
+    /// Shadow pages replaced by an updated copy, with the EPT generation every processor must reach before they are freed.
+    retired_shadow_pages: Vec<(u64, Box<Page>)>,
 }
 
 impl MemoryManager {
@@ -55,6 +111,9 @@ impl MemoryManager {
         Self {
             guest_page_mappings: BTreeMap::new(),
             large_page_table_mappings: BTreeMap::new(),
+            large_page_ref_counts: BTreeMap::new(),
+            retired_page_tables: Vec::new(),
+            retired_shadow_pages: Vec::new(),
         }
     }
 
@@ -77,6 +136,7 @@ impl MemoryManager {
     /// * `guest_function_pa` - The guest physical address of the function.
     /// * `ept_hook_type` - The type of EPT hook.
     /// * `function_hash` - The hash of the function.
+    /// * `session_id` - The session whose session space is hooked, if any.
     ///
     /// # Returns
     /// `Ok(())` if successful, or an error if no free pages are available or if already mapped.
@@ -87,6 +147,7 @@ impl MemoryManager {
         guest_function_pa: u64,
         ept_hook_type: EptHookType,
         function_hash: u32,
+        session_id: Option<u32>,
     ) -> Result<(), HypervisorError> {
         trace!("Mapping guest page and shadow page for PA: {:#x}", guest_page_pa);
 
@@ -95,6 +156,8 @@ impl MemoryManager {
             guest_function_pa,
             ept_hook_type,
             function_hash,
+            session_id,
+            counters: HookCounters::default(),
         };
 
         // Check if the guest page is already mapped
@@ -105,12 +168,14 @@ impl MemoryManager {
             if mapping.hooks.iter().any(|hook| hook.guest_function_pa == guest_function_pa) {
                 trace!("Hook already exists for function PA: {:#x}", guest_function_pa);
             } else {
+                Self::count_inline_hook(ept_hook_type, true);
                 mapping.hooks.push(hook_info); // Add new hook info
             }
         } else {
             trace!("Mapping does not exist, creating new mapping");
             // Allocate a new shadow page
             let shadow_page = unsafe { box_zeroed::<Page>() };
+            Self::count_inline_hook(ept_hook_type, true);
             let mut hooks = Vec::new();
             hooks.push(hook_info);
 
@@ -122,26 +187,37 @@ impl MemoryManager {
         Ok(())
     }
 
-    /// Maps a free page table to a large guest physical address, allocating memory as needed.
+    /// Maps a new page table to a large guest physical address in the EPT of a processor.
+    ///
+    /// The page table is a copy of the one the large page is split with on another processor, if any,
+    /// so it maps the same shadow pages and hidden pages.
     ///
     /// # Arguments
     /// * `guest_large_page_pa` - The large guest physical address to map.
+    /// * `ept` - The EPT of the processor.
     ///
     /// # Returns
-    /// `Ok(())` if successful, or an error if no free page tables are available.
-    pub fn map_large_page_to_pt(&mut self, guest_large_page_pa: u64) -> Result<(), HypervisorError> {
-        // Check if the large page is already mapped
-        if !self.large_page_table_mappings.contains_key(&guest_large_page_pa) {
-            trace!("Large page not mapped to page table, mapping now");
-            // Allocate a new page table
-            let pt = unsafe { box_zeroed::<Pt>() };
-            self.large_page_table_mappings.insert(guest_large_page_pa, pt);
-            trace!("Large page mapped to page table successfully");
-        } else {
+    /// The page table and whether it was copied from another processor, or an error if the large page already has a page table in the EPT.
+    pub fn map_large_page_to_pt(&mut self, guest_large_page_pa: u64, ept: &Ept) -> Result<(&mut Pt, bool), HypervisorError> {
+        let key = (guest_large_page_pa, ept as *const Ept as u64);
+
+        if self.large_page_table_mappings.contains_key(&key) {
             trace!("Large page PA: {:#x} is already mapped to a page table", guest_large_page_pa);
+            return Err(HypervisorError::PageAlreadySplit);
         }
 
-        Ok(())
+        // Allocate a new page table
+        let mut pt = unsafe { box_zeroed::<Pt>() };
+        let is_copied = match self.page_tables(guest_large_page_pa).next() {
+            Some(populated_pt) => {
+                *pt = *populated_pt;
+                true
+            }
+            None => false,
+        };
+
+        trace!("Large page mapped to page table successfully");
+        Ok((&mut **self.large_page_table_mappings.entry(key).or_insert(pt), is_copied))
     }
 
     /// Unmaps a shadow page from a guest physical address, removing the associated hooks.
@@ -155,7 +231,8 @@ impl MemoryManager {
         trace!("Unmapping guest page and shadow page for PA: {:#x}", guest_page_pa);
 
         // Remove the mapping if it exists
-        if self.guest_page_mappings.remove(&guest_page_pa).is_some() {
+        if let Some(mapping) = self.guest_page_mappings.remove(&guest_page_pa) {
+            mapping.hooks.iter().for_each(|hook| Self::count_inline_hook(hook.ept_hook_type, false));
             trace!("Guest page unmapped from shadow page successfully");
             Ok(())
         } else {
@@ -164,35 +241,274 @@ impl MemoryManager {
         }
     }
 
-    /// Unmaps a page table from a large guest physical address.
+    /// Removes the hook information of a single function from a guest page, leaving the other hooks on the page in place.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest physical address of the page.
+    /// * `guest_function_pa` - The guest physical address of the hooked function.
+    ///
+    /// # Returns
+    /// The number of hooks left on the page, or an error if the function was not hooked.
+    pub fn remove_hook_info(&mut self, guest_page_pa: u64, guest_function_pa: u64) -> Result<usize, HypervisorError> {
+        trace!("Removing hook info for function PA: {:#x} from page PA: {:#x}", guest_function_pa, guest_page_pa);
+
+        let mapping = self.guest_page_mappings.get_mut(&guest_page_pa).ok_or(HypervisorError::HookNotFound)?;
+
+        let index = mapping
+            .hooks
+            .iter()
+            .position(|hook| hook.guest_function_pa == guest_function_pa)
+            .ok_or(HypervisorError::HookNotFound)?;
+
+        let hook = mapping.hooks.remove(index);
+        Self::count_inline_hook(hook.ept_hook_type, false);
+
+        Ok(mapping.hooks.len())
+    }
+
+    /// Checks whether a function hook of an inline hook type is installed, without locking the hook manager.
+    ///
+    /// # Arguments
+    /// * `inline_hook_type` - The inline hook type.
+    ///
+    /// # Returns
+    /// `true` if at least one hook of the type is installed.
+    pub fn has_inline_hooks(inline_hook_type: InlineHookType) -> bool {
+        INLINE_HOOK_COUNTS[inline_hook_type as usize].load(Ordering::Acquire) > 0
+    }
+
+    /// Updates the number of installed hooks of the inline hook type of a function hook.
+    ///
+    /// # Arguments
+    /// * `ept_hook_type` - The type of the hook that was installed or removed. Page hooks are not counted.
+    /// * `installed` - Whether the hook was installed or removed.
+    fn count_inline_hook(ept_hook_type: EptHookType, installed: bool) {
+        if let EptHookType::Function(inline_hook_type) = ept_hook_type {
+            let count = &INLINE_HOOK_COUNTS[inline_hook_type as usize];
+
+            if installed {
+                count.fetch_add(1, Ordering::AcqRel);
+            } else {
+                count.fetch_sub(1, Ordering::AcqRel);
+            }
+        }
+    }
+
+    /// Takes a reference on a large guest page for a hook or hidden page installed in it, keeping it split.
+    ///
+    /// # Arguments
+    /// * `guest_large_page_pa` - The large guest physical address.
+    ///
+    /// # Returns
+    /// The number of references held on the large page.
+    pub fn acquire_large_page(&mut self, guest_large_page_pa: u64) -> usize {
+        let ref_count = self.large_page_ref_counts.entry(guest_large_page_pa).or_insert(0);
+        *ref_count += 1;
+
+        trace!("Large page PA: {:#x} now has {} references", guest_large_page_pa, *ref_count);
+        *ref_count
+    }
+
+    /// Drops a reference on a large guest page when a hook installed in it is removed.
+    ///
+    /// # Arguments
+    /// * `guest_large_page_pa` - The large guest physical address.
+    ///
+    /// # Returns
+    /// The number of references left on the large page, or an error if it held none.
+    pub fn release_large_page(&mut self, guest_large_page_pa: u64) -> Result<usize, HypervisorError> {
+        let ref_count = self
+            .large_page_ref_counts
+            .get_mut(&guest_large_page_pa)
+            .ok_or(HypervisorError::LargePageUnmapError)?;
+        *ref_count -= 1;
+
+        let ref_count = *ref_count;
+        trace!("Large page PA: {:#x} now has {} references", guest_large_page_pa, ref_count);
+
+        if ref_count == 0 {
+            self.large_page_ref_counts.remove(&guest_large_page_pa);
+        }
+
+        Ok(ref_count)
+    }
+
+    /// Returns the number of references held on a large guest page by the hooks and hidden pages installed in it.
+    ///
+    /// # Arguments
+    /// * `guest_large_page_pa` - The large guest physical address.
+    ///
+    /// # Returns
+    /// The number of references, zero if the large page is not referenced.
+    pub fn large_page_ref_count(&self, guest_large_page_pa: u64) -> usize {
+        self.large_page_ref_counts.get(&guest_large_page_pa).copied().unwrap_or(0)
+    }
+
+    /// Checks whether a page table is mapped to a large guest physical address on any processor.
+    ///
+    /// # Arguments
+    /// * `guest_large_page_pa` - The large guest physical address.
+    ///
+    /// # Returns
+    /// `true` if the large page is split with a page table on at least one processor.
+    pub fn has_page_tables(&self, guest_large_page_pa: u64) -> bool {
+        self.page_tables(guest_large_page_pa).next().is_some()
+    }
+
+    /// Unmaps the page tables of a large guest physical address that was merged back into a large page, keeping them
+    /// allocated until every processor has invalidated the EPT generation they may still be cached for.
     ///
     /// # Arguments
     /// * `guest_large_page_pa` - The large guest physical address to unmap.
+    /// * `generation` - The EPT generation of the merge.
     ///
     /// # Returns
-    /// `Ok(())` if successful, or an error if the page table was not mapped.
-    pub fn unmap_large_page_from_pt(&mut self, guest_large_page_pa: u64) -> Result<(), HypervisorError> {
-        trace!("Unmapping large page and page table for PA: {:#x}", guest_large_page_pa);
+    /// `Ok(())` if successful, or an error if no page table was mapped.
+    pub fn retire_large_page_tables(&mut self, guest_large_page_pa: u64, generation: u64) -> Result<(), HypervisorError> {
+        trace!("Retiring page tables for large page PA: {:#x}", guest_large_page_pa);
 
-        // Remove the mapping if it exists
-        if self.large_page_table_mappings.remove(&guest_large_page_pa).is_some() {
-            trace!("Large page unmapped from page table successfully");
-            Ok(())
-        } else {
-            trace!("Large page PA: {:#x} was not mapped", guest_large_page_pa);
-            Err(HypervisorError::LargePageUnmapError)
+        let keys: Vec<(u64, u64)> = self
+            .large_page_table_mappings
+            .range((guest_large_page_pa, 0)..=(guest_large_page_pa, u64::MAX))
+            .map(|(key, _)| *key)
+            .collect();
+
+        if keys.is_empty() {
+            return Err(HypervisorError::LargePageUnmapError);
+        }
+
+        for key in keys {
+            if let Some(pt) = self.large_page_table_mappings.remove(&key) {
+                self.retired_page_tables.push((generation, pt));
+            }
         }
+
+        Ok(())
+    }
+
+    /// Allocates a copy of the shadow page of a guest page, to be updated and then swapped in with `replace_shadow_page`.
+    ///
+    /// The shadow page in use may be executed by other processors at any time, so it is never modified in place.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest physical address of the page.
+    ///
+    /// # Returns
+    /// The copy of the shadow page, or an error if the guest page has no shadow page.
+    pub fn copy_shadow_page(&self, guest_page_pa: u64) -> Result<Box<Page>, HypervisorError> {
+        let mapping = self.guest_page_mappings.get(&guest_page_pa).ok_or(HypervisorError::ShadowPageNotFound)?;
+
+        let mut shadow_page = unsafe { box_zeroed::<Page>() };
+        *shadow_page = *mapping.shadow_page;
+
+        Ok(shadow_page)
     }
 
-    /// Retrieves a mutable reference to the page table associated with a large guest physical address.
+    /// Replaces the shadow page of a guest page with an updated copy.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest physical address of the page.
+    /// * `shadow_page` - The new shadow page.
+    ///
+    /// # Returns
+    /// The previous shadow page, which must be retired with `retire_shadow_page` once no EPT maps it, or an error if the guest page has no shadow page.
+    pub fn replace_shadow_page(&mut self, guest_page_pa: u64, shadow_page: Box<Page>) -> Result<Box<Page>, HypervisorError> {
+        let mapping = self
+            .guest_page_mappings
+            .get_mut(&guest_page_pa)
+            .ok_or(HypervisorError::ShadowPageNotFound)?;
+        Ok(core::mem::replace(&mut mapping.shadow_page, shadow_page))
+    }
+
+    /// Keeps a replaced shadow page allocated until every processor has invalidated the EPT generation it may still be cached for.
+    ///
+    /// # Arguments
+    /// * `shadow_page` - The replaced shadow page.
+    /// * `generation` - The EPT generation of the replacement.
+    pub fn retire_shadow_page(&mut self, shadow_page: Box<Page>, generation: u64) {
+        self.retired_shadow_pages.push((generation, shadow_page));
+    }
+
+    /// Frees the retired page tables and shadow pages that no processor can have cached anymore.
+    ///
+    /// # Arguments
+    /// * `flushed_generation` - The oldest EPT generation any processor has invalidated.
+    pub fn free_retired_memory(&mut self, flushed_generation: u64) {
+        self.retired_page_tables.retain(|(generation, _)| *generation > flushed_generation);
+        self.retired_shadow_pages.retain(|(generation, _)| *generation > flushed_generation);
+        trace!("{} retired page tables and {} retired shadow pages left", self.retired_page_tables.len(), self.retired_shadow_pages.len());
+    }
+
+    /// Retrieves a mutable reference to the page table a large guest physical address is split with in the EPT of a processor.
     ///
     /// # Arguments
     /// * `guest_large_page_pa` - The large guest physical address.
+    /// * `ept` - The EPT of the processor.
     ///
     /// # Returns
     /// An `Option` containing a mutable reference to the `Pt` if found.
-    pub fn get_page_table_as_mut(&mut self, guest_large_page_pa: u64) -> Option<&mut Pt> {
-        self.large_page_table_mappings.get_mut(&guest_large_page_pa).map(|pt| &mut **pt)
+    pub fn get_page_table_as_mut(&mut self, guest_large_page_pa: u64, ept: &Ept) -> Option<&mut Pt> {
+        self.large_page_table_mappings
+            .get_mut(&(guest_large_page_pa, ept as *const Ept as u64))
+            .map(|pt| &mut **pt)
+    }
+
+    /// Retrieves a reference to the page table a large guest physical address is split with in the EPT of a processor.
+    ///
+    /// # Arguments
+    /// * `guest_large_page_pa` - The large guest physical address.
+    /// * `ept` - The EPT of the processor.
+    ///
+    /// # Returns
+    /// An `Option` containing a reference to the `Pt` if found.
+    pub fn get_page_table(&self, guest_large_page_pa: u64, ept: &Ept) -> Option<&Pt> {
+        self.large_page_table_mappings
+            .get(&(guest_large_page_pa, ept as *const Ept as u64))
+            .map(|pt| &**pt)
+    }
+
+    /// Returns an iterator over the page tables a large guest physical address is split with, one per processor.
+    ///
+    /// # Arguments
+    /// * `guest_large_page_pa` - The large guest physical address.
+    ///
+    /// # Returns
+    /// An iterator of references to the `Pt` of each processor.
+    fn page_tables(&self, guest_large_page_pa: u64) -> impl Iterator<Item = &Pt> {
+        self.large_page_table_mappings
+            .range((guest_large_page_pa, 0)..=(guest_large_page_pa, u64::MAX))
+            .map(|(_, pt)| &**pt)
+    }
+
+    /// Returns an iterator over all hooked guest pages and their hook mappings, ordered by guest physical address.
+    ///
+    /// # Returns
+    /// An iterator of `(guest_page_pa, &HookMapping)` pairs.
+    pub fn hook_mappings(&self) -> impl Iterator<Item = (u64, &HookMapping)> {
+        self.guest_page_mappings.iter().map(|(guest_page_pa, mapping)| (*guest_page_pa, mapping))
+    }
+
+    /// Checks if a physical address range overlaps a shadow page or a page table owned by the memory manager.
+    ///
+    /// # Arguments
+    /// * `start` - The start of the physical address range.
+    /// * `end` - The end of the physical address range (exclusive).
+    ///
+    /// # Returns
+    /// `true` if any shadow page or page table overlaps the range, otherwise `false`.
+    pub fn overlaps_managed_memory(&self, start: u64, end: u64) -> bool {
+        let overlaps = |pa: u64, size: usize| start < pa + size as u64 && pa < end;
+
+        self.guest_page_mappings
+            .values()
+            .map(|mapping| &mapping.shadow_page)
+            .chain(self.retired_shadow_pages.iter().map(|(_, page)| page))
+            .any(|page| overlaps(&**page as *const Page as u64, size_of::<Page>()))
+            || self
+                .large_page_table_mappings
+                .values()
+                .chain(self.retired_page_tables.iter().map(|(_, pt)| pt))
+                .any(|pt| overlaps(&**pt as *const Pt as u64, size_of::<Pt>()))
     }
 
     /// Retrieves a pointer to the shadow page associated with a guest physical address.
@@ -235,6 +551,40 @@ impl MemoryManager {
             .find(|hook| hook.guest_function_pa == guest_function_pa)
     }
 
+    /// Retrieves a mutable reference to the `HookInfo` instance associated with a guest function physical address.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest physical address.
+    /// * `guest_function_pa` - The guest function physical address.
+    ///
+    /// # Returns
+    /// An `Option` containing a mutable reference to the `HookInfo` instance if found.
+    pub fn get_hook_info_by_function_pa_mut(&mut self, guest_page_pa: u64, guest_function_pa: u64) -> Option<&mut HookInfo> {
+        self.guest_page_mappings
+            .get_mut(&guest_page_pa)?
+            .hooks
+            .iter_mut()
+            .find(|hook| hook.guest_function_pa == guest_function_pa)
+    }
+
+    /// Retrieves the page hook watching a guest page.
+    ///
+    /// # Arguments
+    /// * `guest_page_pa` - The guest page physical address.
+    ///
+    /// # Returns
+    /// An `Option` containing a reference to the `HookInfo` of the page hook, together with the accesses it watches, if the page is watched.
+    pub fn get_page_watch(&self, guest_page_pa: u64) -> Option<(&HookInfo, AccessType)> {
+        self.guest_page_mappings
+            .get(&guest_page_pa)?
+            .hooks
+            .iter()
+            .find_map(|hook| match hook.ept_hook_type {
+                EptHookType::Page(watched_access) => Some((hook, watched_access)),
+                EptHookType::Function(_) => None,
+            })
+    }
+
     /// Retrieves a reference to the `HookInfo` instance associated with a guest function virtual address.
     ///
     /// # Arguments
@@ -1,4 +1,5 @@
 pub mod descriptor_manager;
+pub mod handlers;
 pub mod hook_manager;
 pub mod inline;
 pub mod memory_manager;
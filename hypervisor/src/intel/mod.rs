@@ -1,9 +1,11 @@
 pub mod addresses;
+pub mod apic;
 pub mod bitmap;
 pub mod capture;
 pub mod controls;
 pub mod descriptor;
 pub mod ept;
+pub mod ept_sync;
 pub mod events;
 pub mod hooks;
 pub mod invept;
@@ -12,6 +14,7 @@ pub mod mtrr;
 pub mod page;
 pub mod paging;
 pub mod segmentation;
+pub mod session;
 pub mod state;
 pub mod support;
 pub mod vm;
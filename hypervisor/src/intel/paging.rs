@@ -6,13 +6,16 @@
 //! https://github.com/tandasat/Hello-VT-rp/blob/main/hypervisor/src/paging_structures.rs
 
 use {
-    crate::error::HypervisorError,
+    crate::{error::HypervisorError, intel::hooks::hook_manager::HookManager},
     bitfield::bitfield,
     core::ptr::addr_of,
     log::error,
     x86::bits64::paging::{pd_index, pdpt_index, pml4_index, pt_index, VAddr, BASE_PAGE_SHIFT, BASE_PAGE_SIZE, HUGE_PAGE_SIZE, LARGE_PAGE_SIZE},
 };
 
+/// The size of the host identity map built by `PageTables::build_identity`: 512 PDPT entries of 1GB each.
+pub const IDENTITY_MAP_SIZE: u64 = 512 * HUGE_PAGE_SIZE as u64;
+
 /// Represents the entire Page Tables structure for the hypervisor.
 ///
 /// The Page Tables mechanism is crucial for virtual memory management in x86-64 architecture.
@@ -106,76 +109,99 @@ impl PageTables {
     /// # Credits
     /// Credits to Jessie (jessiep_) for the help.
     pub unsafe fn translate_guest_virtual_to_guest_physical(guest_cr3: u64, guest_va: u64) -> Result<u64, HypervisorError> {
-        let guest_va = VAddr::from(guest_va);
+        let walk = Self::walk_guest_virtual_to_guest_physical(guest_cr3, guest_va);
 
-        // Cast guest CR3 to the PML4 table structure
-        let pml4_table = guest_cr3 as *const Pml4;
-
-        // Calculate the PML4 index and access the corresponding entry.
-        let pml4_index = pml4_index(guest_va);
-        let pml4_entry = &(*pml4_table).0.entries[pml4_index];
+        walk.address.ok_or_else(|| {
+            error!("{} entry is not present: {:#x}", PageWalk::LEVEL_NAMES[walk.levels - 1], guest_va);
+            walk.error()
+        })
+    }
 
-        // Check if the PML4 entry is present (readable).
-        if !pml4_entry.present() {
-            error!("PML4 entry is not present: {:#x}", guest_va);
-            return Err(HypervisorError::InvalidPml4Entry);
-        }
+    /// Walks the guest's page tables for a guest virtual address, recording every entry visited.
+    ///
+    /// The CR3 may come from the user mode client, so every table is read through the host identity map only if it lies
+    /// within it and does not overlap hypervisor memory. A table failing this check is treated as a non-present entry.
+    ///
+    /// # Arguments
+    /// * `guest_cr3` - The guest CR3 register value, which contains the base address of the guest's page table hierarchy.
+    /// * `guest_va` - The guest virtual address to translate.
+    ///
+    /// # Safety
+    /// This function is unsafe because it involves raw memory access based on potentially
+    /// arbitrary addresses, which may lead to undefined behavior if the addresses are invalid
+    /// or the memory is not properly mapped.
+    ///
+    /// # Returns
+    /// A `PageWalk` with the PML4E, PDPTE, PDE and PTE visited, stopping at the first non-present entry or the entry mapping the page.
+    pub unsafe fn walk_guest_virtual_to_guest_physical(guest_cr3: u64, guest_va: u64) -> PageWalk {
+        let guest_va = VAddr::from(guest_va);
+        let indices = [pml4_index(guest_va), pdpt_index(guest_va), pd_index(guest_va), pt_index(guest_va)];
 
-        // Cast the entry to the PDPT table structure.
-        let pdpt_table = (pml4_entry.pfn() << BASE_PAGE_SHIFT) as *const Pdpt;
+        let mut walk = PageWalk::default();
 
-        // Calculate the PDPT index and access the corresponding entry.
-        let pdpt_index = pdpt_index(guest_va);
-        let pdpt_entry = &(*pdpt_table).0.entries[pdpt_index];
+        // Start at the PML4 table, ignoring the PCID and flags in the low bits of CR3.
+        let mut table = (guest_cr3 & PAGE_FRAME_MASK) as *const Table;
 
-        // Check if the PDPT entry is present (readable).
-        if !pdpt_entry.present() {
-            error!("PDPT entry is not present: {:#x}", guest_va);
-            return Err(HypervisorError::InvalidPdptEntry);
-        }
+        for (level, index) in indices.into_iter().enumerate() {
+            walk.levels = level + 1;
 
-        // Check if the PDPT entry is a huge page (1 GB), if so, calculate the guest physical address.
-        if pdpt_entry.large() {
-            let guest_pa = (pdpt_entry.pfn() << BASE_PAGE_SHIFT) + (guest_va.as_u64() % HUGE_PAGE_SIZE as u64);
-            return Ok(guest_pa);
-        }
+            if !Self::is_guest_table(table as u64) {
+                error!("Refusing to read {} table at {:#x}", PageWalk::LEVEL_NAMES[level], table as u64);
+                return walk;
+            }
 
-        // Cast the entry to the PD table structure.
-        let pd_table = (pdpt_entry.pfn() << BASE_PAGE_SHIFT) as *const Pd;
+            let entry = (*table).entries[index];
+            walk.entries[level] = entry.0;
 
-        // Calculate the PD index and access the corresponding entry.
-        let pd_index = pd_index(guest_va);
-        let pd_entry = &(*pd_table).0.entries[pd_index];
+            // Check if the entry is present (readable).
+            if !entry.present() {
+                return walk;
+            }
 
-        // Check if the PD entry is present (readable).
-        if !pd_entry.present() {
-            error!("PD entry is not present: {:#x}", guest_va);
-            return Err(HypervisorError::InvalidPdEntry);
-        }
+            // A PDPTE or PDE may map a huge (1 GB) or large (2 MB) page; a PTE always maps a 4 KB page.
+            if level == PageWalk::PT_LEVEL || (level != PageWalk::PML4_LEVEL && entry.large()) {
+                let page_size = PageWalk::PAGE_SIZES[level];
+                walk.page_size = page_size;
+                walk.address = Some(((entry.pfn() << BASE_PAGE_SHIFT) & !(page_size - 1)) + (guest_va.as_u64() % page_size));
+                return walk;
+            }
 
-        // Check if the PD entry is a large page (2 MB), if so, calculate the guest physical address.
-        if pd_entry.large() {
-            let guest_pa = (pd_entry.pfn() << BASE_PAGE_SHIFT) + (guest_va.as_u64() % LARGE_PAGE_SIZE as u64);
-            return Ok(guest_pa);
+            // Cast the entry to the next table structure.
+            table = (entry.pfn() << BASE_PAGE_SHIFT) as *const Table;
         }
 
-        // Cast the entry to the PT table structure.
-        let pt_table = (pd_entry.pfn() << BASE_PAGE_SHIFT) as *const Pt;
+        unreachable!("the PT level always terminates the walk")
+    }
 
-        // Calculate the PT index and access the corresponding entry.
-        let pt_index = pt_index(guest_va);
-        let pt_entry = &(*pt_table).0.entries[pt_index];
+    /// Checks if a guest paging structure can be read through the host identity map.
+    ///
+    /// # Arguments
+    /// * `table_pa` - The physical address of the paging structure.
+    ///
+    /// # Returns
+    /// `true` if the table is identity mapped and does not overlap memory allocated by the hypervisor, otherwise `false`.
+    fn is_guest_table(table_pa: u64) -> bool {
+        table_pa + BASE_PAGE_SIZE as u64 <= IDENTITY_MAP_SIZE && !HookManager::overlaps_allocated_memory(table_pa, BASE_PAGE_SIZE as u64)
+    }
 
-        // Check if the PT entry is present (readable).
-        if !pt_entry.present() {
-            error!("PT entry is not present: {:#x}", guest_va);
-            return Err(HypervisorError::InvalidPtEntry);
+    /// Computes the effective access rights of a guest page walk that reached a mapped page.
+    ///
+    /// A page is writable or user accessible only if every visited entry allows it,
+    /// and it is not executable if any visited entry sets the execute-disable bit.
+    ///
+    /// # Arguments
+    /// * `walk` - The guest page walk.
+    ///
+    /// # Returns
+    /// The `GuestAccessRights` of the mapped page.
+    pub fn effective_access_rights(walk: &PageWalk) -> GuestAccessRights {
+        let entries = walk.entries[..walk.levels].iter().map(|&entry| Entry(entry));
+
+        GuestAccessRights {
+            writable: entries.clone().all(|entry| entry.writable()),
+            user: entries.clone().all(|entry| entry.user()),
+            executable: !entries.clone().any(|entry| entry.execute_disable()),
         }
-
-        // The PT entry is a 4 KB page, calculate the guest physical address.
-        let guest_pa = (pt_entry.pfn() << BASE_PAGE_SHIFT) + (guest_va.as_u64() % BASE_PAGE_SIZE as u64);
-
-        Ok(guest_pa)
     }
 
     /// Gets the physical address of the PML4 table, ensuring it is 4KB aligned.
@@ -228,14 +254,6 @@ pub struct Pdpt(Table);
 #[derive(Debug, Clone, Copy)]
 struct Pd(Table);
 
-/// Represents an EPT Page-Table Entry (PTE) that maps a 4-KByte Page.
-///
-/// PTEs are part of the fourth level in the standard x86-64 paging hierarchy.
-///
-/// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.5 Paging
-#[derive(Debug, Clone, Copy)]
-pub struct Pt(Table);
-
 /*
 /// Represents a Page-Table Entry (PTE) that maps a 4-KByte Page.
 ///
@@ -266,8 +284,10 @@ bitfield! {
     ///
     /// * `present` - If set, the memory region is accessible.
     /// * `writable` - If set, the memory region can be written to.
+    /// * `user` - If set, the memory region can be accessed from user mode.
     /// * `large` - If set, this entry maps a large page.
     /// * `pfn` - The Page Frame Number, indicating the physical address.
+    /// * `execute_disable` - If set, code cannot be executed from the memory region.
     ///
     /// Reference: Intel® 64 and IA-32 Architectures Software Developer's Manual: 4.5 Paging
     #[derive(Clone, Copy)]
@@ -276,6 +296,59 @@ bitfield! {
 
     present, set_present: 0;
     writable, set_writable: 1;
+    user, set_user: 2;
     large, set_large: 7;
     pfn, set_pfn: 51, 12;
+    execute_disable, set_execute_disable: 63;
+}
+
+/// Mask of the page frame bits of CR3 and of paging structure entries.
+const PAGE_FRAME_MASK: u64 = 0x000F_FFFF_FFFF_F000;
+
+/// The entries visited while walking a four-level paging hierarchy, used for both guest paging and EPT.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PageWalk {
+    /// The raw entries visited, PML4E first. Only the first `levels` entries are valid.
+    pub entries: [u64; 4],
+    /// The number of entries visited.
+    pub levels: usize,
+    /// The size of the mapped page, or 0 if the walk stopped at a non-present entry.
+    pub page_size: u64,
+    /// The translated address, if the walk reached the entry mapping the page.
+    pub address: Option<u64>,
+}
+
+impl PageWalk {
+    /// Index of the PML4 level in `entries`.
+    pub const PML4_LEVEL: usize = 0;
+    /// Index of the PT level in `entries`.
+    pub const PT_LEVEL: usize = 3;
+    /// Names of the levels, used for logging.
+    pub const LEVEL_NAMES: [&'static str; 4] = ["PML4", "PDPT", "PD", "PT"];
+    /// Size of the page mapped by an entry at each level. A PML4E never maps a page.
+    pub const PAGE_SIZES: [u64; 4] = [0, HUGE_PAGE_SIZE as u64, LARGE_PAGE_SIZE as u64, BASE_PAGE_SIZE as u64];
+
+    /// Returns the error describing the level at which a walk stopped.
+    ///
+    /// # Returns
+    /// `HypervisorError::InvalidPml4Entry`, `InvalidPdptEntry`, `InvalidPdEntry` or `InvalidPtEntry`.
+    pub fn error(&self) -> HypervisorError {
+        match self.levels {
+            0 | 1 => HypervisorError::InvalidPml4Entry,
+            2 => HypervisorError::InvalidPdptEntry,
+            3 => HypervisorError::InvalidPdEntry,
+            _ => HypervisorError::InvalidPtEntry,
+        }
+    }
+}
+
+/// The effective access rights of a guest page, combined over every level of the page walk.
+#[derive(Debug, Clone, Copy)]
+pub struct GuestAccessRights {
+    /// Whether the page can be written to.
+    pub writable: bool,
+    /// Whether the page can be accessed from user mode.
+    pub user: bool,
+    /// Whether code can be executed from the page.
+    pub executable: bool,
 }
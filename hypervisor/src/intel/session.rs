@@ -0,0 +1,153 @@
+//! Authenticated session between the hypervisor and its user mode client.
+//!
+//! `OpenSession` generates a random session key with RDRAND and hands it to the calling process.
+//! Every other command must be signed with that key and carry a nonce greater than the last accepted one,
+//! so forged commands fail the MAC check and replayed commands fail the nonce check.
+//!
+//! The session is bound to the process that opened it. It is released by an authenticated `CloseSession`,
+//! or re-keyed by the next `OpenSession` once the owning process is no longer running.
+
+use {
+    crate::{error::HypervisorError, windows::eprocess::ProcessInformation},
+    log::{debug, error},
+    shared::{ClientCommand, Pod, SessionKey},
+    spin::Mutex,
+    x86::random::rdrand64,
+};
+
+/// Number of times RDRAND is retried before key generation is considered failed.
+const RDRAND_RETRIES: usize = 10;
+
+/// The global session shared by all logical processors.
+pub static SHARED_SESSION: Mutex<Session> = Mutex::new(Session::new());
+
+/// State of the session.
+#[derive(Debug)]
+pub struct Session {
+    /// The session key, or `None` until the handshake has been performed.
+    key: Option<SessionKey>,
+
+    /// The nonce of the last command that was accepted.
+    last_nonce: u64,
+
+    /// The process ID of the process that opened the session, or zero if it could not be determined.
+    owner_process_id: u64,
+
+    /// The directory table base of the owning process, used to tell it apart from a later process reusing its ID.
+    owner_directory_table_base: u64,
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Session {
+    /// Creates a session that has not been established yet.
+    pub const fn new() -> Self {
+        Self {
+            key: None,
+            last_nonce: 0,
+            owner_process_id: 0,
+            owner_directory_table_base: 0,
+        }
+    }
+
+    /// Establishes the session for the current process by generating a fresh key.
+    ///
+    /// A key is never handed out twice: an existing session is only replaced once the process that
+    /// opened it has exited. If the owner could not be identified, the session can only be released
+    /// with `close`.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<SessionKey, HypervisorError>` - The new session key, or an error if a live session already exists or RDRAND failed.
+    pub fn open(&mut self) -> Result<SessionKey, HypervisorError> {
+        if self.key.is_some() {
+            if self.is_owner_running() {
+                error!("Session already established by process {}, refusing to hand out the key again", self.owner_process_id);
+                return Err(HypervisorError::SessionAlreadyEstablished);
+            }
+
+            debug!("Owner of the session (process {}) has exited, re-keying", self.owner_process_id);
+        }
+
+        let key = SessionKey {
+            k0: Self::random_u64()?,
+            k1: Self::random_u64()?,
+        };
+
+        let owner = ProcessInformation::get_current_process_info();
+
+        self.key = Some(key);
+        self.last_nonce = 0;
+        self.owner_process_id = owner.as_ref().map_or(0, |process| process.unique_process_id);
+        self.owner_directory_table_base = owner.as_ref().map_or(0, |process| process.directory_table_base);
+        debug!("Session established for process {}", self.owner_process_id);
+
+        Ok(key)
+    }
+
+    /// Releases the session, so the next `OpenSession` generates a new key.
+    ///
+    /// Only called for an authenticated `CloseSession`, so only the key holder can release the session.
+    pub fn close(&mut self) {
+        debug!("Session closed by process {}", self.owner_process_id);
+        *self = Self::new();
+    }
+
+    /// Checks whether the process that opened the session is still running.
+    ///
+    /// # Returns
+    ///
+    /// * `bool` - `true` if the owner is still an active process or could not be identified when the session was opened.
+    fn is_owner_running(&self) -> bool {
+        if self.owner_process_id == 0 {
+            return true;
+        }
+
+        ProcessInformation::get_active_process_by_process_id(self.owner_process_id)
+            .is_some_and(|process| process.directory_table_base == self.owner_directory_table_base)
+    }
+
+    /// Authenticates a command against the session key and consumes its nonce.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - The command read from the guest.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<(), HypervisorError>` - `Ok(())` if the MAC is valid and the nonce has not been used before.
+    pub fn authenticate<T: Pod>(&mut self, command: &ClientCommand<T>) -> Result<(), HypervisorError> {
+        let key = self.key.as_ref().ok_or(HypervisorError::SessionNotEstablished)?;
+
+        if !command.verify(key) {
+            error!("Rejected command with invalid MAC");
+            return Err(HypervisorError::InvalidCommandMac);
+        }
+
+        if command.header.nonce <= self.last_nonce {
+            error!("Rejected replayed command with nonce {} (last accepted: {})", command.header.nonce, self.last_nonce);
+            return Err(HypervisorError::ReplayedCommand);
+        }
+
+        self.last_nonce = command.header.nonce;
+
+        Ok(())
+    }
+
+    /// Reads a random 64-bit value using RDRAND.
+    fn random_u64() -> Result<u64, HypervisorError> {
+        let mut value = 0;
+
+        for _ in 0..RDRAND_RETRIES {
+            if unsafe { rdrand64(&mut value) } {
+                return Ok(value);
+            }
+        }
+
+        Err(HypervisorError::SessionKeyGenerationFailed)
+    }
+}
@@ -66,6 +66,31 @@ pub fn rdtsc() -> u64 {
     unsafe { core::arch::x86_64::_rdtsc() }
 }
 
+/// Returns the APIC ID of the current processor.
+///
+/// The 32-bit x2APIC ID of the extended topology leaves is preferred, as the initial APIC ID of leaf 1 only has
+/// 8 bits and repeats on systems with more than 256 logical processors.
+pub fn apic_id() -> u32 {
+    // See: (Intel) Table 3-8. Information Returned by CPUID Instruction, leaves 0BH and 1FH
+    // See: (AMD) CPUID Fn0000_000B_EDX x2APIC_ID
+    let max_leaf = x86::cpuid::cpuid!(0x0).eax;
+
+    for leaf in [0x1f, 0xb] {
+        if max_leaf < leaf {
+            continue;
+        }
+
+        // A leaf that is not implemented reports no logical processors at its first level.
+        let topology = x86::cpuid::cpuid!(leaf, 0);
+        if topology.ebx & 0xffff != 0 {
+            return topology.edx;
+        }
+    }
+
+    // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
+    x86::cpuid::cpuid!(0x1).ebx >> 24
+}
+
 /// Reads an MSR.
 pub fn rdmsr(msr: u32) -> u64 {
     unsafe { x86::msr::rdmsr(msr) }
@@ -16,6 +16,7 @@ use {
             support::{vmclear, vmptrld, vmread, vmxon},
             vmcs::Vmcs,
             vmerror::{VmInstructionError, VmxBasicExitReason},
+            vmexit::mtf::MtfRestorePages,
             vmlaunch::launch_vm,
             vmxon::Vmxon,
         },
@@ -83,6 +84,26 @@ pub struct Vm {
     /// - Size: 8 bytes (Option<u64>) (0x8)
     pub mtf_counter: Option<u64>,
 
+    /// The guest pages whose EPT permissions are restored when the MTF counter reaches zero, recorded by every VM exit that relaxed them while single-stepping.
+    /// - Size: 128 bytes (8 * Option<MtfRestorePage>) (0x80)
+    pub mtf_restore_pages: MtfRestorePages,
+
+    /// The last EPT generation this processor invalidated its EPT and VPID contexts for, see `ept_sync`.
+    /// - Size: 8 bytes (0x8)
+    pub ept_generation: u64,
+
+    /// The EPT generation every processor has to reach before the guest of this processor resumes, after it changed the EPTs.
+    /// - Size: 8 bytes (Option<u64>) (0x8)
+    pub pending_ept_flush: Option<u64>,
+
+    /// Whether the exception bitmap of this processor intercepts breakpoints, which it only does while int3 hooks are installed.
+    /// - Size: 1 byte (0x1)
+    pub intercepts_breakpoints: bool,
+
+    /// Whether an NMI of the guest waits for the NMI window to be injected, see `vmexit::nmi`.
+    /// - Size: 1 byte (0x1)
+    pub pending_guest_nmi: bool,
+
     /// The CPUID feature information for the VM.
     pub cpuid_feature_info: FeatureInfo,
 
@@ -139,9 +160,16 @@ impl Vm {
         trace!("Initializing Launch State");
         self.has_launched = false;
 
-        trace!("Initializing Old RFLAGS and MTF Counter");
+        trace!("Initializing Old RFLAGS, MTF Counter and MTF Restore Pages");
         self.old_rflags = None;
         self.mtf_counter = None;
+        self.mtf_restore_pages = MtfRestorePages::new();
+
+        trace!("Initializing EPT Generation, Breakpoint Interception and Pending Guest NMI");
+        self.ept_generation = 0;
+        self.pending_ept_flush = None;
+        self.intercepts_breakpoints = false;
+        self.pending_guest_nmi = false;
 
         trace!("Getting and Setting CPUID Feature Information and XCR0 Unsupported Mask");
         let cpuid_ext_state_info = cpuid!(0x0d, 0x00);
@@ -135,7 +135,8 @@ impl Vmcs {
 
         vmwrite(vmcs::host::TR_BASE, host_descriptor.tss.base);
         vmwrite(vmcs::host::GDTR_BASE, host_descriptor.gdtr.base as u64);
-        vmwrite(vmcs::host::IDTR_BASE, u64::MAX); // Bogus. No proper exception handling.
+        // Only NMIs are handled, see `Descriptors::initialize_for_host`.
+        vmwrite(vmcs::host::IDTR_BASE, host_descriptor.idtr.base as u64);
 
         log::debug!("Host Registers State setup successfully!");
 
@@ -176,13 +177,21 @@ impl Vmcs {
         const EXIT_CTL: u64 = (vmcs::control::ExitControls::HOST_ADDRESS_SPACE_SIZE.bits()
             | vmcs::control::ExitControls::SAVE_DEBUG_CONTROLS.bits()
             | vmcs::control::ExitControls::CONCEAL_VMX_FROM_PT.bits()) as u64;
-        const PINBASED_CTL: u64 = 0;
+        // NMIs make the processor exit to invalidate EPT changes made by other processors, see `ept_sync`.
+        // Virtual NMIs track the NMI blocking of the guest, so NMIs that are not for the hypervisor can be forwarded.
+        const PINBASED_CTL: u64 = (vmcs::control::PinbasedControls::NMI_EXITING.bits() | vmcs::control::PinbasedControls::VIRTUAL_NMIS.bits()) as u64;
 
         vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased, PRIMARY_CTL));
         vmwrite(vmcs::control::SECONDARY_PROCBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::ProcessorBased2, SECONDARY_CTL));
         vmwrite(vmcs::control::VMENTRY_CONTROLS, adjust_vmx_controls(VmxControl::VmEntry, ENTRY_CTL));
         vmwrite(vmcs::control::VMEXIT_CONTROLS, adjust_vmx_controls(VmxControl::VmExit, EXIT_CTL));
-        vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL));
+
+        let pinbased_controls = adjust_vmx_controls(VmxControl::PinBased, PINBASED_CTL);
+        if pinbased_controls & PINBASED_CTL != PINBASED_CTL {
+            log::error!("NMI exiting or virtual NMIs are not supported");
+            return Err(HypervisorError::VMXUnsupported);
+        }
+        vmwrite(vmcs::control::PINBASED_EXEC_CONTROLS, pinbased_controls);
 
         let vmx_cr0_fixed0 = unsafe { msr::rdmsr(msr::IA32_VMX_CR0_FIXED0) };
         let vmx_cr0_fixed1 = unsafe { msr::rdmsr(msr::IA32_VMX_CR0_FIXED1) };
@@ -201,7 +210,8 @@ impl Vmcs {
         vmwrite(vmcs::control::CR4_READ_SHADOW, Cr4::read_raw() & !Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits());
 
         vmwrite(vmcs::control::MSR_BITMAPS_ADDR_FULL, msr_bitmap);
-        //vmwrite(vmcs::control::EXCEPTION_BITMAP, 1u64 << (ExceptionInterrupt::Breakpoint as u32));
+        // #BP is only intercepted while int3 hooks are installed, see `ept_sync::sync_exception_bitmap`.
+        vmwrite(vmcs::control::EXCEPTION_BITMAP, 0u64);
 
         vmwrite(vmcs::control::EPTP_FULL, primary_eptp);
         vmwrite(vmcs::control::VPID, VPID_TAG);
@@ -1,23 +1,49 @@
 use {
     crate::{
+        allocator::HEAP,
+        error::HypervisorError,
+        global_const::TOTAL_HEAP_SIZE,
         intel::{
-            addresses::PhysicalAddress,
+            addresses::{AccessMode, GuestMemory},
+            ept::{AccessType, Ept},
+            ept_sync::{begin_breakpoint_hook, end_breakpoint_hook, wait_for_pending_flush},
             hooks::{
-                hook_manager::{EptHookType, SHARED_HOOK_MANAGER},
+                hook_manager::{EptHookType, HookManager, HookSession, SHARED_HOOK_MANAGER},
                 inline::InlineHookType,
             },
+            mtrr::{MemoryType, Mtrr},
+            page::Page,
+            paging::{PageTables, IDENTITY_MAP_SIZE},
+            session::SHARED_SESSION,
+            support::vmread,
             vm::Vm,
         },
+        vmm::{VIRTUALIZED_PROCESSORS, VMX_CAPABILITIES},
         windows::eprocess::ProcessInformation,
     },
-    log::{debug, error},
-    shared::{ClientCommand, ClientDataPayload, Command, HookData, ProcessMemoryOperation},
+    alloc::string::String,
+    core::sync::atomic::Ordering,
+    log::{debug, error, trace},
+    shared::{
+        AddressHookData, AddressTranslation, BatchData, ClientCommand, CloseSessionData, Command, CommandHeader, CommandResult, CommandStatus,
+        HookData, HookEntry, HookKind, HookStatistics, HypervisorInfo, InfoData, ListData, ModuleEntry, ModuleListData, OpenProcessBy,
+        OpenProcessData, OpenedProcess, PatternScanData, PhysicalMemoryOperation, Pod, ProcessEntry, ProcessMemoryOperation, SessionData,
+        TranslationData, FEATURE_HIDE_HV_WITH_EPT, FEATURE_VMWARE, HYPERVISOR_INFO_VERSION, MAX_BATCH_SIZE, MAX_PATTERN_SCAN_SIZE,
+        MAX_PHYSICAL_MEMORY_SIZE, MODULE_FLAG_WOW64, MODULE_NAME_LENGTH, NO_SESSION, PAGE_EXECUTABLE, PAGE_USER, PAGE_WRITABLE, PROTOCOL_VERSION,
+        WATCH_READ, WATCH_WRITE, WIN32K_SYSCALL_BASE,
+    },
+    x86::{
+        bits64::paging::{PAddr, BASE_PAGE_SIZE},
+        vmx::vmcs,
+    },
 };
 
 /// Handles guest commands sent to the hypervisor.
 ///
 /// This function processes commands issued by the guest, such as opening a process,
-/// reading or writing memory, and enabling or disabling kernel EPT hooks.
+/// reading or writing memory, and enabling or disabling kernel EPT hooks. The guest passes
+/// a pointer to a `ClientCommand` in RCX; its `CommandHeader` is validated before the typed
+/// payload is read, authenticated against the session and dispatched.
 ///
 /// # Arguments
 ///
@@ -25,85 +51,254 @@ use {
 ///
 /// # Returns
 ///
-/// * `Option<()>` - Returns `Some(())` if the command was handled successfully, or `None` if an error occurred.
-pub fn handle_guest_commands(vm: &mut Vm) -> Option<()> {
+/// * `CommandResult` - The status of the command, along with the error code or protocol version that explains a failure.
+pub fn handle_guest_commands(vm: &mut Vm) -> CommandResult {
     debug!("Handling commands");
 
-    // Convert guest RCX register value to a physical address pointer to `ClientCommand`.
-    let client_command_ptr = PhysicalAddress::pa_from_va_with_current_cr3(vm.guest_registers.rcx).ok()?;
-    let client_command = ClientCommand::from_ptr(client_command_ptr);
-
-    // Match the command and handle accordingly
-    match client_command.command {
-        Command::OpenProcess => {
-            if let ClientDataPayload::Memory(memory) = client_command.payload {
-                handle_open_process(vm, memory)
-            } else {
-                error!("Expected ProcessMemoryOperation for OpenProcess command.");
-                None
-            }
-        }
-        Command::ReadProcessMemory => {
-            if let ClientDataPayload::Memory(memory) = client_command.payload {
-                handle_read_memory(vm, memory)
-            } else {
-                error!("Expected Memory for ReadProcessMemory command.");
-                None
+    let command_ptr = vm.guest_registers.rcx;
+    command_result(dispatch_command(vm, command_ptr, true))
+}
+
+/// Converts the outcome of a command into the `CommandResult` reported to the guest.
+///
+/// # Arguments
+///
+/// * `result` - The outcome of the command.
+///
+/// # Returns
+///
+/// * `CommandResult` - `VersionMismatch` with this hypervisor's protocol version for incompatible clients,
+///   `Failure` with the error code for other errors, and `Success` otherwise.
+fn command_result(result: Result<(), HypervisorError>) -> CommandResult {
+    match result {
+        Ok(()) => CommandResult {
+            status: CommandStatus::Success.to_u64(),
+            error_code: 0,
+            protocol_version: 0,
+        },
+        Err(HypervisorError::ProtocolVersionMismatch) => CommandResult {
+            status: CommandStatus::VersionMismatch.to_u64(),
+            error_code: 0,
+            protocol_version: PROTOCOL_VERSION as u64,
+        },
+        Err(e) => {
+            error!("Command failed: {}", e);
+            CommandResult {
+                status: CommandStatus::Failure.to_u64(),
+                error_code: e.code().to_u64(),
+                protocol_version: 0,
             }
         }
-        Command::WriteProcessMemory => {
-            if let ClientDataPayload::Memory(memory) = client_command.payload {
-                handle_write_memory(vm, memory)
-            } else {
-                error!("Expected Memory for WriteProcessMemory command.");
-                None
-            }
+    }
+}
+
+/// Validates, authenticates and executes a single command.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `command_ptr` - The guest virtual address of the `ClientCommand`.
+/// * `allow_batch` - Whether the command may be a `Batch`; batches cannot be nested.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the command was handled successfully, or the error that caused it to fail.
+///   A header that does not match this hypervisor's protocol yields `HypervisorError::ProtocolVersionMismatch`.
+fn dispatch_command(vm: &mut Vm, command_ptr: u64, allow_batch: bool) -> Result<(), HypervisorError> {
+    // Read and validate the fixed-layout header first, so we know which payload type follows it.
+    let header = client_memory().read::<CommandHeader>(command_ptr)?;
+
+    let command = header.validate().map_err(|status| {
+        error!("Rejected command header: {:?} (magic: {:#x}, version: {})", status, header.magic, header.version);
+        match status {
+            CommandStatus::VersionMismatch => HypervisorError::ProtocolVersionMismatch,
+            _ => HypervisorError::InvalidCommand,
         }
-        Command::EnableKernelEptHook | Command::DisableKernelEptHook => {
-            if let ClientDataPayload::Hook(hook) = client_command.payload {
-                handle_hook_command(vm, client_command.command, hook)
-            } else {
-                error!("Expected HookData for hook command.");
-                None
-            }
+    })?;
+
+    match command {
+        Command::OpenProcess => handle_open_process(vm, read_command(command_ptr)?),
+        Command::ReadProcessMemory => handle_read_memory(vm, read_command(command_ptr)?),
+        Command::WriteProcessMemory => handle_write_memory(vm, read_command(command_ptr)?),
+        Command::EnableKernelEptHook | Command::DisableKernelEptHook => handle_hook_command(vm, command, read_command(command_ptr)?),
+        Command::OpenSession => handle_open_session(vm, read_unauthenticated_command(command_ptr)?),
+        Command::CloseSession => handle_close_session(vm, read_command(command_ptr)?),
+        Command::EnumerateHooks => handle_enumerate_hooks(vm, read_command(command_ptr)?),
+        Command::GetHypervisorInfo => handle_get_hypervisor_info(vm, read_command(command_ptr)?),
+        Command::EnumerateProcesses => handle_enumerate_processes(vm, read_command(command_ptr)?),
+        Command::EnumerateModules => handle_enumerate_modules(vm, read_command(command_ptr)?),
+        Command::ReadPhysicalMemory => handle_read_physical_memory(vm, read_command(command_ptr)?),
+        Command::WritePhysicalMemory => handle_write_physical_memory(vm, read_command(command_ptr)?),
+        Command::TranslateAddress => handle_translate_address(vm, read_command(command_ptr)?),
+        Command::PatternScan => handle_pattern_scan(vm, read_command(command_ptr)?),
+        Command::EnableAddressEptHook | Command::DisableAddressEptHook => handle_address_hook_command(vm, command, read_command(command_ptr)?),
+        Command::QueryHookStatistics => handle_query_hook_statistics(vm, read_command(command_ptr)?),
+        Command::Batch if allow_batch => handle_batch(vm, read_command(command_ptr)?),
+        Command::Batch => {
+            error!("Nested batches are not supported");
+            Err(HypervisorError::InvalidCommand)
         }
         Command::Invalid => {
             error!("Invalid command received");
-            None
+            Err(HypervisorError::InvalidCommand)
         }
     }
 }
 
+/// Reads a complete `ClientCommand<T>` from the guest, authenticates it and returns its payload.
+///
+/// # Arguments
+///
+/// * `command_ptr` - The guest virtual address of the `ClientCommand<T>`.
+///
+/// # Returns
+///
+/// * `Result<T, HypervisorError>` - The payload if the command is well-formed, carries a valid MAC and a fresh nonce.
+fn read_command<T: Pod>(command_ptr: u64) -> Result<T, HypervisorError> {
+    let client_command = read_client_command::<T>(command_ptr)?;
+    SHARED_SESSION.lock().authenticate(&client_command)?;
+    Ok(client_command.payload)
+}
+
+/// Reads a complete `ClientCommand<T>` from the guest without authenticating it.
+///
+/// Only used for `OpenSession`, which is the command that establishes the session key.
+///
+/// # Arguments
+///
+/// * `command_ptr` - The guest virtual address of the `ClientCommand<T>`.
+///
+/// # Returns
+///
+/// * `Result<T, HypervisorError>` - The payload if the command could be read and its size matches `T`.
+fn read_unauthenticated_command<T: Pod>(command_ptr: u64) -> Result<T, HypervisorError> {
+    Ok(read_client_command::<T>(command_ptr)?.payload)
+}
+
+/// Reads a complete `ClientCommand<T>` from the guest.
+///
+/// The header is validated again against the size of `T`, so a client that sent a payload of
+/// the wrong shape for the command is rejected instead of being reinterpreted.
+///
+/// # Arguments
+///
+/// * `command_ptr` - The guest virtual address of the `ClientCommand<T>`.
+///
+/// # Returns
+///
+/// * `Result<ClientCommand<T>, HypervisorError>` - The command if it could be read and its size matches `T`.
+fn read_client_command<T: Pod>(command_ptr: u64) -> Result<ClientCommand<T>, HypervisorError> {
+    let client_command = client_memory().read::<ClientCommand<T>>(command_ptr)?;
+
+    if let Err(status) = client_command.validate() {
+        error!("Command payload does not match its header: {:?} (size: {:#x})", status, client_command.header.size);
+        return Err(HypervisorError::InvalidCommandSize);
+    }
+
+    Ok(client_command)
+}
+
+/// Returns a view of the memory of the client that issued the command.
+///
+/// Client buffers are accessed through the current guest CR3 and checked against the privilege level
+/// of the client, so a user mode client cannot make the hypervisor access kernel memory on its behalf.
+fn client_memory() -> GuestMemory {
+    GuestMemory::with_current_cr3(AccessMode::current())
+}
+
+/// Handles the `OpenSession` command.
+///
+/// This function establishes a session for the calling process and writes the session key to the buffer
+/// provided by the user mode client. It fails while the process that opened the current session is running.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `session` - The `SessionData` containing the buffer that receives the key.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the session was established, or the error that occurred.
+fn handle_open_session(_vm: &mut Vm, session: SessionData) -> Result<(), HypervisorError> {
+    debug!("Opening session");
+
+    let mut shared_session = SHARED_SESSION.lock();
+    let key = shared_session.open()?;
+
+    client_memory().write(session.key_buffer, &key)
+}
+
+/// Handles the `CloseSession` command.
+///
+/// This function releases the session, so that the next `OpenSession` generates a new key. The command is
+/// authenticated, so only the holder of the session key can close it.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `_request` - The `CloseSessionData`, which carries no parameters.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - Always `Ok(())`.
+fn handle_close_session(_vm: &mut Vm, _request: CloseSessionData) -> Result<(), HypervisorError> {
+    SHARED_SESSION.lock().close();
+
+    Ok(())
+}
+
 /// Handles the `OpenProcess` command.
 ///
-/// This function retrieves the guest CR3 (directory table base) of the specified process
-/// and writes it to the buffer provided by the user mode client.
+/// This function looks up the specified process by process ID, image name or `_EPROCESS` address
+/// and writes its process ID and directory table bases to the buffer provided by the user mode client.
 ///
 /// # Arguments
 ///
 /// * `vm` - A mutable reference to the virtual machine (VM) instance.
-/// * `memory` - The `ProcessMemoryOperation` containing details about the process to open.
+/// * `request` - The `OpenProcessData` identifying the process to open.
 ///
 /// # Returns
 ///
-/// * `Option<()>` - Returns `Some(())` if the process was opened successfully, or `None` if an error occurred.
-fn handle_open_process(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
-    debug!("Opening process with ID: {}", memory.process_id?);
+/// * `Result<(), HypervisorError>` - `Ok(())` if the process was opened successfully, `AmbiguousProcessName` if more than
+///   one process matches the image name, or the error that occurred.
+fn handle_open_process(_vm: &mut Vm, request: OpenProcessData) -> Result<(), HypervisorError> {
+    let process = match OpenProcessBy::from_u32(request.by) {
+        Some(OpenProcessBy::ProcessId) => {
+            debug!("Opening process with ID: {}", request.process_id);
+            ProcessInformation::get_active_process_by_process_id(request.process_id).ok_or(HypervisorError::ProcessNotFound)?
+        }
+        Some(OpenProcessBy::ImageName) => {
+            let image_name = String::from_utf16(request.image_name()).map_err(|_| HypervisorError::InvalidCommand)?;
+            debug!("Opening process with image name: {}", image_name);
+            ProcessInformation::get_active_process_by_image_name(&image_name)?
+        }
+        Some(OpenProcessBy::Eprocess) => {
+            debug!("Opening process with _EPROCESS: {:#x}", request.eprocess);
+            ProcessInformation::get_active_process_by_eprocess(request.eprocess).ok_or(HypervisorError::ProcessNotFound)?
+        }
+        None => {
+            error!("Unknown OpenProcess criterion: {}", request.by);
+            return Err(HypervisorError::InvalidCommand);
+        }
+    };
 
-    // Retrieve the guest CR3 for the process ID
-    let target_process_cr3 = ProcessInformation::get_directory_table_base_by_process_id(memory.process_id?)?;
-    debug!("Obtained process CR3: {:#x}", target_process_cr3);
+    debug!("Obtained process CR3: {:#x}", process.directory_table_base);
 
-    // Write the CR3 to the buffer provided by the user mode client
-    PhysicalAddress::write_guest_virt_with_current_cr3(memory.buffer as *mut u64, target_process_cr3)?;
+    let opened_process = OpenedProcess {
+        process_id: process.unique_process_id,
+        eprocess: process.eprocess,
+        directory_table_base: process.directory_table_base,
+        user_directory_table_base: process.user_directory_table_base,
+    };
 
-    Some(())
+    // Write the process to the buffer provided by the user mode client
+    client_memory().write(request.buffer, &opened_process)
 }
 
 /// Handles the `ReadProcessMemory` command.
 ///
-/// This function reads a block of memory from the guest target process identified by the stored CR3
-/// and writes the read data to the buffer provided by the user mode client.
+/// This function copies a block of memory from the guest target process identified by the stored CR3
+/// to the buffer provided by the user mode client, page by page.
 ///
 /// # Arguments
 ///
@@ -112,24 +307,21 @@ fn handle_open_process(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<(
 ///
 /// # Returns
 ///
-/// * `Option<()>` - Returns `Some(())` if the memory was read successfully, or `None` if an error occurred.
-fn handle_read_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
-    debug!("Reading memory from process, address: {:#x} with CR3: {:#x}", memory.address?, memory.guest_cr3?);
+/// * `Result<(), HypervisorError>` - `Ok(())` if the memory was read successfully, `PartialMemoryCopy` if a page was not present part way through, or the error that occurred.
+fn handle_read_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Result<(), HypervisorError> {
+    debug!("Reading memory from process, address: {:#x} with CR3: {:#x}", memory.address, memory.guest_cr3);
 
-    // Read the memory from the specified address in the target process
-    let data =
-        PhysicalAddress::read_guest_virt_slice_with_explicit_cr3(memory.address? as *const u8, memory.buffer_size as usize, memory.guest_cr3?)?;
+    // Copy the memory from the specified address in the target process to the buffer provided by the user mode client
+    let target = GuestMemory::new(memory.guest_cr3, AccessMode::Kernel);
+    let copied = GuestMemory::copy(&target, memory.address, &client_memory(), memory.buffer, memory.buffer_size as usize);
 
-    // Write the read data to the buffer provided by the user mode client
-    PhysicalAddress::write_guest_virt_slice_with_current_cr3(memory.buffer as *mut u8, data)?;
-
-    Some(())
+    report_transferred(&memory, copied)
 }
 
 /// Handles the `WriteProcessMemory` command.
 ///
-/// This function writes a block of memory to the guest target process identified by the stored CR3
-/// using the data provided in the user mode client's buffer.
+/// This function copies the data provided in the user mode client's buffer to the guest target process
+/// identified by the stored CR3, page by page.
 ///
 /// # Arguments
 ///
@@ -138,37 +330,703 @@ fn handle_read_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()
 ///
 /// # Returns
 ///
-/// * `Option<()>` - Returns `Some(())` if the memory was written successfully, or `None` if an error occurred.
-fn handle_write_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Option<()> {
-    debug!("Writing memory to process, address: {:#x} with CR3: {:#x}", memory.address?, memory.guest_cr3?);
+/// * `Result<(), HypervisorError>` - `Ok(())` if the memory was written successfully, `PartialMemoryCopy` if a page was not present part way through, or the error that occurred.
+fn handle_write_memory(_vm: &mut Vm, memory: ProcessMemoryOperation) -> Result<(), HypervisorError> {
+    debug!("Writing memory to process, address: {:#x} with CR3: {:#x}", memory.address, memory.guest_cr3);
+
+    // Copy the data from the buffer provided by the user mode client to the specified address in the target process
+    let target = GuestMemory::new(memory.guest_cr3, AccessMode::Kernel);
+    let copied = GuestMemory::copy(&client_memory(), memory.buffer, &target, memory.address, memory.buffer_size as usize);
+
+    report_transferred(&memory, copied)
+}
+
+/// Reports the number of bytes copied by a process memory operation back to the user mode client.
+///
+/// # Arguments
+///
+/// * `memory` - The `ProcessMemoryOperation` that was performed.
+/// * `copied` - The number of bytes copied before the first page that was not present.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the whole buffer was copied, or `PartialMemoryCopy` otherwise.
+fn report_transferred(memory: &ProcessMemoryOperation, copied: usize) -> Result<(), HypervisorError> {
+    if memory.transferred != 0 {
+        client_memory().write(memory.transferred, &(copied as u64))?;
+    }
+
+    if (copied as u64) < memory.buffer_size {
+        debug!("Copied {:#x} of {:#x} bytes", copied, memory.buffer_size);
+        return Err(HypervisorError::PartialMemoryCopy);
+    }
+
+    Ok(())
+}
+
+/// Handles the `ReadPhysicalMemory` command.
+///
+/// This function reads a block of guest physical memory page by page and writes it to the buffer provided by the
+/// user mode client. Ranges that are not guest RAM or overlap hypervisor memory are refused.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `memory` - The `PhysicalMemoryOperation` containing the guest physical address and the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the memory was read successfully, or the error that occurred.
+fn handle_read_physical_memory(vm: &mut Vm, memory: PhysicalMemoryOperation) -> Result<(), HypervisorError> {
+    debug!("Reading physical memory, address: {:#x}, size: {:#x}", memory.address, memory.buffer_size);
+
+    for_each_physical_page(vm, &memory, |pa, offset, chunk| {
+        // Guest physical memory is identity mapped in the host.
+        let data = unsafe { core::slice::from_raw_parts(pa as *const u8, chunk) };
+        client_memory().write_from(memory.buffer + offset as u64, data)
+    })
+}
+
+/// Handles the `WritePhysicalMemory` command.
+///
+/// This function writes the data from the buffer provided by the user mode client to guest physical memory page by page.
+/// Ranges that are not guest RAM or overlap hypervisor memory are refused.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `memory` - The `PhysicalMemoryOperation` containing the guest physical address and the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the memory was written successfully, or the error that occurred.
+fn handle_write_physical_memory(vm: &mut Vm, memory: PhysicalMemoryOperation) -> Result<(), HypervisorError> {
+    debug!("Writing physical memory, address: {:#x}, size: {:#x}", memory.address, memory.buffer_size);
+
+    for_each_physical_page(vm, &memory, |pa, offset, chunk| {
+        // Guest physical memory is identity mapped in the host.
+        let data = unsafe { core::slice::from_raw_parts_mut(pa as *mut u8, chunk) };
+        client_memory().read_into(memory.buffer + offset as u64, data)
+    })
+}
+
+/// Splits a guest physical memory operation at page boundaries, checks each part and calls `f` with it.
+///
+/// Every page is checked before any of them is passed to `f`, so a refused range is not partially transferred.
+///
+/// # Arguments
+///
+/// * `vm` - A reference to the virtual machine (VM) instance.
+/// * `memory` - The `PhysicalMemoryOperation` to split.
+/// * `f` - Called with the physical address, the offset into the range and the length of each part.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if every part was transferred, `TransferTooLarge` if the range exceeds
+///   `MAX_PHYSICAL_MEMORY_SIZE`, the error of the first page that failed its check, or the first error returned by `f`.
+fn for_each_physical_page(
+    vm: &Vm,
+    memory: &PhysicalMemoryOperation,
+    mut f: impl FnMut(u64, usize, usize) -> Result<(), HypervisorError>,
+) -> Result<(), HypervisorError> {
+    if memory.buffer_size > MAX_PHYSICAL_MEMORY_SIZE {
+        error!("Physical memory operation of {:#x} bytes exceeds the maximum of {:#x}", memory.buffer_size, MAX_PHYSICAL_MEMORY_SIZE);
+        return Err(HypervisorError::TransferTooLarge);
+    }
+
+    let Some(end) = memory.address.checked_add(memory.buffer_size) else {
+        return Err(HypervisorError::NotGuestRam);
+    };
+
+    // The parts of the range, each as its physical address, offset into the range and length.
+    let pages = || {
+        let mut pa = memory.address;
+
+        core::iter::from_fn(move || {
+            (pa < end).then(|| {
+                let chunk = (end - pa).min(BASE_PAGE_SIZE as u64 - pa % BASE_PAGE_SIZE as u64);
+                let page = (pa, (pa - memory.address) as usize, chunk as usize);
+                pa += chunk;
+                page
+            })
+        })
+    };
+
+    let mut mtrr = Mtrr::new();
+
+    for (pa, _, chunk) in pages() {
+        check_physical_page(vm, &mut mtrr, pa, chunk as u64)?;
+    }
+
+    pages().try_for_each(|(pa, offset, chunk)| f(pa, offset, chunk))
+}
+
+/// Ensures a page of a guest physical memory operation is guest RAM that does not belong to the hypervisor.
+///
+/// The page must be identity mapped in the host and write-back memory according to the MTRRs, which excludes
+/// memory-mapped devices. It is then checked against the memory tracked by the hook manager (hypervisor image,
+/// heap, stacks, shadow pages and page tables) and against the EPT of the current processor.
+///
+/// # Arguments
+///
+/// * `vm` - A reference to the virtual machine (VM) instance.
+/// * `mtrr` - The MTRR memory ranges.
+/// * `pa` - The physical address of the part of the page to access.
+/// * `size` - The number of bytes to access, not crossing into the next page.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the page is guest RAM, `NotGuestRam` if it is not, or
+///   `HypervisorMemoryAccessDenied` if it belongs to the hypervisor.
+fn check_physical_page(vm: &Vm, mtrr: &mut Mtrr, pa: u64, size: u64) -> Result<(), HypervisorError> {
+    if pa + size > IDENTITY_MAP_SIZE || mtrr.find(pa..pa + size) != Some(MemoryType::WriteBack) {
+        error!("Refusing physical memory access to {:#x} (size: {:#x}), not guest RAM", pa, size);
+        return Err(HypervisorError::NotGuestRam);
+    }
+
+    let ept_start = &vm.primary_ept as *const Ept as u64;
+    let ept_end = ept_start + size_of::<Ept>() as u64;
+    let overlaps_ept = pa < ept_end && ept_start < pa + size;
+
+    if overlaps_ept || SHARED_HOOK_MANAGER.lock().overlaps_hypervisor_memory(pa, size) {
+        error!("Refusing physical memory access to hypervisor memory at {:#x} (size: {:#x})", pa, size);
+        return Err(HypervisorError::HypervisorMemoryAccessDenied);
+    }
+
+    Ok(())
+}
+
+/// Handles the `TranslateAddress` command.
+///
+/// This function walks the guest page tables for the requested virtual address, then the EPT in use on this
+/// processor for the resulting guest physical address, and reports every entry visited to the client buffer.
+/// An address that is not mapped is not an error: the walks simply stop at the first non-present entry.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `request` - The `TranslationData` containing the CR3, the virtual address and the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the translation was written successfully, or the error that occurred.
+fn handle_translate_address(_vm: &mut Vm, request: TranslationData) -> Result<(), HypervisorError> {
+    let guest_cr3 = if request.guest_cr3 == 0 {
+        vmread(vmcs::guest::CR3)
+    } else {
+        request.guest_cr3
+    };
+    debug!("Translating address: {:#x} with CR3: {:#x}", request.guest_va, guest_cr3);
+
+    let mut translation = AddressTranslation {
+        guest_cr3,
+        guest_va: request.guest_va,
+        paging_entries: [0; 4],
+        paging_levels: 0,
+        page_permissions: 0,
+        page_size: 0,
+        guest_pa: 0,
+        ept_entries: [0; 4],
+        ept_levels: 0,
+        ept_permissions: 0,
+        ept_page_size: 0,
+        host_pa: 0,
+    };
+
+    let walk = unsafe { PageTables::walk_guest_virtual_to_guest_physical(guest_cr3, request.guest_va) };
+    translation.paging_entries = walk.entries;
+    translation.paging_levels = walk.levels as u32;
+
+    if let Some(guest_pa) = walk.address {
+        let access_rights = PageTables::effective_access_rights(&walk);
+        translation.page_permissions = (access_rights.writable as u32 * PAGE_WRITABLE)
+            | (access_rights.user as u32 * PAGE_USER)
+            | (access_rights.executable as u32 * PAGE_EXECUTABLE);
+        translation.page_size = walk.page_size;
+        translation.guest_pa = guest_pa;
+
+        // Walk the EPT that is currently in use, as guest memory accesses would.
+        let (pml4_address, _, _) = Ept::decode_eptp(vmread(vmcs::control::EPTP_FULL))?;
+        let ept_walk = unsafe { Ept::walk_guest_pa_to_host_pa(pml4_address, guest_pa) };
+        translation.ept_entries = ept_walk.entries;
+        translation.ept_levels = ept_walk.levels as u32;
+
+        if let Some(host_pa) = ept_walk.address {
+            // Bits 2:0 of an EPT entry are its read, write and execute permissions.
+            translation.ept_permissions = AccessType::from_bits_truncate(ept_walk.entries[ept_walk.levels - 1] as u8).bits() as u32;
+            translation.ept_page_size = ept_walk.page_size;
+            translation.host_pa = host_pa;
+        }
+    }
+
+    client_memory().write(request.buffer, &translation)
+}
+
+/// Handles the `PatternScan` command.
+///
+/// This function scans a guest virtual address range for a pattern page by page, skipping pages that
+/// are not present, and writes the offset of every match to the buffer provided by the user mode client.
+/// The scan stops after `MAX_PATTERN_SCAN_SIZE` bytes or when the buffer is full, and the address to
+/// resume it at is written to the cursor provided by the client.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `scan` - The `PatternScanData` containing the CR3, the range, the pattern and the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the matches were written successfully, or the error that occurred.
+fn handle_pattern_scan(_vm: &mut Vm, scan: PatternScanData) -> Result<(), HypervisorError> {
+    let guest_cr3 = if scan.guest_cr3 == 0 { vmread(vmcs::guest::CR3) } else { scan.guest_cr3 };
+    debug!("Scanning {:#x} bytes at {:#x} with CR3: {:#x}", scan.size, scan.start, guest_cr3);
+
+    if !scan.pattern.is_valid() {
+        return Err(HypervisorError::InvalidPattern);
+    }
+
+    // Every match found would be left for the next command, which would find it again.
+    if !scan.has_list_capacity() {
+        error!("Pattern scan without room for a match");
+        return Err(HypervisorError::InvalidCommand);
+    }
+
+    if scan.start.checked_add(scan.size).is_none() {
+        return Err(HypervisorError::ScanRangeTooLarge);
+    }
+
+    let memory = GuestMemory::new(guest_cr3, AccessMode::Kernel);
+    let mut matches = memory.scan(scan.start, scan.size, MAX_PATTERN_SCAN_SIZE, &scan.pattern);
+
+    let mut written = 0u64;
+    let mut cursor = None;
+
+    for offset in matches.by_ref() {
+        // The match is reported again when the client resumes the scan at it.
+        if written == scan.list.capacity {
+            cursor = Some(scan.start + offset);
+            break;
+        }
+
+        client_memory().write(scan.list.buffer.wrapping_add(written * size_of::<u64>() as u64), &offset)?;
+        written += 1;
+    }
+
+    let cursor = cursor.unwrap_or_else(|| matches.cursor());
+    trace!("Wrote {} matches, resuming at {:#x}", written, cursor);
+
+    client_memory().write(scan.list.count, &written)?;
+    client_memory().write(scan.cursor, &cursor)
+}
+
+/// Handles the `Batch` command.
+///
+/// This function executes every command of the batch in order within the current VM exit and writes
+/// the outcome of each one to the corresponding result slot provided by the user mode client.
+/// A failing entry does not stop the batch.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `batch` - The `BatchData` describing the command and result arrays.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if every entry was processed and its result written, or the error that occurred.
+fn handle_batch(vm: &mut Vm, batch: BatchData) -> Result<(), HypervisorError> {
+    debug!("Handling batch of {} commands", batch.count);
+
+    if batch.count > MAX_BATCH_SIZE {
+        error!("Batch of {} commands exceeds the maximum of {}", batch.count, MAX_BATCH_SIZE);
+        return Err(HypervisorError::BatchTooLarge);
+    }
+
+    for index in 0..batch.count {
+        let entry_ptr = client_memory().read::<u64>(batch.commands.wrapping_add(index * size_of::<u64>() as u64))?;
+
+        let result = command_result(dispatch_command(vm, entry_ptr, false));
+
+        client_memory().write(batch.results.wrapping_add(index * size_of::<CommandResult>() as u64), &result)?;
+    }
+
+    Ok(())
+}
+
+/// Handles the `EnumerateHooks` command.
+///
+/// This function serializes every hook known to the memory manager, together with its shadow page
+/// and the current EPT permissions of the hooked page on this processor, into the client buffer.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `list` - The `ListData` describing the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the hooks were written successfully, or the error that occurred.
+fn handle_enumerate_hooks(vm: &mut Vm, list: ListData) -> Result<(), HypervisorError> {
+    debug!("Enumerating hooks");
+
+    let hook_manager = SHARED_HOOK_MANAGER.lock();
+    let memory_manager = &hook_manager.memory_manager;
+
+    let entries = memory_manager.hook_mappings().flat_map(|(guest_page_pa, mapping)| {
+        let shadow_page_pa = &*mapping.shadow_page as *const Page as u64;
+
+        let ept_permissions = memory_manager
+            .get_page_table(PAddr::from(guest_page_pa).align_down_to_large_page().as_u64(), &vm.primary_ept)
+            .map_or(0, |pt| vm.primary_ept.get_page_permissions(guest_page_pa, pt).bits() as u64);
+
+        mapping.hooks.iter().map(move |hook_info| HookEntry {
+            guest_function_va: hook_info.guest_function_va,
+            guest_function_pa: hook_info.guest_function_pa,
+            guest_page_pa,
+            shadow_page_pa,
+            function_hash: hook_info.function_hash,
+            hook_kind: HookKind::from(hook_info.ept_hook_type) as u32,
+            ept_permissions,
+            session_id: hook_info.session_id.map_or(NO_SESSION, u64::from),
+        })
+    });
+
+    write_list(list, entries)
+}
+
+/// Handles the `QueryHookStatistics` command.
+///
+/// This function serializes the hit counters of every hook known to the memory manager, and the context
+/// of its last hit on any processor, into the client buffer.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `list` - The `ListData` describing the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the statistics were written successfully, or the error that occurred.
+fn handle_query_hook_statistics(_vm: &mut Vm, list: ListData) -> Result<(), HypervisorError> {
+    debug!("Querying hook statistics");
+
+    let hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    let entries = hook_manager.memory_manager.hook_mappings().flat_map(|(_, mapping)| {
+        mapping.hooks.iter().map(|hook_info| {
+            let counters = &hook_info.counters;
+
+            HookStatistics {
+                guest_function_va: hook_info.guest_function_va,
+                guest_function_pa: hook_info.guest_function_pa,
+                function_hash: hook_info.function_hash,
+                last_vcpu_id: counters.last_vcpu_id,
+                hit_count: counters.hit_count,
+                last_hit_tsc: counters.last_hit_tsc,
+                last_caller_rip: counters.last_caller_rip,
+                last_process_id: counters.last_process_id,
+            }
+        })
+    });
+
+    write_list(list, entries)
+}
+
+/// Handles the `EnumerateProcesses` command.
+///
+/// This function walks `_EPROCESS.ActiveProcessLinks` and serializes every process into the client
+/// buffer, independently of any user mode API the guest could tamper with.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `list` - The `ListData` describing the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the processes were written successfully, or the error that occurred.
+fn handle_enumerate_processes(_vm: &mut Vm, list: ListData) -> Result<(), HypervisorError> {
+    debug!("Enumerating processes");
+
+    let processes = ProcessInformation::enumerate_active_processes().ok_or(HypervisorError::GuestMemoryAccessFailed)?;
+
+    let entries = processes.into_iter().map(|process| ProcessEntry {
+        eprocess: process.eprocess,
+        process_id: process.unique_process_id,
+        parent_process_id: process.parent_process_id,
+        directory_table_base: process.directory_table_base,
+        user_directory_table_base: process.user_directory_table_base,
+        image_file_name: process.image_file_name,
+    });
+
+    write_list(list, entries)
+}
+
+/// Handles the `EnumerateModules` command.
+///
+/// This function walks the `PEB` loader data of the specified process under its own directory table base,
+/// including the 32-bit `PEB` of WOW64 processes, and serializes every module into the client buffer.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `request` - The `ModuleListData` containing the process ID and the client buffer.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the modules were written successfully, or the error that occurred.
+fn handle_enumerate_modules(_vm: &mut Vm, request: ModuleListData) -> Result<(), HypervisorError> {
+    debug!("Enumerating modules of process with ID: {}", request.process_id);
 
-    // Write the data from the buffer provided by the user mode client to the specified address in the target process
-    let data = PhysicalAddress::read_guest_virt_slice_with_current_cr3(memory.buffer as *const u8, memory.buffer_size as usize)?;
-    PhysicalAddress::write_guest_virt_slice_with_explicit_cr3(memory.address? as *mut u8, data, memory.guest_cr3?)?;
+    let modules = ProcessInformation::get_process_modules(request.process_id)?;
 
-    Some(())
+    let entries = modules.into_iter().map(|module| {
+        let mut name = [0u16; MODULE_NAME_LENGTH];
+        let name_length = module.name.len().min(MODULE_NAME_LENGTH);
+        name[..name_length].copy_from_slice(&module.name[..name_length]);
+
+        ModuleEntry {
+            base: module.base,
+            size: module.size,
+            flags: if module.wow64 { MODULE_FLAG_WOW64 } else { 0 },
+            name_length: name_length as u32,
+            name,
+        }
+    });
+
+    write_list(request.list, entries)
+}
+
+/// Handles the `GetHypervisorInfo` command.
+///
+/// This function reports the compile-time features of the hypervisor, the number of virtualized
+/// processors, the heap usage and the VMX capabilities detected at startup to the client buffer.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `info` - The `InfoData` containing the buffer that receives the `HypervisorInfo`.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the information was written successfully, or the error that occurred.
+fn handle_get_hypervisor_info(_vm: &mut Vm, info: InfoData) -> Result<(), HypervisorError> {
+    debug!("Getting hypervisor information");
+
+    let mut features = 0;
+    if cfg!(feature = "vmware") {
+        features |= FEATURE_VMWARE;
+    }
+    if cfg!(feature = "hide_hv_with_ept") {
+        features |= FEATURE_HIDE_HV_WITH_EPT;
+    }
+
+    let capabilities = VMX_CAPABILITIES.get();
+
+    let hypervisor_info = HypervisorInfo {
+        info_version: HYPERVISOR_INFO_VERSION,
+        protocol_version: PROTOCOL_VERSION,
+        features,
+        virtualized_processors: VIRTUALIZED_PROCESSORS.load(Ordering::SeqCst),
+        heap_size: TOTAL_HEAP_SIZE as u64,
+        heap_free: unsafe { (*core::ptr::addr_of!(HEAP)).free_space() } as u64,
+        vmx_basic: capabilities.map_or(0, |c| c.vmx_basic),
+        vmx_procbased_ctls2: capabilities.map_or(0, |c| c.procbased_ctls2),
+        vmx_ept_vpid_cap: capabilities.map_or(0, |c| c.ept_vpid_cap),
+    };
+
+    client_memory().write(info.buffer, &hypervisor_info)
+}
+
+/// Writes entries to a client buffer described by `ListData`.
+///
+/// At most `list.capacity` entries are written, but all of them are counted, and the total is written
+/// to `list.count` so the client can retry with a larger buffer.
+///
+/// # Arguments
+///
+/// * `list` - The `ListData` describing the client buffer.
+/// * `entries` - The entries to write.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the entries and the count were written successfully.
+fn write_list<T: Pod>(list: ListData, entries: impl Iterator<Item = T>) -> Result<(), HypervisorError> {
+    let mut total = 0u64;
+
+    for entry in entries {
+        if total < list.capacity {
+            client_memory().write(list.buffer.wrapping_add(total * size_of::<T>() as u64), &entry)?;
+        }
+        total += 1;
+    }
+
+    trace!("Wrote {} of {} entries", total.min(list.capacity), total);
+
+    client_memory().write(list.count, &total)
 }
 
 /// Handles commands related to enabling or disabling kernel EPT hooks.
 ///
 /// This function manages the setup or removal of kernel EPT hooks based on the provided command.
-/// It enables or disables the hooks for specific functions based on the function hash and syscall number provided.
+/// It enables or disables the hooks for specific functions based on the module hash, function hash and syscall number provided.
+/// Win32k syscalls are hooked in the session space of the requested session, translated through one of its GUI processes.
 ///
 /// # Arguments
 ///
 /// * `vm` - A mutable reference to the virtual machine (VM) instance.
 /// * `command` - The command indicating whether to enable or disable the hook.
-/// * `hook` - The `HookData` containing details about the hook, including the function hash and syscall number.
+/// * `hook` - The `HookData` containing details about the hook, including the module hash, function hash, syscall number and session.
 ///
 /// # Returns
 ///
-/// * `Option<()>` - Returns `Some(())` if the hook command was handled successfully, or `None` if an error occurred.
-fn handle_hook_command(vm: &mut Vm, command: Command, hook: HookData) -> Option<()> {
+/// * `Result<(), HypervisorError>` - `Ok(())` if the hook command was handled successfully, or the error that occurred.
+fn handle_hook_command(vm: &mut Vm, command: Command, hook: HookData) -> Result<(), HypervisorError> {
     let enable = command == Command::EnableKernelEptHook;
+
+    // Resolve the session before locking the hook manager, which the process walk locks as well.
+    let session = if hook.syscall_number >= WIN32K_SYSCALL_BASE {
+        Some(HookSession {
+            session_id: hook.session_id,
+            directory_table_base: ProcessInformation::get_session_directory_table_base(hook.session_id)?,
+        })
+    } else {
+        None
+    };
+
+    let result = SHARED_HOOK_MANAGER
+        .lock()
+        .manage_kernel_ept_hook(vm, hook, session, EptHookType::Function(InlineHookType::Vmcall), enable);
+
+    // The hook is only reported as done once every processor sees it, the lock must be released for that.
+    result.and(wait_for_pending_flush(vm))
+}
+
+/// Handles commands related to enabling or disabling EPT hooks at arbitrary guest addresses.
+///
+/// Unlike `handle_hook_command`, the target is not limited to ntoskrnl exports: the address is translated
+/// through the requested CR3, so any kernel or user mode code can be hooked. A function hook is only installed
+/// if it lies within a single present, executable page, while a page hook watches the accesses to the present
+/// page containing the address. Disabling translates the same CR3 and address again and removes the hook
+/// installed at the resulting guest physical address.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+/// * `command` - The command indicating whether to enable or disable the hook.
+/// * `hook` - The `AddressHookData` containing the CR3, the address, the hook kind and the identifier of the hook.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the hook command was handled successfully, or the error that occurred.
+fn handle_address_hook_command(vm: &mut Vm, command: Command, hook: AddressHookData) -> Result<(), HypervisorError> {
+    let enable = command == Command::EnableAddressEptHook;
+    let guest_cr3 = if hook.guest_cr3 == 0 { vmread(vmcs::guest::CR3) } else { hook.guest_cr3 };
+
+    let ept_hook_type = match HookKind::from_u32(hook.hook_kind) {
+        Some(HookKind::Vmcall) => EptHookType::Function(InlineHookType::Vmcall),
+        Some(HookKind::Int3) => EptHookType::Function(InlineHookType::Int3),
+        Some(HookKind::Cpuid) => EptHookType::Function(InlineHookType::Cpuid),
+        Some(HookKind::Page) if !enable => EptHookType::Page(AccessType::empty()),
+        Some(HookKind::Page) => EptHookType::Page(watched_access(hook.watch_access)?),
+        None => {
+            error!("Invalid hook kind: {}", hook.hook_kind);
+            return Err(HypervisorError::InvalidCommand);
+        }
+    };
+
+    debug!("{} EPT hook at address: {:#x} with CR3: {:#x}", if enable { "Enabling" } else { "Disabling" }, hook.guest_va, guest_cr3);
+
+    // The int3 of the hook must not be reachable before every processor intercepts breakpoints.
+    let is_breakpoint_hook = enable && matches!(ept_hook_type, EptHookType::Function(InlineHookType::Int3));
+    if is_breakpoint_hook {
+        begin_breakpoint_hook(vm)?;
+    }
+
     let mut hook_manager = SHARED_HOOK_MANAGER.lock();
 
-    hook_manager
-        .manage_kernel_ept_hook(vm, hook.function_hash, hook.syscall_number, EptHookType::Function(InlineHookType::Vmcall), enable)
-        .ok()?;
-    Some(())
+    let result = if enable {
+        validate_hook_address(&hook_manager, guest_cr3, hook.guest_va, ept_hook_type)
+            .and_then(|()| hook_manager.ept_hook_function(vm, guest_cr3, hook.guest_va, hook.function_hash, None, ept_hook_type))
+    } else {
+        hook_manager.ept_unhook_function(vm, guest_cr3, hook.guest_va, ept_hook_type)
+    };
+
+    drop(hook_manager);
+
+    // The hook is only reported as done once every processor sees it.
+    let flushed = wait_for_pending_flush(vm);
+
+    if is_breakpoint_hook {
+        end_breakpoint_hook();
+    }
+
+    result.and(flushed)
+}
+
+/// Checks that a hook can be installed at a guest virtual address.
+///
+/// # Arguments
+///
+/// * `hook_manager` - The hook manager, used to refuse hooks on hypervisor memory.
+/// * `guest_cr3` - The CR3 to translate the address with.
+/// * `guest_va` - The guest virtual address of the hook.
+/// * `ept_hook_type` - The type of the hook. Only function hooks need an executable page.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the page is present, guest memory, executable for a function hook, and the hook
+///   does not cross into the next page, otherwise the error describing the first check that failed.
+fn validate_hook_address(hook_manager: &HookManager, guest_cr3: u64, guest_va: u64, ept_hook_type: EptHookType) -> Result<(), HypervisorError> {
+    let hook_size = HookManager::hook_size(ept_hook_type);
+    let walk = unsafe { PageTables::walk_guest_virtual_to_guest_physical(guest_cr3, guest_va) };
+
+    let Some(guest_pa) = walk.address else {
+        error!("Hook address {:#x} is not mapped with CR3: {:#x}", guest_va, guest_cr3);
+        return Err(walk.error());
+    };
+
+    if hook_manager.overlaps_hypervisor_memory(PAddr::from(guest_pa).align_down_to_base_page().as_u64(), BASE_PAGE_SIZE as u64) {
+        error!("Hook address {:#x} maps hypervisor memory at {:#x}", guest_va, guest_pa);
+        return Err(HypervisorError::HypervisorMemoryAccessDenied);
+    }
+
+    if matches!(ept_hook_type, EptHookType::Function(_)) && !PageTables::effective_access_rights(&walk).executable {
+        error!("Hook address {:#x} is not executable", guest_va);
+        return Err(HypervisorError::AddressNotExecutable);
+    }
+
+    if (guest_va as usize % BASE_PAGE_SIZE) + hook_size > BASE_PAGE_SIZE {
+        error!("Hook of {} bytes at {:#x} crosses a page boundary", hook_size, guest_va);
+        return Err(HypervisorError::HookCrossesPageBoundary);
+    }
+
+    Ok(())
+}
+
+/// Converts the `WATCH_*` bits of an `AddressHookData` to the accesses watched by a page hook.
+///
+/// # Arguments
+///
+/// * `watch_access` - The `WATCH_*` bits requested by the client.
+///
+/// # Returns
+///
+/// * `Result<AccessType, HypervisorError>` - The watched accesses, or `InvalidCommand` if no access or an unknown access is requested.
+fn watched_access(watch_access: u64) -> Result<AccessType, HypervisorError> {
+    if watch_access == 0 || watch_access & !(WATCH_READ | WATCH_WRITE) != 0 {
+        error!("Invalid watched access for page hook: {:#x}", watch_access);
+        return Err(HypervisorError::InvalidCommand);
+    }
+
+    let mut access_type = AccessType::empty();
+
+    if watch_access & WATCH_READ != 0 {
+        access_type |= AccessType::READ;
+    }
+
+    if watch_access & WATCH_WRITE != 0 {
+        access_type |= AccessType::WRITE;
+    }
+
+    Ok(access_type)
 }
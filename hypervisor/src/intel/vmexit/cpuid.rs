@@ -5,13 +5,14 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
+            hooks::inline::InlineHookType,
             vm::Vm,
-            vmexit::{commands::handle_guest_commands, ExitType},
+            vmexit::{commands::handle_guest_commands, inline_hook::handle_inline_hook, ExitType},
         },
     },
     bitfield::BitMut,
     log::*,
-    shared::CommandStatus,
+    shared::HYPERCALL_LEAF,
     x86::cpuid::cpuid,
 };
 
@@ -70,9 +71,6 @@ enum FeatureBits {
     HypervisorPresentBit = 31,
 }
 
-/// The password used for authentication with the hypervisor.
-const PASSWORD: u64 = 0xDEADBEEF;
-
 /// Handles the `CPUID` VM-exit.
 ///
 /// This function is invoked when the guest executes the `CPUID` instruction.
@@ -94,17 +92,22 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
 
     // const HYPERV_CPUID_LEAF_RANGE: RangeInclusive<u32> = 0x40000000..=0x4FFFFFFF;
 
+    // A CPUID written over a hooked function by a cpuid inline hook is recognized by its RIP.
+    if let Some(exit_type) = handle_inline_hook(vm, InlineHookType::Cpuid)? {
+        return Ok(exit_type);
+    }
+
     let leaf = vm.guest_registers.rax as u32;
     let sub_leaf = vm.guest_registers.rcx as u32;
 
-    if vm.guest_registers.rax == PASSWORD {
-        // Handle the guest command and update the CPUID result accordingly
-        vm.guest_registers.rax = match handle_guest_commands(vm) {
-            Some(_) => CommandStatus::Success.to_u64(), // Command handled successfully
-            None => CommandStatus::Failure.to_u64(),    // Command handling failed
-        };
+    if vm.guest_registers.rax == HYPERCALL_LEAF {
+        // Handle the guest command and report the status in RAX, the error code in RBX and the protocol version in RDX.
+        let result = handle_guest_commands(vm);
+        vm.guest_registers.rax = result.status;
+        vm.guest_registers.rbx = result.error_code;
+        vm.guest_registers.rdx = result.protocol_version;
 
-        trace!("Command executed successfully with leaf {:#x}", leaf);
+        trace!("Command executed with status {:#x} and leaf {:#x}", result.status, leaf);
     } else {
         // Execute CPUID instruction on the host and retrieve the result
         let mut cpuid_result = cpuid!(leaf, sub_leaf);
@@ -134,29 +137,49 @@ pub fn handle_cpuid(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
                     // Test UEFI boot-time hooks
                     hook_manager.manage_kernel_ept_hook(
                         vm,
-                        crate::windows::nt::pe::djb2_hash("NtQuerySystemInformation".as_bytes()),
-                        0x0036,
+                        shared::HookData {
+                            function_hash: crate::windows::nt::pe::djb2_hash("NtQuerySystemInformation".as_bytes()),
+                            syscall_number: 0x0036,
+                            module_hash: 0,
+                            session_id: 0,
+                        },
+                        None,
                         crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
                         true,
                     )?;
                     hook_manager.manage_kernel_ept_hook(
                         vm,
-                        crate::windows::nt::pe::djb2_hash("NtCreateFile".as_bytes()),
-                        0x0055,
+                        shared::HookData {
+                            function_hash: crate::windows::nt::pe::djb2_hash("NtCreateFile".as_bytes()),
+                            syscall_number: 0x0055,
+                            module_hash: 0,
+                            session_id: 0,
+                        },
+                        None,
                         crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
                         true,
                     )?;
                     hook_manager.manage_kernel_ept_hook(
                         vm,
-                        crate::windows::nt::pe::djb2_hash("NtAllocateVirtualMemory".as_bytes()),
-                        0x18,
+                        shared::HookData {
+                            function_hash: crate::windows::nt::pe::djb2_hash("NtAllocateVirtualMemory".as_bytes()),
+                            syscall_number: 0x18,
+                            module_hash: 0,
+                            session_id: 0,
+                        },
+                        None,
                         crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
                         true,
                     )?;
                     hook_manager.manage_kernel_ept_hook(
                         vm,
-                        crate::windows::nt::pe::djb2_hash("NtQueryInformationProcess".as_bytes()),
-                        0x19,
+                        shared::HookData {
+                            function_hash: crate::windows::nt::pe::djb2_hash("NtQueryInformationProcess".as_bytes()),
+                            syscall_number: 0x19,
+                            module_hash: 0,
+                            session_id: 0,
+                        },
+                        None,
                         crate::intel::hooks::hook_manager::EptHookType::Function(crate::intel::hooks::inline::InlineHookType::Vmcall),
                         true,
                     )?;
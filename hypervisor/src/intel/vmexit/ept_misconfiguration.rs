@@ -36,7 +36,7 @@ pub fn handle_ept_misconfiguration(vm: &mut Vm) -> Result<ExitType, HypervisorEr
 
     let pre_alloc_pt = hook_manager
         .memory_manager
-        .get_page_table_as_mut(guest_physical_address.align_down_to_large_page().as_u64())
+        .get_page_table_as_mut(guest_physical_address.align_down_to_large_page().as_u64(), &vm.primary_ept)
         .ok_or(HypervisorError::PageTableNotFound)?;
 
     dump_primary_ept_entries(vm, guest_physical_address.as_u64(), pre_alloc_pt)?;
@@ -4,14 +4,16 @@ use {
         intel::{
             ept::AccessType,
             hooks::hook_manager::SHARED_HOOK_MANAGER,
-            support::vmread,
+            support::{apic_id, rdtsc, vmread},
             vm::Vm,
             vmerror::EptViolationExitQualification,
             vmexit::{
-                mtf::{set_monitor_trap_flag, update_guest_interrupt_flag},
+                inline_hook::handle_return_trap,
+                mtf::{begin_single_step, MtfRestorePage},
                 ExitType,
             },
         },
+        windows::eprocess::ProcessInformation,
     },
     log::*,
     x86::{bits64::paging::PAddr, vmx::vmcs},
@@ -22,7 +24,8 @@ use {
 ///
 /// This function addresses the EPT violation by either swapping the page to a shadow page
 /// or restoring the original page based on the exit qualification. It also sets up the monitor trap flag
-/// if necessary.
+/// if necessary. Accesses to a page watched by a page hook are logged and single-stepped with the page accessible,
+/// and instruction fetches from a page a hooked call returns to are dispatched to `handle_return_trap`.
 ///
 /// # Arguments
 ///
@@ -46,6 +49,49 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     // Lock the shared hook manager
     let mut hook_manager = SHARED_HOOK_MANAGER.lock();
 
+    if let Some((hook_info, watched_access)) = hook_manager.memory_manager.get_page_watch(guest_page_pa.as_u64()) {
+        let exit_qualification = EptViolationExitQualification::from_exit_qualification(vmread(vmcs::ro::EXIT_QUALIFICATION));
+
+        // Watching reads also traps writes, only the watched accesses are reported.
+        if (exit_qualification.data_read && watched_access.contains(AccessType::READ))
+            || (exit_qualification.data_write && watched_access.contains(AccessType::WRITE))
+        {
+            let guest_function_pa = hook_info.guest_function_pa;
+            log_page_watch_access(vm, guest_pa, hook_info.function_hash, &exit_qualification);
+
+            let process_id = ProcessInformation::get_current_process_id().unwrap_or(0);
+            if let Some(hook_info) = hook_manager
+                .memory_manager
+                .get_hook_info_by_function_pa_mut(guest_page_pa.as_u64(), guest_function_pa)
+            {
+                hook_info.counters.record_hit(rdtsc(), vm.guest_registers.rip, process_id, apic_id());
+            }
+        }
+
+        // Allow the access while the faulting instruction is single-stepped, the watch is restored by handle_monitor_trap_flag.
+        // The instruction may itself be single-stepped for a hook, in which case the watch is restored along with the hook.
+        begin_single_step(vm, 1, MtfRestorePage::Watch(guest_page_pa.as_u64()))?;
+
+        let pre_alloc_pt = hook_manager
+            .memory_manager
+            .get_page_table_as_mut(guest_large_page_pa.as_u64(), &vm.primary_ept)
+            .ok_or(HypervisorError::PageTableNotFound)?;
+
+        vm.primary_ept
+            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+
+        // Do not increment RIP, since we want it to execute the same instruction again.
+        return Ok(ExitType::Continue);
+    }
+
+    // An instruction fetch from the page a hooked call in progress returns to, which may be that return.
+    if hook_manager.is_return_trap(guest_page_pa.as_u64()) {
+        handle_return_trap(vm, &mut hook_manager, guest_page_pa.as_u64())?;
+
+        // Do not increment RIP, since we want it to execute the same instruction again.
+        return Ok(ExitType::Continue);
+    }
+
     let shadow_page_pa = PAddr::from(
         hook_manager
             .memory_manager
@@ -56,7 +102,7 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
 
     let pre_alloc_pt = hook_manager
         .memory_manager
-        .get_page_table_as_mut(guest_large_page_pa.as_u64())
+        .get_page_table_as_mut(guest_large_page_pa.as_u64(), &vm.primary_ept)
         .ok_or(HypervisorError::PageTableNotFound)?;
 
     // dump_primary_ept_entries(vm, guest_pa, pre_alloc_pt)?;
@@ -81,19 +127,12 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
         //   Page Permissions: R:false, W:false, X:true (non-readable, non-writable, but executable).
         trace!("Read/Write attempt on execute-only page, restoring original page.");
         trace!("Page Permissions: R:false, W:false, X:true (non-readable, non-writable, but executable).");
-        vm.primary_ept
-            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
-
         // We make this read-write-execute to allow the instruction performing a read-write
         // operation and then switch back to execute-only shadow page from handle_mtf vmexit
-        vm.mtf_counter = Some(1);
-
-        // Set the monitor trap flag and initialize counter to the number of overwritten instructions
-        set_monitor_trap_flag(true);
+        begin_single_step(vm, 1, MtfRestorePage::Hook(guest_page_pa.as_u64()))?;
 
-        // Ensure all data mutations to vm are done before calling this.
-        // This function will update the guest interrupt flag to prevent interrupts while single-stepping
-        update_guest_interrupt_flag(vm, false)?;
+        vm.primary_ept
+            .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
     }
 
     trace!("EPT Violation handled successfully!");
@@ -101,3 +140,33 @@ pub fn handle_ept_violation(vm: &mut Vm) -> Result<ExitType, HypervisorError> {
     // Do not increment RIP, since we want it to execute the same instruction again.
     Ok(ExitType::Continue)
 }
+
+/// Logs an access to a page watched by a page hook.
+///
+/// # Arguments
+///
+/// * `vm` - A reference to the virtual machine (VM) instance.
+/// * `guest_pa` - The faulting guest physical address.
+/// * `function_hash` - The identifier of the page hook.
+/// * `exit_qualification` - The exit qualification of the EPT violation.
+fn log_page_watch_access(vm: &Vm, guest_pa: u64, function_hash: u32, exit_qualification: &EptViolationExitQualification) {
+    let access = match (exit_qualification.data_read, exit_qualification.data_write) {
+        (true, true) => "read-write",
+        (false, true) => "write",
+        _ => "read",
+    };
+
+    // The linear address is not reported for accesses made by the processor itself, such as page walks.
+    if exit_qualification.guest_linear_address_valid {
+        info!(
+            "Page watch {:#x}: {} of GPA: {:#x} at linear address: {:#x} from RIP: {:#x}",
+            function_hash,
+            access,
+            guest_pa,
+            vmread(vmcs::ro::GUEST_LINEAR_ADDR),
+            vm.guest_registers.rip
+        );
+    } else {
+        info!("Page watch {:#x}: {} of GPA: {:#x} from RIP: {:#x}", function_hash, access, guest_pa, vm.guest_registers.rip);
+    }
+}
@@ -3,12 +3,16 @@
 //! general protection faults, breakpoints, and invalid opcodes.
 
 use {
-    crate::intel::{
-        events::EventInjection,
-        support::vmread,
-        vm::Vm,
-        vmerror::{EptViolationExitQualification, ExceptionInterrupt, VmExitInterruptionInformation},
-        vmexit::ExitType,
+    crate::{
+        error::HypervisorError,
+        intel::{
+            events::EventInjection,
+            hooks::inline::InlineHookType,
+            support::vmread,
+            vm::Vm,
+            vmerror::{EptViolationExitQualification, ExceptionInterrupt, InterruptionType, VmExitInterruptionInformation},
+            vmexit::{inline_hook::handle_inline_hook, nmi::handle_nmi, ExitType},
+        },
     },
     x86::vmx::vmcs,
 };
@@ -21,19 +25,22 @@ use {
 ///
 /// # Arguments
 ///
-/// * `guest_registers` - A mutable reference to the guest's register state.
-/// * `vmx` - A mutable reference to the Vmx structure representing the current VM.
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
 ///
 /// # Returns
 ///
 /// * `ExitType::Continue` - Indicating that VM execution should continue after handling the exception
-pub fn handle_exception(_vm: &mut Vm) -> ExitType {
+pub fn handle_exception(vm: &mut Vm) -> ExitType {
     log::debug!("Handling ExceptionOrNmi VM exit...");
 
     let interruption_info_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_INFO);
     let interruption_error_code_value = vmread(vmcs::ro::VMEXIT_INTERRUPTION_ERR_CODE);
 
     if let Some(interruption_info) = VmExitInterruptionInformation::from_u32(interruption_info_value as u32) {
+        if interruption_info.interruption_type == InterruptionType::NonMaskableInterrupt {
+            return handle_nmi(vm);
+        }
+
         if let Some(exception_interrupt) = ExceptionInterrupt::from_u32(interruption_info.vector.into()) {
             match exception_interrupt {
                 ExceptionInterrupt::PageFault => {
@@ -46,8 +53,7 @@ pub fn handle_exception(_vm: &mut Vm) -> ExitType {
                     EventInjection::vmentry_inject_gp(interruption_error_code_value as u32);
                 }
                 ExceptionInterrupt::Breakpoint => {
-                    //handle_breakpoint_exception(guest_registers, vm);
-                    EventInjection::vmentry_inject_bp();
+                    handle_breakpoint_exception(vm).expect("Failed to handle breakpoint exception");
                 }
                 ExceptionInterrupt::InvalidOpcode => {
                     EventInjection::vmentry_inject_ud();
@@ -68,53 +74,33 @@ pub fn handle_exception(_vm: &mut Vm) -> ExitType {
     ExitType::Continue
 }
 
-/*
 /// Handles breakpoint (`#BP`) exceptions specifically.
 ///
-/// When a breakpoint exception occurs, this function checks for a registered hook
-/// at the current instruction pointer (RIP). If a hook is found, it transfers control
-/// to the hook's handler. Otherwise, it injects a breakpoint exception into the VM.
+/// When a breakpoint exception occurs, this function checks for an `int3` inline hook
+/// at the current instruction pointer (RIP). If a hook is found, it is dispatched like
+/// any other inline hook. Otherwise, the breakpoint is re-injected into the guest.
 ///
 /// # Arguments
 ///
-/// * `guest_registers` - A mutable reference to the guest's current register state.
-/// * `vmx` - A mutable reference to the Vmx structure.
-fn handle_breakpoint_exception(guest_registers: &mut GuestRegisters, vm: &mut Vm) {
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// * `Result<(), HypervisorError>` - `Ok(())` if the breakpoint was handled, or the error of the hook.
+fn handle_breakpoint_exception(vm: &mut Vm) -> Result<(), HypervisorError> {
     log::debug!("Breakpoint Exception");
 
-    let hook_manager = vm.hook_manager.as_mut();
-
-    log::trace!("Finding hook for RIP: {:#x}", guest_registers.rip);
-
-    // Find the handler address for the current instruction pointer (RIP) and
-    // transfer the execution to it. If we couldn't find a hook, we inject the
-    // #BP exception.
-    //
-    if let Some(Some(handler)) =
-        hook_manager
-            .find_hook_by_address(guest_registers.rip)
-            .map(|hook| {
-                log::trace!("Found hook for RIP: {:#x}", guest_registers.rip);
-                if let HookType::Function { inline_hook } = &hook.hook_type {
-                    log::trace!("Getting handler address");
-                    Some(inline_hook.handler_address())
-                } else {
-                    None
-                }
-            })
-    {
-        // Call our hook handle function (it will automatically call trampoline).
-        log::trace!("Transferring execution to handler: {:#x}", handler);
-        guest_registers.rip = handler;
-        vmwrite(vmcs::guest::RIP, guest_registers.rip);
+    log::trace!("Finding hook for RIP: {:#x}", vm.guest_registers.rip);
 
+    if handle_inline_hook(vm, InlineHookType::Int3)?.is_some() {
         log::debug!("Breakpoint (int3) hook handled successfully!");
     } else {
-        EventInjection::vmentry_inject_bp();
+        EventInjection::vmentry_inject_bp(vmread(vmcs::ro::VMEXIT_INSTRUCTION_LEN) as u32);
         log::debug!("Breakpoint exception handled successfully!");
-    };
+    }
+
+    Ok(())
 }
-*/
 
 /// Handles undefined opcode (`#UD`) exceptions.
 ///
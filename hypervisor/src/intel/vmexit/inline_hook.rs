@@ -0,0 +1,222 @@
+//! Dispatches inline hooks to their handlers, whichever instruction the hook is installed with.
+//!
+//! A `vmcall`, `int3` or `cpuid` instruction written over the start of a hooked function in its shadow page
+//! causes a VM exit with RIP at the hooked function. The hook handler is run, then the overwritten instructions
+//! are single-stepped on the original page and `handle_monitor_trap_flag` restores the shadow page.
+//!
+//! The return of a call through a hook with a return handler is trapped by an EPT violation on the page holding
+//! the return address, which `handle_return_trap` dispatches to the return handler.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::{
+            addresses::{AccessMode, GuestMemory, PhysicalAddress},
+            ept::AccessType,
+            hooks::{
+                handlers::HookAction,
+                hook_manager::{EptHookType, HookManager, SHARED_HOOK_MANAGER},
+                inline::InlineHookType,
+                memory_manager::MemoryManager,
+            },
+            support::{apic_id, rdtsc, vmwrite},
+            vm::Vm,
+            vmexit::{
+                mtf::{begin_single_step, MtfRestorePage},
+                ExitType,
+            },
+        },
+        windows::eprocess::ProcessInformation,
+    },
+    core::mem::size_of,
+    log::*,
+    x86::{bits64::paging::PAddr, vmx::vmcs},
+};
+
+/// Handles a VM exit caused by an instruction that may be an inline hook of the given type.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `inline_hook_type` - The type of inline hook the instruction that caused the VM exit would be.
+///
+/// # Returns
+///
+/// * `Ok(Some(ExitType))` - The instruction at RIP is an inline hook of this type, and it was handled.
+/// * `Ok(None)` - The instruction at RIP is not an inline hook of this type and must be handled as executed by the guest.
+/// * `Err(HypervisorError)` - The hook could not be handled.
+pub fn handle_inline_hook(vm: &mut Vm, inline_hook_type: InlineHookType) -> Result<Option<ExitType>, HypervisorError> {
+    // Most of these instructions are executed by the guest itself, so skip the page walk and the lock when no hook could match.
+    if !MemoryManager::has_inline_hooks(inline_hook_type) {
+        return Ok(None);
+    }
+
+    trace!("Guest RIP: {:#x}", vm.guest_registers.rip);
+
+    let Ok(guest_function_pa) = PhysicalAddress::pa_from_va_with_current_cr3(vm.guest_registers.rip) else {
+        return Ok(None);
+    };
+
+    let guest_function_pa = PAddr::from(guest_function_pa);
+    trace!("Guest PA: {:#x}", guest_function_pa.as_u64());
+
+    let guest_page_pa = guest_function_pa.align_down_to_base_page();
+    trace!("Guest Page PA: {:#x}", guest_page_pa.as_u64());
+
+    let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
+    trace!("Guest Large Page PA: {:#x}", guest_large_page_pa.as_u64());
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    let is_inline_hook = hook_manager
+        .memory_manager
+        .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+        .is_some_and(|hook_info| matches!(hook_info.ept_hook_type, EptHookType::Function(hook_type) if hook_type == inline_hook_type));
+
+    if !is_inline_hook {
+        return Ok(None);
+    }
+
+    trace!(
+        "Executing {:?} hook on shadow page for EPT hook at PA: {:#x} with VA: {:#x}",
+        inline_hook_type,
+        guest_function_pa,
+        vm.guest_registers.rip
+    );
+
+    // The return address is at the top of the stack on entry, a failed read only leaves the caller unknown.
+    let caller_rip = GuestMemory::with_current_cr3(AccessMode::Kernel)
+        .read::<u64>(vm.guest_registers.rsp)
+        .unwrap_or(0);
+    let process_id = ProcessInformation::get_current_process_id().unwrap_or(0);
+
+    hook_manager
+        .memory_manager
+        .get_hook_info_by_function_pa_mut(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+        .ok_or(HypervisorError::HookInfoNotFound)?
+        .counters
+        .record_hit(rdtsc(), caller_rip, process_id, apic_id());
+
+    let hook_info = hook_manager
+        .memory_manager
+        .get_hook_info_by_function_pa(guest_page_pa.as_u64(), guest_function_pa.as_u64())
+        .ok_or(HypervisorError::HookInfoNotFound)?;
+
+    debug!("Hook info: {:#x?}", hook_info);
+
+    let ept_hook_type = hook_info.ept_hook_type;
+    let trap_return = hook_manager.handlers.find_return(hook_info).is_some();
+
+    // Run the handler registered for the hook before the overwritten instructions are restored. It can rewrite the
+    // argument registers, which launch_vm restores into the guest, or skip the function entirely.
+    if let Some(handler) = hook_manager.handlers.find(hook_info) {
+        if let HookAction::Return(return_value) = handler(&mut vm.guest_registers, hook_info) {
+            debug!("Hook handler skipped the function at VA: {:#x} with return value: {:#x}", vm.guest_registers.rip, return_value);
+            return_to_caller(vm, return_value)?;
+            return Ok(Some(ExitType::Continue));
+        }
+    }
+
+    // A return that cannot be trapped only loses the return handler, so the call goes ahead regardless.
+    if trap_return {
+        if let Err(e) = hook_manager.trap_return(vm, guest_function_pa.as_u64()) {
+            warn!("Failed to trap return of the call at VA: {:#x}: {:?}", vm.guest_registers.rip, e);
+        }
+    }
+
+    // Calculate the number of instructions in the function to set the MTF counter for restoring overwritten instructions by single-stepping.
+    let instruction_count =
+        unsafe { HookManager::calculate_instruction_count(guest_function_pa.as_u64(), HookManager::hook_size(ept_hook_type)) as u64 };
+
+    // The instructions may leave the hooked page, so the page to restore is recorded rather than derived from the final RIP.
+    begin_single_step(vm, instruction_count, MtfRestorePage::Hook(guest_page_pa.as_u64()))?;
+
+    let pre_alloc_pt = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(guest_large_page_pa.as_u64(), &vm.primary_ept)
+        .ok_or(HypervisorError::PageTableNotFound)?;
+
+    vm.primary_ept
+        .swap_page(guest_page_pa.as_u64(), guest_page_pa.as_u64(), AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)?;
+
+    // Do not increment RIP, the original instructions are executed from the hooked function itself.
+    Ok(Some(ExitType::Continue))
+}
+
+/// Handles an instruction fetch from a page holding the return address of a hooked call in progress.
+///
+/// If the fetch is the return of the call, its return handler is run with the return value in RAX and the call frame
+/// recorded on entry. The instruction is then executed, single-stepped with the page executable on this processor if
+/// other returns to the page are still pending.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine instance.
+/// * `hook_manager` - The locked hook manager.
+/// * `guest_page_pa` - The guest physical address of the trapped page.
+///
+/// # Returns
+///
+/// * `Ok(())` - The guest can resume at the faulting instruction.
+/// * `Err(HypervisorError)` - The page could not be made executable.
+pub fn handle_return_trap(vm: &mut Vm, hook_manager: &mut HookManager, guest_page_pa: u64) -> Result<(), HypervisorError> {
+    if let Some(pending_return) = hook_manager.take_returned_call(vm)? {
+        let frame = pending_return.frame;
+        trace!("Trapped return of the call at VA: {:#x} to VA: {:#x}", frame.function_va, frame.return_address);
+
+        // The hook may have been removed while the call was in progress, which also drops its pending returns.
+        let guest_function_page_pa = PAddr::from(pending_return.guest_function_pa).align_down_to_base_page().as_u64();
+        if let Some(hook_info) = hook_manager
+            .memory_manager
+            .get_hook_info_by_function_pa(guest_function_page_pa, pending_return.guest_function_pa)
+        {
+            if let Some(return_handler) = hook_manager.handlers.find_return(hook_info) {
+                return_handler(&mut vm.guest_registers, hook_info, &frame);
+            }
+        }
+    }
+
+    hook_manager.evict_stale_returns(vm)?;
+
+    // The page is executable on every processor again once no return to it is pending.
+    if !hook_manager.is_return_trap(guest_page_pa) {
+        return Ok(());
+    }
+
+    begin_single_step(vm, 1, MtfRestorePage::ReturnTrap(guest_page_pa))?;
+
+    let pre_alloc_pt = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(PAddr::from(guest_page_pa).align_down_to_large_page().as_u64(), &vm.primary_ept)
+        .ok_or(HypervisorError::PageTableNotFound)?;
+
+    vm.primary_ept
+        .swap_page(guest_page_pa, guest_page_pa, AccessType::READ_WRITE_EXECUTE, pre_alloc_pt)
+}
+
+/// Returns from the hooked function to its caller without executing it, as if it had returned the given value.
+///
+/// The hook fires on the first instruction of the function, so the return address is still at the top of the guest stack.
+///
+/// # Arguments
+///
+/// * `vm` - The virtual machine instance whose guest is returned to its caller.
+/// * `return_value` - The value placed in RAX as the return value of the function.
+///
+/// # Returns
+///
+/// * `Ok(())` - The guest will resume at the return address.
+/// * `Err(HypervisorError)` - The return address could not be read from the guest stack.
+fn return_to_caller(vm: &mut Vm, return_value: u64) -> Result<(), HypervisorError> {
+    let return_address = GuestMemory::with_current_cr3(AccessMode::Kernel).read::<u64>(vm.guest_registers.rsp)?;
+    trace!("Returning to caller at VA: {:#x}", return_address);
+
+    vm.guest_registers.rax = return_value;
+    vm.guest_registers.rip = return_address;
+    vm.guest_registers.rsp += size_of::<u64>() as u64;
+
+    vmwrite(vmcs::guest::RIP, vm.guest_registers.rip);
+    vmwrite(vmcs::guest::RSP, vm.guest_registers.rsp);
+
+    Ok(())
+}
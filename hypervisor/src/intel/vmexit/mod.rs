@@ -6,11 +6,13 @@ pub mod ept_violation;
 pub mod exception;
 pub mod halt;
 pub mod init;
+pub mod inline_hook;
 pub mod invd;
 pub mod invept;
 pub mod invvpid;
 pub mod msr;
 pub mod mtf;
+pub mod nmi;
 pub mod rdtsc;
 pub mod sipi;
 pub mod vmcall;
@@ -2,9 +2,8 @@ use {
     crate::{
         error::HypervisorError,
         intel::{
-            addresses::PhysicalAddress,
             ept::AccessType,
-            hooks::hook_manager::SHARED_HOOK_MANAGER,
+            hooks::hook_manager::{HookManager, SHARED_HOOK_MANAGER},
             support::{vmread, vmwrite},
             vm::Vm,
             vmexit::ExitType,
@@ -15,6 +14,108 @@ use {
     x86_64::registers::rflags::RFlags,
 };
 
+/// The maximum number of pages whose EPT permissions can wait to be restored while single-stepping, such as a hooked
+/// page and the watched pages its overwritten instructions access.
+pub const MAX_MTF_RESTORE_PAGES: usize = 8;
+
+/// A page whose EPT permissions are relaxed while single-stepping and restored on the final MTF exit.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MtfRestorePage {
+    /// A page with function hooks, whose shadow page is mapped back as execute-only.
+    Hook(u64),
+
+    /// A page watched by a page hook, whose watch permissions are restored.
+    Watch(u64),
+
+    /// A page holding the return address of a hooked call in progress, which is mapped without execute access again.
+    ReturnTrap(u64),
+}
+
+/// The pages a processor restores once it finishes single-stepping.
+#[derive(Debug, Clone, Copy)]
+pub struct MtfRestorePages {
+    /// The recorded pages, in the order they were relaxed.
+    pages: [Option<MtfRestorePage>; MAX_MTF_RESTORE_PAGES],
+}
+
+impl Default for MtfRestorePages {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MtfRestorePages {
+    /// Creates an empty list.
+    pub const fn new() -> Self {
+        Self {
+            pages: [None; MAX_MTF_RESTORE_PAGES],
+        }
+    }
+
+    /// Records a page to restore, unless it is already recorded.
+    ///
+    /// # Arguments
+    /// * `page` - The page to restore.
+    ///
+    /// # Returns
+    /// * `Result<(), HypervisorError>`: Ok if the page is recorded, or `MtfRestoreListFull` if there is no room left for it.
+    pub fn push(&mut self, page: MtfRestorePage) -> Result<(), HypervisorError> {
+        if self.pages.contains(&Some(page)) {
+            return Ok(());
+        }
+
+        let slot = self
+            .pages
+            .iter_mut()
+            .find(|slot| slot.is_none())
+            .ok_or(HypervisorError::MtfRestoreListFull)?;
+        *slot = Some(page);
+
+        Ok(())
+    }
+
+    /// Takes the most recently recorded page.
+    ///
+    /// # Returns
+    /// An `Option` containing the page, or `None` if every page was taken.
+    pub fn pop(&mut self) -> Option<MtfRestorePage> {
+        self.pages.iter_mut().rev().find_map(|slot| slot.take())
+    }
+}
+
+/// Single-steps the guest with interrupts disabled for a number of instructions, and restores the EPT permissions of a
+/// page once the last of them was executed.
+///
+/// An instruction being single-stepped can cause another VM exit that relaxes a page, such as an access to a watched page
+/// from the overwritten instructions of a hook. The step in progress then continues for at least as many instructions
+/// as requested, the RFLAGS saved when it started are kept, and the page is restored along with the others on the final
+/// MTF exit.
+///
+/// # Parameters
+/// * `vm`: A mutable reference to the virtual machine instance.
+/// * `instruction_count`: The number of instructions to single-step.
+/// * `restore_page`: The page whose EPT permissions the caller relaxes for the instructions.
+///
+/// # Returns
+/// * `Result<(), HypervisorError>`: Ok if single-stepping is armed, or `MtfRestoreListFull` if the page cannot be recorded.
+pub fn begin_single_step(vm: &mut Vm, instruction_count: u64, restore_page: MtfRestorePage) -> Result<(), HypervisorError> {
+    vm.mtf_restore_pages.push(restore_page)?;
+
+    if let Some(counter) = vm.mtf_counter.as_mut() {
+        trace!("Extending single-step in progress with {} instructions left to restore {:x?}", *counter, restore_page);
+        *counter = (*counter).max(instruction_count);
+        return Ok(());
+    }
+
+    vm.mtf_counter = Some(instruction_count);
+
+    // Set the monitor trap flag and initialize counter to the number of instructions to step.
+    set_monitor_trap_flag(true);
+
+    // This function will update the guest interrupt flag to prevent interrupts while single-stepping
+    update_guest_interrupt_flag(vm, false)
+}
+
 /// Handles the Monitor Trap Flag (MTF) VM exit.
 ///
 /// This function ensures single stepping through overwritten instructions on a hooked function
@@ -34,37 +135,19 @@ pub fn handle_monitor_trap_flag(vm: &mut Vm) -> Result<ExitType, HypervisorError
         *counter = counter.saturating_sub(1); // Safely decrement the counter
         trace!("MTF counter after decremented: {}", *counter);
 
-        // If the counter is zero, we have completed the single-stepping process, restore the hook.
+        // If the counter is zero, we have completed the single-stepping process, restore every relaxed page.
         if *counter == 0 {
+            vm.mtf_counter = None;
             set_monitor_trap_flag(false);
 
-            let guest_pa = PAddr::from(PhysicalAddress::pa_from_va_with_current_cr3(vm.guest_registers.rip)?);
-            trace!("Guest PA: {:#x}", guest_pa.as_u64());
-
-            let guest_page_pa = guest_pa.align_down_to_base_page();
-            trace!("Guest Page PA: {:#x}", guest_page_pa.as_u64());
-
-            let guest_large_page_pa = guest_page_pa.align_down_to_large_page();
-            trace!("Guest Large Page PA: {:#x}", guest_large_page_pa.as_u64());
-
-            let mut hook_manager = SHARED_HOOK_MANAGER.lock();
-
-            let shadow_page_pa = PAddr::from(
-                hook_manager
-                    .memory_manager
-                    .get_shadow_page_as_ptr(guest_page_pa.as_u64())
-                    .ok_or(HypervisorError::ShadowPageNotFound)?,
-            );
-            trace!("Shadow Page PA: {:#x}", shadow_page_pa);
-
-            let pre_alloc_pt = hook_manager
-                .memory_manager
-                .get_page_table_as_mut(guest_large_page_pa.as_u64())
-                .ok_or(HypervisorError::PageTableNotFound)?;
-
-            // Restore the hook to continue monitoring
-            vm.primary_ept
-                .swap_page(guest_pa.align_down_to_base_page().as_u64(), shadow_page_pa.as_u64(), AccessType::EXECUTE, pre_alloc_pt)?;
+            // The stepped instructions may have left the hooked page, so the pages are those recorded while stepping.
+            while let Some(restore_page) = vm.mtf_restore_pages.pop() {
+                match restore_page {
+                    MtfRestorePage::Hook(guest_page_pa) => restore_page_hook(vm, guest_page_pa)?,
+                    MtfRestorePage::Watch(guest_page_pa) => restore_page_watch(vm, guest_page_pa)?,
+                    MtfRestorePage::ReturnTrap(guest_page_pa) => restore_return_trap(vm, guest_page_pa)?,
+                }
+            }
 
             restore_guest_interrupt_flag(vm)?;
         } else {
@@ -78,6 +161,104 @@ pub fn handle_monitor_trap_flag(vm: &mut Vm) -> Result<ExitType, HypervisorError
     Ok(ExitType::Continue)
 }
 
+/// Restores the shadow page of a hooked page after its original instructions were single-stepped.
+///
+/// Another processor may have removed the last hook on the page, and with it the shadow page and the
+/// page table of the large page, while this processor was single-stepping. The page has then already
+/// been restored to its original mapping on every EPT, so there is nothing left to do.
+///
+/// # Parameters
+/// * `vm`: A mutable reference to the virtual machine instance.
+/// * `guest_page_pa`: The guest physical address of the hooked page.
+///
+/// # Returns
+/// * `Result<(), HypervisorError>`: Ok if the hook was restored or has been removed in the meantime, or an error.
+fn restore_page_hook(vm: &mut Vm, guest_page_pa: u64) -> Result<(), HypervisorError> {
+    trace!("Guest Page PA: {:#x}", guest_page_pa);
+
+    let guest_large_page_pa = PAddr::from(guest_page_pa).align_down_to_large_page().as_u64();
+    trace!("Guest Large Page PA: {:#x}", guest_large_page_pa);
+
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    let Some(shadow_page_pa) = hook_manager.memory_manager.get_shadow_page_as_ptr(guest_page_pa) else {
+        trace!("Hook at PA: {:#x} was removed while single-stepping", guest_page_pa);
+        return Ok(());
+    };
+    trace!("Shadow Page PA: {:#x}", shadow_page_pa);
+
+    let Some(pre_alloc_pt) = hook_manager.memory_manager.get_page_table_as_mut(guest_large_page_pa, &vm.primary_ept) else {
+        trace!("Page table of large page at PA: {:#x} was released while single-stepping", guest_large_page_pa);
+        return Ok(());
+    };
+
+    // Restore the hook to continue monitoring
+    vm.primary_ept
+        .swap_page(guest_page_pa, shadow_page_pa, AccessType::EXECUTE, pre_alloc_pt)?;
+
+    Ok(())
+}
+
+/// Restores the EPT permissions of a page watched by a page hook after an access to it was single-stepped.
+///
+/// # Parameters
+/// * `vm`: A mutable reference to the virtual machine instance.
+/// * `guest_page_pa`: The guest physical address of the watched page.
+///
+/// # Returns
+/// * `Result<(), HypervisorError>`: Ok if the watch was restored or has been removed in the meantime, or an error.
+fn restore_page_watch(vm: &mut Vm, guest_page_pa: u64) -> Result<(), HypervisorError> {
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    let Some((_, watched_access)) = hook_manager.memory_manager.get_page_watch(guest_page_pa) else {
+        trace!("Page watch at PA: {:#x} was removed while single-stepping", guest_page_pa);
+        return Ok(());
+    };
+
+    let Some(pre_alloc_pt) = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(PAddr::from(guest_page_pa).align_down_to_large_page().as_u64(), &vm.primary_ept)
+    else {
+        trace!("Page table of watched page at PA: {:#x} was released while single-stepping", guest_page_pa);
+        return Ok(());
+    };
+
+    vm.primary_ept
+        .swap_page(guest_page_pa, guest_page_pa, HookManager::page_hook_permissions(watched_access), pre_alloc_pt)?;
+
+    Ok(())
+}
+
+/// Removes execute access from a page holding the return address of a hooked call after an instruction on it was single-stepped.
+///
+/// # Parameters
+/// * `vm`: A mutable reference to the virtual machine instance.
+/// * `guest_page_pa`: The guest physical address of the trapped page.
+///
+/// # Returns
+/// * `Result<(), HypervisorError>`: Ok if the trap was restored or no return to the page is pending anymore, or an error.
+fn restore_return_trap(vm: &mut Vm, guest_page_pa: u64) -> Result<(), HypervisorError> {
+    let mut hook_manager = SHARED_HOOK_MANAGER.lock();
+
+    if !hook_manager.is_return_trap(guest_page_pa) {
+        trace!("Returns to page at PA: {:#x} are no longer trapped", guest_page_pa);
+        return Ok(());
+    }
+
+    let Some(pre_alloc_pt) = hook_manager
+        .memory_manager
+        .get_page_table_as_mut(PAddr::from(guest_page_pa).align_down_to_large_page().as_u64(), &vm.primary_ept)
+    else {
+        trace!("Page table of trapped page at PA: {:#x} was released while single-stepping", guest_page_pa);
+        return Ok(());
+    };
+
+    vm.primary_ept
+        .swap_page(guest_page_pa, guest_page_pa, AccessType::READ_WRITE, pre_alloc_pt)?;
+
+    Ok(())
+}
+
 /// Set the monitor trap flag
 ///
 /// # Arguments
@@ -136,7 +317,9 @@ pub fn update_guest_interrupt_flag(vm: &mut Vm, enable: bool) -> Result<(), Hype
     Ok(())
 }
 
-/// Restores the guest's RFLAGS register from the saved old RFLAGS.
+/// Restores the guest's Interrupt Flag from the saved old RFLAGS.
+///
+/// The other flags are left as the single-stepped instructions set them.
 ///
 /// # Parameters
 /// * `vm`: A mutable reference to the virtual machine instance.
@@ -144,14 +327,16 @@ pub fn update_guest_interrupt_flag(vm: &mut Vm, enable: bool) -> Result<(), Hype
 /// # Returns
 /// * `Result<(), HypervisorError>`: Ok if successful, Err if an error occurred during VMCS read/write operations.
 pub fn restore_guest_interrupt_flag(vm: &mut Vm) -> Result<(), HypervisorError> {
-    if let Some(old_rflags_bits) = vm.old_rflags {
-        trace!("Restoring guest RFLAGS to old value: {:#x}", old_rflags_bits);
+    if let Some(old_rflags_bits) = vm.old_rflags.take() {
+        let mut rflags = RFlags::from_bits_retain(vmread(vmcs::guest::RFLAGS));
+        rflags.set(RFlags::INTERRUPT_FLAG, RFlags::from_bits_retain(old_rflags_bits).contains(RFlags::INTERRUPT_FLAG));
+        trace!("Restoring guest RFLAGS interrupt flag from old value: {:#x} to {:#x}", old_rflags_bits, rflags.bits());
 
         // Update VM register state first
-        vm.guest_registers.rflags = old_rflags_bits;
+        vm.guest_registers.rflags = rflags.bits();
 
         // Then write to VMCS
-        vmwrite(vmcs::guest::RFLAGS, old_rflags_bits);
+        vmwrite(vmcs::guest::RFLAGS, rflags.bits());
         Ok(())
     } else {
         Err(HypervisorError::OldRflagsNotSet)
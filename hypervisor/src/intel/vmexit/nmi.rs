@@ -0,0 +1,80 @@
+//! Handles VM exits caused by NMIs and by the opening of the NMI window of the guest.
+//!
+//! Processors exit on every NMI, so `ept_sync` can make them invalidate EPT changes by sending one. Every other NMI
+//! belongs to the guest and is forwarded to it once it does not block NMIs, which the NMI-window exit reports.
+//! NMIs received while the processor runs the hypervisor are deferred to the NMI-window exit by the host IDT.
+
+use {
+    crate::intel::{
+        ept_sync::take_nmi_kick,
+        events::EventInjection,
+        support::{vmread, vmwrite},
+        vm::Vm,
+        vmexit::ExitType,
+    },
+    log::trace,
+    x86::vmx::vmcs,
+};
+
+/// Handles the VM exit caused by an NMI received while the guest was running.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// Returns `ExitType::Continue` to resume the guest at the interrupted instruction.
+pub fn handle_nmi(vm: &mut Vm) -> ExitType {
+    trace!("Handling NMI VM exit...");
+
+    if take_nmi_kick(vm) {
+        trace!("NMI sent to invalidate EPT changes, not forwarding it to the guest");
+        return ExitType::Continue;
+    }
+
+    vm.pending_guest_nmi = true;
+    set_nmi_window_exiting(true);
+
+    ExitType::Continue
+}
+
+/// Handles the VM exit caused by the opening of the NMI window of the guest, and injects the NMI waiting for it.
+///
+/// The window was requested either by `handle_nmi` for a guest NMI, or by the host IDT for an NMI received while the
+/// processor ran the hypervisor, which is only forwarded if it was not sent to make the processor exit.
+///
+/// # Arguments
+///
+/// * `vm` - A mutable reference to the virtual machine (VM) instance.
+///
+/// # Returns
+///
+/// Returns `ExitType::Continue` to resume the guest at the interrupted instruction.
+pub fn handle_nmi_window(vm: &mut Vm) -> ExitType {
+    trace!("Handling NMI window VM exit...");
+
+    set_nmi_window_exiting(false);
+
+    let is_guest_nmi = core::mem::take(&mut vm.pending_guest_nmi) || !take_nmi_kick(vm);
+    if is_guest_nmi {
+        trace!("Injecting NMI into the guest");
+        EventInjection::vmentry_inject_nmi();
+    }
+
+    ExitType::Continue
+}
+
+/// Enables or disables NMI-window exiting.
+///
+/// # Arguments
+///
+/// * `set` - Whether the processor should exit as soon as the guest does not block NMIs.
+fn set_nmi_window_exiting(set: bool) {
+    let controls = vmread(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS);
+    let mut primary_controls = unsafe { vmcs::control::PrimaryControls::from_bits_unchecked(controls as u32) };
+
+    primary_controls.set(vmcs::control::PrimaryControls::NMI_WINDOW_EXITING, set);
+
+    vmwrite(vmcs::control::PRIMARY_PROCBASED_EXEC_CONTROLS, primary_controls.bits());
+}
@@ -8,7 +8,7 @@
 //!
 
 use {
-    crate::intel::support::{inb, outb},
+    crate::intel::support::{apic_id, inb, outb},
     core::{fmt, fmt::Write},
     spin::Mutex,
 };
@@ -166,14 +166,3 @@ impl Write for Serial {
         Ok(())
     }
 }
-
-/// Gets an APIC ID.
-///
-/// # Returns
-///
-/// Returns the APIC ID of the current processor.
-fn apic_id() -> u32 {
-    // See: (AMD) CPUID Fn0000_0001_EBX LocalApicId, LogicalProcessorCount, CLFlush
-    // See: (Intel) Table 3-8. Information Returned by CPUID Instruction
-    x86::cpuid::cpuid!(0x1).ebx >> 24
-}
@@ -10,6 +10,7 @@ use {
         intel::{
             bitmap::MsrAccessType,
             capture::GuestRegisters,
+            ept_sync::{park, sync_ept, sync_exception_bitmap, wait_for_pending_flush, SHARED_EPT_REGISTRY},
             support::{rdmsr, vmread, vmwrite},
             vm::Vm,
             vmerror::VmxBasicExitReason,
@@ -26,6 +27,7 @@ use {
                 invvpid::handle_invvpid,
                 msr::handle_msr_access,
                 mtf::handle_monitor_trap_flag,
+                nmi::handle_nmi_window,
                 rdtsc::handle_rdtsc,
                 sipi::handle_sipi_signal,
                 vmcall::handle_vmcall,
@@ -36,13 +38,32 @@ use {
         },
         windows::eprocess::ProcessInformation,
     },
+    core::sync::atomic::{AtomicU64, Ordering},
     log::*,
+    spin::Once,
     x86::{
-        msr::IA32_VMX_EPT_VPID_CAP,
+        msr::{IA32_VMX_BASIC, IA32_VMX_EPT_VPID_CAP, IA32_VMX_PROCBASED_CTLS2},
         vmx::vmcs::{guest, ro},
     },
 };
 
+/// Number of logical processors that have been virtualized.
+pub static VIRTUALIZED_PROCESSORS: AtomicU64 = AtomicU64::new(0);
+
+/// VMX capabilities detected by the first processor that passed `check_supported_cpu`.
+pub static VMX_CAPABILITIES: Once<VmxCapabilities> = Once::new();
+
+/// The VMX capability MSRs read while checking CPU support.
+#[derive(Debug, Clone, Copy)]
+pub struct VmxCapabilities {
+    /// Value of `IA32_VMX_BASIC`.
+    pub vmx_basic: u64,
+    /// Value of `IA32_VMX_PROCBASED_CTLS2`.
+    pub procbased_ctls2: u64,
+    /// Value of `IA32_VMX_EPT_VPID_CAP`.
+    pub ept_vpid_cap: u64,
+}
+
 /// Initiates the hypervisor, activating VMX and setting up the initial VM state.
 ///
 /// Validates CPU compatibility and VMX support, then proceeds to enable VMX operation.
@@ -82,6 +103,12 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
 
     trace!("VMCS Dump: {:#x?}", vm.vmcs_region);
 
+    // The VM stays at this address until the hypervisor exits, so other processors can change its EPT.
+    SHARED_EPT_REGISTRY.lock().register(&mut vm);
+
+    // Processors started after an int3 hook was installed intercept breakpoints from their first instruction on.
+    sync_exception_bitmap(&mut vm);
+
     #[cfg(feature = "hide_hv_with_ept")]
     {
         debug!("Hiding hypervisor memory... (NOTE: EPT HOOKS WON'T WORK IF THIS IS ENABLED UNLESS SHADOW PAGES ARE EXCLUDED)");
@@ -91,12 +118,24 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
             Ok(_) => debug!("Hypervisor memory hidden"),
             Err(e) => panic!("Failed to hide hypervisor memory: {:?}", e),
         };
+        drop(hook_manager);
+        if let Err(e) = wait_for_pending_flush(&mut vm) {
+            error!("Failed to hide hypervisor memory on every processor: {:?}", e);
+        }
     }
 
+    let processor_count = VIRTUALIZED_PROCESSORS.fetch_add(1, Ordering::SeqCst) + 1;
+    debug!("Virtualized processors: {}", processor_count);
+
     info!("Launching the VM until a vmexit occurs...");
 
     loop {
         if let Ok(basic_exit_reason) = vm.run() {
+            // Invalidate EPT changes made by other processors before handling the exit, intercepting breakpoints first
+            // if they installed an int3 hook.
+            sync_exception_bitmap(&mut vm);
+            sync_ept(&mut vm);
+
             // Log the VM exit reason along with the current process information, only if available
             if let Some(p) = ProcessInformation::get_current_process_info() {
                 debug!(
@@ -116,6 +155,8 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
                 VmxBasicExitReason::InitSignal => handle_init_signal(&mut vm.guest_registers),
                 // 4
                 VmxBasicExitReason::StartupIpi => handle_sipi_signal(&mut vm.guest_registers),
+                // 8
+                VmxBasicExitReason::NmiWindow => handle_nmi_window(&mut vm),
                 // 10
                 VmxBasicExitReason::Cpuid => handle_cpuid(&mut vm).expect("Failed to handle CPUID"),
                 // 11
@@ -170,6 +211,17 @@ pub fn start_hypervisor(guest_registers: &GuestRegisters) -> ! {
             if exit_type == ExitType::IncrementRIP {
                 advance_guest_rip(&mut vm.guest_registers);
             }
+
+            // A processor waiting for a SIPI runs no guest code, so others changing the EPTs must not wait for it.
+            if basic_exit_reason == VmxBasicExitReason::InitSignal {
+                park(&mut vm);
+            }
+
+            // Make EPT changes made while handling the exit visible on every processor before the guest resumes.
+            // Commands wait before reporting their result, so only changes made by other exits are left here.
+            if let Err(e) = wait_for_pending_flush(&mut vm) {
+                error!("EPT changes may not be visible on every processor: {:?}", e);
+            }
         } else {
             panic!("Failed to run the VM");
         }
@@ -194,7 +246,8 @@ fn advance_guest_rip(guest_registers: &mut GuestRegisters) {
 
 /// Checks if the CPU is supported for hypervisor operation.
 ///
-/// Verifies the CPU is Intel with VMX support and Memory Type Range Registers (MTRRs) support.
+/// Verifies the CPU is Intel with VMX support and Memory Type Range Registers (MTRRs) support,
+/// and records the detected VMX capabilities in `VMX_CAPABILITIES`.
 ///
 /// # Returns
 ///
@@ -213,6 +266,12 @@ fn check_supported_cpu() -> Result<(), HypervisorError> {
     check_ept_support()?;
     info!("Extended Page Tables (EPT) are supported");
 
+    VMX_CAPABILITIES.call_once(|| VmxCapabilities {
+        vmx_basic: rdmsr(IA32_VMX_BASIC),
+        procbased_ctls2: rdmsr(IA32_VMX_PROCBASED_CTLS2),
+        ept_vpid_cap: rdmsr(IA32_VMX_EPT_VPID_CAP),
+    });
+
     Ok(())
 }
 
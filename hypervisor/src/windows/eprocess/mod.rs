@@ -1,14 +1,21 @@
 use {
     crate::{
-        intel::{addresses::PhysicalAddress, hooks::hook_manager::SHARED_HOOK_MANAGER},
-        windows::nt::{
-            pe::{djb2_hash, get_export_by_hash},
-            types::{UNICODE_STRING, _LIST_ENTRY},
+        error::HypervisorError,
+        intel::{
+            addresses::{AccessMode, GuestMemory},
+            hooks::hook_manager::SHARED_HOOK_MANAGER,
+        },
+        windows::{
+            nt::{
+                pe::{djb2_hash, get_export_by_hash},
+                types::_LIST_ENTRY,
+            },
+            peb::{get_loaded_modules, get_loaded_modules_wow64, LoadedModule},
         },
     },
-    alloc::string::String,
+    alloc::{string::String, vec::Vec},
+    core::mem::offset_of,
     log::*,
-    widestring::U16CStr,
     x86::{bits64::vmx::vmread, vmx::vmcs},
 };
 
@@ -20,6 +27,43 @@ const IMAGE_FILE_POINTER_OFFSET: u64 = 0x5a0;
 const IMAGE_FILE_NAME_OFFSET: u64 = 0x58;
 const DIRECTORY_TABLE_BASE_OFFSET: u64 = 0x28;
 const ACTIVE_PROCESS_LINKS_OFFSET: u64 = 0x448;
+const USER_DIRECTORY_TABLE_BASE_OFFSET: u64 = 0x388;
+const INHERITED_FROM_UNIQUE_PROCESS_ID_OFFSET: u64 = 0x540;
+const IMAGE_FILE_NAME_SHORT_OFFSET: u64 = 0x5a8;
+const PEB_OFFSET: u64 = 0x550;
+const WOW64_PROCESS_OFFSET: u64 = 0x580;
+const WOW64_PROCESS_PEB_OFFSET: u64 = 0x0;
+const WIN32_PROCESS_OFFSET: u64 = 0x508;
+const SESSION_OFFSET: u64 = 0x558;
+const SESSION_ID_OFFSET: u64 = 0x8;
+
+/// Length of the `_EPROCESS.ImageFileName` array.
+const IMAGE_FILE_NAME_SHORT_LENGTH: usize = 15;
+
+/// Upper bound on the number of processes walked, so a corrupted list cannot keep the hypervisor looping forever.
+const MAX_ACTIVE_PROCESSES: usize = 0x10000;
+
+/// Struct representing a process found by walking `ActiveProcessLinks`
+#[derive(Debug, Clone, Copy)]
+pub struct ActiveProcess {
+    /// The guest virtual address of the `_EPROCESS` structure.
+    pub eprocess: u64,
+
+    /// The unique process ID of the process.
+    pub unique_process_id: u64,
+
+    /// The unique process ID of the parent process.
+    pub parent_process_id: u64,
+
+    /// The directory table base of the process (kernel CR3).
+    pub directory_table_base: u64,
+
+    /// The user directory table base of the process (user CR3 with KVA shadowing, otherwise 0).
+    pub user_directory_table_base: u64,
+
+    /// The short image file name of the process (`_EPROCESS.ImageFileName`), NUL padded.
+    pub image_file_name: [u8; IMAGE_FILE_NAME_SHORT_LENGTH + 1],
+}
 
 /// Struct representing process information
 #[derive(Debug)]
@@ -69,33 +113,20 @@ impl ProcessInformation {
         // Retrieve the physical address of the current process (_EPROCESS structure).
         let process = Self::ps_get_current_process()?;
 
-        // Read the image file pointer from the _EPROCESS structure.
-        let image_file_pointer = PhysicalAddress::read_guest_virt_with_current_cr3((process + IMAGE_FILE_POINTER_OFFSET) as *const u64)?;
+        let memory = Self::kernel_memory();
 
-        if image_file_pointer == 0 {
-            return None;
-        }
-
-        // Read the image file name from the _FILE_OBJECT structure.
-        let image_file_name =
-            unsafe { &*(PhysicalAddress::pa_from_va_with_current_cr3(image_file_pointer + IMAGE_FILE_NAME_OFFSET).ok()? as *const UNICODE_STRING) };
-
-        // Read the image file name bytes from the UNICODE_STRING structure.
-        let image_file_name_buffer =
-            PhysicalAddress::read_guest_virt_slice_with_current_cr3(image_file_name.Buffer, image_file_name.MaximumLength as usize / 2)?;
-
-        // Convert the image file name bytes to a string.
-        let file_name = U16CStr::from_slice_truncate(image_file_name_buffer).ok()?.to_string().ok()?;
+        // Read the image file name from the _FILE_OBJECT structure of the process.
+        let file_name = Self::read_image_file_name(process)?;
 
         // Read the directory table base (CR3) from the _KPROCESS structure within _EPROCESS.
-        let directory_table_base = PhysicalAddress::read_guest_virt_with_current_cr3((process + DIRECTORY_TABLE_BASE_OFFSET) as *const u64)?;
+        let directory_table_base = memory.read::<u64>(process + DIRECTORY_TABLE_BASE_OFFSET).ok()?;
 
         if directory_table_base == 0 {
             return None;
         }
 
         // Read the unique process ID from the _EPROCESS structure.
-        let unique_process_id = PhysicalAddress::read_guest_virt_with_current_cr3((process + UNIQUE_PROCESS_ID_OFFSET) as *const u64)?;
+        let unique_process_id = memory.read::<u64>(process + UNIQUE_PROCESS_ID_OFFSET).ok()?;
 
         // Return the populated ProcessInformation struct.
         Some(Self {
@@ -105,6 +136,57 @@ impl ProcessInformation {
         })
     }
 
+    /// Gets the unique process ID of the current process, without reading its image file name.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The `UniqueProcessId` of the current `_EPROCESS`, or `None` if it could not be read.
+    ///
+    /// # Example
+    ///
+    /// struct _EPROCESS
+    ///     VOID* UniqueProcessId;                                                  //0x440
+    pub fn get_current_process_id() -> Option<u64> {
+        let process = Self::ps_get_current_process()?;
+
+        Self::kernel_memory().read::<u64>(process + UNIQUE_PROCESS_ID_OFFSET).ok()
+    }
+
+    /// Reads the image file name of a process from the `_FILE_OBJECT` its `ImageFilePointer` refers to.
+    ///
+    /// # Arguments
+    ///
+    /// * `process` - The guest virtual address of the `_EPROCESS` structure.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<String>` - The full path of the image, such as `\Windows\System32\notepad.exe`, or `None` if the
+    ///   process has no image file (e.g. the SYSTEM process) or it could not be read.
+    ///
+    /// # Example
+    ///
+    /// struct _EPROCESS
+    ///     struct _FILE_OBJECT* ImageFilePointer;                                  //0x5a0
+    ///
+    /// struct _FILE_OBJECT
+    ///     struct _UNICODE_STRING FileName;                                        //0x58
+    fn read_image_file_name(process: u64) -> Option<String> {
+        let memory = Self::kernel_memory();
+
+        // Read the image file pointer from the _EPROCESS structure.
+        let image_file_pointer = memory.read::<u64>(process + IMAGE_FILE_POINTER_OFFSET).ok()?;
+
+        if image_file_pointer == 0 {
+            return None;
+        }
+
+        // Read the image file name from the _FILE_OBJECT structure.
+        let image_file_name = memory.read_unicode_string(image_file_pointer + IMAGE_FILE_NAME_OFFSET).ok()?;
+
+        // Convert the image file name characters to a string.
+        String::from_utf16(&image_file_name).ok()
+    }
+
     /// Manually implemented version of the `PsGetCurrentProcess` function.
     ///
     /// This function mimics the behavior of the Windows `PsGetCurrentProcess` function,
@@ -141,7 +223,7 @@ impl ProcessInformation {
         }
 
         // Compute the address of the current thread.
-        let current_thread = PhysicalAddress::read_guest_virt_with_current_cr3((gs + THREAD_OFFSET) as *const u64)?;
+        let current_thread = Self::kernel_memory().read::<u64>(gs + THREAD_OFFSET).ok()?;
         trace!("Current thread address: {:#x}", current_thread);
 
         if current_thread == 0 {
@@ -149,7 +231,7 @@ impl ProcessInformation {
         }
 
         // Compute the address of the _EPROCESS structure.
-        let current_process = PhysicalAddress::read_guest_virt_with_current_cr3((current_thread + THREAD_PROCESS_OFFSET) as *const u64)?;
+        let current_process = Self::kernel_memory().read::<u64>(current_thread + THREAD_PROCESS_OFFSET).ok()?;
         trace!("Current process address: {:#x}", current_process);
 
         if current_process == 0 {
@@ -159,12 +241,91 @@ impl ProcessInformation {
         Some(current_process)
     }
 
-    /// Retrieves the process ID of a process by its process ID.
+    /// Retrieves the `_EPROCESS` address of a process by its process ID.
     ///
     /// # Arguments
     ///
     /// * `process_id` - The process ID of the process to retrieve.
     ///
+    /// # Returns
+    ///
+    /// * `Option<u64>` - The guest virtual address of the `_EPROCESS` structure of the specified process, or `None` if not found.
+    fn get_process_by_process_id(process_id: u64) -> Option<u64> {
+        trace!("Searching for process with ID: {:#x}", process_id);
+
+        let memory = Self::kernel_memory();
+        let process = Self::get_active_process_list()?
+            .into_iter()
+            .find(|&process| memory.read::<u64>(process + UNIQUE_PROCESS_ID_OFFSET).ok() == Some(process_id));
+
+        match process {
+            Some(_) => trace!("Found process with ID: {:#x}", process_id),
+            None => trace!("Process with ID: {:#x} not found", process_id),
+        }
+
+        process
+    }
+
+    /// Retrieves every process linked into `ActiveProcessLinks`.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<Vec<ActiveProcess>>` - The processes in list order, or `None` if the list could not be walked.
+    ///   Processes whose fields cannot be read (e.g. while being torn down) are skipped.
+    pub fn enumerate_active_processes() -> Option<Vec<ActiveProcess>> {
+        let processes = Self::get_active_process_list()?;
+
+        Some(processes.into_iter().filter_map(Self::read_active_process).collect())
+    }
+
+    /// Reads the fields reported by `enumerate_active_processes` from an `_EPROCESS` structure.
+    ///
+    /// # Arguments
+    ///
+    /// * `process` - The guest virtual address of the `_EPROCESS` structure.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ActiveProcess>` - The process information, or `None` if any field could not be read.
+    ///
+    /// # Example
+    ///
+    /// struct _EPROCESS
+    ///     struct _KPROCESS Pcb;                                                   //0x0
+    ///     VOID* UniqueProcessId;                                                  //0x440
+    ///     VOID* InheritedFromUniqueProcessId;                                     //0x540
+    ///     UCHAR ImageFileName[15];                                                //0x5a8
+    ///
+    /// struct _KPROCESS
+    ///     ULONGLONG DirectoryTableBase;                                           //0x28
+    ///     ULONGLONG UserDirectoryTableBase;                                       //0x388
+    fn read_active_process(process: u64) -> Option<ActiveProcess> {
+        let memory = Self::kernel_memory();
+        let unique_process_id = memory.read::<u64>(process + UNIQUE_PROCESS_ID_OFFSET).ok()?;
+        let parent_process_id = memory.read::<u64>(process + INHERITED_FROM_UNIQUE_PROCESS_ID_OFFSET).ok()?;
+        let directory_table_base = memory.read::<u64>(process + DIRECTORY_TABLE_BASE_OFFSET).ok()?;
+        let user_directory_table_base = memory.read::<u64>(process + USER_DIRECTORY_TABLE_BASE_OFFSET).ok()?;
+
+        let mut image_file_name = [0u8; IMAGE_FILE_NAME_SHORT_LENGTH + 1];
+        memory
+            .read_into(process + IMAGE_FILE_NAME_SHORT_OFFSET, &mut image_file_name[..IMAGE_FILE_NAME_SHORT_LENGTH])
+            .ok()?;
+
+        Some(ActiveProcess {
+            eprocess: process,
+            unique_process_id,
+            parent_process_id,
+            directory_table_base,
+            user_directory_table_base,
+            image_file_name,
+        })
+    }
+
+    /// Walks `ActiveProcessLinks` and collects the address of every `_EPROCESS` structure.
+    ///
+    /// The walk starts at the list head (`PsActiveProcessHead`), found through the `Blink` of the
+    /// SYSTEM process, so the head itself is never mistaken for a process.
+    ///
     /// # Example
     ///
     /// struct _EPROCESS
@@ -175,72 +336,216 @@ impl ProcessInformation {
     ///
     /// # Returns
     ///
-    /// * `Option<u64>` - The physical address of the `_EPROCESS` structure of the specified process, or `None` if not found.
-    fn get_process_by_process_id(process_id: u64) -> Option<u64> {
-        trace!("Searching for process with ID: {:#x}", process_id);
-
+    /// * `Option<Vec<u64>>` - The guest virtual addresses of the `_EPROCESS` structures, or `None` if the list could not be walked.
+    fn get_active_process_list() -> Option<Vec<u64>> {
         // Lock the shared hook manager
         let hook_manager = SHARED_HOOK_MANAGER.lock();
         trace!("Hook manager locked");
 
-        // Get the base address of the NT kernel module.
+        // Get the address of the PsInitialSystemProcess export.
         let ps_initial_system_process = unsafe {
             get_export_by_hash(hook_manager.ntoskrnl_base_pa as _, hook_manager.ntoskrnl_base_va as _, djb2_hash("PsInitialSystemProcess".as_bytes()))
         }?;
 
         trace!("PsInitialSystemProcess address: {:#p}", ps_initial_system_process);
 
-        // Retrieve the physical address of the SYSTEM process (_EPROCESS structure).
-        let start_process = PhysicalAddress::read_guest_virt_with_current_cr3(ps_initial_system_process as *const u64)?;
-        trace!("Current process address: {:#x}", start_process);
+        let memory = Self::kernel_memory();
+        let flink = |links: u64| memory.read::<u64>(links + offset_of!(_LIST_ENTRY, Flink) as u64).ok();
+
+        // Retrieve the address of the SYSTEM process (_EPROCESS structure).
+        let system_process = memory.read::<u64>(ps_initial_system_process as u64).ok()?;
+        trace!("System process address: {:#x}", system_process);
 
-        let mut current_process = start_process;
+        // The SYSTEM process is the first entry, so its Blink is the list head.
+        let list_head = memory
+            .read::<u64>(system_process + ACTIVE_PROCESS_LINKS_OFFSET + offset_of!(_LIST_ENTRY, Blink) as u64)
+            .ok()?;
 
-        loop {
-            // Read the unique process ID from the _EPROCESS structure.
-            let unique_process_id = PhysicalAddress::read_guest_virt_with_current_cr3((current_process + UNIQUE_PROCESS_ID_OFFSET) as *const u64)?;
-            trace!("Checking process with ID: {:#x}", unique_process_id);
+        let mut processes = Vec::new();
+        let mut current_links = flink(list_head)?;
 
-            // Check if the current process ID matches the specified process ID
-            if unique_process_id == process_id {
-                trace!("Found process with ID: {:#x}", process_id);
-                return Some(current_process);
+        while current_links != list_head {
+            if processes.len() >= MAX_ACTIVE_PROCESSES {
+                error!("ActiveProcessLinks has more than {} entries, stopping the walk", MAX_ACTIVE_PROCESSES);
+                break;
             }
 
-            trace!("Moving to the next process");
+            processes.push(current_links - ACTIVE_PROCESS_LINKS_OFFSET);
+
             // Move to the next process in the list by following the Flink pointer.
-            let next_process_links =
-                PhysicalAddress::read_guest_virt_with_current_cr3((current_process + ACTIVE_PROCESS_LINKS_OFFSET) as *const _LIST_ENTRY)?;
-            current_process = next_process_links.Flink as u64 - ACTIVE_PROCESS_LINKS_OFFSET;
+            current_links = flink(current_links)?;
+        }
 
-            trace!("Next process address: {:#x}", current_process);
+        trace!("Found {} active processes", processes.len());
 
-            // If we've looped back to the starting process, exit the loop.
-            if current_process == start_process {
-                trace!("Reached the end of the process list");
-                break;
+        Some(processes)
+    }
+
+    /// Retrieves the modules loaded into a process by walking its loader data.
+    ///
+    /// The 64-bit `PEB` is walked first; for WOW64 processes the modules of the 32-bit `PEB` follow.
+    /// All user mode memory is read through the directory table base of the process.
+    ///
+    /// # Arguments
+    ///
+    /// * `process_id` - The process ID of the process.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<Vec<LoadedModule>, HypervisorError>` - The modules, `ProcessNotFound` if no process has this ID,
+    ///   or `GuestMemoryAccessFailed` if the loader data could not be read (e.g. paged out or not initialized yet).
+    ///
+    /// # Example
+    ///
+    /// struct _EPROCESS
+    ///     struct _PEB* Peb;                                                       //0x550
+    ///     struct _EWOW64PROCESS* WoW64Process;                                    //0x580
+    ///
+    /// struct _EWOW64PROCESS
+    ///     VOID* Peb;                                                              //0x0
+    pub fn get_process_modules(process_id: u64) -> Result<Vec<LoadedModule>, HypervisorError> {
+        let process = Self::get_process_by_process_id(process_id).ok_or(HypervisorError::ProcessNotFound)?;
+
+        let memory = Self::kernel_memory();
+        let read = |offset: u64| memory.read::<u64>(process + offset);
+
+        let directory_table_base = read(DIRECTORY_TABLE_BASE_OFFSET)?;
+        let peb = read(PEB_OFFSET)?;
+        let wow64_process = read(WOW64_PROCESS_OFFSET)?;
+
+        trace!("Process {:#x} PEB: {:#x}, WoW64Process: {:#x}", process_id, peb, wow64_process);
+
+        if peb == 0 {
+            // The SYSTEM process and minimal processes have no user mode loader data.
+            return Ok(Vec::new());
+        }
+
+        let mut modules = get_loaded_modules(peb, directory_table_base)?;
+
+        if wow64_process != 0 {
+            let peb32 = memory.read::<u64>(wow64_process + WOW64_PROCESS_PEB_OFFSET)?;
+
+            if peb32 != 0 {
+                modules.extend(get_loaded_modules_wow64(peb32, directory_table_base)?);
             }
         }
 
-        None
+        Ok(modules)
     }
 
-    /// Retrieves the directory table base (CR3) of a process by its process ID.
+    /// Retrieves an active process by its process ID.
     ///
     /// # Arguments
     ///
-    /// * `process_id` - The process ID of the process to retrieve the directory table base from.
+    /// * `process_id` - The process ID of the process.
     ///
     /// # Returns
     ///
-    /// * `Option<u64>` - The directory table base (CR3) of the process, or `None` if not found.
-    pub fn get_directory_table_base_by_process_id(process_id: u64) -> Option<u64> {
-        // Retrieve the physical address of the process by its process ID.
-        let process = Self::get_process_by_process_id(process_id)?;
+    /// * `Option<ActiveProcess>` - The process, or `None` if not found.
+    pub fn get_active_process_by_process_id(process_id: u64) -> Option<ActiveProcess> {
+        Self::read_active_process(Self::get_process_by_process_id(process_id)?)
+    }
 
-        trace!("Reading Guest Virtual Address");
+    /// Retrieves an active process by the address of its `_EPROCESS` structure.
+    ///
+    /// The address is only trusted if it is linked into `ActiveProcessLinks`.
+    ///
+    /// # Arguments
+    ///
+    /// * `eprocess` - The guest virtual address of the `_EPROCESS` structure.
+    ///
+    /// # Returns
+    ///
+    /// * `Option<ActiveProcess>` - The process, or `None` if no active process has this `_EPROCESS` address.
+    pub fn get_active_process_by_eprocess(eprocess: u64) -> Option<ActiveProcess> {
+        if !Self::get_active_process_list()?.contains(&eprocess) {
+            trace!("{:#x} is not an active _EPROCESS", eprocess);
+            return None;
+        }
 
-        // Read the directory table base (CR3) from the _KPROCESS structure within _EPROCESS.
-        PhysicalAddress::read_guest_virt_with_current_cr3((process + DIRECTORY_TABLE_BASE_OFFSET) as *const u64)
+        Self::read_active_process(eprocess)
+    }
+
+    /// Retrieves an active process by its image name.
+    ///
+    /// The name is compared case-insensitively against the full image path decoded by `read_image_file_name`
+    /// and against its file name, so both `notepad.exe` and `\Windows\System32\notepad.exe` match.
+    ///
+    /// # Arguments
+    ///
+    /// * `image_name` - The image name of the process.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<ActiveProcess, HypervisorError>` - The process, `ProcessNotFound` if no process matches,
+    ///   or `AmbiguousProcessName` if more than one process matches.
+    pub fn get_active_process_by_image_name(image_name: &str) -> Result<ActiveProcess, HypervisorError> {
+        trace!("Searching for process with image name: {}", image_name);
+
+        let mut matches = Self::get_active_process_list()
+            .ok_or(HypervisorError::ProcessNotFound)?
+            .into_iter()
+            .filter(|&process| {
+                Self::read_image_file_name(process).is_some_and(|file_name| {
+                    let base_name = file_name.rsplit('\\').next().unwrap_or_default();
+                    file_name.eq_ignore_ascii_case(image_name) || base_name.eq_ignore_ascii_case(image_name)
+                })
+            });
+
+        let process = matches.next().ok_or(HypervisorError::ProcessNotFound)?;
+
+        if matches.next().is_some() {
+            error!("More than one process matches image name: {}", image_name);
+            return Err(HypervisorError::AmbiguousProcessName);
+        }
+
+        Self::read_active_process(process).ok_or(HypervisorError::GuestMemoryAccessFailed)
+    }
+
+    /// Finds the directory table base of a GUI process in a session.
+    ///
+    /// Session space, where win32k lives, is only mapped in the address spaces of the processes of its session,
+    /// and win32k is only guaranteed to be mapped once the process has been converted to a GUI process.
+    ///
+    /// # Arguments
+    ///
+    /// * `session_id` - The ID of the session.
+    ///
+    /// # Returns
+    ///
+    /// * `Result<u64, HypervisorError>` - The directory table base, or `SessionNotFound` if the session has no GUI process.
+    ///
+    /// # Example
+    ///
+    /// struct _EPROCESS
+    ///     VOID* Win32Process;                                                     //0x508
+    ///     VOID* Session;                                                          //0x558
+    ///
+    /// struct _MM_SESSION_SPACE
+    ///     ULONG SessionId;                                                        //0x8
+    pub fn get_session_directory_table_base(session_id: u32) -> Result<u64, HypervisorError> {
+        trace!("Searching for a GUI process in session: {}", session_id);
+
+        let memory = Self::kernel_memory();
+
+        let process = Self::get_active_process_list()
+            .ok_or(HypervisorError::SessionNotFound)?
+            .into_iter()
+            .find(|&process| {
+                let read = |offset: u64| memory.read::<u64>(process + offset).ok().filter(|&value| value != 0);
+
+                read(WIN32_PROCESS_OFFSET).is_some()
+                    && read(SESSION_OFFSET).is_some_and(|session| memory.read::<u32>(session + SESSION_ID_OFFSET).ok() == Some(session_id))
+            })
+            .ok_or(HypervisorError::SessionNotFound)?;
+
+        trace!("Found GUI process {:#x} in session: {}", process, session_id);
+
+        memory.read::<u64>(process + DIRECTORY_TABLE_BASE_OFFSET)
+    }
+
+    /// Returns a view of kernel memory through the current guest CR3, used to read the kernel process structures.
+    fn kernel_memory() -> GuestMemory {
+        GuestMemory::with_current_cr3(AccessMode::Kernel)
     }
 }
@@ -1,6 +1,5 @@
 use {crate::intel::capture::GuestRegisters, log::info};
 
-#[allow(dead_code)]
 pub fn log_mm_is_address_valid_params(regs: &GuestRegisters) {
     info!(
         "MmIsAddressValid called with parameters:\n\
@@ -9,7 +8,6 @@ pub fn log_mm_is_address_valid_params(regs: &GuestRegisters) {
     );
 }
 
-#[allow(dead_code)]
 pub fn log_nt_query_system_information_params(regs: &GuestRegisters) {
     info!(
         "NtQuerySystemInformation called with parameters: SystemInformationClass: {}, \
@@ -81,7 +79,6 @@ pub fn system_information_class_name(class: u32) -> &'static str {
     }
 }
 
-#[allow(dead_code)]
 pub fn log_nt_create_file_params(regs: &GuestRegisters) {
     info!(
         "NtCreateFile called with parameters:\n\
@@ -103,7 +100,6 @@ pub fn log_nt_create_file_params(regs: &GuestRegisters) {
     );
 }
 
-#[allow(dead_code)]
 pub fn log_nt_open_process_params(regs: &GuestRegisters) {
     info!(
         "NtOpenProcess called with parameters:\n\
@@ -117,3 +113,18 @@ pub fn log_nt_open_process_params(regs: &GuestRegisters) {
         regs.r9   // ClientId, pointer to a CLIENT_ID structure (which typically includes a PID)
     );
 }
+
+pub fn log_nt_status_result(function_name: &str, regs: &GuestRegisters) {
+    info!(
+        "{} returned NTSTATUS: {:#010x}",
+        function_name,
+        regs.rax as u32 // NTSTATUS, returned in EAX
+    );
+}
+
+pub fn log_nt_handle_result(function_name: &str, regs: &GuestRegisters, handle: Option<u64>) {
+    match handle {
+        Some(handle) if regs.rax as u32 == 0 => info!("{} returned NTSTATUS: {:#010x}, Handle: {:#x}", function_name, regs.rax as u32, handle),
+        _ => log_nt_status_result(function_name, regs),
+    }
+}
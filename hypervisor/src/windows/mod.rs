@@ -1,4 +1,5 @@
 pub mod eprocess;
 pub mod log;
 pub mod nt;
+pub mod peb;
 pub mod ssdt;
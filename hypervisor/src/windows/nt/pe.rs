@@ -4,14 +4,27 @@
 use {
     crate::{
         error::HypervisorError,
-        intel::addresses::PhysicalAddress,
+        intel::addresses::{GuestMemory, PhysicalAddress},
         windows::nt::types::{
-            IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DOS_SIGNATURE, IMAGE_NT_SIGNATURE, PIMAGE_DOS_HEADER, PIMAGE_EXPORT_DIRECTORY, PIMAGE_NT_HEADERS64,
+            IMAGE_DATA_DIRECTORY, IMAGE_DIRECTORY_ENTRY_EXPORT, IMAGE_DOS_HEADER, IMAGE_DOS_SIGNATURE, IMAGE_EXPORT_DIRECTORY, IMAGE_NT_HEADERS64,
+            IMAGE_NT_SIGNATURE, IMAGE_OPTIONAL_HEADER64, PIMAGE_DOS_HEADER, PIMAGE_EXPORT_DIRECTORY, PIMAGE_NT_HEADERS64,
         },
     },
-    core::slice::from_raw_parts,
+    alloc::vec::Vec,
+    core::{mem::offset_of, slice::from_raw_parts},
+    shared::Pod,
+    x86::bits64::paging::BASE_PAGE_SIZE,
 };
 
+/// Upper bound on the number of named exports read from a guest image, so a corrupted header cannot exhaust the heap.
+const MAX_GUEST_EXPORTS: usize = 0x10000;
+
+/// Upper bound on the length of an export name read from a guest image.
+const MAX_GUEST_EXPORT_NAME_LENGTH: usize = 0x200;
+
+unsafe impl Pod for IMAGE_DATA_DIRECTORY {}
+unsafe impl Pod for IMAGE_EXPORT_DIRECTORY {}
+
 /// Get a pointer to IMAGE_DOS_HEADER
 ///
 /// # Arguments
@@ -93,6 +106,106 @@ pub unsafe fn get_export_by_hash(module_base_pa: *mut u8, module_base_va: u64, e
     return None;
 }
 
+/// Get the address of an export by hash, reading the image through guest virtual memory
+///
+/// Unlike `get_export_by_hash`, the image does not have to be physically contiguous, so this works for
+/// any loaded driver. Forwarded exports are not resolved.
+///
+/// # Arguments
+///
+/// * `memory` - The guest memory the image is mapped in.
+/// * `module_base` - The guest virtual base address of the module.
+/// * `export_hash` - The hash of the export.
+///
+/// # Returns
+///
+/// * `Option<u64>` - The guest virtual address of the export, or `None` if it was not found or the image could not be read.
+pub fn get_guest_export_by_hash(memory: &GuestMemory, module_base: u64, export_hash: u32) -> Option<u64> {
+    if memory.read::<u16>(module_base).ok()? != IMAGE_DOS_SIGNATURE {
+        return None;
+    }
+
+    let e_lfanew = memory.read::<u32>(module_base + offset_of!(IMAGE_DOS_HEADER, e_lfanew) as u64).ok()? as i32;
+    let nt_headers = module_base.wrapping_add(e_lfanew as i64 as u64);
+
+    if memory.read::<u32>(nt_headers).ok()? != IMAGE_NT_SIGNATURE {
+        return None;
+    }
+
+    let export_data_directory = memory
+        .read::<IMAGE_DATA_DIRECTORY>(
+            nt_headers
+                + (offset_of!(IMAGE_NT_HEADERS64, OptionalHeader)
+                    + offset_of!(IMAGE_OPTIONAL_HEADER64, DataDirectory)
+                    + IMAGE_DIRECTORY_ENTRY_EXPORT as usize * size_of::<IMAGE_DATA_DIRECTORY>()) as u64,
+        )
+        .ok()?;
+
+    let export_directory = memory
+        .read::<IMAGE_EXPORT_DIRECTORY>(module_base + export_data_directory.VirtualAddress as u64)
+        .ok()?;
+
+    let number_of_names = export_directory.NumberOfNames as usize;
+    if number_of_names > MAX_GUEST_EXPORTS {
+        return None;
+    }
+
+    let names = memory
+        .read_vec::<u32>(module_base + export_directory.AddressOfNames as u64, number_of_names)
+        .ok()?;
+
+    let index = names
+        .iter()
+        .position(|name| read_guest_export_name(memory, module_base + *name as u64).is_some_and(|name| djb2_hash(&name) == export_hash))?;
+
+    let ordinal = memory
+        .read::<u16>(module_base + export_directory.AddressOfNameOrdinals as u64 + index as u64 * size_of::<u16>() as u64)
+        .ok()?;
+    let function = memory
+        .read::<u32>(module_base + export_directory.AddressOfFunctions as u64 + ordinal as u64 * size_of::<u32>() as u64)
+        .ok()?;
+
+    // The address of a forwarded export points at the name of its target inside the export directory.
+    let export_directory_range = export_data_directory.VirtualAddress..export_data_directory.VirtualAddress + export_data_directory.Size;
+    if export_directory_range.contains(&function) {
+        return None;
+    }
+
+    Some(module_base + function as u64)
+}
+
+/// Reads the null-terminated name of an export from guest virtual memory, without the terminator.
+///
+/// # Arguments
+///
+/// * `memory` - The guest memory the image is mapped in.
+/// * `name_va` - The guest virtual address of the name.
+///
+/// # Returns
+///
+/// * `Option<Vec<u8>>` - The name, or `None` if it could not be read or is longer than `MAX_GUEST_EXPORT_NAME_LENGTH`.
+fn read_guest_export_name(memory: &GuestMemory, name_va: u64) -> Option<Vec<u8>> {
+    let mut name = Vec::new();
+    let mut chunk = [0u8; 64];
+
+    while name.len() < MAX_GUEST_EXPORT_NAME_LENGTH {
+        let va = name_va + name.len() as u64;
+        // Never read past the end of the page, the next one may not be mapped.
+        let len = chunk.len().min(BASE_PAGE_SIZE - (va as usize & (BASE_PAGE_SIZE - 1)));
+        memory.read_into(va, &mut chunk[..len]).ok()?;
+
+        match chunk[..len].iter().position(|byte| *byte == 0) {
+            Some(end) => {
+                name.extend_from_slice(&chunk[..end]);
+                return Some(name);
+            }
+            None => name.extend_from_slice(&chunk[..len]),
+        }
+    }
+
+    None
+}
+
 /// Get the size of an image
 ///
 /// # Arguments
@@ -185,12 +185,14 @@ pub struct IMAGE_OPTIONAL_HEADER64 {
 }
 
 #[repr(C)]
+#[derive(Debug, Copy, Clone)]
 pub struct IMAGE_DATA_DIRECTORY {
     pub VirtualAddress: u32,
     pub Size: u32,
 }
 
 #[repr(C)]
+#[derive(Debug, Copy, Clone)]
 pub struct IMAGE_EXPORT_DIRECTORY {
     pub Characteristics: u32,
     pub TimeDateStamp: u32,
@@ -0,0 +1,216 @@
+//! Walks the loader data of a user mode process to enumerate its modules.
+//!
+//! Both the native 64-bit `PEB` and, for WOW64 processes, the 32-bit `PEB` are supported.
+//! All user mode memory is read through the directory table base of the target process.
+//! The kernel's `PsLoadedModuleList` links entries with the same 64-bit layout, so it is walked the same way.
+
+use {
+    crate::{
+        error::HypervisorError,
+        intel::addresses::{AccessMode, GuestMemory},
+        windows::nt::pe::djb2_hash,
+    },
+    alloc::vec::Vec,
+    log::*,
+    shared::Pod,
+};
+
+/// Offsets in the 64-bit loader structures
+const PEB_LDR_OFFSET: u64 = 0x18;
+const LDR_IN_LOAD_ORDER_MODULE_LIST_OFFSET: u64 = 0x10;
+const LDR_ENTRY_DLL_BASE_OFFSET: u64 = 0x30;
+const LDR_ENTRY_SIZE_OF_IMAGE_OFFSET: u64 = 0x40;
+const LDR_ENTRY_BASE_DLL_NAME_OFFSET: u64 = 0x58;
+
+/// Offsets in the 32-bit loader structures
+const PEB32_LDR_OFFSET: u64 = 0xC;
+const LDR32_IN_LOAD_ORDER_MODULE_LIST_OFFSET: u64 = 0xC;
+const LDR32_ENTRY_DLL_BASE_OFFSET: u64 = 0x18;
+const LDR32_ENTRY_SIZE_OF_IMAGE_OFFSET: u64 = 0x20;
+const LDR32_ENTRY_BASE_DLL_NAME_OFFSET: u64 = 0x2C;
+
+/// Upper bound on the number of modules walked, so a corrupted list cannot keep the hypervisor looping forever.
+const MAX_LOADED_MODULES: usize = 0x1000;
+
+/// Struct representing a module found in the loader data of a process
+#[derive(Debug, Clone)]
+pub struct LoadedModule {
+    /// The base address of the module.
+    pub base: u64,
+
+    /// The size of the module image in bytes.
+    pub size: u64,
+
+    /// The base name of the module as UTF-16, without a terminator.
+    pub name: Vec<u16>,
+
+    /// Whether the module was found in the 32-bit loader data of a WOW64 process.
+    pub wow64: bool,
+}
+
+impl LoadedModule {
+    /// Returns the `djb2_hash` of the base name, which matches the hash of the same ASCII name computed by the client.
+    pub fn name_hash(&self) -> u32 {
+        // `djb2_hash` skips zero bytes, so the high bytes of ASCII characters do not change the hash.
+        let name: Vec<u8> = self.name.iter().flat_map(|c| c.to_le_bytes()).collect();
+        djb2_hash(&name)
+    }
+}
+
+/// Layout of a `_UNICODE_STRING32` in a WOW64 process.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+struct UnicodeString32 {
+    length: u16,
+    maximum_length: u16,
+    buffer: u32,
+}
+
+unsafe impl Pod for UnicodeString32 {}
+
+/// Walks `PEB.Ldr->InLoadOrderModuleList` of a 64-bit process.
+///
+/// # Arguments
+///
+/// * `peb` - The guest virtual address of the 64-bit `PEB`.
+/// * `guest_cr3` - The directory table base of the process.
+///
+/// # Returns
+///
+/// * `Result<Vec<LoadedModule>, HypervisorError>` - The modules in load order, or `GuestMemoryAccessFailed` if the list could not be walked.
+///
+/// # Example
+///
+/// struct _PEB
+///     struct _PEB_LDR_DATA* Ldr;                                              //0x18
+///
+/// struct _PEB_LDR_DATA
+///     struct _LIST_ENTRY InLoadOrderModuleList;                               //0x10
+///
+/// struct _LDR_DATA_TABLE_ENTRY
+///     struct _LIST_ENTRY InLoadOrderLinks;                                    //0x0
+///     VOID* DllBase;                                                          //0x30
+///     ULONG SizeOfImage;                                                      //0x40
+///     struct _UNICODE_STRING BaseDllName;                                     //0x58
+pub fn get_loaded_modules(peb: u64, guest_cr3: u64) -> Result<Vec<LoadedModule>, HypervisorError> {
+    trace!("Walking 64-bit loader data of PEB: {:#x}", peb);
+
+    let ldr = GuestMemory::new(guest_cr3, AccessMode::Kernel).read::<u64>(peb + PEB_LDR_OFFSET)?;
+
+    get_loaded_modules_from_list(ldr + LDR_IN_LOAD_ORDER_MODULE_LIST_OFFSET, guest_cr3)
+}
+
+/// Walks a list of 64-bit `_LDR_DATA_TABLE_ENTRY` structures linked through `InLoadOrderLinks`.
+///
+/// Besides `PEB.Ldr->InLoadOrderModuleList`, this walks the kernel's `PsLoadedModuleList`, whose
+/// `_KLDR_DATA_TABLE_ENTRY` structures place `DllBase`, `SizeOfImage` and `BaseDllName` at the same offsets.
+///
+/// # Arguments
+///
+/// * `list_head` - The guest virtual address of the list head.
+/// * `guest_cr3` - The directory table base the list is mapped in.
+///
+/// # Returns
+///
+/// * `Result<Vec<LoadedModule>, HypervisorError>` - The modules in load order, or `GuestMemoryAccessFailed` if the list could not be walked.
+pub fn get_loaded_modules_from_list(list_head: u64, guest_cr3: u64) -> Result<Vec<LoadedModule>, HypervisorError> {
+    let memory = GuestMemory::new(guest_cr3, AccessMode::Kernel);
+
+    walk_list(
+        list_head,
+        guest_cr3,
+        |link| memory.read::<u64>(link),
+        |entry| {
+            Ok(LoadedModule {
+                base: memory.read::<u64>(entry + LDR_ENTRY_DLL_BASE_OFFSET)?,
+                size: memory.read::<u32>(entry + LDR_ENTRY_SIZE_OF_IMAGE_OFFSET)? as u64,
+                name: memory.read_unicode_string(entry + LDR_ENTRY_BASE_DLL_NAME_OFFSET)?,
+                wow64: false,
+            })
+        },
+    )
+}
+
+/// Walks `PEB32.Ldr->InLoadOrderModuleList` of a WOW64 process.
+///
+/// # Arguments
+///
+/// * `peb32` - The guest virtual address of the 32-bit `PEB`.
+/// * `guest_cr3` - The directory table base of the process.
+///
+/// # Returns
+///
+/// * `Result<Vec<LoadedModule>, HypervisorError>` - The modules in load order, or `GuestMemoryAccessFailed` if the list could not be walked.
+///
+/// # Example
+///
+/// struct _PEB32
+///     ULONG Ldr;                                                              //0xc
+///
+/// struct _PEB_LDR_DATA32
+///     struct LIST_ENTRY32 InLoadOrderModuleList;                              //0xc
+///
+/// struct _LDR_DATA_TABLE_ENTRY32
+///     struct LIST_ENTRY32 InLoadOrderLinks;                                   //0x0
+///     ULONG DllBase;                                                          //0x18
+///     ULONG SizeOfImage;                                                      //0x20
+///     struct _STRING32 BaseDllName;                                           //0x2c
+pub fn get_loaded_modules_wow64(peb32: u64, guest_cr3: u64) -> Result<Vec<LoadedModule>, HypervisorError> {
+    trace!("Walking 32-bit loader data of PEB32: {:#x}", peb32);
+
+    let memory = GuestMemory::new(guest_cr3, AccessMode::Kernel);
+    let ldr = memory.read::<u32>(peb32 + PEB32_LDR_OFFSET)? as u64;
+    let list_head = ldr + LDR32_IN_LOAD_ORDER_MODULE_LIST_OFFSET;
+
+    walk_list(
+        list_head,
+        guest_cr3,
+        |link| memory.read::<u32>(link).map(u64::from),
+        |entry| {
+            let name = memory.read::<UnicodeString32>(entry + LDR32_ENTRY_BASE_DLL_NAME_OFFSET)?;
+
+            Ok(LoadedModule {
+                base: memory.read::<u32>(entry + LDR32_ENTRY_DLL_BASE_OFFSET)? as u64,
+                size: memory.read::<u32>(entry + LDR32_ENTRY_SIZE_OF_IMAGE_OFFSET)? as u64,
+                name: memory.read_vec::<u16>(name.buffer as u64, name.length as usize / 2)?,
+                wow64: true,
+            })
+        },
+    )
+}
+
+/// Walks a circular doubly linked list whose entries start with their links, from its head.
+///
+/// # Arguments
+///
+/// * `list_head` - The guest virtual address of the list head.
+/// * `guest_cr3` - The directory table base of the process.
+/// * `read_flink` - Reads the `Flink` of the link at the given address.
+/// * `read_entry` - Reads an entry at the given address.
+///
+/// # Returns
+///
+/// * `Result<Vec<LoadedModule>, HypervisorError>` - The entries in list order.
+fn walk_list(
+    list_head: u64,
+    guest_cr3: u64,
+    read_flink: impl Fn(u64) -> Result<u64, HypervisorError>,
+    read_entry: impl Fn(u64) -> Result<LoadedModule, HypervisorError>,
+) -> Result<Vec<LoadedModule>, HypervisorError> {
+    let mut modules = Vec::new();
+    let mut current = read_flink(list_head)?;
+
+    while current != list_head {
+        if modules.len() >= MAX_LOADED_MODULES {
+            error!("Loader list of CR3 {:#x} has more than {} entries, stopping the walk", guest_cr3, MAX_LOADED_MODULES);
+            break;
+        }
+
+        modules.push(read_entry(current)?);
+        current = read_flink(current)?;
+    }
+
+    trace!("Found {} modules", modules.len());
+
+    Ok(modules)
+}
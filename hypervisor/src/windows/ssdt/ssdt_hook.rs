@@ -5,8 +5,13 @@
 //! or security tools.
 
 use {
-    crate::{error::HypervisorError, intel::addresses::PhysicalAddress, windows::ssdt::ssdt_find::SsdtFind},
+    crate::{
+        error::HypervisorError,
+        intel::addresses::{AccessMode, GuestMemory},
+        windows::ssdt::ssdt_find::SsdtFind,
+    },
     log::*,
+    shared::WIN32K_SYSCALL_BASE,
 };
 
 /// Represents the layout of the System Service Dispatch Table (SSDT).
@@ -44,6 +49,8 @@ impl SsdtHook {
     /// * `get_from_win32k` - Whether to get the function from the Win32k table instead of the NT table.
     /// * `kernel_base` - The base address of the kernel in memory.
     /// * `kernel_size` - The size of the kernel memory space.
+    /// * `guest_cr3` - The CR3 used to read the service table. The win32k table is in session space, so it must be
+    ///   the directory table base of a process in the session.
     ///
     /// # Returns
     ///
@@ -54,6 +61,7 @@ impl SsdtHook {
         get_from_win32k: bool,
         kernel_base: *const u8,
         kernel_size: usize,
+        guest_cr3: u64,
     ) -> Result<Self, HypervisorError> {
         trace!("Finding SSDT function address");
 
@@ -66,7 +74,7 @@ impl SsdtHook {
             unsafe { &*(ssdt.nt_table as *const SSDTStruct) }
         } else {
             // Adjust the API number for Win32k syscalls, which start from 0x1000.
-            api_number = api_number - 0x1000;
+            api_number -= WIN32K_SYSCALL_BASE as i32;
             unsafe { &*(ssdt.win32k_table as *const SSDTStruct) }
         };
 
@@ -80,12 +88,16 @@ impl SsdtHook {
 
         trace!("SSDT base address: {:p}", ssdt_base_va);
 
+        if api_number < 0 || api_number as u64 >= ssdt.number_of_services {
+            error!("API number {:#x} is out of range, the SSDT has {} services", api_number, ssdt.number_of_services);
+            return Err(HypervisorError::SsdtNotFound);
+        }
+
         // Get a pointer to the target offset within the SSDT.
         // let offset = unsafe { ssdt.p_service_table.add(api_number as usize).read() as usize >> 4 }; // We can't do this because it's a guest VA.
         //
         let offset_ptr_va = unsafe { ssdt.p_service_table.add(api_number as usize) };
-        let offset_ptr_pa = PhysicalAddress::pa_from_va_with_current_cr3(offset_ptr_va as u64)? as *const i32;
-        let offset = unsafe { offset_ptr_pa.read() as usize >> 4 };
+        let offset = GuestMemory::new(guest_cr3, AccessMode::Kernel).read::<u32>(offset_ptr_va as u64)? as i32 as usize >> 4;
         trace!("SSDT function offset: {:#x}", offset);
 
         // Compute the function's address by adding its offset to the base address.
@@ -1,7 +1,33 @@
 #![no_std]
 
-/// The password used for authentication with the hypervisor.
-pub const PASSWORD: u64 = 0xDEADBEEF;
+use core::mem::size_of;
+
+/// The CPUID leaf (RAX) that marks a CPUID as a hypercall carrying a `ClientCommand` pointer in RCX.
+///
+/// This only selects the command channel; commands are authenticated by the session MAC in `CommandHeader`.
+pub const HYPERCALL_LEAF: u64 = 0xDEADBEEF;
+
+/// Magic value placed at the start of every `CommandHeader` ("ILUS" in little-endian).
+pub const PROTOCOL_MAGIC: u32 = u32::from_le_bytes(*b"ILUS");
+
+/// Version of the hypercall ABI. Bump this whenever the layout of the header or any payload changes.
+pub const PROTOCOL_VERSION: u32 = 9;
+
+/// Maximum number of commands accepted in a single `Command::Batch`.
+pub const MAX_BATCH_SIZE: u64 = 4096;
+
+/// Marker for plain-old-data types that can be exchanged across the hypercall boundary.
+///
+/// # Safety
+///
+/// Implementors must be `#[repr(C)]`, contain no padding, no pointers or references the receiver
+/// would dereference directly, and must be valid for any bit pattern.
+pub unsafe trait Pod: Copy + 'static {}
+
+unsafe impl Pod for u8 {}
+unsafe impl Pod for u16 {}
+unsafe impl Pod for u32 {}
+unsafe impl Pod for u64 {}
 
 /// Enumeration of possible commands that can be issued to the hypervisor.
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -14,7 +40,7 @@ pub enum Command {
     /// Command to disable a kernel EPT hook.
     DisableKernelEptHook = 1,
 
-    /// Command to open a process and retrieve its CR3 / directory table base.
+    /// Command to open a process by process ID, image name or `_EPROCESS` address and retrieve its directory table bases.
     OpenProcess = 2,
 
     /// Command to read the memory of a process.
@@ -23,6 +49,48 @@ pub enum Command {
     /// Command to write the memory of a process.
     WriteProcessMemory = 4,
 
+    /// Command to establish the session and retrieve its key. Refused while the process that opened the current session is running.
+    OpenSession = 5,
+
+    /// Command to execute an array of commands in a single VM exit.
+    Batch = 6,
+
+    /// Command to list the installed EPT hooks.
+    EnumerateHooks = 7,
+
+    /// Command to query the build features, state and detected capabilities of the hypervisor.
+    GetHypervisorInfo = 8,
+
+    /// Command to list the processes linked into `_EPROCESS.ActiveProcessLinks`.
+    EnumerateProcesses = 9,
+
+    /// Command to list the modules loaded into a process, walking its `PEB` loader data.
+    EnumerateModules = 10,
+
+    /// Command to read guest physical memory.
+    ReadPhysicalMemory = 11,
+
+    /// Command to write guest physical memory.
+    WritePhysicalMemory = 12,
+
+    /// Command to translate a guest virtual address, reporting every guest paging and EPT entry visited.
+    TranslateAddress = 13,
+
+    /// Command to scan a guest virtual address range for a byte pattern.
+    PatternScan = 14,
+
+    /// Command to enable an EPT hook at an arbitrary guest virtual address, translated through a given CR3.
+    EnableAddressEptHook = 15,
+
+    /// Command to disable an EPT hook installed by `EnableAddressEptHook`, identified by the same CR3 and address.
+    DisableAddressEptHook = 16,
+
+    /// Command to list the hit counters and the context of the last hit of every installed EPT hook.
+    QueryHookStatistics = 17,
+
+    /// Command to release the session, so that another client can open a new one.
+    CloseSession = 18,
+
     /// Invalid command.
     Invalid,
 }
@@ -36,6 +104,20 @@ impl Command {
             2 => Command::OpenProcess,
             3 => Command::ReadProcessMemory,
             4 => Command::WriteProcessMemory,
+            5 => Command::OpenSession,
+            6 => Command::Batch,
+            7 => Command::EnumerateHooks,
+            8 => Command::GetHypervisorInfo,
+            9 => Command::EnumerateProcesses,
+            10 => Command::EnumerateModules,
+            11 => Command::ReadPhysicalMemory,
+            12 => Command::WritePhysicalMemory,
+            13 => Command::TranslateAddress,
+            14 => Command::PatternScan,
+            15 => Command::EnableAddressEptHook,
+            16 => Command::DisableAddressEptHook,
+            17 => Command::QueryHookStatistics,
+            18 => Command::CloseSession,
             _ => Command::Invalid,
         }
     }
@@ -45,7 +127,11 @@ impl Command {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum CommandStatus {
     Success,
+    /// The command failed. The hypervisor reports the `ErrorCode` in RBX alongside this status.
     Failure,
+    /// The request did not carry the expected magic or protocol version.
+    /// The hypervisor reports its own `PROTOCOL_VERSION` in RDX alongside this status.
+    VersionMismatch,
 }
 
 impl CommandStatus {
@@ -54,6 +140,7 @@ impl CommandStatus {
         match self {
             CommandStatus::Success => 0x1,
             CommandStatus::Failure => 0x0,
+            CommandStatus::VersionMismatch => 0x2,
         }
     }
 
@@ -62,50 +149,1462 @@ impl CommandStatus {
         match value {
             0x1 => Some(CommandStatus::Success),
             0x0 => Some(CommandStatus::Failure),
+            0x2 => Some(CommandStatus::VersionMismatch),
             _ => None,
         }
     }
 }
 
+/// Declares `ErrorCode` together with its decoding function, keeping the two in sync.
+macro_rules! error_codes {
+    ($($name:ident = $value:literal,)*) => {
+        /// Stable numeric codes for every `HypervisorError` variant, returned in RBX when a command fails.
+        ///
+        /// Codes are part of the hypercall ABI: never renumber an existing code, only append new ones.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        #[repr(u64)]
+        pub enum ErrorCode {
+            $($name = $value,)*
+        }
+
+        impl ErrorCode {
+            /// Converts a `u64` value to an `ErrorCode` enum variant.
+            pub fn from_u64(value: u64) -> Option<Self> {
+                match value {
+                    $($value => Some(ErrorCode::$name),)*
+                    _ => None,
+                }
+            }
+
+            /// Converts `ErrorCode` to a u64 for returning in registers.
+            pub fn to_u64(self) -> u64 {
+                self as u64
+            }
+        }
+    };
+}
+
+error_codes! {
+    CPUUnsupported = 1,
+    VMXUnsupported = 2,
+    EPTUnsupported = 3,
+    MTRRUnsupported = 4,
+    VMXBIOSLock = 5,
+    MemoryAllocationFailed = 6,
+    VirtualToPhysicalAddressFailed = 7,
+    VMXONFailed = 8,
+    VMXOFFFailed = 9,
+    VMCLEARFailed = 10,
+    VMPTRLDFailed = 11,
+    VMREADFailed = 12,
+    VMWRITEFailed = 13,
+    VMLAUNCHFailed = 14,
+    VMRESUMEFailed = 15,
+    ProcessorSwitchFailed = 16,
+    VcpuIsNone = 17,
+    UnknownVMExitReason = 18,
+    UnknownVMInstructionError = 19,
+    VmFailInvalid = 20,
+    UnhandledVmExit = 21,
+    KeRaiseIrqlToDpcLevelNull = 22,
+    InvalidEptPml4BaseAddress = 23,
+    MemoryTypeResolutionError = 24,
+    InvalidCr3BaseAddress = 25,
+    InvalidBytes = 26,
+    NotEnoughBytes = 27,
+    NoInstructions = 28,
+    RelativeInstruction = 29,
+    UnsupportedInstruction = 30,
+    VmxNotInitialized = 31,
+    HookError = 32,
+    PrimaryEPTNotProvided = 33,
+    InvalidPml4Entry = 34,
+    InvalidPdptEntry = 35,
+    InvalidPdEntry = 36,
+    InvalidPtEntry = 37,
+    InvalidPermissionCharacter = 38,
+    UnalignedAddressError = 39,
+    AlreadySplitError = 40,
+    OutOfMemory = 41,
+    PageAlreadySplit = 42,
+    HookManagerNotProvided = 43,
+    NtQuerySystemInformationFailed = 44,
+    ExAllocatePoolFailed = 45,
+    PatternNotFound = 46,
+    SsdtNotFound = 47,
+    FailedToCreateCString = 48,
+    GetKernelBaseFailed = 49,
+    FailedToGetKernelSize = 50,
+    FailedToGetExport = 51,
+    HexParseError = 52,
+    VMFailToLaunch = 53,
+    VmInstructionError = 54,
+    LargePageRemapError = 55,
+    FailedToGetImageBaseAddress = 56,
+    UnknownVmcallCommand = 57,
+    UnknownGuestAgentCommand = 58,
+    OutOfHooks = 59,
+    FailedToGetCurrentHookIndex = 60,
+    TooManyHooks = 61,
+    HookNotFound = 62,
+    InlineHookNotFound = 63,
+    OldRflagsNotSet = 64,
+    MtfCounterNotSet = 65,
+    InvalidPreAllocPtIndex = 66,
+    ShadowPageAllocationError = 67,
+    PageTablesAllocationError = 68,
+    ShadowPagesUnavailable = 69,
+    PageTablesUnavailable = 70,
+    ShadowPageNotFound = 71,
+    PageTableNotFound = 72,
+    PageTableAlreadyMapped = 73,
+    ShadowPageAlreadyMapped = 74,
+    KernelHookMissing = 75,
+    ActiveMappingError = 76,
+    LargePtMappingError = 77,
+    HookInfoNotFound = 78,
+    EptMisconfiguration = 79,
+    LargePageUnmapError = 80,
+    GuestPageUnmapError = 81,
+    ProtocolVersionMismatch = 82,
+    InvalidCommand = 83,
+    InvalidCommandSize = 84,
+    GuestMemoryAccessFailed = 85,
+    ProcessNotFound = 86,
+    HookAlreadyInstalled = 87,
+    SessionNotEstablished = 88,
+    SessionAlreadyEstablished = 89,
+    SessionKeyGenerationFailed = 90,
+    InvalidCommandMac = 91,
+    ReplayedCommand = 92,
+    BatchTooLarge = 93,
+    HypervisorMemoryAccessDenied = 94,
+    PartialMemoryCopy = 95,
+    InvalidPattern = 96,
+    ScanRangeTooLarge = 97,
+    AmbiguousProcessName = 98,
+    UnsupportedHookType = 99,
+    AddressNotExecutable = 100,
+    HookCrossesPageBoundary = 101,
+    ModuleNotFound = 102,
+    SessionNotFound = 103,
+    PageNotSplit = 104,
+    TransferTooLarge = 105,
+    NotGuestRam = 106,
+    MtfRestoreListFull = 107,
+    EptFlushTimeout = 108,
+}
+
+/// The outcome of a command, as returned in RAX, RBX and RDX or in a batch result slot.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandResult {
+    /// The `CommandStatus` of the command.
+    pub status: u64,
+    /// The `ErrorCode` if the status is `CommandStatus::Failure`, otherwise zero.
+    pub error_code: u64,
+    /// The hypervisor's `PROTOCOL_VERSION` if the status is `CommandStatus::VersionMismatch`, otherwise zero.
+    pub protocol_version: u64,
+}
+
+unsafe impl Pod for CommandResult {}
+
+/// Fixed-layout header that precedes every command payload.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CommandHeader {
+    /// Must be `PROTOCOL_MAGIC`.
+    pub magic: u32,
+    /// Must be `PROTOCOL_VERSION`.
+    pub version: u32,
+    /// Total size in bytes of the header and the payload that follows it.
+    pub size: u64,
+    /// The `Command` discriminant.
+    pub command: u64,
+    /// Strictly increasing per-session counter, used to reject replayed commands.
+    pub nonce: u64,
+    /// SipHash-2-4 tag over the whole command (with this field zeroed), keyed with the session key.
+    pub mac: u64,
+}
+
+unsafe impl Pod for CommandHeader {}
+
+impl CommandHeader {
+    /// Creates a header for `command` describing a request of `size` bytes in total.
+    pub fn new(command: Command, size: usize) -> Self {
+        Self {
+            magic: PROTOCOL_MAGIC,
+            version: PROTOCOL_VERSION,
+            size: size as u64,
+            command: command as u64,
+            nonce: 0,
+            mac: 0,
+        }
+    }
+
+    /// Validates the magic and version of the header and decodes the command.
+    ///
+    /// # Returns
+    ///
+    /// * `Ok(Command)` - The command carried by the header.
+    /// * `Err(CommandStatus::VersionMismatch)` - The header was produced by an incompatible client.
+    /// * `Err(CommandStatus::Failure)` - The command id is unknown.
+    pub fn validate(&self) -> Result<Command, CommandStatus> {
+        if self.magic != PROTOCOL_MAGIC || self.version != PROTOCOL_VERSION {
+            return Err(CommandStatus::VersionMismatch);
+        }
+
+        match Command::from_u64(self.command) {
+            Command::Invalid => Err(CommandStatus::Failure),
+            command => Ok(command),
+        }
+    }
+
+    /// Reads a header from the start of a byte buffer.
+    pub fn read_from(bytes: &[u8]) -> Option<Self> {
+        read_pod(bytes)
+    }
+}
+
 /// Structure representing the hook data sent by the client to the hypervisor.
+#[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct HookData {
+    /// Hash of the name of the export to hook.
     pub function_hash: u32,
-    pub syscall_number: u16,
+    /// Syscall number used to find the function in the SSDT if ntoskrnl.exe does not export it.
+    /// Numbers of at least `WIN32K_SYSCALL_BASE` are looked up in the win32k shadow SSDT.
+    pub syscall_number: u32,
+    /// Hash of the base name of the kernel module exporting the function, such as `fltmgr.sys`, or 0 for ntoskrnl.exe.
+    pub module_hash: u32,
+    /// The session whose session space is hooked for win32k syscalls. Ignored for other functions.
+    pub session_id: u32,
 }
 
+/// The first win32k syscall number. Lower numbers are ntoskrnl syscalls.
+pub const WIN32K_SYSCALL_BASE: u32 = 0x1000;
+
+/// `HookEntry::session_id` of a hook that does not apply to a single session.
+pub const NO_SESSION: u64 = u64::MAX;
+
+unsafe impl Pod for HookData {}
+
+/// Structure representing the request of a `Command::EnableAddressEptHook` or `Command::DisableAddressEptHook` command.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressHookData {
+    /// The CR3 to translate `guest_va` with, or 0 to use the CR3 of the calling process.
+    pub guest_cr3: u64,
+    /// The guest virtual address to hook. A function hook must not cross a page boundary, a page hook watches the page containing it.
+    pub guest_va: u64,
+    /// The `HookKind` of the hook.
+    pub hook_kind: u32,
+    /// An identifier chosen by the client, reported as the function hash by `Command::EnumerateHooks`.
+    pub function_hash: u32,
+    /// The accesses watched by a `HookKind::Page` hook (`WATCH_*` bits). Ignored for other kinds and when disabling.
+    pub watch_access: u64,
+}
+
+unsafe impl Pod for AddressHookData {}
+
+/// `AddressHookData::watch_access` bit watching reads of the page. Writes are trapped as well, as EPT cannot grant write access without read access.
+pub const WATCH_READ: u64 = 1 << 0;
+
+/// `AddressHookData::watch_access` bit watching writes to the page.
+pub const WATCH_WRITE: u64 = 1 << 1;
+
 /// Structure representing the memory operation data sent by the client to the hypervisor.
+///
+/// Fields that are not used by a given command are ignored and should be zero.
+#[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct ProcessMemoryOperation {
-    pub process_id: Option<u64>,
-    pub guest_cr3: Option<u64>,
-    pub address: Option<u64>,
+    pub process_id: u64,
+    pub guest_cr3: u64,
+    pub address: u64,
     pub buffer: u64,
     pub buffer_size: u64,
+    /// Client pointer to a `u64` that receives the number of bytes copied, or zero if not needed.
+    ///
+    /// A copy stops at the first page that is not present, in which case `PartialMemoryCopy` is returned.
+    pub transferred: u64,
+}
+
+unsafe impl Pod for ProcessMemoryOperation {}
+
+/// How a `Command::OpenProcess` request identifies the process to open.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum OpenProcessBy {
+    /// By `OpenProcessData::process_id`.
+    ProcessId = 0,
+    /// By `OpenProcessData::image_name`, matched case-insensitively against the full image path or its file name.
+    ImageName = 1,
+    /// By `OpenProcessData::eprocess`, which must be linked into `ActiveProcessLinks`.
+    Eprocess = 2,
+}
+
+impl OpenProcessBy {
+    /// Converts a `u32` value to an `OpenProcessBy` enum variant.
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(OpenProcessBy::ProcessId),
+            1 => Some(OpenProcessBy::ImageName),
+            2 => Some(OpenProcessBy::Eprocess),
+            _ => None,
+        }
+    }
 }
 
-/// Enum representing the data that can be sent by the client to the hypervisor.
+/// Maximum number of UTF-16 characters of the image name accepted by `Command::OpenProcess`.
+pub const PROCESS_NAME_LENGTH: usize = 256;
+
+/// Structure representing the request of a `Command::OpenProcess` command.
+///
+/// Only the field selected by `by` is used; the others are ignored.
+#[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub enum ClientDataPayload {
-    Hook(HookData),
-    Memory(ProcessMemoryOperation),
+pub struct OpenProcessData {
+    /// The `OpenProcessBy` criterion.
+    pub by: u32,
+    /// Number of valid characters in `image_name`.
+    pub image_name_length: u32,
+    /// The process ID of the process to open.
+    pub process_id: u64,
+    /// The guest virtual address of the `_EPROCESS` structure of the process to open.
+    pub eprocess: u64,
+    /// The image name of the process to open as UTF-16, such as `notepad.exe`.
+    pub image_name: [u16; PROCESS_NAME_LENGTH],
+    /// Client buffer that receives the `OpenedProcess`.
+    pub buffer: u64,
+}
+
+unsafe impl Pod for OpenProcessData {}
+
+impl OpenProcessData {
+    /// Creates a request that opens a process by its process ID.
+    pub fn by_process_id(process_id: u64, buffer: u64) -> Self {
+        Self {
+            by: OpenProcessBy::ProcessId as u32,
+            process_id,
+            ..Self::empty(buffer)
+        }
+    }
+
+    /// Creates a request that opens a process by its image name.
+    ///
+    /// Returns `None` if the name is empty or longer than `PROCESS_NAME_LENGTH` UTF-16 characters.
+    pub fn by_image_name(image_name: &str, buffer: u64) -> Option<Self> {
+        let mut request = Self {
+            by: OpenProcessBy::ImageName as u32,
+            ..Self::empty(buffer)
+        };
+
+        for (index, character) in image_name.encode_utf16().enumerate() {
+            *request.image_name.get_mut(index)? = character;
+            request.image_name_length += 1;
+        }
+
+        (request.image_name_length != 0).then_some(request)
+    }
+
+    /// Creates a request that opens a process by the address of its `_EPROCESS` structure.
+    pub fn by_eprocess(eprocess: u64, buffer: u64) -> Self {
+        Self {
+            by: OpenProcessBy::Eprocess as u32,
+            eprocess,
+            ..Self::empty(buffer)
+        }
+    }
+
+    /// Returns the valid characters of the image name.
+    pub fn image_name(&self) -> &[u16] {
+        &self.image_name[..(self.image_name_length as usize).min(PROCESS_NAME_LENGTH)]
+    }
+
+    /// Creates a request with no criterion set.
+    fn empty(buffer: u64) -> Self {
+        Self {
+            by: 0,
+            image_name_length: 0,
+            process_id: 0,
+            eprocess: 0,
+            image_name: [0; PROCESS_NAME_LENGTH],
+            buffer,
+        }
+    }
 }
 
-/// Structure representing the data sent by the client to the hypervisor.
+/// Structure describing an opened process, as reported by `Command::OpenProcess`.
+#[repr(C)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-pub struct ClientCommand {
-    pub command: Command,
-    pub payload: ClientDataPayload,
+pub struct OpenedProcess {
+    /// The process ID of the process.
+    pub process_id: u64,
+    /// The guest virtual address of the `_EPROCESS` structure.
+    pub eprocess: u64,
+    /// The directory table base of the process (kernel CR3).
+    pub directory_table_base: u64,
+    /// The user directory table base of the process (user CR3 with KVA shadowing, otherwise 0).
+    pub user_directory_table_base: u64,
 }
 
-impl ClientCommand {
+unsafe impl Pod for OpenedProcess {}
+
+/// Structure representing a guest physical memory operation sent by the client to the hypervisor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PhysicalMemoryOperation {
+    /// Guest physical address to read from or write to.
+    pub address: u64,
+    /// Client buffer that receives or provides the data.
+    pub buffer: u64,
+    /// Number of bytes to transfer.
+    pub buffer_size: u64,
+}
+
+unsafe impl Pod for PhysicalMemoryOperation {}
+
+/// Maximum number of bytes transferred by a single `Command::ReadPhysicalMemory` or `Command::WritePhysicalMemory`.
+pub const MAX_PHYSICAL_MEMORY_SIZE: u64 = 0x10_0000;
+
+/// Structure representing the data sent by the client to the hypervisor: a `CommandHeader` followed by a typed payload.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientCommand<T: Pod> {
+    pub header: CommandHeader,
+    pub payload: T,
+}
+
+unsafe impl<T: Pod> Pod for ClientCommand<T> {}
+
+impl<T: Pod> ClientCommand<T> {
+    /// Creates a command with a header describing `payload`.
+    pub fn new(command: Command, payload: T) -> Self {
+        Self {
+            header: CommandHeader::new(command, size_of::<Self>()),
+            payload,
+        }
+    }
+
     /// Converts `ClientCommand` to a pointer.
     pub fn as_ptr(&self) -> u64 {
-        self as *const ClientCommand as u64
+        self as *const Self as u64
+    }
+
+    /// Returns the raw bytes of the command as they are seen by the hypervisor.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { core::slice::from_raw_parts(self as *const Self as *const u8, size_of::<Self>()) }
+    }
+
+    /// Validates the header and checks that the declared size matches the payload type.
+    pub fn validate(&self) -> Result<Command, CommandStatus> {
+        let command = self.header.validate()?;
+
+        if self.header.size != size_of::<Self>() as u64 {
+            return Err(CommandStatus::Failure);
+        }
+
+        Ok(command)
+    }
+
+    /// Reads a command from the start of a byte buffer.
+    pub fn read_from(bytes: &[u8]) -> Option<Self> {
+        read_pod(bytes)
+    }
+
+    /// Computes the MAC of the command with the `mac` field treated as zero.
+    pub fn compute_mac(&self, key: &SessionKey) -> u64 {
+        let mut unsigned = *self;
+        unsigned.header.mac = 0;
+        siphash24(key, unsigned.as_bytes())
+    }
+
+    /// Stamps the command with `nonce` and signs it with the session key.
+    pub fn sign(&mut self, key: &SessionKey, nonce: u64) {
+        self.header.nonce = nonce;
+        self.header.mac = self.compute_mac(key);
+    }
+
+    /// Checks the MAC of the command against the session key.
+    ///
+    /// Every byte of the MAC is compared, so the time taken does not reveal how many leading bytes were correct.
+    pub fn verify(&self, key: &SessionKey) -> bool {
+        let expected = self.compute_mac(key).to_le_bytes();
+        let actual = self.header.mac.to_le_bytes();
+
+        let difference = expected
+            .iter()
+            .zip(actual.iter())
+            .fold(0u8, |difference, (a, b)| difference | core::hint::black_box(a ^ b));
+
+        difference == 0
+    }
+}
+
+/// The session key shared by the hypervisor and the client after `Command::OpenSession`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionKey {
+    pub k0: u64,
+    pub k1: u64,
+}
+
+unsafe impl Pod for SessionKey {}
+
+/// Structure representing the session handshake data sent by the client to the hypervisor.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SessionData {
+    /// Client buffer that receives the `SessionKey`.
+    pub key_buffer: u64,
+}
+
+unsafe impl Pod for SessionData {}
+
+/// Structure representing the payload of `Command::CloseSession`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct CloseSessionData {
+    /// Reserved, must be zero.
+    pub reserved: u64,
+}
+
+unsafe impl Pod for CloseSessionData {}
+
+/// Structure representing a batch of commands sent by the client to the hypervisor.
+///
+/// Each entry is a complete, individually signed `ClientCommand` whose nonce is greater than the
+/// nonce of the batch itself; entries are executed in order and may not be batches themselves.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BatchData {
+    /// Client array of `count` pointers to `ClientCommand`s.
+    pub commands: u64,
+    /// Client array of `count` `CommandResult`s that receive the outcome of each entry.
+    pub results: u64,
+    /// Number of entries, at most `MAX_BATCH_SIZE`.
+    pub count: u64,
+}
+
+unsafe impl Pod for BatchData {}
+
+/// Structure describing a client buffer that receives an array of entries.
+///
+/// The hypervisor writes at most `capacity` entries to `buffer` and always writes the total number
+/// of available entries to `count`, so the client can retry with a larger buffer.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ListData {
+    /// Client array that receives the entries.
+    pub buffer: u64,
+    /// Number of entries that fit in `buffer`.
+    pub capacity: u64,
+    /// Client `u64` that receives the total number of entries.
+    pub count: u64,
+}
+
+unsafe impl Pod for ListData {}
+
+/// The kind of an installed EPT hook, as reported by `Command::EnumerateHooks`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u32)]
+pub enum HookKind {
+    /// A page hook.
+    Page = 0,
+    /// A function hook using an `int3` inline hook.
+    Int3 = 1,
+    /// A function hook using a `cpuid` inline hook.
+    Cpuid = 2,
+    /// A function hook using a `vmcall` inline hook.
+    Vmcall = 3,
+}
+
+impl HookKind {
+    /// Converts a `u32` value to a `HookKind` enum variant.
+    pub fn from_u32(value: u32) -> Option<Self> {
+        match value {
+            0 => Some(HookKind::Page),
+            1 => Some(HookKind::Int3),
+            2 => Some(HookKind::Cpuid),
+            3 => Some(HookKind::Vmcall),
+            _ => None,
+        }
+    }
+}
+
+/// Structure representing an installed EPT hook, as reported by `Command::EnumerateHooks`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookEntry {
+    /// Guest virtual address of the hooked function.
+    pub guest_function_va: u64,
+    /// Guest physical address of the hooked function.
+    pub guest_function_pa: u64,
+    /// Guest physical address of the hooked page.
+    pub guest_page_pa: u64,
+    /// Host physical address of the shadow page backing the hooked page.
+    pub shadow_page_pa: u64,
+    /// Hash of the hooked function.
+    pub function_hash: u32,
+    /// The `HookKind` of the hook.
+    pub hook_kind: u32,
+    /// Current EPT permissions of the hooked page on the reporting processor (bit 0: read, bit 1: write, bit 2: execute).
+    pub ept_permissions: u64,
+    /// The session whose session space the hook was installed in, or `NO_SESSION`.
+    pub session_id: u64,
+}
+
+unsafe impl Pod for HookEntry {}
+
+/// Structure representing the hit counters of an installed EPT hook, as reported by `Command::QueryHookStatistics`.
+///
+/// The context of the last hit is only meaningful when `hit_count` is not zero.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HookStatistics {
+    /// Guest virtual address of the hooked function.
+    pub guest_function_va: u64,
+    /// Guest physical address of the hooked function.
+    pub guest_function_pa: u64,
+    /// Hash of the hooked function.
+    pub function_hash: u32,
+    /// APIC ID of the processor the hook was last hit on.
+    pub last_vcpu_id: u32,
+    /// Number of times the hook was hit.
+    pub hit_count: u64,
+    /// Timestamp counter of the processor the hook was last hit on, at the time of the hit.
+    pub last_hit_tsc: u64,
+    /// Return address of the last call through a function hook, or RIP of the last watched access of a page hook.
+    pub last_caller_rip: u64,
+    /// Unique process ID of the process the hook was last hit in, or 0 if it could not be read.
+    pub last_process_id: u64,
+}
+
+unsafe impl Pod for HookStatistics {}
+
+/// Version of the `HypervisorInfo` layout. Bump this whenever fields are added to it.
+pub const HYPERVISOR_INFO_VERSION: u32 = 1;
+
+/// `HypervisorInfo::features` bit set when the hypervisor was built with the `vmware` feature.
+pub const FEATURE_VMWARE: u64 = 1 << 0;
+
+/// `HypervisorInfo::features` bit set when the hypervisor was built with the `hide_hv_with_ept` feature.
+pub const FEATURE_HIDE_HV_WITH_EPT: u64 = 1 << 1;
+
+/// Structure representing the request of a `Command::GetHypervisorInfo` command.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InfoData {
+    /// Client buffer that receives the `HypervisorInfo`.
+    pub buffer: u64,
+}
+
+unsafe impl Pod for InfoData {}
+
+/// Structure describing the hypervisor build and the capabilities it detected, as reported by `Command::GetHypervisorInfo`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HypervisorInfo {
+    /// Layout version of this structure, `HYPERVISOR_INFO_VERSION` at the time the hypervisor was built.
+    pub info_version: u32,
+    /// Version of the hypercall ABI spoken by the hypervisor.
+    pub protocol_version: u32,
+    /// Compile-time features of the hypervisor (`FEATURE_*` bits).
+    pub features: u64,
+    /// Number of logical processors that have been virtualized.
+    pub virtualized_processors: u64,
+    /// Total size of the hypervisor heap in bytes.
+    pub heap_size: u64,
+    /// Free space left in the hypervisor heap in bytes.
+    pub heap_free: u64,
+    /// Value of `IA32_VMX_BASIC` read while checking CPU support.
+    pub vmx_basic: u64,
+    /// Value of `IA32_VMX_PROCBASED_CTLS2` read while checking CPU support.
+    pub vmx_procbased_ctls2: u64,
+    /// Value of `IA32_VMX_EPT_VPID_CAP` read while checking CPU support.
+    pub vmx_ept_vpid_cap: u64,
+}
+
+unsafe impl Pod for HypervisorInfo {}
+
+/// Structure representing a guest process, as reported by `Command::EnumerateProcesses`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProcessEntry {
+    /// Guest virtual address of the `_EPROCESS` structure.
+    pub eprocess: u64,
+    /// Unique process ID.
+    pub process_id: u64,
+    /// Unique process ID of the parent process (`InheritedFromUniqueProcessId`).
+    pub parent_process_id: u64,
+    /// Kernel directory table base (CR3) of the process.
+    pub directory_table_base: u64,
+    /// User directory table base of the process, or 0 if KVA shadowing is not used.
+    pub user_directory_table_base: u64,
+    /// Short image file name (`_EPROCESS.ImageFileName`), NUL padded.
+    pub image_file_name: [u8; 16],
+}
+
+unsafe impl Pod for ProcessEntry {}
+
+impl ProcessEntry {
+    /// Returns the image file name without its NUL padding.
+    pub fn image_file_name(&self) -> &[u8] {
+        let len = self.image_file_name.iter().position(|&c| c == 0).unwrap_or(self.image_file_name.len());
+        &self.image_file_name[..len]
+    }
+}
+
+/// Maximum number of UTF-16 characters of a module name reported by `Command::EnumerateModules`.
+pub const MODULE_NAME_LENGTH: usize = 128;
+
+/// `ModuleEntry::flags` bit set for modules found in the 32-bit `PEB` of a WOW64 process.
+pub const MODULE_FLAG_WOW64: u32 = 1 << 0;
+
+/// Structure representing the request of a `Command::EnumerateModules` command.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModuleListData {
+    /// The process ID of the process whose modules are listed.
+    pub process_id: u64,
+    /// The client buffer that receives the `ModuleEntry`s.
+    pub list: ListData,
+}
+
+unsafe impl Pod for ModuleListData {}
+
+/// Structure representing a module loaded into a process, as reported by `Command::EnumerateModules`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ModuleEntry {
+    /// Base address of the module.
+    pub base: u64,
+    /// Size of the module image in bytes.
+    pub size: u64,
+    /// `MODULE_FLAG_*` bits.
+    pub flags: u32,
+    /// Number of valid characters in `name`.
+    pub name_length: u32,
+    /// Base name of the module (`BaseDllName`) as UTF-16, truncated to `MODULE_NAME_LENGTH` characters.
+    pub name: [u16; MODULE_NAME_LENGTH],
+}
+
+unsafe impl Pod for ModuleEntry {}
+
+impl ModuleEntry {
+    /// Returns the valid characters of the module name.
+    pub fn name(&self) -> &[u16] {
+        &self.name[..(self.name_length as usize).min(MODULE_NAME_LENGTH)]
+    }
+
+    /// Returns whether the module was found in the 32-bit `PEB` of a WOW64 process.
+    pub fn is_wow64(&self) -> bool {
+        self.flags & MODULE_FLAG_WOW64 != 0
+    }
+}
+
+/// `AddressTranslation::page_permissions` bit set when the page is writable at every paging level.
+pub const PAGE_WRITABLE: u32 = 1 << 0;
+
+/// `AddressTranslation::page_permissions` bit set when the page is user accessible at every paging level.
+pub const PAGE_USER: u32 = 1 << 1;
+
+/// `AddressTranslation::page_permissions` bit set when no paging level sets the execute-disable bit.
+pub const PAGE_EXECUTABLE: u32 = 1 << 2;
+
+/// Structure representing the request of a `Command::TranslateAddress` command.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TranslationData {
+    /// The CR3 to translate with, or 0 to use the CR3 of the calling process.
+    pub guest_cr3: u64,
+    /// The guest virtual address to translate.
+    pub guest_va: u64,
+    /// Client buffer that receives the `AddressTranslation`.
+    pub buffer: u64,
+}
+
+unsafe impl Pod for TranslationData {}
+
+/// Structure describing the translation of a guest virtual address, as reported by `Command::TranslateAddress`.
+///
+/// Each walk stops at the first non-present entry; its size is then 0 and the following fields are zero.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AddressTranslation {
+    /// The CR3 that was used for the guest page walk.
+    pub guest_cr3: u64,
+    /// The translated guest virtual address.
+    pub guest_va: u64,
+    /// The raw PML4E, PDPTE, PDE and PTE visited by the guest page walk. Only the first `paging_levels` are valid.
+    pub paging_entries: [u64; 4],
+    /// The number of guest paging entries visited.
+    pub paging_levels: u32,
+    /// The effective `PAGE_*` permissions of the guest page.
+    pub page_permissions: u32,
+    /// The size of the guest page in bytes, or 0 if the address is not mapped.
+    pub page_size: u64,
+    /// The guest physical address.
+    pub guest_pa: u64,
+    /// The raw EPT PML4E, PDPTE, PDE and PTE visited by the EPT walk. Only the first `ept_levels` are valid.
+    pub ept_entries: [u64; 4],
+    /// The number of EPT entries visited.
+    pub ept_levels: u32,
+    /// The EPT permissions of the guest physical page (bit 0: read, bit 1: write, bit 2: execute).
+    pub ept_permissions: u32,
+    /// The size of the EPT mapping in bytes, or 0 if the guest physical address is not mapped.
+    pub ept_page_size: u64,
+    /// The host physical address.
+    pub host_pa: u64,
+}
+
+unsafe impl Pod for AddressTranslation {}
+
+/// Maximum number of bytes, including wildcards, in a `Pattern`.
+pub const MAX_PATTERN_LENGTH: usize = 64;
+
+/// Maximum number of bytes read by a single `Command::PatternScan`. Larger ranges are resumed from the returned cursor.
+pub const MAX_PATTERN_SCAN_SIZE: u64 = 0x40_0000;
+
+/// Number of bytes `PatternMatches` reads at a time.
+const SCAN_PAGE_SIZE: usize = 0x1000;
+
+/// A byte pattern with wildcards, as used by `Command::PatternScan`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Pattern {
+    /// Number of valid bytes in `bytes` and `mask`.
+    pub length: u64,
+    /// The bytes to match. Bytes at wildcard positions are ignored.
+    pub bytes: [u8; MAX_PATTERN_LENGTH],
+    /// 1 where the byte must match, 0 for a wildcard.
+    pub mask: [u8; MAX_PATTERN_LENGTH],
+}
+
+unsafe impl Pod for Pattern {}
+
+impl Pattern {
+    /// Parses an IDA-style pattern such as `"4C 8B ? 48 83 EC"`.
+    ///
+    /// Bytes are separated by whitespace and written in hex; `?` is a wildcard.
+    ///
+    /// # Returns
+    ///
+    /// The pattern, or `None` if a byte is not valid hex, or the pattern is empty or longer than `MAX_PATTERN_LENGTH`.
+    pub fn parse(pattern: &str) -> Option<Self> {
+        let mut parsed = Self {
+            length: 0,
+            bytes: [0; MAX_PATTERN_LENGTH],
+            mask: [0; MAX_PATTERN_LENGTH],
+        };
+
+        for (index, byte) in pattern.split_whitespace().enumerate() {
+            if index >= MAX_PATTERN_LENGTH {
+                return None;
+            }
+
+            if byte != "?" {
+                parsed.bytes[index] = u8::from_str_radix(byte, 16).ok()?;
+                parsed.mask[index] = 1;
+            }
+
+            parsed.length += 1;
+        }
+
+        parsed.is_valid().then_some(parsed)
+    }
+
+    /// Returns whether the pattern has between 1 and `MAX_PATTERN_LENGTH` bytes.
+    pub fn is_valid(&self) -> bool {
+        (1..=MAX_PATTERN_LENGTH as u64).contains(&self.length)
+    }
+
+    /// Returns the number of bytes in the pattern.
+    pub fn len(&self) -> usize {
+        (self.length as usize).min(MAX_PATTERN_LENGTH)
+    }
+
+    /// Returns whether the pattern has no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns whether the pattern matches the start of `data`.
+    pub fn matches(&self, data: &[u8]) -> bool {
+        let length = self.len();
+
+        data.len() >= length
+            && data[..length]
+                .iter()
+                .zip(&self.bytes[..length])
+                .zip(&self.mask[..length])
+                .all(|((byte, pattern_byte), mask)| *mask == 0 || byte == pattern_byte)
+    }
+}
+
+/// Iterator over the matches of a pattern in a virtual address range, read one 4 KB page at a time.
+///
+/// Each page is read into `window` after the last `pattern.len() - 1` bytes of the previous page, so matches that
+/// span two consecutive pages are found. A page that cannot be read is skipped along with the bytes carried into it.
+pub struct PatternMatches<'a, F> {
+    /// The pattern to search for. It must not be empty.
+    pattern: &'a Pattern,
+    /// Reads the bytes at a virtual address into a buffer, returning `false` if they are not accessible.
+    read: F,
+    /// The virtual address the scan started at.
+    start: u64,
+    /// The virtual address matches must end before (exclusive).
+    end: u64,
+    /// The virtual address reading stops at (exclusive), at most `end`.
+    read_end: u64,
+    /// The virtual address of the next page to read.
+    next_va: u64,
+    /// The bytes carried over from the previous page, followed by the current page.
+    window: [u8; SCAN_PAGE_SIZE + MAX_PATTERN_LENGTH - 1],
+    /// The virtual address of the first byte in `window`.
+    window_va: u64,
+    /// The number of valid bytes in `window`.
+    window_len: usize,
+    /// The index in `window` of the next candidate match.
+    position: usize,
+}
+
+impl<'a, F: FnMut(u64, &mut [u8]) -> bool> PatternMatches<'a, F> {
+    /// Creates an iterator over the matches of `pattern` in `start..start + size`.
+    ///
+    /// # Arguments
+    ///
+    /// * `pattern` - The pattern to search for. It must not be empty.
+    /// * `start` - The virtual address the scan starts at.
+    /// * `size` - The number of bytes to scan.
+    /// * `max_read` - The number of bytes after which the scan stops early; `cursor` then tells where to resume it.
+    /// * `read` - Reads the bytes at a virtual address into a buffer, returning `false` if they are not accessible.
+    pub fn new(pattern: &'a Pattern, start: u64, size: u64, max_read: u64, read: F) -> Self {
+        let end = start.saturating_add(size);
+
+        Self {
+            pattern,
+            read,
+            start,
+            end,
+            read_end: start.saturating_add(max_read).min(end),
+            next_va: start,
+            window: [0; SCAN_PAGE_SIZE + MAX_PATTERN_LENGTH - 1],
+            window_va: start,
+            window_len: 0,
+            position: 0,
+        }
+    }
+
+    /// Returns the virtual address to resume the scan at to find the matches this iterator has not returned yet.
+    ///
+    /// Once the whole range has been scanned, this is its end.
+    pub fn cursor(&self) -> u64 {
+        if self.window_len == 0 {
+            return self.next_va;
+        }
+
+        if self.next_va >= self.end && self.position + self.pattern.len() > self.window_len {
+            return self.end;
+        }
+
+        self.window_va + self.position as u64
+    }
+}
+
+impl<F: FnMut(u64, &mut [u8]) -> bool> Iterator for PatternMatches<'_, F> {
+    type Item = u64;
+
+    fn next(&mut self) -> Option<u64> {
+        let length = self.pattern.len();
+
+        loop {
+            while self.position + length <= self.window_len {
+                let position = self.position;
+                self.position += 1;
+
+                if self.pattern.matches(&self.window[position..self.window_len]) {
+                    return Some(self.window_va + position as u64 - self.start);
+                }
+            }
+
+            if self.next_va >= self.read_end {
+                return None;
+            }
+
+            // Keep the bytes that can still start a match, unless the next page does not follow them.
+            let carried = length.saturating_sub(1).min(self.window_len);
+            self.window.copy_within(self.window_len - carried..self.window_len, 0);
+
+            let bytes_left_in_page = SCAN_PAGE_SIZE - (self.next_va as usize & (SCAN_PAGE_SIZE - 1));
+            let chunk = ((self.read_end - self.next_va) as usize).min(bytes_left_in_page);
+
+            if (self.read)(self.next_va, &mut self.window[carried..carried + chunk]) {
+                self.window_va = self.next_va - carried as u64;
+                self.window_len = carried + chunk;
+            } else {
+                self.window_len = 0;
+            }
+
+            self.position = 0;
+            self.next_va += chunk as u64;
+        }
+    }
+}
+
+/// Structure representing the request of a `Command::PatternScan` command.
+///
+/// A single command reads at most `MAX_PATTERN_SCAN_SIZE` bytes and stops early when `list` is full. Unlike other
+/// lists, `list.count` receives the number of matches written, and the rest of the range is scanned by sending the
+/// command again from the address written to `cursor`.
+#[repr(C)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PatternScanData {
+    /// The CR3 to scan with, or 0 to use the CR3 of the calling process.
+    pub guest_cr3: u64,
+    /// The guest virtual address the scan starts at.
+    pub start: u64,
+    /// The number of bytes to scan.
+    pub size: u64,
+    /// The pattern to search for.
+    pub pattern: Pattern,
+    /// The client buffer that receives the `u64` offset of every match from `start`. It must hold at least one entry.
+    pub list: ListData,
+    /// Client `u64` that receives the guest virtual address to resume the scan at, `start + size` once the range is done.
+    pub cursor: u64,
+}
+
+unsafe impl Pod for PatternScanData {}
+
+impl PatternScanData {
+    /// Returns whether `list` can hold a match. A scan without room for one could never report a match or move past it.
+    pub fn has_list_capacity(&self) -> bool {
+        self.list.capacity > 0
+    }
+}
+
+/// Computes SipHash-2-4 of `data` keyed with `key`.
+///
+/// Reference: https://www.aumasson.jp/siphash/siphash.pdf
+pub fn siphash24(key: &SessionKey, data: &[u8]) -> u64 {
+    #[inline(always)]
+    fn sipround(v: &mut [u64; 4]) {
+        v[0] = v[0].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(13) ^ v[0];
+        v[0] = v[0].rotate_left(32);
+        v[2] = v[2].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(16) ^ v[2];
+        v[0] = v[0].wrapping_add(v[3]);
+        v[3] = v[3].rotate_left(21) ^ v[0];
+        v[2] = v[2].wrapping_add(v[1]);
+        v[1] = v[1].rotate_left(17) ^ v[2];
+        v[2] = v[2].rotate_left(32);
+    }
+
+    let mut v = [
+        key.k0 ^ 0x736f6d6570736575,
+        key.k1 ^ 0x646f72616e646f6d,
+        key.k0 ^ 0x6c7967656e657261,
+        key.k1 ^ 0x7465646279746573,
+    ];
+
+    let (blocks, remainder) = data.as_chunks::<8>();
+    for block in blocks {
+        let m = u64::from_le_bytes(*block);
+        v[3] ^= m;
+        sipround(&mut v);
+        sipround(&mut v);
+        v[0] ^= m;
+    }
+
+    // The final block holds the remaining bytes and the message length in its top byte.
+    let mut last = [0u8; 8];
+    last[..remainder.len()].copy_from_slice(remainder);
+    let m = u64::from_le_bytes(last) | ((data.len() as u64) << 56);
+    v[3] ^= m;
+    sipround(&mut v);
+    sipround(&mut v);
+    v[0] ^= m;
+
+    v[2] ^= 0xff;
+    for _ in 0..4 {
+        sipround(&mut v);
+    }
+
+    v[0] ^ v[1] ^ v[2] ^ v[3]
+}
+
+/// Reads a `Pod` value from the start of `bytes`, returning `None` if the buffer is too short.
+fn read_pod<T: Pod>(bytes: &[u8]) -> Option<T> {
+    if bytes.len() < size_of::<T>() {
+        return None;
+    }
+
+    Some(unsafe { core::ptr::read_unaligned(bytes.as_ptr() as *const T) })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hook_command_round_trip() {
+        let command = ClientCommand::new(
+            Command::EnableKernelEptHook,
+            HookData {
+                function_hash: 0xcafebabe,
+                syscall_number: 0x55,
+                module_hash: 0,
+                session_id: 0,
+            },
+        );
+
+        let header = CommandHeader::read_from(command.as_bytes()).unwrap();
+        assert_eq!(header.validate(), Ok(Command::EnableKernelEptHook));
+
+        let decoded = ClientCommand::<HookData>::read_from(command.as_bytes()).unwrap();
+        assert_eq!(decoded.validate(), Ok(Command::EnableKernelEptHook));
+        assert_eq!(decoded, command);
+    }
+
+    #[test]
+    fn test_address_hook_command_round_trip() {
+        let command = ClientCommand::new(
+            Command::DisableAddressEptHook,
+            AddressHookData {
+                guest_cr3: 0x1ad000,
+                guest_va: 0x7ff6_1234_5678,
+                hook_kind: HookKind::Vmcall as u32,
+                function_hash: 0xcafebabe,
+                watch_access: 0,
+            },
+        );
+
+        let decoded = ClientCommand::<AddressHookData>::read_from(command.as_bytes()).unwrap();
+        assert_eq!(decoded.validate(), Ok(Command::DisableAddressEptHook));
+        assert_eq!(decoded, command);
+        assert_eq!(size_of::<AddressHookData>(), 32);
+        assert_eq!(HookKind::from_u32(decoded.payload.hook_kind), Some(HookKind::Vmcall));
+    }
+
+    #[test]
+    fn test_memory_command_round_trip() {
+        let command = ClientCommand::new(
+            Command::ReadProcessMemory,
+            ProcessMemoryOperation {
+                process_id: 4,
+                guest_cr3: 0x1ad000,
+                address: 0x7ff6_0000_0000,
+                buffer: 0x1000,
+                buffer_size: 0x20,
+                transferred: 0x2000,
+            },
+        );
+
+        let decoded = ClientCommand::<ProcessMemoryOperation>::read_from(command.as_bytes()).unwrap();
+        assert_eq!(decoded.validate(), Ok(Command::ReadProcessMemory));
+        assert_eq!(decoded.payload, command.payload);
+    }
+
+    #[test]
+    fn test_header_layout_is_stable() {
+        assert_eq!(size_of::<CommandHeader>(), 40);
+        assert_eq!(core::mem::offset_of!(ClientCommand<HookData>, payload), size_of::<CommandHeader>());
+        assert_eq!(core::mem::offset_of!(ClientCommand<ProcessMemoryOperation>, payload), size_of::<CommandHeader>());
+    }
+
+    #[test]
+    fn test_version_mismatch() {
+        let mut command = ClientCommand::new(
+            Command::OpenProcess,
+            ProcessMemoryOperation {
+                process_id: 4,
+                guest_cr3: 0,
+                address: 0,
+                buffer: 0,
+                buffer_size: 0,
+                transferred: 0,
+            },
+        );
+
+        command.header.version = PROTOCOL_VERSION + 1;
+        assert_eq!(command.validate(), Err(CommandStatus::VersionMismatch));
+
+        command.header.version = PROTOCOL_VERSION;
+        command.header.magic = 0;
+        assert_eq!(command.validate(), Err(CommandStatus::VersionMismatch));
+    }
+
+    #[test]
+    fn test_size_mismatch_is_rejected() {
+        let mut command = ClientCommand::new(
+            Command::DisableKernelEptHook,
+            HookData {
+                function_hash: 1,
+                syscall_number: 2,
+                module_hash: 0,
+                session_id: 0,
+            },
+        );
+
+        command.header.size += 8;
+        assert_eq!(command.validate(), Err(CommandStatus::Failure));
+    }
+
+    #[test]
+    fn test_truncated_buffer_is_rejected() {
+        let command = ClientCommand::new(
+            Command::DisableKernelEptHook,
+            HookData {
+                function_hash: 1,
+                syscall_number: 2,
+                module_hash: 0,
+                session_id: 0,
+            },
+        );
+
+        assert!(ClientCommand::<HookData>::read_from(&command.as_bytes()[..size_of::<CommandHeader>()]).is_none());
+    }
+
+    #[test]
+    fn test_error_code_round_trip() {
+        for value in 1..=ErrorCode::EptFlushTimeout.to_u64() {
+            let code = ErrorCode::from_u64(value).unwrap();
+            assert_eq!(code.to_u64(), value);
+        }
+
+        assert_eq!(ErrorCode::from_u64(0), None);
+        assert_eq!(ErrorCode::from_u64(ErrorCode::EptFlushTimeout.to_u64() + 1), None);
+    }
+
+    #[test]
+    fn test_error_codes_are_stable() {
+        assert_eq!(ErrorCode::CPUUnsupported.to_u64(), 1);
+        assert_eq!(ErrorCode::GuestPageUnmapError.to_u64(), 81);
+        assert_eq!(ErrorCode::ProcessNotFound.to_u64(), 86);
+    }
+
+    #[test]
+    fn test_hypervisor_info_layout_is_stable() {
+        assert_eq!(size_of::<HypervisorInfo>(), 64);
+        assert_eq!(Command::from_u64(Command::GetHypervisorInfo as u64), Command::GetHypervisorInfo);
+    }
+
+    #[test]
+    fn test_hook_statistics_layout_is_stable() {
+        assert_eq!(size_of::<HookStatistics>(), 56);
+        assert_eq!(Command::from_u64(Command::QueryHookStatistics as u64), Command::QueryHookStatistics);
+    }
+
+    #[test]
+    fn test_close_session_command_round_trip() {
+        assert_eq!(Command::from_u64(Command::CloseSession as u64), Command::CloseSession);
+        assert_eq!(Command::from_u64(Command::CloseSession as u64 + 1), Command::Invalid);
+    }
+
+    #[test]
+    fn test_process_entry_image_file_name() {
+        let mut entry = ProcessEntry {
+            eprocess: 0,
+            process_id: 4,
+            parent_process_id: 0,
+            directory_table_base: 0x1ad000,
+            user_directory_table_base: 0,
+            image_file_name: [0; 16],
+        };
+        entry.image_file_name[..6].copy_from_slice(b"System");
+        assert_eq!(entry.image_file_name(), b"System");
+
+        entry.image_file_name = *b"0123456789abcdef";
+        assert_eq!(entry.image_file_name(), b"0123456789abcdef");
+    }
+
+    #[test]
+    fn test_module_entry_name_is_clamped() {
+        let mut entry = ModuleEntry {
+            base: 0x7ff800000000,
+            size: 0x1f0000,
+            flags: MODULE_FLAG_WOW64,
+            name_length: 9,
+            name: [0; MODULE_NAME_LENGTH],
+        };
+        assert_eq!(entry.name().len(), 9);
+        assert!(entry.is_wow64());
+
+        entry.name_length = u32::MAX;
+        assert_eq!(entry.name().len(), MODULE_NAME_LENGTH);
+    }
+
+    #[test]
+    fn test_address_translation_layout_is_stable() {
+        assert_eq!(size_of::<AddressTranslation>(), 128);
+        assert_eq!(size_of::<TranslationData>(), 24);
+    }
+
+    #[test]
+    fn test_siphash_reference_vector() {
+        // Test vector from the SipHash paper: key 00..0f, message 00..0e.
+        let key = SessionKey {
+            k0: 0x0706050403020100,
+            k1: 0x0f0e0d0c0b0a0908,
+        };
+        let message: [u8; 15] = core::array::from_fn(|i| i as u8);
+        assert_eq!(siphash24(&key, &message), 0xa129ca6149be45e5);
+    }
+
+    #[test]
+    fn test_signed_command_round_trip() {
+        let key = SessionKey {
+            k0: 0x1122334455667788,
+            k1: 0x99aabbccddeeff00,
+        };
+        let mut command = ClientCommand::new(
+            Command::EnableKernelEptHook,
+            HookData {
+                function_hash: 0xcafebabe,
+                syscall_number: 0x55,
+                module_hash: 0,
+                session_id: 0,
+            },
+        );
+        command.sign(&key, 7);
+
+        let decoded = ClientCommand::<HookData>::read_from(command.as_bytes()).unwrap();
+        assert_eq!(decoded.header.nonce, 7);
+        assert!(decoded.verify(&key));
+    }
+
+    #[test]
+    fn test_forged_command_is_rejected() {
+        let key = SessionKey { k0: 1, k1: 2 };
+        let mut command = ClientCommand::new(
+            Command::ReadProcessMemory,
+            ProcessMemoryOperation {
+                process_id: 0,
+                guest_cr3: 0x1ad000,
+                address: 0x1000,
+                buffer: 0x2000,
+                buffer_size: 8,
+                transferred: 0,
+            },
+        );
+        command.sign(&key, 1);
+
+        let mut tampered = command;
+        tampered.payload.address = 0xfffff800_00000000;
+        assert!(!tampered.verify(&key));
+
+        let mut renumbered = command;
+        renumbered.header.nonce = 2;
+        assert!(!renumbered.verify(&key));
+
+        for bit in [0, 31, 63] {
+            let mut flipped = command;
+            flipped.header.mac ^= 1 << bit;
+            assert!(!flipped.verify(&key));
+        }
+
+        assert!(!command.verify(&SessionKey { k0: 1, k1: 3 }));
+    }
+
+    #[test]
+    fn test_pattern_parse_and_match() {
+        let pattern = Pattern::parse("4C 8B ? 48").unwrap();
+        assert_eq!(pattern.len(), 4);
+        assert_eq!(&pattern.mask[..4], &[1, 1, 0, 1]);
+
+        assert!(pattern.matches(&[0x4c, 0x8b, 0xd1, 0x48, 0x83]));
+        assert!(pattern.matches(&[0x4c, 0x8b, 0x00, 0x48]));
+        assert!(!pattern.matches(&[0x4c, 0x8b, 0xd1, 0x49]));
+        assert!(!pattern.matches(&[0x4c, 0x8b, 0xd1]));
+
+        assert_eq!(Pattern::parse(""), None);
+        assert_eq!(Pattern::parse("4C XX"), None);
+        assert_eq!(Pattern::parse(&"? ".repeat(MAX_PATTERN_LENGTH + 1)), None);
+        assert!(Pattern::parse(&"? ".repeat(MAX_PATTERN_LENGTH)).is_some());
+    }
+
+    #[test]
+    fn test_pattern_scan_requires_list_capacity() {
+        let mut scan = PatternScanData {
+            guest_cr3: 0,
+            start: 0x1000,
+            size: 0x1000,
+            pattern: Pattern::parse("4C 8B ? 48").unwrap(),
+            list: ListData {
+                buffer: 0x2000,
+                capacity: 1,
+                count: 0x3000,
+            },
+            cursor: 0x4000,
+        };
+        assert!(scan.has_list_capacity());
+
+        scan.list.capacity = 0;
+        assert!(!scan.has_list_capacity());
+    }
+
+    #[test]
+    fn test_pattern_matches_across_page_boundary() {
+        const BASE: u64 = 0x10000;
+
+        // Two readable pages followed by one that is not present.
+        let mut memory = [0u8; 2 * SCAN_PAGE_SIZE];
+        for offset in [0x10, SCAN_PAGE_SIZE - 2, 2 * SCAN_PAGE_SIZE - 2] {
+            let bytes = [0x4c, 0x8b, 0xd1, 0x48];
+            let len = bytes.len().min(memory.len() - offset);
+            memory[offset..offset + len].copy_from_slice(&bytes[..len]);
+        }
+
+        let read = |va: u64, buffer: &mut [u8]| {
+            let offset = (va - BASE) as usize;
+            if offset >= memory.len() {
+                return false;
+            }
+            buffer.copy_from_slice(&memory[offset..offset + buffer.len()]);
+            true
+        };
+
+        let pattern = Pattern::parse("4C 8B ? 48").unwrap();
+        let size = 3 * SCAN_PAGE_SIZE as u64;
+
+        // The window carries the start of the second match into the next page; the third runs into the missing page.
+        let mut matches = PatternMatches::new(&pattern, BASE, size, size, read);
+        assert_eq!(matches.next(), Some(0x10));
+        assert_eq!(matches.next(), Some(SCAN_PAGE_SIZE as u64 - 2));
+        assert_eq!(matches.next(), None);
+        assert_eq!(matches.cursor(), BASE + size);
+
+        // Stopping after the first page leaves the bytes that could start a match to the resumed scan.
+        let mut matches = PatternMatches::new(&pattern, BASE, size, SCAN_PAGE_SIZE as u64, read);
+        assert_eq!(matches.next(), Some(0x10));
+        assert_eq!(matches.next(), None);
+
+        let cursor = matches.cursor();
+        assert_eq!(cursor, BASE + SCAN_PAGE_SIZE as u64 - 3);
+
+        let mut matches = PatternMatches::new(&pattern, cursor, BASE + size - cursor, size, read);
+        assert_eq!(matches.next(), Some(1));
+        assert_eq!(matches.next(), None);
+    }
+
+    #[test]
+    fn test_open_process_by_image_name() {
+        let request = OpenProcessData::by_image_name("notepad.exe", 0x1000).unwrap();
+        assert_eq!(OpenProcessBy::from_u32(request.by), Some(OpenProcessBy::ImageName));
+        assert!(request.image_name().iter().copied().eq("notepad.exe".encode_utf16()));
+        assert_eq!(request.buffer, 0x1000);
+
+        assert_eq!(OpenProcessData::by_image_name("", 0), None);
+        assert_eq!(OpenProcessData::by_image_name(&"a".repeat(PROCESS_NAME_LENGTH + 1), 0), None);
+        assert!(OpenProcessData::by_image_name(&"a".repeat(PROCESS_NAME_LENGTH), 0).is_some());
     }
 
-    /// Converts a pointer to `ClientCommand`.
-    pub fn from_ptr(ptr: u64) -> &'static ClientCommand {
-        unsafe { &*(ptr as *const ClientCommand) }
+    #[test]
+    fn test_unknown_command_is_rejected() {
+        let mut header = CommandHeader::new(Command::OpenProcess, size_of::<CommandHeader>());
+        header.command = 0xffff;
+        assert_eq!(header.validate(), Err(CommandStatus::Failure));
     }
 }